@@ -0,0 +1,9 @@
+CREATE TABLE playlist_shares (
+	id INTEGER PRIMARY KEY NOT NULL,
+	playlist INTEGER NOT NULL,
+	user INTEGER,
+	collaborative INTEGER NOT NULL,
+	FOREIGN KEY(playlist) REFERENCES playlists(id) ON DELETE CASCADE,
+	FOREIGN KEY(user) REFERENCES users(id) ON DELETE CASCADE,
+	UNIQUE(playlist, user) ON CONFLICT REPLACE
+);
@@ -0,0 +1,13 @@
+CREATE TEMPORARY TABLE playlist_songs_backup(id, playlist, path, ordering);
+INSERT INTO playlist_songs_backup SELECT id, playlist, path, ordering FROM playlist_songs;
+DROP TABLE playlist_songs;
+CREATE TABLE playlist_songs (
+	id INTEGER PRIMARY KEY NOT NULL,
+	playlist INTEGER NOT NULL,
+	path TEXT NOT NULL,
+	ordering INTEGER NOT NULL,
+	FOREIGN KEY(playlist) REFERENCES playlists(id) ON DELETE CASCADE ON UPDATE CASCADE,
+	UNIQUE(playlist, ordering) ON CONFLICT REPLACE
+);
+INSERT INTO playlist_songs SELECT * FROM playlist_songs_backup;
+DROP TABLE playlist_songs_backup;
@@ -0,0 +1,14 @@
+ALTER TABLE playlist_songs ADD COLUMN track_number INTEGER;
+ALTER TABLE playlist_songs ADD COLUMN disc_number INTEGER;
+ALTER TABLE playlist_songs ADD COLUMN title TEXT;
+ALTER TABLE playlist_songs ADD COLUMN artist TEXT;
+ALTER TABLE playlist_songs ADD COLUMN album TEXT;
+ALTER TABLE playlist_songs ADD COLUMN duration INTEGER;
+
+UPDATE playlist_songs SET
+	track_number = (SELECT s.track_number FROM songs s WHERE s.path = playlist_songs.path),
+	disc_number = (SELECT s.disc_number FROM songs s WHERE s.path = playlist_songs.path),
+	title = (SELECT s.title FROM songs s WHERE s.path = playlist_songs.path),
+	artist = (SELECT s.artist FROM songs s WHERE s.path = playlist_songs.path),
+	album = (SELECT s.album FROM songs s WHERE s.path = playlist_songs.path),
+	duration = (SELECT s.duration FROM songs s WHERE s.path = playlist_songs.path);
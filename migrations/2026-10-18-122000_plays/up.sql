@@ -0,0 +1,11 @@
+CREATE TABLE plays (
+	id INTEGER PRIMARY KEY NOT NULL,
+	user INTEGER NOT NULL,
+	path TEXT NOT NULL,
+	parent TEXT NOT NULL,
+	artist TEXT,
+	played_at INTEGER NOT NULL,
+	scrobbled INTEGER NOT NULL DEFAULT 0,
+	FOREIGN KEY(user) REFERENCES users(id) ON DELETE CASCADE
+);
+CREATE INDEX plays_user_played_at ON plays(user, played_at);
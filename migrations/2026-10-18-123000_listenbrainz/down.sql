@@ -0,0 +1,30 @@
+CREATE TEMPORARY TABLE users_backup(id, name, password_salt, password_hash, admin, lastfm_username, lastfm_session_key);
+INSERT INTO users_backup SELECT id, name, password_salt, password_hash, admin, lastfm_username, lastfm_session_key FROM users;
+DROP TABLE users;
+CREATE TABLE users (
+	id INTEGER PRIMARY KEY NOT NULL,
+	name TEXT NOT NULL,
+	password_salt BLOB NOT NULL,
+	password_hash BLOB NOT NULL,
+	admin INTEGER NOT NULL,
+	lastfm_username TEXT,
+	lastfm_session_key TEXT,
+	UNIQUE(name)
+);
+INSERT INTO users SELECT * FROM users_backup;
+DROP TABLE users_backup;
+
+CREATE TEMPORARY TABLE misc_settings_backup(id, auth_secret, index_sleep_duration_seconds, index_album_art_pattern, prefix_url);
+INSERT INTO misc_settings_backup
+SELECT id, auth_secret, index_sleep_duration_seconds, index_album_art_pattern, prefix_url
+FROM misc_settings;
+DROP TABLE misc_settings;
+CREATE TABLE misc_settings (
+	id INTEGER PRIMARY KEY NOT NULL CHECK(id = 0),
+	auth_secret BLOB NOT NULL DEFAULT (randomblob(32)),
+	index_sleep_duration_seconds INTEGER NOT NULL,
+	index_album_art_pattern TEXT NOT NULL,
+	prefix_url TEXT NOT NULL DEFAULT ""
+);
+INSERT INTO misc_settings SELECT * FROM misc_settings_backup;
+DROP TABLE misc_settings_backup;
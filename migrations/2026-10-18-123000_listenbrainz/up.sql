@@ -0,0 +1,2 @@
+ALTER TABLE users ADD COLUMN listenbrainz_token TEXT;
+ALTER TABLE misc_settings ADD COLUMN listenbrainz_url TEXT NOT NULL DEFAULT "https://api.listenbrainz.org";
@@ -0,0 +1,14 @@
+CREATE TABLE scrobble_queue (
+	id INTEGER PRIMARY KEY NOT NULL,
+	user INTEGER NOT NULL,
+	service TEXT NOT NULL,
+	artist TEXT,
+	title TEXT,
+	album TEXT,
+	track_number INTEGER,
+	duration INTEGER,
+	played_at INTEGER NOT NULL,
+	attempts INTEGER NOT NULL DEFAULT 0,
+	next_attempt INTEGER NOT NULL DEFAULT 0,
+	FOREIGN KEY(user) REFERENCES users(id) ON DELETE CASCADE
+);
@@ -0,0 +1,11 @@
+CREATE TABLE ratings (
+	id INTEGER PRIMARY KEY NOT NULL,
+	user INTEGER NOT NULL,
+	kind TEXT NOT NULL,
+	target TEXT NOT NULL,
+	rating INTEGER,
+	starred INTEGER NOT NULL DEFAULT 0,
+	FOREIGN KEY(user) REFERENCES users(id) ON DELETE CASCADE,
+	UNIQUE(user, kind, target) ON CONFLICT REPLACE
+);
+ALTER TABLE songs ADD COLUMN tag_rating INTEGER;
@@ -0,0 +1,4 @@
+ALTER TABLE songs ADD COLUMN replaygain_track_gain REAL;
+ALTER TABLE songs ADD COLUMN replaygain_track_peak REAL;
+ALTER TABLE songs ADD COLUMN replaygain_album_gain REAL;
+ALTER TABLE songs ADD COLUMN replaygain_album_peak REAL;
@@ -0,0 +1,9 @@
+CREATE TABLE loudness (
+	id INTEGER PRIMARY KEY NOT NULL,
+	path TEXT NOT NULL,
+	integrated REAL,
+	true_peak REAL NOT NULL,
+	modified INTEGER NOT NULL,
+	UNIQUE(path) ON CONFLICT REPLACE
+);
+ALTER TABLE misc_settings ADD COLUMN analyze_loudness INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,31 @@
+CREATE TEMPORARY TABLE songs_backup(id, path, parent, track_number, disc_number, title, artist, album_artist, year, album, artwork, duration, tag_rating, replaygain_track_gain, replaygain_track_peak, replaygain_album_gain, replaygain_album_peak, codec, bitrate, sample_rate, bit_depth, channels, file_size);
+INSERT INTO songs_backup SELECT id, path, parent, track_number, disc_number, title, artist, album_artist, year, album, artwork, duration, tag_rating, replaygain_track_gain, replaygain_track_peak, replaygain_album_gain, replaygain_album_peak, codec, bitrate, sample_rate, bit_depth, channels, file_size FROM songs;
+DROP TABLE songs;
+CREATE TABLE songs (
+	id INTEGER PRIMARY KEY NOT NULL,
+	path TEXT NOT NULL,
+	parent TEXT NOT NULL,
+	track_number INTEGER,
+	disc_number INTEGER,
+	title TEXT,
+	artist TEXT,
+	album_artist TEXT,
+	year INTEGER,
+	album TEXT,
+	artwork TEXT,
+	duration INTEGER,
+	tag_rating INTEGER,
+	replaygain_track_gain REAL,
+	replaygain_track_peak REAL,
+	replaygain_album_gain REAL,
+	replaygain_album_peak REAL,
+	codec TEXT,
+	bitrate INTEGER,
+	sample_rate INTEGER,
+	bit_depth INTEGER,
+	channels INTEGER,
+	file_size BIGINT,
+	UNIQUE(path) ON CONFLICT REPLACE
+);
+INSERT INTO songs SELECT * FROM songs_backup;
+DROP TABLE songs_backup;
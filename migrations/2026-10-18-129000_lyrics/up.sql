@@ -0,0 +1 @@
+ALTER TABLE songs ADD COLUMN has_lyrics INTEGER NOT NULL DEFAULT 0;
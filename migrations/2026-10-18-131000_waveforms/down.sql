@@ -0,0 +1,16 @@
+CREATE TEMPORARY TABLE misc_settings_backup(id, auth_secret, index_sleep_duration_seconds, index_album_art_pattern, prefix_url, listenbrainz_url, analyze_loudness);
+INSERT INTO misc_settings_backup
+SELECT id, auth_secret, index_sleep_duration_seconds, index_album_art_pattern, prefix_url, listenbrainz_url, analyze_loudness
+FROM misc_settings;
+DROP TABLE misc_settings;
+CREATE TABLE misc_settings (
+	id INTEGER PRIMARY KEY NOT NULL CHECK(id = 0),
+	auth_secret BLOB NOT NULL DEFAULT (randomblob(32)),
+	index_sleep_duration_seconds INTEGER NOT NULL,
+	index_album_art_pattern TEXT NOT NULL,
+	prefix_url TEXT NOT NULL DEFAULT "",
+	listenbrainz_url TEXT NOT NULL DEFAULT "https://api.listenbrainz.org",
+	analyze_loudness INTEGER NOT NULL DEFAULT 0
+);
+INSERT INTO misc_settings SELECT * FROM misc_settings_backup;
+DROP TABLE misc_settings_backup;
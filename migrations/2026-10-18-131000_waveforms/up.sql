@@ -0,0 +1 @@
+ALTER TABLE misc_settings ADD COLUMN precompute_waveforms INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,13 @@
+CREATE TABLE share_links (
+	id INTEGER PRIMARY KEY NOT NULL,
+	token TEXT NOT NULL,
+	owner INTEGER NOT NULL,
+	kind TEXT NOT NULL,
+	target TEXT NOT NULL,
+	created INTEGER NOT NULL,
+	expires INTEGER,
+	password_salt BLOB,
+	password_hash BLOB,
+	FOREIGN KEY(owner) REFERENCES users(id) ON DELETE CASCADE,
+	UNIQUE(token)
+);
@@ -0,0 +1,13 @@
+CREATE TABLE metadata_overrides (
+	id INTEGER PRIMARY KEY NOT NULL,
+	path TEXT NOT NULL,
+	title TEXT,
+	artist TEXT,
+	album_artist TEXT,
+	album TEXT,
+	year INTEGER,
+	track_number INTEGER,
+	disc_number INTEGER,
+	genre TEXT,
+	UNIQUE(path) ON CONFLICT REPLACE
+);
@@ -0,0 +1,70 @@
+CREATE TEMPORARY TABLE songs_backup(id, path, parent, track_number, disc_number, title, artist, album_artist, year, album, artwork, duration, tag_rating, replaygain_track_gain, replaygain_track_peak, replaygain_album_gain, replaygain_album_peak, codec, bitrate, sample_rate, bit_depth, channels, file_size, has_lyrics, cue_source, cue_start, cue_end, genre);
+INSERT INTO songs_backup SELECT id, path, parent, track_number, disc_number, title, artist, album_artist, year, album, artwork, duration, tag_rating, replaygain_track_gain, replaygain_track_peak, replaygain_album_gain, replaygain_album_peak, codec, bitrate, sample_rate, bit_depth, channels, file_size, has_lyrics, cue_source, cue_start, cue_end, genre FROM songs;
+DROP TABLE songs;
+CREATE TABLE songs (
+	id INTEGER PRIMARY KEY NOT NULL,
+	path TEXT NOT NULL,
+	parent TEXT NOT NULL,
+	track_number INTEGER,
+	disc_number INTEGER,
+	title TEXT,
+	artist TEXT,
+	album_artist TEXT,
+	year INTEGER,
+	album TEXT,
+	artwork TEXT,
+	duration INTEGER,
+	tag_rating INTEGER,
+	replaygain_track_gain REAL,
+	replaygain_track_peak REAL,
+	replaygain_album_gain REAL,
+	replaygain_album_peak REAL,
+	codec TEXT,
+	bitrate INTEGER,
+	sample_rate INTEGER,
+	bit_depth INTEGER,
+	channels INTEGER,
+	file_size BIGINT,
+	has_lyrics INTEGER NOT NULL DEFAULT 0,
+	cue_source TEXT,
+	cue_start INTEGER,
+	cue_end INTEGER,
+	genre TEXT,
+	UNIQUE(path) ON CONFLICT REPLACE
+);
+INSERT INTO songs SELECT * FROM songs_backup;
+DROP TABLE songs_backup;
+CREATE TEMPORARY TABLE directories_backup(id, path, parent, artist, year, album, artwork, date_added, disc_number);
+INSERT INTO directories_backup SELECT id, path, parent, artist, year, album, artwork, date_added, disc_number FROM directories;
+DROP TABLE directories;
+CREATE TABLE directories (
+	id INTEGER PRIMARY KEY NOT NULL,
+	path TEXT NOT NULL,
+	parent TEXT,
+	artist TEXT,
+	year INTEGER,
+	album TEXT,
+	artwork TEXT,
+	date_added INTEGER DEFAULT 0 NOT NULL,
+	disc_number INTEGER,
+	UNIQUE(path) ON CONFLICT REPLACE
+);
+INSERT INTO directories SELECT * FROM directories_backup;
+DROP TABLE directories_backup;
+CREATE TEMPORARY TABLE misc_settings_backup(id, auth_secret, index_sleep_duration_seconds, index_album_art_pattern, prefix_url, listenbrainz_url, analyze_loudness, precompute_waveforms);
+INSERT INTO misc_settings_backup
+SELECT id, auth_secret, index_sleep_duration_seconds, index_album_art_pattern, prefix_url, listenbrainz_url, analyze_loudness, precompute_waveforms
+FROM misc_settings;
+DROP TABLE misc_settings;
+CREATE TABLE misc_settings (
+	id INTEGER PRIMARY KEY NOT NULL CHECK(id = 0),
+	auth_secret BLOB NOT NULL DEFAULT (randomblob(32)),
+	index_sleep_duration_seconds INTEGER NOT NULL,
+	index_album_art_pattern TEXT NOT NULL,
+	prefix_url TEXT NOT NULL DEFAULT "",
+	listenbrainz_url TEXT NOT NULL DEFAULT "https://api.listenbrainz.org",
+	analyze_loudness INTEGER NOT NULL DEFAULT 0,
+	precompute_waveforms INTEGER NOT NULL DEFAULT 0
+);
+INSERT INTO misc_settings SELECT * FROM misc_settings_backup;
+DROP TABLE misc_settings_backup;
@@ -0,0 +1,8 @@
+ALTER TABLE songs ADD COLUMN sort_path TEXT;
+ALTER TABLE songs ADD COLUMN sort_title TEXT;
+ALTER TABLE songs ADD COLUMN sort_artist TEXT;
+ALTER TABLE songs ADD COLUMN sort_album TEXT;
+ALTER TABLE directories ADD COLUMN sort_path TEXT;
+ALTER TABLE directories ADD COLUMN sort_artist TEXT;
+ALTER TABLE directories ADD COLUMN sort_album TEXT;
+ALTER TABLE misc_settings ADD COLUMN index_sort_articles TEXT NOT NULL DEFAULT "The A An";
@@ -0,0 +1,7 @@
+CREATE TABLE search_index (
+	id INTEGER PRIMARY KEY NOT NULL,
+	path TEXT NOT NULL,
+	parent TEXT NOT NULL,
+	terms TEXT NOT NULL,
+	UNIQUE(path) ON CONFLICT REPLACE
+);
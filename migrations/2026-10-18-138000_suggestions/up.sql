@@ -0,0 +1,9 @@
+CREATE TABLE suggestions (
+	id INTEGER PRIMARY KEY NOT NULL,
+	kind TEXT NOT NULL,
+	value TEXT NOT NULL,
+	term TEXT NOT NULL,
+	weight INTEGER NOT NULL,
+	UNIQUE(kind, value, term) ON CONFLICT IGNORE
+);
+CREATE INDEX suggestions_prefix ON suggestions(kind, term);
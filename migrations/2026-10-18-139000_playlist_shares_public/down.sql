@@ -0,0 +1 @@
+DROP INDEX playlist_shares_public;
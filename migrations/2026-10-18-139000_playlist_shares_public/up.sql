@@ -0,0 +1,4 @@
+DELETE FROM playlist_shares WHERE user IS NULL AND id NOT IN (
+	SELECT MAX(id) FROM playlist_shares WHERE user IS NULL GROUP BY playlist
+);
+CREATE UNIQUE INDEX playlist_shares_public ON playlist_shares(playlist) WHERE user IS NULL;
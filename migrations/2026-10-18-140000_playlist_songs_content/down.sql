@@ -0,0 +1,19 @@
+CREATE TEMPORARY TABLE playlist_songs_backup(id, playlist, path, ordering, track_number, disc_number, title, artist, album, duration);
+INSERT INTO playlist_songs_backup SELECT id, playlist, path, ordering, track_number, disc_number, title, artist, album, duration FROM playlist_songs;
+DROP TABLE playlist_songs;
+CREATE TABLE playlist_songs (
+	id INTEGER PRIMARY KEY NOT NULL,
+	playlist INTEGER NOT NULL,
+	path TEXT NOT NULL,
+	ordering INTEGER NOT NULL,
+	track_number INTEGER,
+	disc_number INTEGER,
+	title TEXT,
+	artist TEXT,
+	album TEXT,
+	duration INTEGER,
+	FOREIGN KEY(playlist) REFERENCES playlists(id) ON DELETE CASCADE ON UPDATE CASCADE,
+	UNIQUE(playlist, ordering) ON CONFLICT REPLACE
+);
+INSERT INTO playlist_songs SELECT * FROM playlist_songs_backup;
+DROP TABLE playlist_songs_backup;
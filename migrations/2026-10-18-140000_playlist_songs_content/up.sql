@@ -0,0 +1,5 @@
+ALTER TABLE playlist_songs ADD COLUMN file_size BIGINT;
+ALTER TABLE playlist_songs ADD COLUMN content_hash TEXT;
+
+UPDATE playlist_songs SET
+	file_size = (SELECT s.file_size FROM songs s WHERE s.path = playlist_songs.path);
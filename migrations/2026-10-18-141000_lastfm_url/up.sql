@@ -0,0 +1 @@
+ALTER TABLE misc_settings ADD COLUMN lastfm_url TEXT NOT NULL DEFAULT "https://ws.audioscrobbler.com/2.0/";
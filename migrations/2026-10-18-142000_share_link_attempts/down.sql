@@ -0,0 +1,20 @@
+CREATE TEMPORARY TABLE share_links_backup(id, token, owner, kind, target, created, expires, password_salt, password_hash);
+INSERT INTO share_links_backup
+SELECT id, token, owner, kind, target, created, expires, password_salt, password_hash
+FROM share_links;
+DROP TABLE share_links;
+CREATE TABLE share_links (
+	id INTEGER PRIMARY KEY NOT NULL,
+	token TEXT NOT NULL,
+	owner INTEGER NOT NULL,
+	kind TEXT NOT NULL,
+	target TEXT NOT NULL,
+	created INTEGER NOT NULL,
+	expires INTEGER,
+	password_salt BLOB,
+	password_hash BLOB,
+	FOREIGN KEY(owner) REFERENCES users(id) ON DELETE CASCADE,
+	UNIQUE(token)
+);
+INSERT INTO share_links SELECT * FROM share_links_backup;
+DROP TABLE share_links_backup;
@@ -0,0 +1,2 @@
+ALTER TABLE share_links ADD COLUMN failed_attempts INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE share_links ADD COLUMN blocked_until INTEGER;
@@ -0,0 +1,18 @@
+CREATE TEMPORARY TABLE users_backup(id, name, password_salt, password_hash, admin, lastfm_username, lastfm_session_key, listenbrainz_token);
+INSERT INTO users_backup
+SELECT id, name, password_salt, password_hash, admin, lastfm_username, lastfm_session_key, listenbrainz_token
+FROM users;
+DROP TABLE users;
+CREATE TABLE users (
+	id INTEGER PRIMARY KEY NOT NULL,
+	name TEXT NOT NULL,
+	password_salt BLOB NOT NULL,
+	password_hash BLOB NOT NULL,
+	admin INTEGER NOT NULL,
+	lastfm_username TEXT,
+	lastfm_session_key TEXT,
+	listenbrainz_token TEXT,
+	UNIQUE(name)
+);
+INSERT INTO users SELECT * FROM users_backup;
+DROP TABLE users_backup;
@@ -0,0 +1,3 @@
+ALTER TABLE users ADD COLUMN listenbrainz_linked_at INTEGER;
+ALTER TABLE users ADD COLUMN listenbrainz_imported_until INTEGER NOT NULL DEFAULT 0;
+UPDATE users SET listenbrainz_linked_at = CAST(strftime('%s', 'now') AS INTEGER) WHERE listenbrainz_token IS NOT NULL;
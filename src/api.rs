@@ -1,8 +1,10 @@
 use error_chain::bail;
-use rocket::http::{Cookie, Cookies, RawStr, Status};
+use rand;
+use rocket::http::{ContentType, Cookie, Cookies, RawStr, Status};
 use rocket::request::{self, FromParam, FromRequest, Request};
-use rocket::response::content::Html;
-use rocket::{delete, get, post, put, routes, Outcome, State};
+use rocket::response::content::{Content, Html};
+use rocket::response::{self, Responder, Response};
+use rocket::{delete, get, post, put, routes, Data, Outcome, State};
 use rocket_contrib::json::Json;
 use serde::{Deserialize, Serialize};
 use std::fs::File;
@@ -12,21 +14,43 @@ use std::str;
 use std::str::FromStr;
 use std::sync::Arc;
 
+use crate::archive;
 use crate::config::{self, Config, Preferences};
+use crate::cue;
 use crate::db::DB;
 use crate::errors;
+use crate::history;
 use crate::index;
 use crate::lastfm;
+use crate::listenbrainz;
+use crate::loudness;
+use crate::lyrics;
+use crate::metadata;
+use crate::overrides;
 use crate::playlist;
+use crate::ratings;
+use crate::scrobble_queue;
 use crate::serve;
+use crate::share_links;
+use crate::suggestions;
+use crate::tag_editor;
 use crate::thumbnails;
+use crate::upload;
 use crate::user;
 use crate::utils;
 use crate::vfs::VFSSource;
+use crate::waveform;
 
 const CURRENT_MAJOR_VERSION: i32 = 3;
 const CURRENT_MINOR_VERSION: i32 = 0;
 const COOKIE_SESSION: &str = "session";
+const COOKIE_SHARE_LINK_PREFIX: &str = "share_";
+const DEFAULT_THUMBNAIL_SIZE: u32 = 400;
+const MAX_THUMBNAIL_SIZE: u32 = 1200;
+const DEFAULT_PAGE_LIMIT: i64 = 20;
+const MAX_PAGE_LIMIT: i64 = 500;
+const DEFAULT_SUGGESTIONS_LIMIT: i64 = 5;
+const MAX_SUGGESTIONS_LIMIT: i64 = 50;
 
 pub fn get_routes() -> Vec<rocket::Route> {
 	routes![
@@ -37,24 +61,69 @@ pub fn get_routes() -> Vec<rocket::Route> {
 		get_preferences,
 		put_preferences,
 		trigger_index,
+		loudness_progress,
 		auth,
 		browse_root,
 		browse,
 		flatten_root,
 		flatten,
 		random,
+		random_songs,
 		recent,
 		search_root,
+		search_suggest,
 		search,
+		genres,
+		years,
+		decades,
+		tag_albums,
+		tag_songs,
+		get_lyrics,
+		get_waveform,
+		edit_tags,
+		edit_song_tags,
+		list_overrides,
+		set_override,
+		delete_override,
+		get_upload,
+		upload,
+		cancel_upload,
 		serve,
+		download,
 		list_playlists,
+		list_shared_playlists,
 		save_playlist,
 		read_playlist,
+		download_playlist,
 		delete_playlist,
+		read_playlist_shares,
+		read_playlist_missing_songs,
+		save_playlist_shares,
+		list_share_links,
+		create_share_link,
+		delete_share_link,
+		read_share_link,
+		unlock_share_link,
+		serve_share_link,
+		serve_share_link_thumbnail,
+		recently_played,
+		top_songs,
+		top_albums,
+		top_artists,
 		lastfm_link,
 		lastfm_unlink,
 		lastfm_now_playing,
 		lastfm_scrobble,
+		lastfm_love,
+		lastfm_unlove,
+		lastfm_import_loved_tracks,
+		listenbrainz_link,
+		listenbrainz_unlink,
+		listenbrainz_import,
+		rate_song,
+		rate_album,
+		rate_artist,
+		starred,
 	]
 }
 
@@ -230,6 +299,11 @@ struct AuthOutput {
 	admin: bool,
 }
 
+#[get("/loudness/progress")]
+fn loudness_progress(_admin_rights: AdminRights) -> Json<loudness::Progress> {
+	Json(loudness::get_progress())
+}
+
 #[post("/auth", data = "<credentials>")]
 fn auth(
 	db: State<'_, Arc<DB>>,
@@ -248,79 +322,550 @@ fn auth(
 	Ok(Json(auth_output))
 }
 
-#[get("/browse")]
+// Responds with one page of results, the size of the whole set goes in a header
+struct Paginated<T>(Vec<T>, i64);
+
+impl<'r, T: Serialize> Responder<'r> for Paginated<T> {
+	fn respond_to(self, request: &Request<'_>) -> response::Result<'r> {
+		Response::build_from(Json(self.0).respond_to(request)?)
+			.raw_header("X-Total-Count", self.1.to_string())
+			.ok()
+	}
+}
+
+fn get_page(
+	offset: Option<i64>,
+	limit: Option<i64>,
+	sort: Option<String>,
+	order: Option<String>,
+) -> Result<index::Page, errors::Error> {
+	let offset = offset.unwrap_or(0);
+	if offset < 0 {
+		bail!(errors::ErrorKind::InvalidPage("negative offset".into()));
+	}
+	if limit.map_or(false, |l| l < 0) {
+		bail!(errors::ErrorKind::InvalidPage("negative limit".into()));
+	}
+	let sort =
+		match sort {
+			Some(name) => Some(index::SortKey::from_name(&name).ok_or_else(|| {
+				errors::ErrorKind::InvalidPage(format!("unknown sort key {}", name))
+			})?),
+			None => None,
+		};
+	let descending = match order.as_ref().map(String::as_str) {
+		None | Some("asc") => false,
+		Some("desc") => true,
+		Some(o) => bail!(errors::ErrorKind::InvalidPage(format!(
+			"unknown order {}",
+			o
+		))),
+	};
+	Ok(index::Page {
+		offset,
+		limit,
+		sort,
+		descending,
+	})
+}
+
+fn get_default_limit(limit: Option<i64>) -> Result<i64, errors::Error> {
+	match limit {
+		Some(l) if l < 0 => bail!(errors::ErrorKind::InvalidPage("negative limit".into())),
+		Some(l) => Ok(l.min(MAX_PAGE_LIMIT)),
+		None => Ok(DEFAULT_PAGE_LIMIT),
+	}
+}
+
+#[get("/browse?<offset>&<limit>&<sort>&<order>")]
 fn browse_root(
 	db: State<'_, Arc<DB>>,
-	_auth: Auth,
-) -> Result<Json<Vec<index::CollectionFile>>, errors::Error> {
-	let result = index::browse(db.deref().deref(), &PathBuf::new())?;
-	Ok(Json(result))
+	auth: Auth,
+	offset: Option<i64>,
+	limit: Option<i64>,
+	sort: Option<String>,
+	order: Option<String>,
+) -> Result<Paginated<index::CollectionFile>, errors::Error> {
+	let page = get_page(offset, limit, sort, order)?;
+	let mut result = index::browse_page(db.deref().deref(), &PathBuf::new(), &page)?;
+	overrides::annotate_collection_files(db.deref().deref(), &mut result.items)?;
+	history::annotate_collection_files(db.deref().deref(), &auth.username, &mut result.items)?;
+	ratings::annotate_collection_files(db.deref().deref(), &auth.username, &mut result.items)?;
+	loudness::annotate_collection_files(db.deref().deref(), &mut result.items)?;
+	Ok(Paginated(result.items, result.total))
 }
 
-#[get("/browse/<path>")]
+#[get("/browse/<path>?<offset>&<limit>&<sort>&<order>")]
 fn browse(
 	db: State<'_, Arc<DB>>,
-	_auth: Auth,
+	auth: Auth,
 	path: VFSPathBuf,
-) -> Result<Json<Vec<index::CollectionFile>>, errors::Error> {
-	let result = index::browse(db.deref().deref(), &path.into() as &PathBuf)?;
-	Ok(Json(result))
+	offset: Option<i64>,
+	limit: Option<i64>,
+	sort: Option<String>,
+	order: Option<String>,
+) -> Result<Paginated<index::CollectionFile>, errors::Error> {
+	let page = get_page(offset, limit, sort, order)?;
+	let mut result = index::browse_page(db.deref().deref(), &path.into() as &PathBuf, &page)?;
+	overrides::annotate_collection_files(db.deref().deref(), &mut result.items)?;
+	history::annotate_collection_files(db.deref().deref(), &auth.username, &mut result.items)?;
+	ratings::annotate_collection_files(db.deref().deref(), &auth.username, &mut result.items)?;
+	loudness::annotate_collection_files(db.deref().deref(), &mut result.items)?;
+	Ok(Paginated(result.items, result.total))
 }
 
-#[get("/flatten")]
+#[get("/flatten?<offset>&<limit>&<sort>&<order>")]
 fn flatten_root(
 	db: State<'_, Arc<DB>>,
-	_auth: Auth,
-) -> Result<Json<Vec<index::Song>>, errors::Error> {
-	let result = index::flatten(db.deref().deref(), &PathBuf::new())?;
-	Ok(Json(result))
+	auth: Auth,
+	offset: Option<i64>,
+	limit: Option<i64>,
+	sort: Option<String>,
+	order: Option<String>,
+) -> Result<Paginated<index::Song>, errors::Error> {
+	let page = get_page(offset, limit, sort, order)?;
+	let mut result = index::flatten_page(db.deref().deref(), &PathBuf::new(), &page)?;
+	overrides::annotate_songs(db.deref().deref(), result.items.iter_mut())?;
+	history::annotate_songs(db.deref().deref(), &auth.username, result.items.iter_mut())?;
+	ratings::annotate_songs(db.deref().deref(), &auth.username, result.items.iter_mut())?;
+	loudness::annotate_songs(db.deref().deref(), result.items.iter_mut())?;
+	Ok(Paginated(result.items, result.total))
 }
 
-#[get("/flatten/<path>")]
+#[get("/flatten/<path>?<offset>&<limit>&<sort>&<order>")]
 fn flatten(
 	db: State<'_, Arc<DB>>,
-	_auth: Auth,
+	auth: Auth,
 	path: VFSPathBuf,
-) -> Result<Json<Vec<index::Song>>, errors::Error> {
-	let result = index::flatten(db.deref().deref(), &path.into() as &PathBuf)?;
-	Ok(Json(result))
+	offset: Option<i64>,
+	limit: Option<i64>,
+	sort: Option<String>,
+	order: Option<String>,
+) -> Result<Paginated<index::Song>, errors::Error> {
+	let page = get_page(offset, limit, sort, order)?;
+	let mut result = index::flatten_page(db.deref().deref(), &path.into() as &PathBuf, &page)?;
+	overrides::annotate_songs(db.deref().deref(), result.items.iter_mut())?;
+	history::annotate_songs(db.deref().deref(), &auth.username, result.items.iter_mut())?;
+	ratings::annotate_songs(db.deref().deref(), &auth.username, result.items.iter_mut())?;
+	loudness::annotate_songs(db.deref().deref(), result.items.iter_mut())?;
+	Ok(Paginated(result.items, result.total))
 }
 
-#[get("/random")]
+// Random albums are drawn anew on each request, so they only come in a single page
+#[get("/random?<limit>")]
 fn random(
 	db: State<'_, Arc<DB>>,
-	_auth: Auth,
-) -> Result<Json<Vec<index::Directory>>, errors::Error> {
-	let result = index::get_random_albums(db.deref().deref(), 20)?;
-	Ok(Json(result))
+	auth: Auth,
+	limit: Option<i64>,
+) -> Result<Paginated<index::Directory>, errors::Error> {
+	let limit = get_default_limit(limit)?;
+	let mut result = index::get_random_albums(db.deref().deref(), limit)?;
+	let total = index::count_albums(db.deref().deref())?;
+	overrides::annotate_directories(db.deref().deref(), result.iter_mut())?;
+	ratings::annotate_directories(db.deref().deref(), &auth.username, result.iter_mut())?;
+	Ok(Paginated(result, total))
+}
+
+// Pages of a shuffle, clients pass the seed back to keep paging through the same one
+struct Shuffled<T>(Paginated<T>, u64);
+
+impl<'r, T: Serialize> Responder<'r> for Shuffled<T> {
+	fn respond_to(self, request: &Request<'_>) -> response::Result<'r> {
+		Response::build_from(self.0.respond_to(request)?)
+			.raw_header("X-Random-Seed", self.1.to_string())
+			.ok()
+	}
+}
+
+#[get(
+	"/random/songs?<genre>&<from_year>&<to_year>&<mount>&<unplayed>&<min_rating>&<seed>&<offset>&<limit>"
+)]
+fn random_songs(
+	db: State<'_, Arc<DB>>,
+	auth: Auth,
+	genre: Option<String>,
+	from_year: Option<i32>,
+	to_year: Option<i32>,
+	mount: Option<String>,
+	unplayed: Option<bool>,
+	min_rating: Option<i32>,
+	seed: Option<u64>,
+	offset: Option<i64>,
+	limit: Option<i64>,
+) -> Result<Shuffled<index::Song>, errors::Error> {
+	let filters = index::RandomFilters {
+		tags: index::TagFilters {
+			genre,
+			from_year,
+			to_year,
+		},
+		mount,
+		unplayed: unplayed.unwrap_or(false),
+		min_rating,
+	};
+	let page = get_page(offset, None, None, None)?;
+	let limit = get_default_limit(limit)?;
+	let seed = seed.unwrap_or_else(rand::random);
+	let mut result = index::get_random_songs(
+		db.deref().deref(),
+		&auth.username,
+		&filters,
+		seed,
+		page.offset,
+		limit,
+	)?;
+	overrides::annotate_songs(db.deref().deref(), result.items.iter_mut())?;
+	history::annotate_songs(db.deref().deref(), &auth.username, result.items.iter_mut())?;
+	ratings::annotate_songs(db.deref().deref(), &auth.username, result.items.iter_mut())?;
+	loudness::annotate_songs(db.deref().deref(), result.items.iter_mut())?;
+	Ok(Shuffled(Paginated(result.items, result.total), seed))
 }
 
-#[get("/recent")]
+#[get("/recent?<offset>&<limit>")]
 fn recent(
 	db: State<'_, Arc<DB>>,
-	_auth: Auth,
-) -> Result<Json<Vec<index::Directory>>, errors::Error> {
-	let result = index::get_recent_albums(db.deref().deref(), 20)?;
-	Ok(Json(result))
+	auth: Auth,
+	offset: Option<i64>,
+	limit: Option<i64>,
+) -> Result<Paginated<index::Directory>, errors::Error> {
+	let page = get_page(offset, None, None, None)?;
+	let limit = get_default_limit(limit)?;
+	let mut result = index::get_recent_albums(db.deref().deref(), page.offset, limit)?;
+	let total = index::count_albums(db.deref().deref())?;
+	overrides::annotate_directories(db.deref().deref(), result.iter_mut())?;
+	ratings::annotate_directories(db.deref().deref(), &auth.username, result.iter_mut())?;
+	Ok(Paginated(result, total))
 }
 
-#[get("/search")]
+#[get(
+	"/search?<codec>&<lossless>&<min_sample_rate>&<min_bit_depth>&<min_bitrate>&<has_lyrics>&<offset>&<limit>&<sort>&<order>"
+)]
 fn search_root(
+	db: State<'_, Arc<DB>>,
+	auth: Auth,
+	codec: Option<String>,
+	lossless: Option<bool>,
+	min_sample_rate: Option<i32>,
+	min_bit_depth: Option<i32>,
+	min_bitrate: Option<i32>,
+	has_lyrics: Option<bool>,
+	offset: Option<i64>,
+	limit: Option<i64>,
+	sort: Option<String>,
+	order: Option<String>,
+) -> Result<Paginated<index::CollectionFile>, errors::Error> {
+	let filters = index::SearchFilters {
+		codec,
+		lossless,
+		min_sample_rate,
+		min_bit_depth,
+		min_bitrate,
+		has_lyrics,
+	};
+	let page = get_page(offset, limit, sort, order)?;
+	let mut result = index::search_page(db.deref().deref(), "", &filters, &page)?;
+	overrides::annotate_collection_files(db.deref().deref(), &mut result.items)?;
+	history::annotate_collection_files(db.deref().deref(), &auth.username, &mut result.items)?;
+	ratings::annotate_collection_files(db.deref().deref(), &auth.username, &mut result.items)?;
+	loudness::annotate_collection_files(db.deref().deref(), &mut result.items)?;
+	Ok(Paginated(result.items, result.total))
+}
+
+#[get("/search/suggest?<q>&<limit>")]
+fn search_suggest(
 	db: State<'_, Arc<DB>>,
 	_auth: Auth,
-) -> Result<Json<Vec<index::CollectionFile>>, errors::Error> {
-	let result = index::search(db.deref().deref(), "")?;
-	Ok(Json(result))
+	q: String,
+	limit: Option<i64>,
+) -> Result<Json<suggestions::Suggestions>, errors::Error> {
+	let limit = match limit {
+		Some(l) if l < 0 => bail!(errors::ErrorKind::InvalidPage("negative limit".into())),
+		Some(l) => l.min(MAX_SUGGESTIONS_LIMIT),
+		None => DEFAULT_SUGGESTIONS_LIMIT,
+	};
+	let suggestions = suggestions::suggest(db.deref().deref(), &q, limit)?;
+	Ok(Json(suggestions))
 }
 
-#[get("/search/<query>")]
+#[get(
+	"/search/<query>?<codec>&<lossless>&<min_sample_rate>&<min_bit_depth>&<min_bitrate>&<has_lyrics>&<offset>&<limit>&<sort>&<order>"
+)]
 fn search(
 	db: State<'_, Arc<DB>>,
-	_auth: Auth,
+	auth: Auth,
 	query: String,
-) -> Result<Json<Vec<index::CollectionFile>>, errors::Error> {
-	let result = index::search(db.deref().deref(), &query)?;
-	Ok(Json(result))
+	codec: Option<String>,
+	lossless: Option<bool>,
+	min_sample_rate: Option<i32>,
+	min_bit_depth: Option<i32>,
+	min_bitrate: Option<i32>,
+	has_lyrics: Option<bool>,
+	offset: Option<i64>,
+	limit: Option<i64>,
+	sort: Option<String>,
+	order: Option<String>,
+) -> Result<Paginated<index::CollectionFile>, errors::Error> {
+	let filters = index::SearchFilters {
+		codec,
+		lossless,
+		min_sample_rate,
+		min_bit_depth,
+		min_bitrate,
+		has_lyrics,
+	};
+	let page = get_page(offset, limit, sort, order)?;
+	let mut result = index::search_page(db.deref().deref(), &query, &filters, &page)?;
+	overrides::annotate_collection_files(db.deref().deref(), &mut result.items)?;
+	history::annotate_collection_files(db.deref().deref(), &auth.username, &mut result.items)?;
+	ratings::annotate_collection_files(db.deref().deref(), &auth.username, &mut result.items)?;
+	loudness::annotate_collection_files(db.deref().deref(), &mut result.items)?;
+	Ok(Paginated(result.items, result.total))
+}
+
+#[get("/genres")]
+fn genres(db: State<'_, Arc<DB>>, _auth: Auth) -> Result<Json<Vec<index::Genre>>, errors::Error> {
+	let genres = index::get_genres(db.deref().deref())?;
+	Ok(Json(genres))
+}
+
+#[get("/years")]
+fn years(db: State<'_, Arc<DB>>, _auth: Auth) -> Result<Json<Vec<index::Year>>, errors::Error> {
+	let years = index::get_years(db.deref().deref())?;
+	Ok(Json(years))
+}
+
+#[get("/decades")]
+fn decades(db: State<'_, Arc<DB>>, _auth: Auth) -> Result<Json<Vec<index::Decade>>, errors::Error> {
+	let decades = index::get_decades(db.deref().deref())?;
+	Ok(Json(decades))
+}
+
+#[get("/albums?<genre>&<from_year>&<to_year>&<offset>&<limit>&<sort>&<order>")]
+fn tag_albums(
+	db: State<'_, Arc<DB>>,
+	auth: Auth,
+	genre: Option<String>,
+	from_year: Option<i32>,
+	to_year: Option<i32>,
+	offset: Option<i64>,
+	limit: Option<i64>,
+	sort: Option<String>,
+	order: Option<String>,
+) -> Result<Paginated<index::Directory>, errors::Error> {
+	let filters = index::TagFilters {
+		genre,
+		from_year,
+		to_year,
+	};
+	let page = get_page(offset, limit, sort, order)?;
+	let mut result = index::get_tag_albums(db.deref().deref(), &filters, &page)?;
+	overrides::annotate_directories(db.deref().deref(), result.items.iter_mut())?;
+	ratings::annotate_directories(db.deref().deref(), &auth.username, result.items.iter_mut())?;
+	Ok(Paginated(result.items, result.total))
+}
+
+#[get("/songs?<genre>&<from_year>&<to_year>&<offset>&<limit>&<sort>&<order>")]
+fn tag_songs(
+	db: State<'_, Arc<DB>>,
+	auth: Auth,
+	genre: Option<String>,
+	from_year: Option<i32>,
+	to_year: Option<i32>,
+	offset: Option<i64>,
+	limit: Option<i64>,
+	sort: Option<String>,
+	order: Option<String>,
+) -> Result<Paginated<index::Song>, errors::Error> {
+	let filters = index::TagFilters {
+		genre,
+		from_year,
+		to_year,
+	};
+	let page = get_page(offset, limit, sort, order)?;
+	let mut result = index::get_tag_songs(db.deref().deref(), &filters, &page)?;
+	overrides::annotate_songs(db.deref().deref(), result.items.iter_mut())?;
+	history::annotate_songs(db.deref().deref(), &auth.username, result.items.iter_mut())?;
+	ratings::annotate_songs(db.deref().deref(), &auth.username, result.items.iter_mut())?;
+	loudness::annotate_songs(db.deref().deref(), result.items.iter_mut())?;
+	Ok(Paginated(result.items, result.total))
+}
+
+#[get("/lyrics/<path>")]
+fn get_lyrics(
+	db: State<'_, Arc<DB>>,
+	_auth: Auth,
+	path: VFSPathBuf,
+) -> Result<Option<Json<lyrics::Lyrics>>, errors::Error> {
+	let db: &DB = db.deref().deref();
+	let vfs = db.get_vfs()?;
+	let real_path = vfs.virtual_to_real(&path.into() as &PathBuf)?;
+	Ok(lyrics::read(&real_path)?.map(Json))
+}
+
+#[get("/waveform/<path>?<buckets>&<format>")]
+fn get_waveform(
+	db: State<'_, Arc<DB>>,
+	_auth: Auth,
+	path: VFSPathBuf,
+	buckets: Option<usize>,
+	format: Option<String>,
+) -> Result<Content<Vec<u8>>, errors::Error> {
+	let db: &DB = db.deref().deref();
+	let vfs = db.get_vfs()?;
+	let real_path = vfs.virtual_to_real(&path.into() as &PathBuf)?;
+	let buckets = buckets
+		.unwrap_or(waveform::DEFAULT_BUCKETS)
+		.max(1)
+		.min(waveform::MAX_BUCKETS);
+
+	// Checked before doing any decoding work
+	let binary = match format.as_ref().map(String::as_str) {
+		None | Some("json") => false,
+		Some("binary") => true,
+		Some(f) => bail!(errors::ErrorKind::UnsupportedWaveformFormat(f.to_owned())),
+	};
+
+	let waveform = match index::get_cue_range(db, &real_path)? {
+		Some((source, start, end)) => waveform::get_waveform(&source, Some((start, end)), buckets)?,
+		None => waveform::get_waveform(&real_path, None, buckets)?,
+	};
+
+	if binary {
+		Ok(Content(ContentType::Binary, waveform.to_bytes()))
+	} else {
+		Ok(Content(ContentType::JSON, serde_json::to_vec(&waveform)?))
+	}
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct EditTagsInput {
+	pub paths: Vec<String>,
+	pub tags: metadata::TagEdits,
+}
+
+#[put("/tags", data = "<input>")]
+fn edit_tags(
+	db: State<'_, Arc<DB>>,
+	_admin_rights: AdminRights,
+	input: Json<EditTagsInput>,
+) -> Result<Json<Vec<index::Song>>, errors::Error> {
+	let songs = tag_editor::edit_songs(db.deref().deref(), &input.paths, &input.tags)?;
+	Ok(Json(songs))
+}
+
+#[put("/tags/<path>", data = "<tags>")]
+fn edit_song_tags(
+	db: State<'_, Arc<DB>>,
+	_admin_rights: AdminRights,
+	path: VFSPathBuf,
+	tags: Json<metadata::TagEdits>,
+) -> Result<Json<index::Song>, errors::Error> {
+	let path: PathBuf = path.into();
+	let paths = vec![path.to_string_lossy().into_owned()];
+	let mut songs = tag_editor::edit_songs(db.deref().deref(), &paths, &tags)?;
+	Ok(Json(songs.remove(0)))
+}
+
+#[get("/overrides")]
+fn list_overrides(
+	db: State<'_, Arc<DB>>,
+	_admin_rights: AdminRights,
+) -> Result<Json<Vec<overrides::MetadataOverride>>, errors::Error> {
+	let overrides = overrides::list(db.deref().deref())?;
+	Ok(Json(overrides))
+}
+
+#[put("/overrides", data = "<metadata_override>")]
+fn set_override(
+	db: State<'_, Arc<DB>>,
+	_admin_rights: AdminRights,
+	metadata_override: Json<overrides::MetadataOverride>,
+) -> Result<(), errors::Error> {
+	overrides::set(db.deref().deref(), &metadata_override)?;
+	Ok(())
+}
+
+#[delete("/overrides/<path>")]
+fn delete_override(
+	db: State<'_, Arc<DB>>,
+	_admin_rights: AdminRights,
+	path: VFSPathBuf,
+) -> Result<(), errors::Error> {
+	let path: PathBuf = path.into();
+	overrides::delete(db.deref().deref(), &path)?;
+	Ok(())
+}
+
+#[get("/upload/<path>?<session>")]
+fn get_upload(
+	_admin_rights: AdminRights,
+	path: VFSPathBuf,
+	session: String,
+) -> Result<Json<upload::UploadStatus>, errors::Error> {
+	let path: PathBuf = path.into();
+	let status = upload::get_status(&path, &session)?;
+	Ok(Json(status))
+}
+
+#[put(
+	"/upload/<path>?<session>&<offset>&<complete>&<on_conflict>",
+	data = "<data>"
+)]
+fn upload(
+	db: State<'_, Arc<DB>>,
+	_admin_rights: AdminRights,
+	path: VFSPathBuf,
+	session: Option<String>,
+	offset: Option<u64>,
+	complete: Option<bool>,
+	on_conflict: Option<String>,
+	data: Data,
+) -> Result<Json<upload::UploadStatus>, errors::Error> {
+	let path: PathBuf = path.into();
+	let on_conflict = match on_conflict {
+		Some(name) => upload::OnConflict::from_name(&name).ok_or("Unknown conflict handling")?,
+		None => upload::OnConflict::default(),
+	};
+	let mut status = upload::write_chunk(
+		db.deref().deref(),
+		&path,
+		session.as_ref().map(|s| s.as_str()),
+		offset.unwrap_or(0),
+		&mut data.open(),
+	)?;
+	if complete.unwrap_or(true) {
+		status = upload::complete(db.deref().deref(), &path, &status.session, on_conflict)?;
+	}
+	Ok(Json(status))
+}
+
+#[delete("/upload/<path>?<session>")]
+fn cancel_upload(
+	_admin_rights: AdminRights,
+	path: VFSPathBuf,
+	session: String,
+) -> Result<(), errors::Error> {
+	let path: PathBuf = path.into();
+	upload::cancel(&path, &session)?;
+	Ok(())
+}
+
+#[get("/download/<path>")]
+fn download(
+	db: State<'_, Arc<DB>>,
+	_auth: Auth,
+	path: VFSPathBuf,
+) -> Result<archive::Archive, errors::Error> {
+	let db: &DB = db.deref().deref();
+	let vfs = db.get_vfs()?;
+	let path: PathBuf = path.into();
+	let songs = index::flatten(db, &path)?;
+	let entries = archive::get_directory_entries(&vfs, &path, &songs);
+	let name = path
+		.file_name()
+		.map(|n| n.to_string_lossy().into_owned())
+		.unwrap_or_else(|| "collection".to_owned());
+	archive::Archive::new(&name, entries)
 }
 
 #[get("/serve/<path>")]
@@ -332,9 +877,14 @@ fn serve(
 	let db: &DB = db.deref().deref();
 	let vfs = db.get_vfs()?;
 	let real_path = vfs.virtual_to_real(&path.into() as &PathBuf)?;
+	serve_file(db, real_path)
+}
 
+fn serve_file(db: &DB, real_path: PathBuf) -> Result<serve::RangeResponder<File>, errors::Error> {
 	let serve_path = if utils::is_image(&real_path) {
-		thumbnails::get_thumbnail(&real_path, 400)?
+		thumbnails::get_thumbnail(&real_path, DEFAULT_THUMBNAIL_SIZE)?
+	} else if let Some((source, start, end)) = index::get_cue_range(db, &real_path)? {
+		cue::extract(&source, start, end)?
 	} else {
 		real_path
 	};
@@ -367,40 +917,286 @@ pub struct SavePlaylistInput {
 	pub tracks: Vec<String>,
 }
 
-#[put("/playlist/<name>", data = "<playlist>")]
+#[get("/playlists/shared")]
+fn list_shared_playlists(
+	db: State<'_, Arc<DB>>,
+	auth: Auth,
+) -> Result<Json<Vec<playlist::SharedPlaylist>>, errors::Error> {
+	let playlists = playlist::list_shared_playlists(&auth.username, db.deref().deref())?;
+	Ok(Json(playlists))
+}
+
+#[put("/playlist/<name>?<owner>", data = "<playlist>")]
 fn save_playlist(
 	db: State<'_, Arc<DB>>,
 	auth: Auth,
 	name: String,
+	owner: Option<String>,
 	playlist: Json<SavePlaylistInput>,
 ) -> Result<(), errors::Error> {
-	playlist::save_playlist(&name, &auth.username, &playlist.tracks, db.deref().deref())?;
+	let owner = owner.as_ref().unwrap_or(&auth.username);
+	playlist::save_playlist(
+		&name,
+		owner,
+		&auth.username,
+		&playlist.tracks,
+		db.deref().deref(),
+	)?;
 	Ok(())
 }
 
-#[get("/playlist/<name>")]
+#[get("/playlist/<name>?<owner>")]
 fn read_playlist(
 	db: State<'_, Arc<DB>>,
 	auth: Auth,
 	name: String,
+	owner: Option<String>,
 ) -> Result<Json<Vec<index::Song>>, errors::Error> {
-	let songs = playlist::read_playlist(&name, &auth.username, db.deref().deref())?;
+	let owner = owner.as_ref().unwrap_or(&auth.username);
+	let mut songs = playlist::read_playlist(&name, owner, &auth.username, db.deref().deref())?;
+	overrides::annotate_songs(db.deref().deref(), songs.iter_mut())?;
+	history::annotate_songs(db.deref().deref(), &auth.username, songs.iter_mut())?;
+	ratings::annotate_songs(db.deref().deref(), &auth.username, songs.iter_mut())?;
+	loudness::annotate_songs(db.deref().deref(), songs.iter_mut())?;
 	Ok(Json(songs))
 }
 
-#[delete("/playlist/<name>")]
-fn delete_playlist(db: State<'_, Arc<DB>>, auth: Auth, name: String) -> Result<(), errors::Error> {
-	playlist::delete_playlist(&name, &auth.username, db.deref().deref())?;
+#[get("/playlist/<name>/download?<owner>")]
+fn download_playlist(
+	db: State<'_, Arc<DB>>,
+	auth: Auth,
+	name: String,
+	owner: Option<String>,
+) -> Result<archive::Archive, errors::Error> {
+	let db: &DB = db.deref().deref();
+	let owner = owner.as_ref().unwrap_or(&auth.username);
+	let songs = playlist::read_playlist(&name, owner, &auth.username, db)?;
+	let entries = archive::get_playlist_entries(&db.get_vfs()?, &songs);
+	archive::Archive::new(&name, entries)
+}
+
+#[delete("/playlist/<name>?<owner>")]
+fn delete_playlist(
+	db: State<'_, Arc<DB>>,
+	auth: Auth,
+	name: String,
+	owner: Option<String>,
+) -> Result<(), errors::Error> {
+	let owner = owner.as_ref().unwrap_or(&auth.username);
+	playlist::delete_playlist(&name, owner, &auth.username, db.deref().deref())?;
+	Ok(())
+}
+
+#[get("/playlist/<name>/missing?<owner>")]
+fn read_playlist_missing_songs(
+	db: State<'_, Arc<DB>>,
+	auth: Auth,
+	name: String,
+	owner: Option<String>,
+) -> Result<Json<Vec<playlist::MissingSong>>, errors::Error> {
+	let owner = owner.as_ref().unwrap_or(&auth.username);
+	let songs = playlist::read_missing_songs(&name, owner, &auth.username, db.deref().deref())?;
+	Ok(Json(songs))
+}
+
+#[get("/playlist/<name>/shares")]
+fn read_playlist_shares(
+	db: State<'_, Arc<DB>>,
+	auth: Auth,
+	name: String,
+) -> Result<Json<Vec<playlist::PlaylistShare>>, errors::Error> {
+	let shares = playlist::read_shares(&name, &auth.username, db.deref().deref())?;
+	Ok(Json(shares))
+}
+
+#[put("/playlist/<name>/shares", data = "<shares>")]
+fn save_playlist_shares(
+	db: State<'_, Arc<DB>>,
+	auth: Auth,
+	name: String,
+	shares: Json<Vec<playlist::PlaylistShare>>,
+) -> Result<(), errors::Error> {
+	playlist::save_shares(&name, &auth.username, &shares, db.deref().deref())?;
 	Ok(())
 }
 
+#[get("/share_links")]
+fn list_share_links(
+	db: State<'_, Arc<DB>>,
+	auth: Auth,
+) -> Result<Json<Vec<share_links::ShareLink>>, errors::Error> {
+	let share_links = share_links::list(db.deref().deref(), &auth.username)?;
+	Ok(Json(share_links))
+}
+
+#[post("/share_links", data = "<share_link>")]
+fn create_share_link(
+	db: State<'_, Arc<DB>>,
+	auth: Auth,
+	share_link: Json<share_links::NewShareLink>,
+) -> Result<Json<share_links::ShareLink>, errors::Error> {
+	let share_link = share_links::create(db.deref().deref(), &auth.username, &share_link)?;
+	Ok(Json(share_link))
+}
+
+#[delete("/share_links/<token>")]
+fn delete_share_link(
+	db: State<'_, Arc<DB>>,
+	auth: Auth,
+	token: String,
+) -> Result<(), errors::Error> {
+	share_links::delete(db.deref().deref(), &auth.username, &token)?;
+	Ok(())
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ShareLinkCredentials {
+	pub password: String,
+}
+
+// Share link endpoints do not require an account, password protected links are
+// unlocked by a private cookie specific to each link.
+fn open_share_link(
+	db: &DB,
+	cookies: &mut Cookies<'_>,
+	token: &str,
+) -> Result<share_links::OpenShareLink, errors::Error> {
+	let cookie_name = format!("{}{}", COOKIE_SHARE_LINK_PREFIX, token);
+	let unlocked = cookies
+		.get_private(&cookie_name)
+		.map_or(false, |c| c.value() == token);
+	share_links::open(db, token, unlocked)
+}
+
+#[get("/share/<token>")]
+fn read_share_link(
+	db: State<'_, Arc<DB>>,
+	mut cookies: Cookies<'_>,
+	token: String,
+) -> Result<Json<share_links::SharedContent>, errors::Error> {
+	let db: &DB = db.deref().deref();
+	let share_link = open_share_link(db, &mut cookies, &token)?;
+	Ok(Json(share_links::get_content(db, &share_link)?))
+}
+
+#[post("/share/<token>/auth", data = "<credentials>")]
+fn unlock_share_link(
+	db: State<'_, Arc<DB>>,
+	mut cookies: Cookies<'_>,
+	token: String,
+	credentials: Json<ShareLinkCredentials>,
+) -> Result<(), errors::Error> {
+	share_links::unlock(db.deref().deref(), &token, &credentials.password)?;
+	let cookie_name = format!("{}{}", COOKIE_SHARE_LINK_PREFIX, token);
+	cookies.add_private(
+		Cookie::build(cookie_name, token)
+			.same_site(rocket::http::SameSite::Lax)
+			.http_only(true)
+			.finish(),
+	);
+	Ok(())
+}
+
+#[get("/share/<token>/serve/<path>")]
+fn serve_share_link(
+	db: State<'_, Arc<DB>>,
+	mut cookies: Cookies<'_>,
+	token: String,
+	path: VFSPathBuf,
+) -> Result<serve::RangeResponder<File>, errors::Error> {
+	let db: &DB = db.deref().deref();
+	let share_link = open_share_link(db, &mut cookies, &token)?;
+	let path: PathBuf = path.into();
+	if !share_links::contains(db, &share_link, &path)? {
+		bail!(errors::ErrorKind::ShareLinkNotFound);
+	}
+	let real_path = db.get_vfs()?.virtual_to_real(&path)?;
+	serve_file(db, real_path)
+}
+
+#[get("/share/<token>/thumbnail/<path>?<size>")]
+fn serve_share_link_thumbnail(
+	db: State<'_, Arc<DB>>,
+	mut cookies: Cookies<'_>,
+	token: String,
+	path: VFSPathBuf,
+	size: Option<u32>,
+) -> Result<serve::RangeResponder<File>, errors::Error> {
+	let db: &DB = db.deref().deref();
+	let share_link = open_share_link(db, &mut cookies, &token)?;
+	let path: PathBuf = path.into();
+	if !share_links::contains(db, &share_link, &path)? {
+		bail!(errors::ErrorKind::ShareLinkNotFound);
+	}
+	let real_path = db.get_vfs()?.virtual_to_real(&path)?;
+	if !utils::is_image(&real_path) {
+		bail!(errors::ErrorKind::ShareLinkNotFound);
+	}
+	let size = size
+		.unwrap_or(DEFAULT_THUMBNAIL_SIZE)
+		.max(1)
+		.min(MAX_THUMBNAIL_SIZE);
+	let file = File::open(thumbnails::get_thumbnail(&real_path, size)?)?;
+	Ok(serve::RangeResponder::new(file))
+}
+
+#[get("/history/recent")]
+fn recently_played(
+	db: State<'_, Arc<DB>>,
+	auth: Auth,
+) -> Result<Json<Vec<index::Song>>, errors::Error> {
+	let result = history::get_recent_songs(db.deref().deref(), &auth.username, 20)?;
+	Ok(Json(result))
+}
+
+#[get("/history/top/songs?<since>&<until>")]
+fn top_songs(
+	db: State<'_, Arc<DB>>,
+	auth: Auth,
+	since: Option<i32>,
+	until: Option<i32>,
+) -> Result<Json<Vec<index::Song>>, errors::Error> {
+	let since = since.unwrap_or(0);
+	let until = until.unwrap_or(i32::max_value());
+	let result = history::get_top_songs(db.deref().deref(), &auth.username, since, until, 20)?;
+	Ok(Json(result))
+}
+
+#[get("/history/top/albums?<since>&<until>")]
+fn top_albums(
+	db: State<'_, Arc<DB>>,
+	auth: Auth,
+	since: Option<i32>,
+	until: Option<i32>,
+) -> Result<Json<Vec<history::AlbumPlays>>, errors::Error> {
+	let since = since.unwrap_or(0);
+	let until = until.unwrap_or(i32::max_value());
+	let result = history::get_top_albums(db.deref().deref(), &auth.username, since, until, 20)?;
+	Ok(Json(result))
+}
+
+#[get("/history/top/artists?<since>&<until>")]
+fn top_artists(
+	db: State<'_, Arc<DB>>,
+	auth: Auth,
+	since: Option<i32>,
+	until: Option<i32>,
+) -> Result<Json<Vec<history::ArtistPlays>>, errors::Error> {
+	let since = since.unwrap_or(0);
+	let until = until.unwrap_or(i32::max_value());
+	let result = history::get_top_artists(db.deref().deref(), &auth.username, since, until, 20)?;
+	Ok(Json(result))
+}
+
 #[put("/lastfm/now_playing/<path>")]
 fn lastfm_now_playing(
 	db: State<'_, Arc<DB>>,
 	auth: Auth,
 	path: VFSPathBuf,
 ) -> Result<(), errors::Error> {
-	lastfm::now_playing(db.deref().deref(), &auth.username, &path.into() as &PathBuf)?;
+	let path: PathBuf = path.into();
+	history::now_playing(db.deref().deref(), &auth.username, &path)?;
+	scrobble_queue::now_playing(db.inner().clone(), &auth.username, &path)?;
 	Ok(())
 }
 
@@ -410,10 +1206,69 @@ fn lastfm_scrobble(
 	auth: Auth,
 	path: VFSPathBuf,
 ) -> Result<(), errors::Error> {
-	lastfm::scrobble(db.deref().deref(), &auth.username, &path.into() as &PathBuf)?;
+	let db: &DB = db.deref().deref();
+	let path: PathBuf = path.into();
+	history::scrobble(db, &auth.username, &path)?;
+	scrobble_queue::enqueue(db, &auth.username, &path)?;
+	Ok(())
+}
+
+#[put("/lastfm/love/<path>")]
+fn lastfm_love(db: State<'_, Arc<DB>>, auth: Auth, path: VFSPathBuf) -> Result<(), errors::Error> {
+	let db: &DB = db.deref().deref();
+	let path: PathBuf = path.into();
+	let target = path.to_string_lossy();
+	ratings::set_rating(
+		db,
+		&auth.username,
+		ratings::Kind::Song,
+		&target,
+		None,
+		Some(true),
+	)?;
+	if user::is_lastfm_linked(db, &auth.username)? {
+		lastfm::love(db, &auth.username, &path)?;
+	}
+	Ok(())
+}
+
+#[delete("/lastfm/love/<path>")]
+fn lastfm_unlove(
+	db: State<'_, Arc<DB>>,
+	auth: Auth,
+	path: VFSPathBuf,
+) -> Result<(), errors::Error> {
+	let db: &DB = db.deref().deref();
+	let path: PathBuf = path.into();
+	let target = path.to_string_lossy();
+	ratings::set_rating(
+		db,
+		&auth.username,
+		ratings::Kind::Song,
+		&target,
+		None,
+		Some(false),
+	)?;
+	if user::is_lastfm_linked(db, &auth.username)? {
+		lastfm::unlove(db, &auth.username, &path)?;
+	}
 	Ok(())
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct LovedTracksImport {
+	pub imported: usize,
+}
+
+#[post("/lastfm/import_loved")]
+fn lastfm_import_loved_tracks(
+	db: State<'_, Arc<DB>>,
+	auth: Auth,
+) -> Result<Json<LovedTracksImport>, errors::Error> {
+	let imported = lastfm::import_loved_tracks(db.deref().deref(), &auth.username)?;
+	Ok(Json(LovedTracksImport { imported }))
+}
+
 #[get("/lastfm/link?<token>&<content>")]
 fn lastfm_link(
 	db: State<'_, Arc<DB>>,
@@ -449,3 +1304,100 @@ fn lastfm_unlink(db: State<'_, Arc<DB>>, auth: Auth) -> Result<(), errors::Error
 	lastfm::unlink(db.deref().deref(), &auth.username)?;
 	Ok(())
 }
+
+#[derive(Serialize, Deserialize)]
+pub struct ListenBrainzLinkInput {
+	pub token: String,
+}
+
+#[put("/listenbrainz/link", data = "<input>")]
+fn listenbrainz_link(
+	db: State<'_, Arc<DB>>,
+	auth: Auth,
+	input: Json<ListenBrainzLinkInput>,
+) -> Result<(), errors::Error> {
+	listenbrainz::link(db.deref().deref(), &auth.username, &input.token)?;
+	Ok(())
+}
+
+#[delete("/listenbrainz/link")]
+fn listenbrainz_unlink(db: State<'_, Arc<DB>>, auth: Auth) -> Result<(), errors::Error> {
+	listenbrainz::unlink(db.deref().deref(), &auth.username)?;
+	Ok(())
+}
+
+#[post("/listenbrainz/import")]
+fn listenbrainz_import(db: State<'_, Arc<DB>>, auth: Auth) -> Result<(), errors::Error> {
+	listenbrainz::import(db.deref().deref(), &auth.username)?;
+	Ok(())
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RatingInput {
+	pub rating: Option<i32>,
+	pub starred: Option<bool>,
+}
+
+#[put("/rating/song/<path>", data = "<input>")]
+fn rate_song(
+	db: State<'_, Arc<DB>>,
+	auth: Auth,
+	path: VFSPathBuf,
+	input: Json<RatingInput>,
+) -> Result<(), errors::Error> {
+	let path = PathBuf::from(path);
+	ratings::set_rating(
+		db.deref().deref(),
+		&auth.username,
+		ratings::Kind::Song,
+		&path.to_string_lossy(),
+		input.rating,
+		input.starred,
+	)?;
+	Ok(())
+}
+
+#[put("/rating/album/<path>", data = "<input>")]
+fn rate_album(
+	db: State<'_, Arc<DB>>,
+	auth: Auth,
+	path: VFSPathBuf,
+	input: Json<RatingInput>,
+) -> Result<(), errors::Error> {
+	let path = PathBuf::from(path);
+	ratings::set_rating(
+		db.deref().deref(),
+		&auth.username,
+		ratings::Kind::Album,
+		&path.to_string_lossy(),
+		input.rating,
+		input.starred,
+	)?;
+	Ok(())
+}
+
+#[put("/rating/artist/<name>", data = "<input>")]
+fn rate_artist(
+	db: State<'_, Arc<DB>>,
+	auth: Auth,
+	name: String,
+	input: Json<RatingInput>,
+) -> Result<(), errors::Error> {
+	ratings::set_rating(
+		db.deref().deref(),
+		&auth.username,
+		ratings::Kind::Artist,
+		&name,
+		input.rating,
+		input.starred,
+	)?;
+	Ok(())
+}
+
+#[get("/starred")]
+fn starred(db: State<'_, Arc<DB>>, auth: Auth) -> Result<Json<ratings::Starred>, errors::Error> {
+	let mut result = ratings::get_starred(db.deref().deref(), &auth.username)?;
+	overrides::annotate_songs(db.deref().deref(), result.songs.iter_mut())?;
+	history::annotate_songs(db.deref().deref(), &auth.username, result.songs.iter_mut())?;
+	Ok(Json(result))
+}
@@ -8,7 +8,16 @@ use crate::api;
 use crate::config;
 use crate::ddns;
 use crate::index;
+use crate::loudness;
+use crate::lyrics;
+use crate::overrides;
+use crate::playlist;
+use crate::ratings;
+use crate::share_links;
+use crate::suggestions;
+use crate::upload;
 use crate::vfs;
+use crate::waveform;
 
 use crate::test::get_test_environment;
 
@@ -21,6 +30,11 @@ fn complete_initial_setup(client: &Client) {
 	let configuration = config::Config {
 		album_art_pattern: None,
 		prefix_url: None,
+		listenbrainz_url: None,
+		lastfm_url: None,
+		analyze_loudness: None,
+		precompute_waveforms: None,
+		sort_articles: None,
 		reindex_every_n_seconds: None,
 		ydns: None,
 		users: Some(vec![config::ConfigUser {
@@ -123,6 +137,11 @@ fn settings() {
 					source: TEST_MOUNT_SOURCE.into()
 				}]),
 				prefix_url: None,
+				listenbrainz_url: Some("https://api.listenbrainz.org".into()),
+				lastfm_url: Some("https://ws.audioscrobbler.com/2.0/".into()),
+				analyze_loudness: Some(false),
+				precompute_waveforms: Some(false),
+				sort_articles: Some(vec!["The".into(), "A".into(), "An".into()]),
 				users: Some(vec![config::ConfigUser {
 					name: TEST_USERNAME.into(),
 					password: "".into(),
@@ -151,6 +170,11 @@ fn settings() {
 			},
 		]),
 		prefix_url: Some("my_prefix".to_owned()),
+		listenbrainz_url: Some("http://localhost:8100".to_owned()),
+		lastfm_url: Some("http://localhost:8200/2.0/".to_owned()),
+		analyze_loudness: Some(true),
+		precompute_waveforms: Some(true),
+		sort_articles: Some(vec!["The".into(), "Les".into()]),
 		users: Some(vec![
 			config::ConfigUser {
 				name: "test_user".into(),
@@ -233,6 +257,28 @@ fn trigger_index() {
 	}
 }
 
+#[test]
+fn loudness_progress() {
+	let env = get_test_environment("api_loudness_progress.sqlite");
+	let client = &env.client;
+	complete_initial_setup(client);
+
+	{
+		let response = client.get("/api/loudness/progress").dispatch();
+		assert_eq!(response.status(), Status::Unauthorized);
+	}
+
+	do_auth(client);
+
+	{
+		let mut response = client.get("/api/loudness/progress").dispatch();
+		assert_eq!(response.status(), Status::Ok);
+		let response_body = response.body_string().unwrap();
+		let response_json: loudness::Progress = serde_json::from_str(&response_body).unwrap();
+		assert!(response_json.analyzed_songs <= response_json.total_songs);
+	}
+}
+
 #[test]
 fn auth() {
 	let env = get_test_environment("api_auth.sqlite");
@@ -361,6 +407,22 @@ fn flatten() {
 		let response_json: Vec<index::Song> = serde_json::from_str(&response_body).unwrap();
 		assert_eq!(response_json.len(), 12);
 	}
+
+	{
+		let mut response = client
+			.get("/api/flatten?offset=10&limit=5&sort=title&order=desc")
+			.dispatch();
+		assert_eq!(response.status(), Status::Ok);
+		assert_eq!(response.headers().get_one("X-Total-Count"), Some("12"));
+		let response_body = response.body_string().unwrap();
+		let response_json: Vec<index::Song> = serde_json::from_str(&response_body).unwrap();
+		assert_eq!(response_json.len(), 2);
+	}
+
+	{
+		let response = client.get("/api/flatten?sort=color").dispatch();
+		assert_eq!(response.status(), Status::BadRequest);
+	}
 }
 
 #[test]
@@ -378,6 +440,47 @@ fn random() {
 	assert_eq!(response_json.len(), 2);
 }
 
+#[test]
+fn random_songs() {
+	let env = get_test_environment("api_random_songs.sqlite");
+	let client = &env.client;
+	complete_initial_setup(client);
+	do_auth(client);
+	env.update_index();
+
+	let seed: String;
+	let first_page: Vec<index::Song>;
+	{
+		let mut response = client.get("/api/random/songs?limit=5").dispatch();
+		assert_eq!(response.status(), Status::Ok);
+		assert_eq!(response.headers().get_one("X-Total-Count"), Some("12"));
+		seed = response
+			.headers()
+			.get_one("X-Random-Seed")
+			.unwrap()
+			.to_owned();
+		let response_body = response.body_string().unwrap();
+		first_page = serde_json::from_str(&response_body).unwrap();
+		assert_eq!(first_page.len(), 5);
+	}
+
+	{
+		let url = format!("/api/random/songs?limit=5&seed={}", seed);
+		let mut response = client.get(url).dispatch();
+		let response_body = response.body_string().unwrap();
+		let response_json: Vec<index::Song> = serde_json::from_str(&response_body).unwrap();
+		assert_eq!(response_json, first_page);
+	}
+
+	{
+		let url = format!("/api/random/songs?limit=5&offset=5&seed={}", seed);
+		let mut response = client.get(url).dispatch();
+		let response_body = response.body_string().unwrap();
+		let response_json: Vec<index::Song> = serde_json::from_str(&response_body).unwrap();
+		assert!(response_json.iter().all(|s| !first_page.contains(s)));
+	}
+}
+
 #[test]
 fn recent() {
 	let env = get_test_environment("api_recent.sqlite");
@@ -386,11 +489,22 @@ fn recent() {
 	do_auth(client);
 	env.update_index();
 
-	let mut response = client.get("/api/recent").dispatch();
-	assert_eq!(response.status(), Status::Ok);
-	let response_body = response.body_string().unwrap();
-	let response_json: Vec<index::Directory> = serde_json::from_str(&response_body).unwrap();
-	assert_eq!(response_json.len(), 2);
+	{
+		let mut response = client.get("/api/recent").dispatch();
+		assert_eq!(response.status(), Status::Ok);
+		let response_body = response.body_string().unwrap();
+		let response_json: Vec<index::Directory> = serde_json::from_str(&response_body).unwrap();
+		assert_eq!(response_json.len(), 2);
+	}
+
+	{
+		let mut response = client.get("/api/recent?offset=1&limit=5").dispatch();
+		assert_eq!(response.status(), Status::Ok);
+		assert_eq!(response.headers().get_one("X-Total-Count"), Some("2"));
+		let response_body = response.body_string().unwrap();
+		let response_json: Vec<index::Directory> = serde_json::from_str(&response_body).unwrap();
+		assert_eq!(response_json.len(), 1);
+	}
 }
 
 #[test]
@@ -412,6 +526,451 @@ fn search() {
 	}
 }
 
+#[test]
+fn search_suggest() {
+	let env = get_test_environment("api_search_suggest.sqlite");
+	let client = &env.client;
+	complete_initial_setup(client);
+	do_auth(client);
+	env.update_index();
+
+	let mut response = client.get("/api/search/suggest?q=khe").dispatch();
+	assert_eq!(response.status(), Status::Ok);
+	let response_body = response.body_string().unwrap();
+	let response_json: suggestions::Suggestions = serde_json::from_str(&response_body).unwrap();
+	assert_eq!(response_json.artists, vec!["Khemmis".to_owned()]);
+
+	let mut response = client.get("/api/search/suggest?q=the&limit=1").dispatch();
+	assert_eq!(response.status(), Status::Ok);
+	let response_body = response.body_string().unwrap();
+	let response_json: suggestions::Suggestions = serde_json::from_str(&response_body).unwrap();
+	assert_eq!(response_json.titles, vec!["Above The Water".to_owned()]);
+
+	let response = client.get("/api/search/suggest?q=the&limit=-1").dispatch();
+	assert_eq!(response.status(), Status::BadRequest);
+}
+
+#[test]
+fn genres_and_years() {
+	let env = get_test_environment("api_genres_and_years.sqlite");
+	let client = &env.client;
+	complete_initial_setup(client);
+	do_auth(client);
+	env.update_index();
+
+	let years: Vec<index::Year>;
+	{
+		let mut response = client.get("/api/years").dispatch();
+		assert_eq!(response.status(), Status::Ok);
+		let response_body = response.body_string().unwrap();
+		years = serde_json::from_str(&response_body).unwrap();
+		assert!(!years.is_empty());
+	}
+
+	{
+		let mut response = client.get("/api/decades").dispatch();
+		assert_eq!(response.status(), Status::Ok);
+		let response_body = response.body_string().unwrap();
+		let response_json: Vec<index::Decade> = serde_json::from_str(&response_body).unwrap();
+		let song_count: i64 = response_json.iter().map(|d| d.song_count).sum();
+		assert_eq!(song_count, years.iter().map(|y| y.song_count).sum());
+	}
+
+	{
+		let url = format!(
+			"/api/songs?from_year={}&to_year={}",
+			years[0].year, years[0].year
+		);
+		let mut response = client.get(url).dispatch();
+		assert_eq!(response.status(), Status::Ok);
+		let total = years[0].song_count.to_string();
+		assert_eq!(
+			response.headers().get_one("X-Total-Count"),
+			Some(&total[..])
+		);
+		let response_body = response.body_string().unwrap();
+		let response_json: Vec<index::Song> = serde_json::from_str(&response_body).unwrap();
+		assert!(response_json.iter().all(|s| s.year == Some(years[0].year)));
+	}
+
+	{
+		let response = client.get("/api/genres").dispatch();
+		assert_eq!(response.status(), Status::Ok);
+	}
+
+	{
+		let mut response = client.get("/api/albums?genre=Polka").dispatch();
+		assert_eq!(response.status(), Status::Ok);
+		assert_eq!(response.headers().get_one("X-Total-Count"), Some("0"));
+	}
+}
+
+#[test]
+fn search_filters() {
+	let env = get_test_environment("api_search_filters.sqlite");
+	let client = &env.client;
+	complete_initial_setup(client);
+	do_auth(client);
+	env.update_index();
+
+	let mut response = client.get("/api/search/door?codec=MP3").dispatch();
+	assert_eq!(response.status(), Status::Ok);
+	let response_body = response.body_string().unwrap();
+	let response_json: Vec<index::CollectionFile> = serde_json::from_str(&response_body).unwrap();
+	assert_eq!(response_json.len(), 1);
+	match response_json[0] {
+		index::CollectionFile::Song(ref s) => {
+			assert_eq!(s.codec, Some("MP3".into()));
+			assert!(s.sample_rate.is_some());
+		}
+		_ => panic!(),
+	}
+
+	let mut response = client.get("/api/search/door?lossless=true").dispatch();
+	assert_eq!(response.status(), Status::Ok);
+	let response_body = response.body_string().unwrap();
+	let response_json: Vec<index::CollectionFile> = serde_json::from_str(&response_body).unwrap();
+	assert!(response_json.is_empty());
+}
+
+#[test]
+fn lyrics() {
+	let env = get_test_environment("api_lyrics.sqlite");
+	let client = &env.client;
+	complete_initial_setup(client);
+	do_auth(client);
+	env.update_index();
+
+	let mut response = client
+		.get("/api/lyrics/collection%2FKhemmis%2FHunted%2F02%20-%20Candlelight.mp3")
+		.dispatch();
+	assert_eq!(response.status(), Status::Ok);
+	let response_body = response.body_string().unwrap();
+	let response_json: lyrics::Lyrics = serde_json::from_str(&response_body).unwrap();
+	assert!(response_json.synchronized);
+	assert_eq!(response_json.lines.len(), 2);
+	assert_eq!(response_json.lines[1].time, Some(42_500));
+
+	let response = client
+		.get("/api/lyrics/collection%2FKhemmis%2FHunted%2F03%20-%20Three%20Gates.mp3")
+		.dispatch();
+	assert_eq!(response.status(), Status::NotFound);
+}
+
+#[test]
+fn waveform() {
+	let env = get_test_environment("api_waveform.sqlite");
+	let client = &env.client;
+	complete_initial_setup(client);
+	do_auth(client);
+	env.update_index();
+
+	let path = "collection%2FKhemmis%2FHunted%2F02%20-%20Candlelight.mp3";
+	let mut response = client
+		.get(format!("/api/waveform/{}?buckets=50", path))
+		.dispatch();
+	assert_eq!(response.status(), Status::Ok);
+	let response_body = response.body_string().unwrap();
+	let response_json: waveform::Waveform = serde_json::from_str(&response_body).unwrap();
+	assert_eq!(response_json.min.len(), 50);
+	assert_eq!(response_json.max.len(), 50);
+
+	let mut response = client
+		.get(format!("/api/waveform/{}?buckets=50&format=binary", path))
+		.dispatch();
+	assert_eq!(response.status(), Status::Ok);
+	assert_eq!(response.body_bytes().unwrap().len(), 100);
+
+	let response = client
+		.get(format!("/api/waveform/{}?format=png", path))
+		.dispatch();
+	assert_eq!(response.status(), Status::BadRequest);
+
+	// The format is rejected before looking at the file
+	let response = client
+		.get("/api/waveform/collection%2Fmissing.mp3?format=png")
+		.dispatch();
+	assert_eq!(response.status(), Status::BadRequest);
+}
+
+#[test]
+fn download() {
+	let env = get_test_environment("api_download.sqlite");
+	let client = &env.client;
+	complete_initial_setup(client);
+	do_auth(client);
+	env.update_index();
+
+	{
+		let mut response = client
+			.get("/api/download/collection%2FKhemmis%2FHunted")
+			.dispatch();
+		assert_eq!(response.status(), Status::Ok);
+		assert_eq!(
+			response.headers().get_one("Content-Disposition").unwrap(),
+			"attachment; filename=\"Hunted.zip\""
+		);
+		let body = response.body_bytes().unwrap();
+		assert_eq!(&body[0..4], b"PK\x03\x04");
+		// Five songs and their artwork
+		let end = &body[body.len() - 22..];
+		assert_eq!(u16::from_le_bytes([end[10], end[11]]), 6);
+	}
+
+	{
+		let songs: Vec<index::Song>;
+		{
+			let mut response = client.get("/api/flatten").dispatch();
+			let response_body = response.body_string().unwrap();
+			songs = serde_json::from_str(&response_body).unwrap();
+		}
+		let my_playlist = api::SavePlaylistInput {
+			tracks: songs[0..2].iter().map(|s| s.path.clone()).collect(),
+		};
+		let response = client
+			.put("/api/playlist/my_playlist")
+			.body(serde_json::to_string(&my_playlist).unwrap())
+			.dispatch();
+		assert_eq!(response.status(), Status::Ok);
+
+		let mut response = client.get("/api/playlist/my_playlist/download").dispatch();
+		assert_eq!(response.status(), Status::Ok);
+		let body = response.body_bytes().unwrap();
+		let end = &body[body.len() - 22..];
+		assert_eq!(u16::from_le_bytes([end[10], end[11]]), 3);
+	}
+}
+
+#[test]
+fn upload() {
+	let env = get_test_environment("api_upload.sqlite");
+	let client = &env.client;
+	complete_initial_setup(client);
+
+	{
+		let response = client
+			.put("/api/upload/collection%2Fnew.mp3")
+			.body("data")
+			.dispatch();
+		assert_eq!(response.status(), Status::Unauthorized);
+	}
+
+	do_auth(client);
+
+	{
+		let response = client
+			.put("/api/upload/collection%2Fnotes.txt")
+			.body("data")
+			.dispatch();
+		assert_eq!(response.status(), Status::UnsupportedMediaType);
+	}
+
+	{
+		let response = client
+			.put("/api/upload/collection%2F..%2Fnew.mp3")
+			.body("data")
+			.dispatch();
+		assert_eq!(response.status(), Status::BadRequest);
+	}
+
+	{
+		let response = client
+			.put("/api/upload/collection%2Fnew.mp3?offset=10&complete=false")
+			.body("data")
+			.dispatch();
+		assert_eq!(response.status(), Status::Conflict);
+	}
+
+	{
+		let mut response = client
+			.put("/api/upload/collection%2Fnew.mp3?complete=false")
+			.body("data")
+			.dispatch();
+		assert_eq!(response.status(), Status::Ok);
+		let response_body = response.body_string().unwrap();
+		let status: upload::UploadStatus = serde_json::from_str(&response_body).unwrap();
+		assert_eq!(status.received, 4);
+
+		let uri = format!(
+			"/api/upload/collection%2Fnew.mp3?session={}&offset=4&complete=false",
+			status.session
+		);
+		let response = client.put(uri).body("more").dispatch();
+		assert_eq!(response.status(), Status::Ok);
+
+		let uri = format!(
+			"/api/upload/collection%2Fnew.mp3?session={}",
+			status.session
+		);
+		let mut response = client.get(uri.clone()).dispatch();
+		assert_eq!(response.status(), Status::Ok);
+		let response_body = response.body_string().unwrap();
+		let status: upload::UploadStatus = serde_json::from_str(&response_body).unwrap();
+		assert_eq!(status.received, 8);
+
+		let response = client.delete(uri).dispatch();
+		assert_eq!(response.status(), Status::Ok);
+	}
+}
+
+#[test]
+fn overrides() {
+	let env = get_test_environment("api_overrides.sqlite");
+	let client = &env.client;
+	complete_initial_setup(client);
+	do_auth(client);
+	env.update_index();
+
+	let metadata_override = overrides::MetadataOverride {
+		path: "collection/Khemmis/Hunted".into(),
+		album: Some("Hunted (Deluxe Edition)".into()),
+		..Default::default()
+	};
+	let response = client
+		.put("/api/overrides")
+		.body(serde_json::to_string(&metadata_override).unwrap())
+		.dispatch();
+	assert_eq!(response.status(), Status::Ok);
+
+	{
+		let mut response = client.get("/api/overrides").dispatch();
+		assert_eq!(response.status(), Status::Ok);
+		let response_body = response.body_string().unwrap();
+		let response_json: Vec<overrides::MetadataOverride> =
+			serde_json::from_str(&response_body).unwrap();
+		assert_eq!(response_json, vec![metadata_override.clone()]);
+	}
+
+	{
+		let mut response = client.get("/api/flatten/collection%2FKhemmis").dispatch();
+		let response_body = response.body_string().unwrap();
+		let response_json: Vec<index::Song> = serde_json::from_str(&response_body).unwrap();
+		assert!(response_json
+			.iter()
+			.all(|s| s.album == metadata_override.album));
+	}
+
+	let response = client
+		.delete("/api/overrides/collection%2FKhemmis%2FHunted")
+		.dispatch();
+	assert_eq!(response.status(), Status::Ok);
+
+	{
+		let mut response = client.get("/api/flatten/collection%2FKhemmis").dispatch();
+		let response_body = response.body_string().unwrap();
+		let response_json: Vec<index::Song> = serde_json::from_str(&response_body).unwrap();
+		assert!(response_json
+			.iter()
+			.all(|s| s.album != metadata_override.album));
+	}
+}
+
+#[test]
+fn share_links() {
+	let env = get_test_environment("api_share_links.sqlite");
+	let client = &env.client;
+	complete_initial_setup(client);
+	do_auth(client);
+	env.update_index();
+	let guest = env.new_client();
+
+	let share_link: share_links::ShareLink;
+	{
+		let new_share_link = share_links::NewShareLink {
+			kind: share_links::Kind::Directory,
+			target: "collection/Khemmis/Hunted".into(),
+			expires: None,
+			password: Some("hunter2".into()),
+		};
+		let mut response = client
+			.post("/api/share_links")
+			.body(serde_json::to_string(&new_share_link).unwrap())
+			.dispatch();
+		assert_eq!(response.status(), Status::Ok);
+		let response_body = response.body_string().unwrap();
+		share_link = serde_json::from_str(&response_body).unwrap();
+		assert!(share_link.protected);
+	}
+
+	let share_url = format!("/api/share/{}", share_link.token);
+	let song_url = format!(
+		"{}/serve/collection%2FKhemmis%2FHunted%2F02%20-%20Candlelight.mp3",
+		share_url
+	);
+	let thumbnail_url = format!(
+		"{}/thumbnail/collection%2FKhemmis%2FHunted%2FFolder.jpg?size=100",
+		share_url
+	);
+	let outsider_url = format!(
+		"{}/serve/collection%2FTobokegao%2FPicnic%2FFolder.png",
+		share_url
+	);
+
+	// Locked until the password is provided
+	{
+		let response = guest.get(&share_url).dispatch();
+		assert_eq!(response.status(), Status::Unauthorized);
+		let response = guest.get(&song_url).dispatch();
+		assert_eq!(response.status(), Status::Unauthorized);
+
+		let credentials = api::ShareLinkCredentials {
+			password: "wrong".into(),
+		};
+		let response = guest
+			.post(format!("{}/auth", share_url))
+			.body(serde_json::to_string(&credentials).unwrap())
+			.dispatch();
+		assert_eq!(response.status(), Status::Unauthorized);
+
+		let credentials = api::ShareLinkCredentials {
+			password: "hunter2".into(),
+		};
+		let response = guest
+			.post(format!("{}/auth", share_url))
+			.body(serde_json::to_string(&credentials).unwrap())
+			.dispatch();
+		assert_eq!(response.status(), Status::Ok);
+	}
+
+	{
+		let mut response = guest.get(&share_url).dispatch();
+		assert_eq!(response.status(), Status::Ok);
+		let response_body = response.body_string().unwrap();
+		let content: share_links::SharedContent = serde_json::from_str(&response_body).unwrap();
+		assert_eq!(content.kind, share_links::Kind::Directory);
+		assert_eq!(content.name, "Hunted");
+		assert_eq!(content.songs.len(), 5);
+	}
+
+	{
+		let response = guest.get(&song_url).dispatch();
+		assert_eq!(response.status(), Status::Ok);
+		let response = guest.get(&thumbnail_url).dispatch();
+		assert_eq!(response.status(), Status::Ok);
+		let response = guest.get(&outsider_url).dispatch();
+		assert_eq!(response.status(), Status::NotFound);
+		let response = guest.get("/api/share_links").dispatch();
+		assert_eq!(response.status(), Status::Unauthorized);
+	}
+
+	{
+		let mut response = client.get("/api/share_links").dispatch();
+		assert_eq!(response.status(), Status::Ok);
+		let response_body = response.body_string().unwrap();
+		let listed: Vec<share_links::ShareLink> = serde_json::from_str(&response_body).unwrap();
+		assert_eq!(listed, vec![share_link.clone()]);
+	}
+
+	{
+		let response = client
+			.delete(format!("/api/share_links/{}", share_link.token))
+			.dispatch();
+		assert_eq!(response.status(), Status::Ok);
+		let response = guest.get(&share_url).dispatch();
+		assert_eq!(response.status(), Status::NotFound);
+	}
+}
+
 #[test]
 fn serve() {
 	let env = get_test_environment("api_serve.sqlite");
@@ -443,6 +1002,54 @@ fn serve() {
 	}
 }
 
+#[test]
+fn serve_cue_track() {
+	let env = get_test_environment("api_serve_cue_track.sqlite");
+	let client = &env.client;
+	complete_initial_setup(client);
+	do_auth(client);
+
+	let configuration = config::Config {
+		album_art_pattern: None,
+		prefix_url: None,
+		listenbrainz_url: None,
+		lastfm_url: None,
+		analyze_loudness: None,
+		precompute_waveforms: None,
+		sort_articles: None,
+		reindex_every_n_seconds: None,
+		ydns: None,
+		users: None,
+		mount_dirs: Some(vec![vfs::MountPoint {
+			name: "cue".into(),
+			source: "test/cue".into(),
+		}]),
+	};
+	let body = serde_json::to_string(&configuration).unwrap();
+	let response = client.put("/api/settings").body(&body).dispatch();
+	assert_eq!(response.status(), Status::Ok);
+	env.update_index();
+
+	{
+		let mut response = client.get("/api/flatten/cue").dispatch();
+		assert_eq!(response.status(), Status::Ok);
+		let response_body = response.body_string().unwrap();
+		let response_json: Vec<index::Song> = serde_json::from_str(&response_body).unwrap();
+		assert_eq!(response_json.len(), 3);
+		assert_eq!(response_json[1].path, "cue/Hunted.cue#02");
+	}
+
+	{
+		// Second track of a 24-bit mono file, from 1.493s to 3s at 8000Hz
+		let mut response = client.get("/api/serve/cue%2FHunted.cue%2302").dispatch();
+		assert_eq!(response.status(), Status::Ok);
+		let body = response.body().unwrap();
+		let body = body.into_bytes().unwrap();
+		assert_eq!(&body[0..4], b"RIFF");
+		assert_eq!(body.len(), 44 + (24_000 - 11_944) * 3);
+	}
+}
+
 #[test]
 fn playlists() {
 	let env = get_test_environment("api_playlists.sqlite");
@@ -499,11 +1106,65 @@ fn playlists() {
 		assert_eq!(response_json.len(), 4);
 	}
 
+	{
+		let mut response = client.get("/api/playlist/my_playlist/missing").dispatch();
+		assert_eq!(response.status(), Status::Ok);
+		let response_body = response.body_string().unwrap();
+		let response_json: Vec<playlist::MissingSong> =
+			serde_json::from_str(&response_body).unwrap();
+		assert_eq!(response_json.len(), 0);
+	}
+
+	{
+		let shares = vec![playlist::PlaylistShare {
+			user: None,
+			collaborative: true,
+		}];
+		let response = client
+			.put("/api/playlist/my_playlist/shares")
+			.body(serde_json::to_string(&shares).unwrap())
+			.dispatch();
+		assert_eq!(response.status(), Status::Ok);
+
+		let mut response = client.get("/api/playlist/my_playlist/shares").dispatch();
+		assert_eq!(response.status(), Status::Ok);
+		let response_body = response.body_string().unwrap();
+		let response_json: Vec<playlist::PlaylistShare> =
+			serde_json::from_str(&response_body).unwrap();
+		assert_eq!(response_json, shares);
+	}
+
+	{
+		let mut response = client.get("/api/playlists/shared").dispatch();
+		assert_eq!(response.status(), Status::Ok);
+		let response_body = response.body_string().unwrap();
+		let response_json: Vec<playlist::SharedPlaylist> =
+			serde_json::from_str(&response_body).unwrap();
+		assert_eq!(response_json.len(), 0);
+	}
+
+	{
+		let uri = format!(
+			"/api/playlist/my_playlist?owner={}",
+			Uri::percent_encode(TEST_USERNAME)
+		);
+		let mut response = client.get(uri).dispatch();
+		assert_eq!(response.status(), Status::Ok);
+		let response_body = response.body_string().unwrap();
+		let response_json: Vec<index::Song> = serde_json::from_str(&response_body).unwrap();
+		assert_eq!(response_json.len(), 4);
+	}
+
 	{
 		let response = client.delete("/api/playlist/my_playlist").dispatch();
 		assert_eq!(response.status(), Status::Ok);
 	}
 
+	{
+		let response = client.delete("/api/playlist/my_playlist").dispatch();
+		assert_eq!(response.status(), Status::NotFound);
+	}
+
 	{
 		let mut response = client.get("/api/playlists").dispatch();
 		assert_eq!(response.status(), Status::Ok);
@@ -513,3 +1174,124 @@ fn playlists() {
 		assert_eq!(response_json.len(), 0);
 	}
 }
+
+#[test]
+fn history() {
+	let env = get_test_environment("api_history.sqlite");
+	let client = &env.client;
+	complete_initial_setup(client);
+	do_auth(client);
+	env.update_index();
+
+	let songs: Vec<index::Song>;
+	{
+		let mut response = client.get("/api/flatten").dispatch();
+		let response_body = response.body_string().unwrap();
+		songs = serde_json::from_str(&response_body).unwrap();
+		assert_eq!(songs[0].play_count, Some(0));
+	}
+
+	{
+		let path = Uri::percent_encode(&songs[0].path);
+		let response = client
+			.put(format!("/api/lastfm/now_playing/{}", path))
+			.dispatch();
+		assert_eq!(response.status(), Status::Ok);
+		let response = client
+			.post(format!("/api/lastfm/scrobble/{}", path))
+			.dispatch();
+		assert_eq!(response.status(), Status::Ok);
+	}
+
+	{
+		let mut response = client.get("/api/history/top/songs").dispatch();
+		assert_eq!(response.status(), Status::Ok);
+		let response_body = response.body_string().unwrap();
+		let response_json: Vec<index::Song> = serde_json::from_str(&response_body).unwrap();
+		assert_eq!(response_json.len(), 1);
+		assert_eq!(response_json[0].path, songs[0].path);
+		assert_eq!(response_json[0].play_count, Some(1));
+	}
+
+	{
+		let mut response = client
+			.get("/api/history/top/songs?since=0&until=1")
+			.dispatch();
+		assert_eq!(response.status(), Status::Ok);
+		let response_body = response.body_string().unwrap();
+		let response_json: Vec<index::Song> = serde_json::from_str(&response_body).unwrap();
+		assert!(response_json.is_empty());
+	}
+
+	{
+		let mut response = client.get("/api/history/recent").dispatch();
+		assert_eq!(response.status(), Status::Ok);
+		let response_body = response.body_string().unwrap();
+		let response_json: Vec<index::Song> = serde_json::from_str(&response_body).unwrap();
+		assert_eq!(response_json.len(), 1);
+	}
+}
+
+#[test]
+fn ratings() {
+	let env = get_test_environment("api_ratings.sqlite");
+	let client = &env.client;
+	complete_initial_setup(client);
+	do_auth(client);
+	env.update_index();
+
+	let songs: Vec<index::Song>;
+	{
+		let mut response = client.get("/api/flatten").dispatch();
+		let response_body = response.body_string().unwrap();
+		songs = serde_json::from_str(&response_body).unwrap();
+		assert_eq!(songs[0].starred, Some(false));
+	}
+
+	{
+		let path = Uri::percent_encode(&songs[0].path);
+		let rating = api::RatingInput {
+			rating: Some(4),
+			starred: Some(true),
+		};
+		let response = client
+			.put(format!("/api/rating/song/{}", path))
+			.body(serde_json::to_string(&rating).unwrap())
+			.dispatch();
+		assert_eq!(response.status(), Status::Ok);
+
+		let rating = api::RatingInput {
+			rating: Some(9),
+			starred: None,
+		};
+		let response = client
+			.put(format!("/api/rating/song/{}", path))
+			.body(serde_json::to_string(&rating).unwrap())
+			.dispatch();
+		assert_eq!(response.status(), Status::BadRequest);
+	}
+
+	{
+		let rating = api::RatingInput {
+			rating: None,
+			starred: Some(true),
+		};
+		let response = client
+			.put("/api/rating/artist/Khemmis")
+			.body(serde_json::to_string(&rating).unwrap())
+			.dispatch();
+		assert_eq!(response.status(), Status::Ok);
+	}
+
+	{
+		let mut response = client.get("/api/starred").dispatch();
+		assert_eq!(response.status(), Status::Ok);
+		let response_body = response.body_string().unwrap();
+		let response_json: ratings::Starred = serde_json::from_str(&response_body).unwrap();
+		assert_eq!(response_json.songs.len(), 1);
+		assert_eq!(response_json.songs[0].path, songs[0].path);
+		assert_eq!(response_json.songs[0].rating, Some(4));
+		assert_eq!(response_json.albums.len(), 0);
+		assert_eq!(response_json.artists, vec!["Khemmis".to_owned()]);
+	}
+}
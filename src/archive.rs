@@ -0,0 +1,580 @@
+use error_chain::bail;
+use log::warn;
+use rocket::http::{ContentType, Header};
+use rocket::response::{self, Responder};
+use rocket::Response;
+use std::cmp;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use std::time;
+
+use crate::cue;
+use crate::errors::*;
+use crate::index::Song;
+use crate::vfs::VFS;
+
+// Archives are written as store-only ZIP files, one file at a time, so they can be
+// streamed without holding more than a single read buffer in memory.
+// File sizes are known ahead of time, CRCs are sent after each file in a data descriptor.
+const LOCAL_HEADER_SIGNATURE: u32 = 0x0403_4b50;
+const DATA_DESCRIPTOR_SIGNATURE: u32 = 0x0807_4b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x0201_4b50;
+const ZIP64_END_SIGNATURE: u32 = 0x0606_4b50;
+const ZIP64_LOCATOR_SIGNATURE: u32 = 0x0706_4b50;
+const END_SIGNATURE: u32 = 0x0605_4b50;
+const VERSION: u16 = 45; // Needed for ZIP64
+const FLAGS: u16 = 0x0808; // Data descriptor and UTF-8 file names
+const ZIP64_EXTRA_ID: u16 = 0x0001;
+
+#[derive(Debug, PartialEq)]
+pub struct Entry {
+	pub name: String,
+	pub path: PathBuf,
+}
+
+struct CentralRecord {
+	name: String,
+	crc: u32,
+	size: u32,
+	offset: u64,
+	dos_time: u16,
+	dos_date: u16,
+}
+
+enum State {
+	NextEntry,
+	Data(File),
+	Done,
+}
+
+pub struct Archive {
+	name: String,
+	entries: std::vec::IntoIter<Entry>,
+	state: State,
+	buffer: Vec<u8>,
+	buffer_position: usize,
+	offset: u64,
+	crc_table: [u32; 256],
+	crc: u32,
+	written: u64,
+	current: Option<CentralRecord>,
+	central_directory: Vec<CentralRecord>,
+}
+
+fn crc_table() -> [u32; 256] {
+	let mut table = [0; 256];
+	for (n, entry) in table.iter_mut().enumerate() {
+		let mut c = n as u32;
+		for _ in 0..8 {
+			c = if c & 1 != 0 {
+				0xEDB8_8320 ^ (c >> 1)
+			} else {
+				c >> 1
+			};
+		}
+		*entry = c;
+	}
+	table
+}
+
+// MS-DOS timestamps have a two second resolution and start in 1980
+fn dos_timestamp(path: &Path) -> (u16, u16) {
+	let seconds = path
+		.metadata()
+		.and_then(|m| m.modified())
+		.ok()
+		.and_then(|m| m.duration_since(time::UNIX_EPOCH).ok())
+		.map(|d| d.as_secs() as i64)
+		.unwrap_or(0);
+	let days = seconds.div_euclid(86400);
+	let time_of_day = seconds.rem_euclid(86400);
+
+	// Days to civil date, from http://howardhinnant.github.io/date_algorithms.html
+	let z = days + 719_468;
+	let era = z.div_euclid(146_097);
+	let day_of_era = z.rem_euclid(146_097);
+	let year_of_era =
+		(day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+	let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+	let mp = (5 * day_of_year + 2) / 153;
+	let day = day_of_year - (153 * mp + 2) / 5 + 1;
+	let month = if mp < 10 { mp + 3 } else { mp - 9 };
+	let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+	if year < 1980 {
+		return (0, (1 << 5) | 1);
+	}
+	let dos_time =
+		(time_of_day / 3600) << 11 | (time_of_day % 3600 / 60) << 5 | (time_of_day % 60) / 2;
+	let dos_date = (cmp::min(year, 2107) - 1980) << 9 | month << 5 | day;
+	(dos_time as u16, dos_date as u16)
+}
+
+impl Archive {
+	pub fn new(name: &str, entries: Vec<Entry>) -> Result<Archive> {
+		// Name lengths are stored on 16 bits, this is checked before anything is sent
+		if let Some(entry) = entries
+			.iter()
+			.find(|e| e.name.len() > usize::from(std::u16::MAX))
+		{
+			bail!(ErrorKind::ArchiveEntryNameTooLong(
+				entry.path.to_string_lossy().into_owned()
+			));
+		}
+		Ok(Archive {
+			name: name.to_owned(),
+			entries: entries.into_iter(),
+			state: State::NextEntry,
+			buffer: Vec::new(),
+			buffer_position: 0,
+			offset: 0,
+			crc_table: crc_table(),
+			crc: 0,
+			written: 0,
+			current: None,
+			central_directory: Vec::new(),
+		})
+	}
+
+	fn push_u16(&mut self, value: u16) {
+		self.buffer.extend_from_slice(&value.to_le_bytes());
+	}
+
+	fn push_u32(&mut self, value: u32) {
+		self.buffer.extend_from_slice(&value.to_le_bytes());
+	}
+
+	fn push_u64(&mut self, value: u64) {
+		self.buffer.extend_from_slice(&value.to_le_bytes());
+	}
+
+	fn start_entry(&mut self, entry: Entry) -> io::Result<Option<File>> {
+		let file = match File::open(&entry.path) {
+			Ok(f) => f,
+			Err(e) => {
+				warn!("Leaving {} out of archive: {}", entry.path.display(), e);
+				return Ok(None);
+			}
+		};
+		let size = match file.metadata() {
+			Ok(m) if m.len() < u64::from(std::u32::MAX) => m.len() as u32,
+			_ => {
+				warn!("Leaving {} out of archive: too large", entry.path.display());
+				return Ok(None);
+			}
+		};
+		let (dos_time, dos_date) = dos_timestamp(&entry.path);
+
+		self.push_u32(LOCAL_HEADER_SIGNATURE);
+		self.push_u16(VERSION);
+		self.push_u16(FLAGS);
+		self.push_u16(0); // Stored
+		self.push_u16(dos_time);
+		self.push_u16(dos_date);
+		self.push_u32(0); // CRC, in data descriptor
+		self.push_u32(size);
+		self.push_u32(size);
+		self.push_u16(entry.name.len() as u16);
+		self.push_u16(0); // Extra field length
+		self.buffer.extend_from_slice(entry.name.as_bytes());
+
+		self.crc = 0xFFFF_FFFF;
+		self.written = 0;
+		self.current = Some(CentralRecord {
+			name: entry.name,
+			crc: 0,
+			size,
+			offset: self.offset,
+			dos_time,
+			dos_date,
+		});
+		Ok(Some(file))
+	}
+
+	fn end_entry(&mut self) {
+		if let Some(mut record) = self.current.take() {
+			record.crc = !self.crc;
+			self.push_u32(DATA_DESCRIPTOR_SIGNATURE);
+			self.push_u32(record.crc);
+			self.push_u32(record.size);
+			self.push_u32(record.size);
+			self.central_directory.push(record);
+		}
+	}
+
+	fn end_archive(&mut self) {
+		let central_directory_offset = self.offset + self.buffer.len() as u64;
+		let records = std::mem::replace(&mut self.central_directory, Vec::new());
+		for record in &records {
+			let zip64 = record.offset >= u64::from(std::u32::MAX);
+			self.push_u32(CENTRAL_HEADER_SIGNATURE);
+			self.push_u16(VERSION);
+			self.push_u16(VERSION);
+			self.push_u16(FLAGS);
+			self.push_u16(0); // Stored
+			self.push_u16(record.dos_time);
+			self.push_u16(record.dos_date);
+			self.push_u32(record.crc);
+			self.push_u32(record.size);
+			self.push_u32(record.size);
+			self.push_u16(record.name.len() as u16);
+			self.push_u16(if zip64 { 12 } else { 0 });
+			self.push_u16(0); // Comment length
+			self.push_u16(0); // Disk number
+			self.push_u16(0); // Internal attributes
+			self.push_u32(0); // External attributes
+			self.push_u32(if zip64 {
+				std::u32::MAX
+			} else {
+				record.offset as u32
+			});
+			self.buffer.extend_from_slice(record.name.as_bytes());
+			if zip64 {
+				self.push_u16(ZIP64_EXTRA_ID);
+				self.push_u16(8);
+				self.push_u64(record.offset);
+			}
+		}
+		let central_directory_size =
+			self.offset + self.buffer.len() as u64 - central_directory_offset;
+
+		let count = records.len() as u64;
+		if count >= u64::from(std::u16::MAX) || central_directory_offset >= u64::from(std::u32::MAX)
+		{
+			let zip64_end_offset = self.offset + self.buffer.len() as u64;
+			self.push_u32(ZIP64_END_SIGNATURE);
+			self.push_u64(44); // Size of the remaining record
+			self.push_u16(VERSION);
+			self.push_u16(VERSION);
+			self.push_u32(0); // Disk number
+			self.push_u32(0); // Disk with the central directory
+			self.push_u64(count);
+			self.push_u64(count);
+			self.push_u64(central_directory_size);
+			self.push_u64(central_directory_offset);
+
+			self.push_u32(ZIP64_LOCATOR_SIGNATURE);
+			self.push_u32(0);
+			self.push_u64(zip64_end_offset);
+			self.push_u32(1); // Total number of disks
+		}
+
+		self.push_u32(END_SIGNATURE);
+		self.push_u16(0); // Disk number
+		self.push_u16(0); // Disk with the central directory
+		self.push_u16(cmp::min(count, u64::from(std::u16::MAX)) as u16);
+		self.push_u16(cmp::min(count, u64::from(std::u16::MAX)) as u16);
+		self.push_u32(cmp::min(central_directory_size, u64::from(std::u32::MAX)) as u32);
+		self.push_u32(cmp::min(central_directory_offset, u64::from(std::u32::MAX)) as u32);
+		self.push_u16(0); // Comment length
+	}
+
+	fn update_crc(&mut self, data: &[u8]) {
+		let mut crc = self.crc;
+		for byte in data {
+			crc = self.crc_table[((crc ^ u32::from(*byte)) & 0xFF) as usize] ^ (crc >> 8);
+		}
+		self.crc = crc;
+	}
+}
+
+impl Read for Archive {
+	fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+		loop {
+			// Headers and such are buffered, file contents are not
+			if self.buffer_position < self.buffer.len() {
+				let length = cmp::min(out.len(), self.buffer.len() - self.buffer_position);
+				out[..length].copy_from_slice(
+					&self.buffer[self.buffer_position..self.buffer_position + length],
+				);
+				self.buffer_position += length;
+				self.offset += length as u64;
+				if self.buffer_position == self.buffer.len() {
+					self.buffer.clear();
+					self.buffer_position = 0;
+				}
+				return Ok(length);
+			}
+
+			match std::mem::replace(&mut self.state, State::Done) {
+				State::NextEntry => match self.entries.next() {
+					Some(entry) => {
+						if let Some(file) = self.start_entry(entry)? {
+							self.state = State::Data(file);
+						} else {
+							self.state = State::NextEntry;
+						}
+					}
+					None => self.end_archive(),
+				},
+				State::Data(mut file) => {
+					let length = file.read(out)?;
+					self.update_crc(&out[..length]);
+					self.offset += length as u64;
+					self.written += length as u64;
+
+					// The size announced in the local header cannot be taken back
+					let size = self.current.as_ref().map_or(0, |r| u64::from(r.size));
+					if self.written > size || (length == 0 && self.written < size) {
+						return Err(io::Error::new(
+							io::ErrorKind::InvalidData,
+							"File size changed while it was being archived",
+						));
+					}
+
+					if length == 0 {
+						self.end_entry();
+						self.state = State::NextEntry;
+					} else {
+						self.state = State::Data(file);
+						return Ok(length);
+					}
+				}
+				State::Done => return Ok(0),
+			}
+		}
+	}
+}
+
+impl<'r> Responder<'r> for Archive {
+	fn respond_to(self, _: &rocket::request::Request<'_>) -> response::Result<'r> {
+		let file_name = format!("{}.zip", self.name.replace('"', ""));
+		Response::build()
+			.header(ContentType::new("application", "zip"))
+			.header(Header::new(
+				"Content-Disposition",
+				format!("attachment; filename=\"{}\"", file_name),
+			))
+			.streamed_body(self)
+			.ok()
+	}
+}
+
+// Archive names use forward slashes, whatever the platform
+fn get_entry_name(path: &Path) -> String {
+	path.components()
+		.filter_map(|c| match c {
+			Component::Normal(s) => Some(s.to_string_lossy().into_owned()),
+			_ => None,
+		})
+		.collect::<Vec<_>>()
+		.join("/")
+}
+
+// Real and virtual paths of the files backing a song, which is a whole album file
+// for songs coming from CUE sheets
+fn get_song_files(vfs: &VFS, song: &Song) -> Vec<(PathBuf, PathBuf)> {
+	let real_path = match vfs.virtual_to_real(Path::new(&song.path)) {
+		Ok(p) => p,
+		Err(_) => return Vec::new(),
+	};
+	match song.cue_source {
+		Some(ref source) => {
+			let mut files = Vec::new();
+			if let Ok(virtual_source) = vfs.real_to_virtual(source) {
+				files.push((PathBuf::from(source), virtual_source));
+			}
+			let real_sheet = cue::get_sheet_path(&real_path.to_string_lossy()).map(Path::to_owned);
+			let virtual_sheet = cue::get_sheet_path(&song.path).map(Path::to_owned);
+			if let (Some(real_sheet), Some(virtual_sheet)) = (real_sheet, virtual_sheet) {
+				files.push((real_sheet, virtual_sheet));
+			}
+			files
+		}
+		None => vec![(real_path, PathBuf::from(&song.path))],
+	}
+}
+
+// Keeps the directory structure below the downloaded directory.
+// Names come from virtual paths, so that real paths on the server are never exposed.
+pub fn get_directory_entries(vfs: &VFS, virtual_root: &Path, songs: &[Song]) -> Vec<Entry> {
+	let mut seen = HashSet::new();
+	let mut entries = Vec::new();
+
+	for song in songs {
+		let mut files = get_song_files(vfs, song);
+		if let Some(ref artwork) = song.artwork {
+			if let Ok(real_artwork) = vfs.virtual_to_real(Path::new(artwork)) {
+				files.push((real_artwork, PathBuf::from(artwork)));
+			}
+		}
+		for (real_path, virtual_path) in files {
+			if !seen.insert(real_path.clone()) {
+				continue;
+			}
+			let relative_path = virtual_path
+				.strip_prefix(virtual_root)
+				.unwrap_or(&virtual_path);
+			entries.push(Entry {
+				name: get_entry_name(relative_path),
+				path: real_path,
+			});
+		}
+	}
+
+	entries
+}
+
+// Songs are numbered in playlist order, artwork is added as cover images
+pub fn get_playlist_entries(vfs: &VFS, songs: &[Song]) -> Vec<Entry> {
+	let mut seen = HashSet::new();
+	let mut entries = Vec::new();
+	let mut covers = 0;
+
+	for (index, song) in songs.iter().enumerate() {
+		for (file, _) in get_song_files(vfs, song) {
+			if !seen.insert(file.clone()) {
+				continue;
+			}
+			let file_name = file
+				.file_name()
+				.map(|n| n.to_string_lossy().into_owned())
+				.unwrap_or_default();
+			entries.push(Entry {
+				name: format!("{:02} - {}", index + 1, file_name),
+				path: file,
+			});
+		}
+
+		let artwork = song
+			.artwork
+			.as_ref()
+			.and_then(|a| vfs.virtual_to_real(Path::new(a)).ok());
+		if let Some(artwork) = artwork {
+			if seen.insert(artwork.clone()) {
+				covers += 1;
+				let extension = artwork
+					.extension()
+					.map(|e| format!(".{}", e.to_string_lossy()))
+					.unwrap_or_default();
+				let name = match covers {
+					1 => format!("cover{}", extension),
+					n => format!("cover {}{}", n, extension),
+				};
+				entries.push(Entry {
+					name,
+					path: artwork,
+				});
+			}
+		}
+	}
+
+	entries
+}
+
+#[test]
+fn test_crc() {
+	let mut archive = Archive::new("test", Vec::new()).unwrap();
+	archive.crc = 0xFFFF_FFFF;
+	archive.update_crc(b"123456789");
+	assert_eq!(!archive.crc, 0xCBF4_3926);
+}
+
+#[test]
+fn test_dos_timestamp() {
+	let (_, date) = dos_timestamp(Path::new("test/sample.mp3"));
+	assert!(date >> 9 >= 36); // 2016 or later
+	assert_eq!(dos_timestamp(Path::new("missing")), (0, (1 << 5) | 1));
+}
+
+#[test]
+fn test_archive() {
+	let entries = vec![
+		Entry {
+			name: "Khemmis/sample.mp3".into(),
+			path: PathBuf::from("test/sample.mp3"),
+		},
+		Entry {
+			name: "missing.mp3".into(),
+			path: PathBuf::from("test/missing.mp3"),
+		},
+		Entry {
+			name: "sample.ogg".into(),
+			path: PathBuf::from("test/sample.ogg"),
+		},
+	];
+	let mut content = Vec::new();
+	Archive::new("test", entries)
+		.unwrap()
+		.read_to_end(&mut content)
+		.unwrap();
+
+	let sizes = 24_142 + 15_760;
+	let names = "Khemmis/sample.mp3".len() + "sample.ogg".len();
+	let local_headers = 2 * (30 + 16) + names;
+	let central_directory = 2 * 46 + names;
+	assert_eq!(
+		content.len(),
+		sizes + local_headers + central_directory + 22
+	);
+	assert_eq!(&content[0..4], &LOCAL_HEADER_SIGNATURE.to_le_bytes());
+
+	// End of central directory lists both files
+	let end = &content[content.len() - 22..];
+	assert_eq!(&end[0..4], &END_SIGNATURE.to_le_bytes());
+	assert_eq!(u16::from_le_bytes([end[10], end[11]]), 2);
+}
+
+#[test]
+fn test_archive_name_too_long() {
+	let entries = vec![Entry {
+		name: "a".repeat(usize::from(std::u16::MAX) + 1),
+		path: PathBuf::from("test/sample.mp3"),
+	}];
+	assert!(Archive::new("test", entries).is_err());
+}
+
+#[test]
+fn test_archive_size_changed() {
+	use crate::utils;
+	use std::fs;
+
+	let mut path = utils::get_data_root().unwrap();
+	path.push("test_archive_size_changed.mp3");
+	fs::copy("test/sample.mp3", &path).unwrap();
+	let entries = vec![Entry {
+		name: "sample.mp3".into(),
+		path: path.clone(),
+	}];
+	let mut archive = Archive::new("test", entries).unwrap();
+
+	// Local header goes out with the original size, then the file shrinks
+	let mut header = [0; 30];
+	archive.read_exact(&mut header).unwrap();
+	fs::write(&path, vec![0; 100]).unwrap();
+	let mut content = Vec::new();
+	assert!(archive.read_to_end(&mut content).is_err());
+
+	fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_get_directory_entries() {
+	use crate::db;
+	use crate::index;
+	use crate::vfs::VFSSource;
+
+	let db = db::_get_test_db("archive_entries.sqlite");
+	index::update(&db).unwrap();
+	let vfs = db.get_vfs().unwrap();
+
+	// Entries are named after virtual paths, starting from the mount name at the collection root
+	let songs = index::flatten(&db, Path::new("")).unwrap();
+	let entries = get_directory_entries(&vfs, Path::new(""), &songs);
+	assert!(!entries.is_empty());
+	assert!(entries.iter().all(|e| e.name.starts_with("root/")));
+	assert!(entries
+		.iter()
+		.any(|e| e.name == "root/Khemmis/Hunted/02 - Candlelight.mp3"));
+
+	let songs = index::flatten(&db, Path::new("root/Khemmis")).unwrap();
+	let entries = get_directory_entries(&vfs, Path::new("root/Khemmis"), &songs);
+	assert!(entries
+		.iter()
+		.any(|e| e.name == "Hunted/02 - Candlelight.mp3"));
+	assert!(entries
+		.iter()
+		.all(|e| e.path.starts_with("test/collection/Khemmis")));
+}
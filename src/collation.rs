@@ -0,0 +1,233 @@
+use std::path::Path;
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+pub const DEFAULT_ARTICLES: &str = "The A An";
+
+// Digit runs are padded to this width so that numbers compare by value
+const NUMBER_WIDTH: usize = 10;
+
+// Sort keys of path components are joined by a separator that sorts before any
+// other character, so a directory is always followed by its own content
+const PATH_SEPARATOR: char = '\u{1}';
+
+// Lowercase text without diacritics, eg. "Björk" becomes "bjork"
+pub fn fold(text: &str) -> String {
+	text.nfkd()
+		.filter(|c| !is_combining_mark(*c))
+		.flat_map(char::to_lowercase)
+		.collect()
+}
+
+fn pad_numbers(text: &str) -> String {
+	let mut padded = String::with_capacity(text.len());
+	let mut number = String::new();
+	for c in text.chars().chain(std::iter::once('\0')) {
+		if c.is_ascii_digit() {
+			number.push(c);
+			continue;
+		}
+		if !number.is_empty() {
+			let digits = number.trim_start_matches('0');
+			let digits = if digits.is_empty() { "0" } else { digits };
+			for _ in digits.len()..NUMBER_WIDTH {
+				padded.push('0');
+			}
+			padded.push_str(digits);
+			number.clear();
+		}
+		if c != '\0' {
+			padded.push(c);
+		}
+	}
+	padded
+}
+
+// Turns names into keys which sort the way people expect them to: ignoring case,
+// accents and leading articles, and with numbers in numeric order.
+pub struct Collator {
+	articles: Vec<String>,
+}
+
+impl Collator {
+	// Articles are separated by whitespace. Articles ending with an apostrophe
+	// (eg. L') are stripped even when they are not followed by a space.
+	pub fn new(articles: &str) -> Collator {
+		Collator {
+			articles: articles.split_whitespace().map(fold).collect(),
+		}
+	}
+
+	fn strip_article<'a>(&self, text: &'a str) -> &'a str {
+		for article in &self.articles {
+			if !text.starts_with(article.as_str()) {
+				continue;
+			}
+			let rest = &text[article.len()..];
+			if article.ends_with('\'') && !rest.is_empty() {
+				return rest.trim_start();
+			}
+			if rest.starts_with(char::is_whitespace) && !rest.trim().is_empty() {
+				return rest.trim_start();
+			}
+		}
+		text
+	}
+
+	pub fn sort_key(&self, text: &str) -> String {
+		let folded = fold(text);
+		pad_numbers(self.strip_article(folded.trim()))
+	}
+
+	pub fn sort_path(&self, path: &Path) -> String {
+		let keys: Vec<String> = path
+			.iter()
+			.map(|c| self.sort_key(&c.to_string_lossy()))
+			.collect();
+		keys.join(&PATH_SEPARATOR.to_string())
+	}
+}
+
+impl Default for Collator {
+	fn default() -> Collator {
+		Collator::new(DEFAULT_ARTICLES)
+	}
+}
+
+// Words of a folded text
+pub fn split_words(text: &str) -> impl Iterator<Item = &str> {
+	text.split(|c: char| !c.is_alphanumeric())
+		.filter(|w| !w.is_empty())
+}
+
+// Number of characters to insert, delete or replace to turn one word into the other
+pub fn edit_distance(a: &str, b: &str) -> usize {
+	let b: Vec<char> = b.chars().collect();
+	let mut distances: Vec<usize> = (0..=b.len()).collect();
+	for (i, ca) in a.chars().enumerate() {
+		let mut previous = distances[0];
+		distances[0] = i + 1;
+		for (j, cb) in b.iter().enumerate() {
+			let substitution = previous + if ca == *cb { 0 } else { 1 };
+			previous = distances[j + 1];
+			distances[j + 1] = substitution.min(previous + 1).min(distances[j] + 1);
+		}
+	}
+	distances[b.len()]
+}
+
+// Short words must match exactly, longer ones may contain a typo or two
+fn get_allowed_typos(word: &str) -> usize {
+	match word.chars().count() {
+		0..=3 => 0,
+		4..=7 => 1,
+		_ => 2,
+	}
+}
+
+fn get_word_distance(query_word: &str, word: &str) -> Option<usize> {
+	if word.contains(query_word) {
+		return Some(0);
+	}
+	let allowed = get_allowed_typos(query_word);
+	if allowed == 0 {
+		return None;
+	}
+	// Query words are often the start of a longer word
+	let length = query_word.chars().count();
+	let prefix_end = word
+		.char_indices()
+		.nth(length)
+		.map_or(word.len(), |(i, _)| i);
+	let distance =
+		edit_distance(query_word, word).min(edit_distance(query_word, &word[..prefix_end]));
+	Some(distance).filter(|d| *d <= allowed)
+}
+
+// Pieces of a query word, at least one of which appears untouched in any close
+// match since each typo can only alter a single piece
+pub fn get_fuzzy_fragments(query_word: &str) -> Vec<String> {
+	let chars: Vec<char> = query_word.chars().collect();
+	let count = get_allowed_typos(query_word) + 1;
+	(0..count)
+		.map(|i| {
+			let start = i * chars.len() / count;
+			let end = (i + 1) * chars.len() / count;
+			chars[start..end].iter().collect()
+		})
+		.collect()
+}
+
+// Sum of the typos in each query word, None when a word of the query has no close
+// enough match in the text. Both the query and the text are expected to be folded.
+pub fn fuzzy_distance(query_words: &[&str], text: &str) -> Option<usize> {
+	let mut total = 0;
+	for query_word in query_words {
+		total += split_words(text)
+			.filter_map(|w| get_word_distance(query_word, w))
+			.min()?;
+	}
+	Some(total)
+}
+
+#[test]
+fn test_sort_key() {
+	let collator = Collator::default();
+	assert_eq!(fold("Björk"), "bjork");
+	assert_eq!(collator.sort_key("The Beatles"), "beatles");
+	assert_eq!(
+		collator.sort_key("Theatre of Tragedy"),
+		"theatre of tragedy"
+	);
+	assert_eq!(collator.sort_key("The"), "the");
+	assert!(collator.sort_key("Track 2") < collator.sort_key("Track 10"));
+	assert!(collator.sort_key("Eels") < collator.sort_key("Émilie Simon"));
+	assert!(collator.sort_key("Émilie Simon") < collator.sort_key("Enya"));
+	assert!(collator.sort_key("Zebra") > collator.sort_key("Ärzte"));
+
+	let collator = Collator::new("L' Les");
+	assert_eq!(collator.sort_key("L'Arc~en~Ciel"), "arc~en~ciel");
+	assert_eq!(collator.sort_key("The Beatles"), "the beatles");
+
+	let collator = Collator::default();
+	assert!(
+		collator.sort_path(Path::new("/music/The Album/02.mp3"))
+			< collator.sort_path(Path::new("/music/The Album 2/01.mp3"))
+	);
+	assert!(
+		collator.sort_path(Path::new("/music/Disc 9/01.mp3"))
+			< collator.sort_path(Path::new("/music/Disc 10/01.mp3"))
+	);
+}
+
+#[test]
+fn test_fuzzy_distance() {
+	assert_eq!(edit_distance("kitten", "sitting"), 3);
+	assert_eq!(edit_distance("", "abc"), 3);
+	assert_eq!(edit_distance("björk", "bjork"), 1);
+
+	let text = fold("Khemmis/Hunted/02 - Candlelight.mp3\nCandlelight");
+	assert_eq!(fuzzy_distance(&["candlelight"], &text), Some(0));
+	assert_eq!(fuzzy_distance(&["candlelght"], &text), Some(1));
+	assert_eq!(fuzzy_distance(&["khemis", "huntd"], &text), Some(2));
+	assert_eq!(fuzzy_distance(&["candlelight", "beatles"], &text), None);
+	assert_eq!(fuzzy_distance(&["hut"], &text), None);
+}
+
+#[test]
+fn test_fuzzy_fragments() {
+	assert_eq!(get_fuzzy_fragments("hut"), vec!["hut"]);
+	assert_eq!(get_fuzzy_fragments("khemis"), vec!["khe", "mis"]);
+	assert_eq!(
+		get_fuzzy_fragments("candlelght"),
+		vec!["can", "dle", "lght"]
+	);
+
+	let text = fold("Khemmis/Hunted/02 - Candlelight.mp3\nCandlelight");
+	for query_word in &["candlelght", "khemis", "huntd", "candelight"] {
+		assert!(fuzzy_distance(&[query_word], &text).is_some());
+		assert!(get_fuzzy_fragments(query_word)
+			.iter()
+			.any(|f| text.contains(f.as_str())));
+	}
+}
@@ -25,6 +25,11 @@ pub struct MiscSettings {
 	pub index_sleep_duration_seconds: i32,
 	pub index_album_art_pattern: String,
 	pub prefix_url: String,
+	pub listenbrainz_url: String,
+	pub analyze_loudness: i32,
+	pub precompute_waveforms: i32,
+	pub index_sort_articles: String,
+	pub lastfm_url: String,
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
@@ -45,6 +50,11 @@ pub struct Config {
 	pub reindex_every_n_seconds: Option<i32>,
 	pub mount_dirs: Option<Vec<MountPoint>>,
 	pub prefix_url: Option<String>,
+	pub listenbrainz_url: Option<String>,
+	pub lastfm_url: Option<String>,
+	pub analyze_loudness: Option<bool>,
+	pub precompute_waveforms: Option<bool>,
+	pub sort_articles: Option<Vec<String>>,
 	pub users: Option<Vec<ConfigUser>>,
 	pub ydns: Option<DDNSConfig>,
 }
@@ -87,21 +97,45 @@ where
 		reindex_every_n_seconds: None,
 		mount_dirs: None,
 		prefix_url: None,
+		listenbrainz_url: None,
+		lastfm_url: None,
+		analyze_loudness: None,
+		precompute_waveforms: None,
+		sort_articles: None,
 		users: None,
 		ydns: None,
 	};
 
-	let (art_pattern, sleep_duration, url) = misc_settings
+	let (art_pattern, sleep_duration, url, listenbrainz, lastfm, loudness, waveforms, articles): (
+		String,
+		i32,
+		String,
+		String,
+		String,
+		i32,
+		i32,
+		String,
+	) = misc_settings
 		.select((
 			index_album_art_pattern,
 			index_sleep_duration_seconds,
 			prefix_url,
+			listenbrainz_url,
+			lastfm_url,
+			analyze_loudness,
+			precompute_waveforms,
+			index_sort_articles,
 		))
 		.get_result(connection.deref())?;
 
 	config.album_art_pattern = Some(art_pattern);
 	config.reindex_every_n_seconds = Some(sleep_duration);
 	config.prefix_url = if url != "" { Some(url) } else { None };
+	config.listenbrainz_url = Some(listenbrainz);
+	config.lastfm_url = Some(lastfm);
+	config.analyze_loudness = Some(loudness != 0);
+	config.precompute_waveforms = Some(waveforms != 0);
+	config.sort_articles = Some(articles.split_whitespace().map(|a| a.to_owned()).collect());
 
 	let mount_dirs;
 	{
@@ -252,6 +286,37 @@ where
 			.execute(connection.deref())?;
 	}
 
+	if let Some(ref listenbrainz_url) = new_config.listenbrainz_url {
+		diesel::update(misc_settings::table)
+			.set(misc_settings::listenbrainz_url.eq(listenbrainz_url))
+			.execute(connection.deref())?;
+	}
+
+	if let Some(ref lastfm_url) = new_config.lastfm_url {
+		diesel::update(misc_settings::table)
+			.set(misc_settings::lastfm_url.eq(lastfm_url))
+			.execute(connection.deref())?;
+	}
+
+	if let Some(analyze_loudness) = new_config.analyze_loudness {
+		diesel::update(misc_settings::table)
+			.set(misc_settings::analyze_loudness.eq(analyze_loudness as i32))
+			.execute(connection.deref())?;
+	}
+
+	if let Some(precompute_waveforms) = new_config.precompute_waveforms {
+		diesel::update(misc_settings::table)
+			.set(misc_settings::precompute_waveforms.eq(precompute_waveforms as i32))
+			.execute(connection.deref())?;
+	}
+
+	// Articles are stored as a single whitespace separated list
+	if let Some(ref sort_articles) = new_config.sort_articles {
+		diesel::update(misc_settings::table)
+			.set(misc_settings::index_sort_articles.eq(sort_articles.join(" ")))
+			.execute(connection.deref())?;
+	}
+
 	Ok(())
 }
 
@@ -322,6 +387,11 @@ fn test_amend() {
 		album_art_pattern: Some("file\\.png".into()),
 		reindex_every_n_seconds: Some(123),
 		prefix_url: None,
+		listenbrainz_url: None,
+		lastfm_url: None,
+		analyze_loudness: None,
+		precompute_waveforms: None,
+		sort_articles: None,
 		mount_dirs: Some(vec![MountPoint {
 			source: "C:\\Music".into(),
 			name: "root".into(),
@@ -338,6 +408,11 @@ fn test_amend() {
 		album_art_pattern: Some("🖼️\\.jpg".into()),
 		reindex_every_n_seconds: None,
 		prefix_url: Some("polaris".into()),
+		listenbrainz_url: Some("http://localhost:8100".into()),
+		lastfm_url: Some("http://localhost:8200/2.0/".into()),
+		analyze_loudness: Some(true),
+		precompute_waveforms: Some(true),
+		sort_articles: Some(vec!["The".into(), "L'".into()]),
 		mount_dirs: Some(vec![MountPoint {
 			source: "/home/music".into(),
 			name: "🎵📁".into(),
@@ -378,6 +453,11 @@ fn test_amend_preserve_password_hashes() {
 		album_art_pattern: None,
 		reindex_every_n_seconds: None,
 		prefix_url: None,
+		listenbrainz_url: None,
+		lastfm_url: None,
+		analyze_loudness: None,
+		precompute_waveforms: None,
+		sort_articles: None,
 		mount_dirs: None,
 		users: Some(vec![ConfigUser {
 			name: "Teddy🐻".into(),
@@ -401,6 +481,11 @@ fn test_amend_preserve_password_hashes() {
 		album_art_pattern: None,
 		reindex_every_n_seconds: None,
 		prefix_url: None,
+		listenbrainz_url: None,
+		lastfm_url: None,
+		analyze_loudness: None,
+		precompute_waveforms: None,
+		sort_articles: None,
 		mount_dirs: None,
 		users: Some(vec![
 			ConfigUser {
@@ -440,6 +525,11 @@ fn test_toggle_admin() {
 		album_art_pattern: None,
 		reindex_every_n_seconds: None,
 		prefix_url: None,
+		listenbrainz_url: None,
+		lastfm_url: None,
+		analyze_loudness: None,
+		precompute_waveforms: None,
+		sort_articles: None,
 		mount_dirs: None,
 		users: Some(vec![ConfigUser {
 			name: "Teddy🐻".into(),
@@ -460,6 +550,11 @@ fn test_toggle_admin() {
 		album_art_pattern: None,
 		reindex_every_n_seconds: None,
 		prefix_url: None,
+		listenbrainz_url: None,
+		lastfm_url: None,
+		analyze_loudness: None,
+		precompute_waveforms: None,
+		sort_articles: None,
 		mount_dirs: None,
 		users: Some(vec![ConfigUser {
 			name: "Teddy🐻".into(),
@@ -485,6 +580,11 @@ fn test_preferences_read_write() {
 		album_art_pattern: None,
 		reindex_every_n_seconds: None,
 		prefix_url: None,
+		listenbrainz_url: None,
+		lastfm_url: None,
+		analyze_loudness: None,
+		precompute_waveforms: None,
+		sort_articles: None,
 		mount_dirs: None,
 		users: Some(vec![ConfigUser {
 			name: "Teddy🐻".into(),
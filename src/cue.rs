@@ -0,0 +1,509 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::decoding;
+use crate::errors::*;
+use crate::metadata::SongTags;
+use crate::utils;
+
+const TRACKS_PATH: &str = "tracks";
+const TRACKS_CACHE_MAX_SIZE: u64 = 2 * 1024 * 1024 * 1024; // Bytes of extracted tracks kept around
+const FRAMES_PER_SECOND: u32 = 75; // CUE sheet timestamps are in mm:ss:ff
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Track {
+	pub number: u32,
+	pub file: String,
+	pub title: Option<String>,
+	pub performer: Option<String>,
+	pub start: u32, // Milliseconds since the start of the file
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct CueSheet {
+	pub title: Option<String>,
+	pub performer: Option<String>,
+	pub year: Option<i32>,
+	pub disc_number: Option<u32>,
+	pub tracks: Vec<Track>,
+}
+
+// A track of a CUE sheet, ready to be indexed as a song
+#[derive(Debug, PartialEq)]
+pub struct VirtualSong {
+	pub path: String,
+	pub source: PathBuf,
+	pub start: u32,
+	pub end: Option<u32>,
+	pub tags: SongTags,
+}
+
+pub fn is_cue_sheet(path: &Path) -> bool {
+	match path.extension().and_then(|e| e.to_str()) {
+		Some(e) => e.eq_ignore_ascii_case("cue"),
+		None => false,
+	}
+}
+
+// Virtual songs are named after their CUE sheet and track number, eg. album.cue#03
+fn get_song_path(sheet_path: &Path, number: u32) -> String {
+	format!("{}#{:02}", sheet_path.to_string_lossy(), number)
+}
+
+pub fn get_sheet_path(song_path: &str) -> Option<&Path> {
+	song_path.rfind('#').map(|i| Path::new(&song_path[..i]))
+}
+
+// Values are either quoted or single words
+fn parse_value(value: &str) -> String {
+	let value = value.trim();
+	if value.starts_with('"') {
+		value[1..].splitn(2, '"').next().unwrap_or("").to_owned()
+	} else {
+		value.to_owned()
+	}
+}
+
+fn parse_timestamp(timestamp: &str) -> Option<u32> {
+	let parts = timestamp
+		.trim()
+		.split(':')
+		.map(|p| p.parse::<u32>().ok())
+		.collect::<Option<Vec<_>>>()?;
+	match parts[..] {
+		[minutes, seconds, frames] => minutes
+			.checked_mul(60)?
+			.checked_add(seconds)?
+			.checked_mul(1000)?
+			.checked_add(frames.checked_mul(1000)? / FRAMES_PER_SECOND),
+		_ => None,
+	}
+}
+
+pub fn parse(content: &str) -> CueSheet {
+	let mut sheet = CueSheet::default();
+	let mut file = None;
+
+	for line in content.trim_start_matches('\u{feff}').lines() {
+		let line = line.trim();
+		let mut words = line.splitn(2, char::is_whitespace);
+		let command = words.next().unwrap_or("").to_uppercase();
+		let arguments = words.next().unwrap_or("").trim();
+		let track = sheet.tracks.last_mut();
+
+		match (command.as_str(), track) {
+			("FILE", _) => {
+				// The file type comes after the name
+				let name = match arguments.rfind(char::is_whitespace) {
+					Some(i) => &arguments[..i],
+					None => arguments,
+				};
+				file = Some(parse_value(name));
+			}
+			("TRACK", _) => {
+				let mut arguments = arguments.split_whitespace();
+				let number = arguments.next().and_then(|n| n.parse::<u32>().ok());
+				let is_audio = arguments.next().map(|t| t.eq_ignore_ascii_case("AUDIO"));
+				if let (Some(number), Some(true), Some(file)) = (number, is_audio, &file) {
+					sheet.tracks.push(Track {
+						number,
+						file: file.clone(),
+						..Default::default()
+					});
+				}
+			}
+			("TITLE", Some(track)) => track.title = Some(parse_value(arguments)),
+			("TITLE", None) => sheet.title = Some(parse_value(arguments)),
+			("PERFORMER", Some(track)) => track.performer = Some(parse_value(arguments)),
+			("PERFORMER", None) => sheet.performer = Some(parse_value(arguments)),
+			("INDEX", Some(track)) => {
+				let mut arguments = arguments.splitn(2, char::is_whitespace);
+				if arguments.next() == Some("01") {
+					if let Some(start) = arguments.next().and_then(parse_timestamp) {
+						track.start = start;
+					}
+				}
+			}
+			("REM", None) => {
+				let mut arguments = arguments.splitn(2, char::is_whitespace);
+				let key = arguments.next().unwrap_or("").to_uppercase();
+				let value = parse_value(arguments.next().unwrap_or(""));
+				match key.as_str() {
+					"DATE" => sheet.year = value.get(0..4).and_then(|y| y.parse().ok()),
+					"DISCNUMBER" => sheet.disc_number = value.parse().ok(),
+					_ => (),
+				}
+			}
+			_ => (),
+		}
+	}
+
+	sheet
+}
+
+// CUE sheets are often not encoded in UTF-8, in which case Latin-1 is assumed
+pub fn read(path: &Path) -> Result<CueSheet> {
+	let content = fs::read(path)?;
+	let content = match String::from_utf8(content) {
+		Ok(s) => s,
+		Err(e) => e.as_bytes().iter().map(|b| char::from(*b)).collect(),
+	};
+	Ok(parse(&content))
+}
+
+// Source files must live next to their CUE sheet, or in a directory below it
+fn get_source(directory: &Path, file: &str) -> Option<PathBuf> {
+	let file = Path::new(file);
+	let is_relative = file.components().all(|c| match c {
+		Component::Normal(_) | Component::CurDir => true,
+		_ => false,
+	});
+	if !is_relative {
+		return None;
+	}
+	let source = directory.join(file);
+
+	// Symbolic links may still point elsewhere
+	if let (Ok(directory), Ok(real_source)) = (directory.canonicalize(), source.canonicalize()) {
+		if !real_source.starts_with(directory) {
+			return None;
+		}
+	}
+	Some(source)
+}
+
+// Splits the source files of a CUE sheet into songs, using the source file tags
+// for anything the sheet does not specify
+pub fn get_songs<F>(sheet_path: &Path, sheet: &CueSheet, mut read_tags: F) -> Vec<VirtualSong>
+where
+	F: FnMut(&Path) -> Option<SongTags>,
+{
+	let directory = sheet_path.parent().unwrap_or_else(|| Path::new(""));
+	let mut songs = Vec::new();
+
+	for (index, track) in sheet.tracks.iter().enumerate() {
+		let source = match get_source(directory, &track.file) {
+			Some(s) => s,
+			None => continue,
+		};
+		if !decoding::can_decode(&source) {
+			continue;
+		}
+		let source_tags = match read_tags(&source) {
+			Some(t) => t,
+			None => continue,
+		};
+
+		let end = match sheet.tracks.get(index + 1) {
+			Some(next) if next.file == track.file => Some(next.start),
+			_ => None,
+		};
+		let duration = match end {
+			Some(end) => Some(end.saturating_sub(track.start) / 1000),
+			None => source_tags
+				.duration
+				.map(|d| d.saturating_sub(track.start / 1000)),
+		};
+
+		let tags = SongTags {
+			title: track.title.clone(),
+			artist: track
+				.performer
+				.clone()
+				.or_else(|| sheet.performer.clone())
+				.or_else(|| source_tags.artist.clone()),
+			album_artist: sheet
+				.performer
+				.clone()
+				.or_else(|| source_tags.album_artist.clone()),
+			album: sheet.title.clone().or_else(|| source_tags.album.clone()),
+			year: sheet.year.or(source_tags.year),
+			disc_number: sheet.disc_number.or(source_tags.disc_number),
+			track_number: Some(track.number),
+			duration,
+			replaygain_track_gain: None,
+			replaygain_track_peak: None,
+			file_size: None,
+			lyrics: None,
+			..source_tags
+		};
+
+		songs.push(VirtualSong {
+			path: get_song_path(sheet_path, track.number),
+			source,
+			start: track.start,
+			end,
+			tags,
+		});
+	}
+
+	songs
+}
+
+fn hash(path: &Path, modified: SystemTime, start: u32, end: Option<u32>) -> u64 {
+	let hash_input = format!(
+		"{}:{:?}:{}:{:?}",
+		path.to_string_lossy(),
+		modified,
+		start,
+		end
+	);
+	let mut hasher = DefaultHasher::new();
+	hash_input.hash(&mut hasher);
+	hasher.finish()
+}
+
+// Bytes per sample in WAV files, which store whole bytes
+fn get_sample_size(format: decoding::Format) -> usize {
+	(format.bits_per_sample.max(1).min(32) as usize + 7) / 8
+}
+
+// Samples are written at the bit depth of the source
+fn write_wav(source: &Path, start: u32, end: Option<u32>, out_path: &Path) -> Result<()> {
+	let mut out = BufWriter::new(fs::File::create(out_path)?);
+	let mut format = None;
+	let mut data_size: u32 = 0;
+	let mut write_result = Ok(());
+
+	// Sizes are filled in once all samples are written
+	out.write_all(&[0; 44])?;
+	decoding::decode_section(source, start, end, |f, samples| {
+		if write_result.is_err() {
+			return;
+		}
+		if format.is_none() {
+			format = Some(f);
+		}
+		let bytes = get_sample_size(f);
+		let scale = (1u64 << (bytes * 8 - 1)) as f64;
+		for sample in samples {
+			let value = (f64::from(*sample) * scale)
+				.round()
+				.max(-scale)
+				.min(scale - 1.0) as i32;
+			// 8-bit WAV samples are unsigned
+			let value = if bytes == 1 { value + 128 } else { value };
+			if let Err(e) = out.write_all(&value.to_le_bytes()[..bytes]) {
+				write_result = Err(e);
+				return;
+			}
+		}
+		data_size = data_size.saturating_add((samples.len() * bytes) as u32);
+	})?;
+	write_result?;
+
+	let format = format.ok_or("No audio data")?;
+	let channels = format.channels as u16;
+	let bytes = get_sample_size(format) as u16;
+	let block_align = channels * bytes;
+	let mut header = Vec::with_capacity(44);
+	header.extend_from_slice(b"RIFF");
+	header.extend_from_slice(&data_size.saturating_add(36).to_le_bytes());
+	header.extend_from_slice(b"WAVEfmt ");
+	header.extend_from_slice(&16u32.to_le_bytes());
+	header.extend_from_slice(&1u16.to_le_bytes()); // PCM
+	header.extend_from_slice(&channels.to_le_bytes());
+	header.extend_from_slice(&format.sample_rate.to_le_bytes());
+	header.extend_from_slice(&(format.sample_rate * u32::from(block_align)).to_le_bytes());
+	header.extend_from_slice(&block_align.to_le_bytes());
+	header.extend_from_slice(&(bytes * 8).to_le_bytes()); // Bits per sample
+	header.extend_from_slice(b"data");
+	header.extend_from_slice(&data_size.to_le_bytes());
+	out.seek(SeekFrom::Start(0))?;
+	out.write_all(&header)?;
+	out.flush()?;
+	Ok(())
+}
+
+// Removes the least recently written tracks until the cache fits within max_size bytes
+fn evict(directory: &Path, max_size: u64) -> Result<()> {
+	let mut tracks = Vec::new();
+	for entry in fs::read_dir(directory)? {
+		let path = entry?.path();
+		if path.extension().and_then(|e| e.to_str()) != Some("wav") {
+			continue;
+		}
+		if let Ok(metadata) = fs::metadata(&path) {
+			let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+			tracks.push((modified, metadata.len(), path));
+		}
+	}
+
+	let mut size: u64 = tracks.iter().map(|(_, len, _)| len).sum();
+	tracks.sort();
+	for (_, len, path) in tracks {
+		if size <= max_size {
+			break;
+		}
+		if fs::remove_file(&path).is_ok() {
+			size -= len;
+		}
+	}
+	Ok(())
+}
+
+// Decodes a section of a file into a WAV file, which is cached under the data root
+pub fn extract(source: &Path, start: u32, end: Option<u32>) -> Result<PathBuf> {
+	let modified = fs::metadata(source)?.modified()?;
+	let mut directory = utils::get_data_root()?;
+	directory.push(TRACKS_PATH);
+	fs::create_dir_all(&directory)?;
+	let out_path = directory.join(format!("{}.wav", hash(source, modified, start, end)));
+
+	if !out_path.exists() {
+		// Concurrent requests for the same track each write their own file
+		let partial_path = directory.join(format!("{:016x}.part", rand::random::<u64>()));
+		if let Err(e) = write_wav(source, start, end, &partial_path) {
+			fs::remove_file(&partial_path).ok();
+			return Err(e);
+		}
+		// Older tracks make room before this one joins the cache, so it cannot be evicted right away
+		evict(&directory, TRACKS_CACHE_MAX_SIZE)?;
+		fs::rename(&partial_path, &out_path)?;
+	}
+
+	Ok(out_path)
+}
+
+#[cfg(test)]
+const SAMPLE_SHEET: &str = "\u{feff}REM GENRE Metal
+REM DATE 2016
+PERFORMER \"Khemmis\"
+TITLE \"Hunted\"
+FILE \"Hunted.flac\" WAVE
+  TRACK 01 AUDIO
+    TITLE \"Above The Water\"
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    TITLE \"Candlelight\"
+    PERFORMER \"Khemmis & Friends\"
+    INDEX 00 08:01:00
+    INDEX 01 08:03:37
+FILE \"Bonus.mp3\" MP3
+  TRACK 03 AUDIO
+    TITLE \"Three Gates\"
+    INDEX 01 00:00:00
+";
+
+#[test]
+fn test_parse() {
+	let sheet = parse(SAMPLE_SHEET);
+	assert_eq!(sheet.title, Some("Hunted".into()));
+	assert_eq!(sheet.performer, Some("Khemmis".into()));
+	assert_eq!(sheet.year, Some(2016));
+	assert_eq!(sheet.tracks.len(), 3);
+	assert_eq!(
+		sheet.tracks[1],
+		Track {
+			number: 2,
+			file: "Hunted.flac".into(),
+			title: Some("Candlelight".into()),
+			performer: Some("Khemmis & Friends".into()),
+			start: 483_493,
+		}
+	);
+	assert_eq!(sheet.tracks[2].file, "Bonus.mp3");
+}
+
+#[test]
+fn test_get_songs() {
+	let sheet = parse(SAMPLE_SHEET);
+	let sheet_path = Path::new("music/Hunted.cue");
+	let songs = get_songs(sheet_path, &sheet, |source| {
+		Some(SongTags {
+			album: Some("Source album".into()),
+			duration: Some(if source.ends_with("Hunted.flac") {
+				900
+			} else {
+				300
+			}),
+			..Default::default()
+		})
+	});
+
+	assert_eq!(songs.len(), 3);
+	assert_eq!(songs[0].path, "music/Hunted.cue#01");
+	assert_eq!(songs[0].source, Path::new("music/Hunted.flac"));
+	assert_eq!(songs[0].end, Some(483_493));
+	assert_eq!(songs[0].tags.duration, Some(483));
+	assert_eq!(songs[0].tags.artist, Some("Khemmis".into()));
+	assert_eq!(songs[0].tags.album, Some("Hunted".into()));
+	assert_eq!(songs[1].end, None);
+	assert_eq!(songs[1].tags.duration, Some(417));
+	assert_eq!(songs[1].tags.artist, Some("Khemmis & Friends".into()));
+	assert_eq!(songs[1].tags.album_artist, Some("Khemmis".into()));
+	assert_eq!(songs[2].source, Path::new("music/Bonus.mp3"));
+	assert_eq!(songs[2].tags.duration, Some(300));
+
+	assert_eq!(get_sheet_path(&songs[0].path), Some(sheet_path));
+
+	let escaping_sheet = parse(
+		"FILE \"../Hunted.flac\" WAVE\n  TRACK 01 AUDIO\n    INDEX 01 00:00:00\n\
+		 FILE \"/etc/Hunted.flac\" WAVE\n  TRACK 02 AUDIO\n    INDEX 01 00:00:00\n",
+	);
+	assert_eq!(escaping_sheet.tracks.len(), 2);
+	assert!(get_songs(sheet_path, &escaping_sheet, |_| Some(SongTags::default())).is_empty());
+}
+
+#[test]
+fn test_parse_timestamp() {
+	assert_eq!(parse_timestamp("08:03:37"), Some(483_493));
+	assert_eq!(parse_timestamp("08:03"), None);
+	assert_eq!(parse_timestamp("71583:00:00"), None);
+	assert_eq!(parse_timestamp("00:4294968:00"), None);
+	assert_eq!(parse_timestamp("00:00:4294968"), None);
+}
+
+#[test]
+fn test_extract() {
+	let source = Path::new("test/sample.ogg");
+	let wav = extract(source, 0, None).unwrap();
+	let content = fs::read(wav).unwrap();
+	assert_eq!(&content[0..4], b"RIFF");
+	assert_eq!(&content[8..12], b"WAVE");
+	let data_size = u32::from_le_bytes([content[40], content[41], content[42], content[43]]);
+	assert_eq!(content.len(), 44 + data_size as usize);
+
+	// Second track of a 24-bit FLAC file, where each block of 4096 samples holds a constant value
+	let sheet_path = Path::new("test/cue/Hunted.cue");
+	let sheet = read(sheet_path).unwrap();
+	let songs = get_songs(sheet_path, &sheet, |_| Some(SongTags::default()));
+	assert_eq!(songs.len(), 3);
+	assert_eq!((songs[1].start, songs[1].end), (1493, Some(3000)));
+	let wav = extract(&songs[1].source, songs[1].start, songs[1].end).unwrap();
+	let content = fs::read(wav).unwrap();
+	assert_eq!(u16::from_le_bytes([content[34], content[35]]), 24);
+	let samples = content[44..]
+		.chunks_exact(3)
+		.map(|s| i32::from_le_bytes([0, s[0], s[1], s[2]]) >> 8)
+		.collect::<Vec<_>>();
+	assert_eq!(samples.len(), 24_000 - 11_944);
+	assert_eq!(samples[0], 300_000);
+	assert_eq!(samples[samples.len() - 1], 600_000);
+}
+
+#[test]
+fn test_evict() {
+	let mut directory = utils::get_data_root().unwrap();
+	directory.push("test_cue_evict");
+	if directory.exists() {
+		fs::remove_dir_all(&directory).unwrap();
+	}
+	fs::create_dir_all(&directory).unwrap();
+	// Written in order, so that a.wav is never more recent than b.wav
+	for name in &["a.wav", "b.wav"] {
+		fs::write(directory.join(name), vec![0; 100]).unwrap();
+	}
+	fs::write(directory.join("abc.part"), vec![0; 100]).unwrap();
+
+	evict(&directory, 150).unwrap();
+	assert!(!directory.join("a.wav").exists());
+	assert!(directory.join("b.wav").exists());
+	assert!(directory.join("abc.part").exists());
+
+	fs::remove_dir_all(&directory).unwrap();
+}
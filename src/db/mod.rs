@@ -103,6 +103,35 @@ pub fn _get_test_db(name: &str) -> DB {
 	db
 }
 
+// Test database whose only mount point, named root, is an empty directory under test/
+pub fn _get_test_db_with_collection(name: &str, collection: &str) -> (DB, PathBuf) {
+	let db = _get_test_db(name);
+
+	let mut collection_path = PathBuf::new();
+	collection_path.push("test");
+	collection_path.push(collection);
+	if collection_path.exists() {
+		fs::remove_dir_all(&collection_path).unwrap();
+	}
+	fs::create_dir_all(&collection_path).unwrap();
+
+	{
+		let connection = db.get_connection();
+		diesel::delete(mount_points::table)
+			.execute(connection.deref())
+			.unwrap();
+		diesel::insert_into(mount_points::table)
+			.values((
+				mount_points::source.eq(collection_path.to_string_lossy()),
+				mount_points::name.eq("root"),
+			))
+			.execute(connection.deref())
+			.unwrap();
+	}
+
+	(db, collection_path)
+}
+
 #[test]
 fn test_migrations_up() {
 	_get_test_db("migrations_up.sqlite");
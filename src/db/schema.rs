@@ -17,6 +17,35 @@ table! {
 		album -> Nullable<Text>,
 		artwork -> Nullable<Text>,
 		date_added -> Integer,
+		disc_number -> Nullable<Integer>,
+		sort_path -> Nullable<Text>,
+		sort_artist -> Nullable<Text>,
+		sort_album -> Nullable<Text>,
+	}
+}
+
+table! {
+	loudness (id) {
+		id -> Integer,
+		path -> Text,
+		integrated -> Nullable<Float>,
+		true_peak -> Float,
+		modified -> Integer,
+	}
+}
+
+table! {
+	metadata_overrides (id) {
+		id -> Integer,
+		path -> Text,
+		title -> Nullable<Text>,
+		artist -> Nullable<Text>,
+		album_artist -> Nullable<Text>,
+		album -> Nullable<Text>,
+		year -> Nullable<Integer>,
+		track_number -> Nullable<Integer>,
+		disc_number -> Nullable<Integer>,
+		genre -> Nullable<Text>,
 	}
 }
 
@@ -27,6 +56,11 @@ table! {
 		index_sleep_duration_seconds -> Integer,
 		index_album_art_pattern -> Text,
 		prefix_url -> Text,
+		listenbrainz_url -> Text,
+		analyze_loudness -> Integer,
+		precompute_waveforms -> Integer,
+		index_sort_articles -> Text,
+		lastfm_url -> Text,
 	}
 }
 
@@ -38,12 +72,29 @@ table! {
 	}
 }
 
+table! {
+	playlist_shares (id) {
+		id -> Integer,
+		playlist -> Integer,
+		user -> Nullable<Integer>,
+		collaborative -> Integer,
+	}
+}
+
 table! {
 	playlist_songs (id) {
 		id -> Integer,
 		playlist -> Integer,
 		path -> Text,
 		ordering -> Integer,
+		track_number -> Nullable<Integer>,
+		disc_number -> Nullable<Integer>,
+		title -> Nullable<Text>,
+		artist -> Nullable<Text>,
+		album -> Nullable<Text>,
+		duration -> Nullable<Integer>,
+		file_size -> Nullable<BigInt>,
+		content_hash -> Nullable<Text>,
 	}
 }
 
@@ -55,6 +106,70 @@ table! {
 	}
 }
 
+table! {
+	plays (id) {
+		id -> Integer,
+		user -> Integer,
+		path -> Text,
+		parent -> Text,
+		artist -> Nullable<Text>,
+		played_at -> Integer,
+		scrobbled -> Integer,
+	}
+}
+
+table! {
+	ratings (id) {
+		id -> Integer,
+		user -> Integer,
+		kind -> Text,
+		target -> Text,
+		rating -> Nullable<Integer>,
+		starred -> Integer,
+	}
+}
+
+table! {
+	scrobble_queue (id) {
+		id -> Integer,
+		user -> Integer,
+		service -> Text,
+		artist -> Nullable<Text>,
+		title -> Nullable<Text>,
+		album -> Nullable<Text>,
+		track_number -> Nullable<Integer>,
+		duration -> Nullable<Integer>,
+		played_at -> Integer,
+		attempts -> Integer,
+		next_attempt -> Integer,
+	}
+}
+
+table! {
+	search_index (id) {
+		id -> Integer,
+		path -> Text,
+		parent -> Text,
+		terms -> Text,
+	}
+}
+
+table! {
+	share_links (id) {
+		id -> Integer,
+		token -> Text,
+		owner -> Integer,
+		kind -> Text,
+		target -> Text,
+		created -> Integer,
+		expires -> Nullable<Integer>,
+		password_salt -> Nullable<Binary>,
+		password_hash -> Nullable<Binary>,
+		failed_attempts -> Integer,
+		blocked_until -> Nullable<Integer>,
+	}
+}
+
 table! {
 	songs (id) {
 		id -> Integer,
@@ -69,6 +184,36 @@ table! {
 		album -> Nullable<Text>,
 		artwork -> Nullable<Text>,
 		duration -> Nullable<Integer>,
+		tag_rating -> Nullable<Integer>,
+		replaygain_track_gain -> Nullable<Float>,
+		replaygain_track_peak -> Nullable<Float>,
+		replaygain_album_gain -> Nullable<Float>,
+		replaygain_album_peak -> Nullable<Float>,
+		codec -> Nullable<Text>,
+		bitrate -> Nullable<Integer>,
+		sample_rate -> Nullable<Integer>,
+		bit_depth -> Nullable<Integer>,
+		channels -> Nullable<Integer>,
+		file_size -> Nullable<BigInt>,
+		has_lyrics -> Integer,
+		cue_source -> Nullable<Text>,
+		cue_start -> Nullable<Integer>,
+		cue_end -> Nullable<Integer>,
+		genre -> Nullable<Text>,
+		sort_path -> Nullable<Text>,
+		sort_title -> Nullable<Text>,
+		sort_artist -> Nullable<Text>,
+		sort_album -> Nullable<Text>,
+	}
+}
+
+table! {
+	suggestions (id) {
+		id -> Integer,
+		kind -> Text,
+		value -> Text,
+		term -> Text,
+		weight -> Integer,
 	}
 }
 
@@ -81,19 +226,36 @@ table! {
 		admin -> Integer,
 		lastfm_username -> Nullable<Text>,
 		lastfm_session_key -> Nullable<Text>,
+		listenbrainz_token -> Nullable<Text>,
+		listenbrainz_linked_at -> Nullable<Integer>,
+		listenbrainz_imported_until -> Integer,
 	}
 }
 
+joinable!(playlist_shares -> playlists (playlist));
 joinable!(playlist_songs -> playlists (playlist));
 joinable!(playlists -> users (owner));
+joinable!(plays -> users (user));
+joinable!(ratings -> users (user));
+joinable!(scrobble_queue -> users (user));
+joinable!(share_links -> users (owner));
 
 allow_tables_to_appear_in_same_query!(
 	ddns_config,
 	directories,
+	loudness,
+	metadata_overrides,
 	misc_settings,
 	mount_points,
+	playlist_shares,
 	playlist_songs,
 	playlists,
+	plays,
+	ratings,
+	scrobble_queue,
+	search_index,
+	share_links,
 	songs,
+	suggestions,
 	users,
 );
@@ -0,0 +1,318 @@
+use claxon;
+use claxon::frame::FrameReader;
+use claxon::input::BufferedReader;
+use error_chain::bail;
+use lewton::inside_ogg::OggStreamReader;
+use minimp3;
+use std::convert::TryInto;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::errors::*;
+use crate::utils;
+use crate::utils::AudioFormat;
+
+const DECODING_BUFFER_SIZE: usize = 8192; // Samples handed out at once
+const FLAC_SEEK_TABLE_BLOCK: u8 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Format {
+	pub sample_rate: u32,
+	pub channels: usize,
+	pub bits_per_sample: u32,
+}
+
+pub fn can_decode(path: &Path) -> bool {
+	match utils::get_audio_format(path) {
+		Some(AudioFormat::FLAC) | Some(AudioFormat::MP3) | Some(AudioFormat::OGG) => true,
+		_ => false,
+	}
+}
+
+// Decodes a whole file, handing out interleaved samples between -1.0 and 1.0
+pub fn decode<F>(path: &Path, on_samples: F) -> Result<()>
+where
+	F: FnMut(Format, &[f32]),
+{
+	decode_section(path, 0, None, on_samples)
+}
+
+// Decodes the part of a file between two timestamps in milliseconds.
+// Formats which support it skip ahead to the start instead of decoding what comes before.
+pub fn decode_section<F>(path: &Path, start: u32, end: Option<u32>, mut on_samples: F) -> Result<()>
+where
+	F: FnMut(Format, &[f32]),
+{
+	// Decoders tell the position (in frames) of the first sample of each block,
+	// and stop once this returns false
+	let on_block = |format: Format, position: u64, samples: &[f32]| -> bool {
+		if format.channels == 0 {
+			return false;
+		}
+		let frame_index = |ms: u32| u64::from(ms) * u64::from(format.sample_rate) / 1000;
+		let first_frame = frame_index(start);
+		let last_frame = end.map(frame_index).unwrap_or(std::u64::MAX);
+		let frames = (samples.len() / format.channels) as u64;
+		let from = first_frame.saturating_sub(position).min(frames) as usize;
+		let to = last_frame.saturating_sub(position).min(frames) as usize;
+		if from < to {
+			on_samples(
+				format,
+				&samples[from * format.channels..to * format.channels],
+			);
+		}
+		position.saturating_add(frames) < last_frame
+	};
+
+	match utils::get_audio_format(path) {
+		Some(AudioFormat::FLAC) => decode_flac(path, start, on_block),
+		Some(AudioFormat::MP3) => decode_mp3(path, on_block),
+		Some(AudioFormat::OGG) => decode_vorbis(path, start, on_block),
+		_ => bail!("Unsupported file format for decoding"),
+	}
+}
+
+// Finds where audio frames begin, and the seek points of the stream as (sample, offset) pairs
+fn read_flac_seek_table<R: Read + Seek>(reader: &mut R) -> Result<(u64, Vec<(u64, u64)>)> {
+	let mut marker = [0; 4];
+	reader.read_exact(&mut marker)?;
+	if &marker != b"fLaC" {
+		bail!("Missing FLAC stream marker");
+	}
+
+	let mut audio_offset: u64 = 4;
+	let mut seek_points = Vec::new();
+	loop {
+		let mut header = [0; 4];
+		reader.read_exact(&mut header)?;
+		let length = u32::from_be_bytes([0, header[1], header[2], header[3]]);
+		audio_offset += 4 + u64::from(length);
+		if header[0] & 0x7f == FLAC_SEEK_TABLE_BLOCK {
+			let mut table = vec![0; length as usize];
+			reader.read_exact(&mut table)?;
+			for point in table.chunks_exact(18) {
+				let sample = u64::from_be_bytes(point[0..8].try_into().unwrap());
+				let offset = u64::from_be_bytes(point[8..16].try_into().unwrap());
+				// Placeholder points have all bits set
+				if sample != std::u64::MAX {
+					seek_points.push((sample, offset));
+				}
+			}
+		} else {
+			reader.seek(SeekFrom::Current(i64::from(length)))?;
+		}
+		if header[0] & 0x80 != 0 {
+			break;
+		}
+	}
+	Ok((audio_offset, seek_points))
+}
+
+fn decode_flac<F>(path: &Path, start: u32, mut on_block: F) -> Result<()>
+where
+	F: FnMut(Format, u64, &[f32]) -> bool,
+{
+	let streaminfo = claxon::FlacReader::open(path)?.streaminfo();
+	let format = Format {
+		sample_rate: streaminfo.sample_rate,
+		channels: streaminfo.channels as usize,
+		bits_per_sample: streaminfo.bits_per_sample,
+	};
+	let scale = 1.0 / (1u64 << (streaminfo.bits_per_sample - 1)) as f32;
+
+	// Decoding resumes from the last seek point before the start
+	let mut file = fs::File::open(path)?;
+	let (audio_offset, seek_points) = read_flac_seek_table(&mut file)?;
+	let target = u64::from(start) * u64::from(format.sample_rate) / 1000;
+	let (mut position, offset) = seek_points
+		.iter()
+		.take_while(|(sample, _)| *sample <= target)
+		.last()
+		.cloned()
+		.unwrap_or((0, 0));
+	let offset = audio_offset
+		.checked_add(offset)
+		.ok_or("Invalid FLAC seek point")?;
+	file.seek(SeekFrom::Start(offset))?;
+
+	let mut frames = FrameReader::new(BufferedReader::new(file));
+	let mut buffer = Vec::new();
+	let mut samples = Vec::with_capacity(DECODING_BUFFER_SIZE);
+	while let Some(block) = frames.read_next_or_eof(buffer)? {
+		samples.clear();
+		for index in 0..block.duration() {
+			for channel in 0..block.channels() {
+				samples.push(block.sample(channel, index) as f32 * scale);
+			}
+		}
+		// Block times reported by claxon are wrong for a short last block, so positions are counted here
+		if !on_block(format, position, &samples) {
+			break;
+		}
+		position += u64::from(block.duration());
+		buffer = block.into_buffer();
+	}
+	Ok(())
+}
+
+// MP3 frames borrow data from the ones before them, so decoding always starts from the beginning
+fn decode_mp3<F>(path: &Path, mut on_block: F) -> Result<()>
+where
+	F: FnMut(Format, u64, &[f32]) -> bool,
+{
+	let file = fs::File::open(path)?;
+	let mut decoder = minimp3::Decoder::new(file);
+	let mut buffer = Vec::with_capacity(DECODING_BUFFER_SIZE);
+	let mut position = 0;
+	loop {
+		match decoder.next_frame() {
+			Ok(frame) => {
+				let format = Format {
+					sample_rate: frame.sample_rate as u32,
+					channels: frame.channels,
+					bits_per_sample: 16,
+				};
+				buffer.clear();
+				buffer.extend(frame.data.iter().map(|s| f32::from(*s) / 32768.0));
+				if !on_block(format, position, &buffer) {
+					break;
+				}
+				position += (frame.data.len() / frame.channels.max(1)) as u64;
+			}
+			Err(minimp3::Error::Eof) => break,
+			Err(minimp3::Error::SkippedData) => continue,
+			Err(minimp3::Error::InsufficientData) => break,
+			Err(minimp3::Error::Io(e)) => return Err(e.into()),
+		}
+	}
+	Ok(())
+}
+
+fn decode_vorbis<F>(path: &Path, start: u32, mut on_block: F) -> Result<()>
+where
+	F: FnMut(Format, u64, &[f32]) -> bool,
+{
+	// Seeking has a page granularity and may land past the start, in which case
+	// decoding starts over from the beginning
+	if start > 0 && read_vorbis(path, Some(start), &mut on_block)? {
+		return Ok(());
+	}
+	read_vorbis(path, None, &mut on_block)?;
+	Ok(())
+}
+
+// Returns false when seeking went past the start, before anything was handed out
+fn read_vorbis<F>(path: &Path, start: Option<u32>, on_block: &mut F) -> Result<bool>
+where
+	F: FnMut(Format, u64, &[f32]) -> bool,
+{
+	let file = fs::File::open(path)?;
+	let mut reader = OggStreamReader::new(file)?;
+	let format = Format {
+		sample_rate: reader.ident_hdr.audio_sample_rate,
+		channels: reader.ident_hdr.audio_channels as usize,
+		bits_per_sample: 16,
+	};
+	let channels = format.channels.max(1);
+
+	// The position of decoded samples is unknown after seeking, until the end of a page
+	let mut position = None;
+	let mut pending = Vec::new();
+	match start {
+		Some(start) => {
+			let target = u64::from(start) * u64::from(format.sample_rate) / 1000;
+			reader.seek_absgp_pg(target)?;
+		}
+		None => position = Some(0),
+	}
+
+	let mut buffer = Vec::with_capacity(DECODING_BUFFER_SIZE);
+	while let Some(packet) = reader.read_dec_packet_itl()? {
+		buffer.clear();
+		buffer.extend(packet.iter().map(|s| f32::from(*s) / 32768.0));
+		let first_frame = match position {
+			Some(p) => p,
+			None => {
+				pending.extend_from_slice(&buffer);
+				let page_end = match reader.get_last_absgp() {
+					Some(p) => p,
+					None => continue,
+				};
+				let first_frame = page_end.saturating_sub((pending.len() / channels) as u64);
+				let target = u64::from(start.unwrap_or(0)) * u64::from(format.sample_rate) / 1000;
+				if first_frame > target {
+					return Ok(false);
+				}
+				std::mem::swap(&mut buffer, &mut pending);
+				pending.clear();
+				first_frame
+			}
+		};
+		if !on_block(format, first_frame, &buffer) {
+			break;
+		}
+		position = Some(first_frame + (buffer.len() / channels) as u64);
+	}
+	Ok(true)
+}
+
+#[test]
+fn test_decode() {
+	for path in &["test/sample.flac", "test/sample.mp3", "test/sample.ogg"] {
+		let mut format = None;
+		let mut sample_count = 0;
+		decode(Path::new(path), |f, samples| {
+			format = Some(f);
+			sample_count += samples.len();
+			assert!(samples.iter().all(|s| *s >= -1.0 && *s <= 1.0));
+		})
+		.unwrap();
+		let format = format.unwrap();
+		assert_eq!(format.sample_rate, 44100);
+		assert!(sample_count > 0);
+		assert_eq!(sample_count % format.channels, 0);
+	}
+
+	let unsupported = Path::new("test/collection/Khemmis/Hunted/Folder.jpg");
+	assert!(unsupported.exists());
+	assert!(!can_decode(unsupported));
+	assert!(decode(unsupported, |_, _| ()).is_err());
+}
+
+#[test]
+fn test_decode_section() {
+	for path in &["test/sample.flac", "test/sample.mp3"] {
+		let path = Path::new(path);
+		let mut format = None;
+		let mut all_samples = Vec::new();
+		decode(path, |f, samples| {
+			format = Some(f);
+			all_samples.extend_from_slice(samples);
+		})
+		.unwrap();
+		let format = format.unwrap();
+
+		let mut section = Vec::new();
+		decode_section(path, 300, Some(400), |_, samples| {
+			section.extend_from_slice(samples);
+		})
+		.unwrap();
+		let first_sample = 300 * format.sample_rate as usize / 1000 * format.channels;
+		let last_sample = 400 * format.sample_rate as usize / 1000 * format.channels;
+		assert_eq!(section[..], all_samples[first_sample..last_sample]);
+	}
+
+	// After seeking, Vorbis positions follow the granule positions of the stream (20375 frames long)
+	let mut frame_count = 0;
+	decode_section(
+		Path::new("test/sample.ogg"),
+		300,
+		None,
+		|format, samples| {
+			frame_count += samples.len() / format.channels;
+		},
+	)
+	.unwrap();
+	assert_eq!(frame_count, 20375 - 13230);
+}
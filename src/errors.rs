@@ -9,6 +9,7 @@ use image;
 use lewton;
 use metaflac;
 use regex;
+use reqwest;
 use rocket;
 use rustfm_scrobble;
 use serde_json;
@@ -18,6 +19,7 @@ use toml;
 error_chain! {
 	foreign_links {
 		Ape(ape::Error);
+		Claxon(claxon::Error);
 		Diesel(diesel::result::Error);
 		DieselConnection(diesel::ConnectionError);
 		DieselMigration(diesel_migrations::RunMigrationsError);
@@ -31,6 +33,7 @@ error_chain! {
 		Time(std::time::SystemTimeError);
 		Toml(toml::de::Error);
 		Regex(regex::Error);
+		Reqwest(reqwest::Error);
 		RocketConfig(rocket::config::ConfigError);
 		Scrobbler(rustfm_scrobble::ScrobblerError);
 		Vorbis(lewton::VorbisError);
@@ -41,6 +44,56 @@ error_chain! {
 		IncorrectCredentials {}
 		EncodingError {}
 		MissingLastFMCredentials {}
+		LastFMRequestFailed(status: u16) {
+			description("Last.fm request failed")
+			display("Last.fm request failed with status {}", status)
+		}
+		MissingListenBrainzCredentials {}
+		ListenBrainzRequestFailed(status: u16) {
+			description("ListenBrainz request failed")
+			display("ListenBrainz request failed with status {}", status)
+		}
+		PlaylistAccessDenied {}
+		PlaylistNotFound {}
+		InvalidRating(rating: i32) {
+			description("Invalid rating")
+			display("Invalid rating {}, expected a value between 0 and 5", rating)
+		}
+		UnsupportedWaveformFormat(format: String) {
+			description("Unsupported waveform format")
+			display("Unsupported waveform format {}, expected json or binary", format)
+		}
+		UnsupportedTagWriting(path: String) {
+			description("Unsupported format for tag writing")
+			display("Tags cannot be written to {}", path)
+		}
+		ShareLinkNotFound {}
+		ShareLinkLocked {}
+		ShareLinkUnlockBlocked {}
+		InvalidUploadPath(path: String) {
+			description("Invalid upload path")
+			display("Files cannot be uploaded to {}", path)
+		}
+		UnsupportedUploadFormat(path: String) {
+			description("Unsupported upload format")
+			display("{} is not a supported audio, artwork or CUE sheet file", path)
+		}
+		UploadConflict(path: String) {
+			description("Upload conflict")
+			display("{} already exists", path)
+		}
+		UploadOffsetMismatch(expected: u64) {
+			description("Upload offset mismatch")
+			display("Upload offset mismatch, expected {}", expected)
+		}
+		InvalidPage(reason: String) {
+			description("Invalid page")
+			display("Invalid page: {}", reason)
+		}
+		ArchiveEntryNameTooLong(path: String) {
+			description("Archive entry name too long")
+			display("Archive entry name is too long for {}", path)
+		}
 	}
 }
 
@@ -50,6 +103,19 @@ impl<'r> rocket::response::Responder<'r> for Error {
 		build
 			.status(match self.0 {
 				ErrorKind::IncorrectCredentials => rocket::http::Status::Unauthorized,
+				ErrorKind::PlaylistAccessDenied => rocket::http::Status::Forbidden,
+				ErrorKind::PlaylistNotFound => rocket::http::Status::NotFound,
+				ErrorKind::InvalidRating(_) => rocket::http::Status::BadRequest,
+				ErrorKind::UnsupportedWaveformFormat(_) => rocket::http::Status::BadRequest,
+				ErrorKind::UnsupportedTagWriting(_) => rocket::http::Status::BadRequest,
+				ErrorKind::ShareLinkNotFound => rocket::http::Status::NotFound,
+				ErrorKind::ShareLinkLocked => rocket::http::Status::Unauthorized,
+				ErrorKind::ShareLinkUnlockBlocked => rocket::http::Status::TooManyRequests,
+				ErrorKind::InvalidUploadPath(_) => rocket::http::Status::BadRequest,
+				ErrorKind::UnsupportedUploadFormat(_) => rocket::http::Status::UnsupportedMediaType,
+				ErrorKind::UploadConflict(_) => rocket::http::Status::Conflict,
+				ErrorKind::UploadOffsetMismatch(_) => rocket::http::Status::Conflict,
+				ErrorKind::InvalidPage(_) => rocket::http::Status::BadRequest,
 				_ => rocket::http::Status::InternalServerError,
 			})
 			.ok()
@@ -0,0 +1,477 @@
+use core::ops::Deref;
+use diesel;
+use diesel::dsl::sql;
+use diesel::prelude::*;
+use diesel::sql_types;
+use diesel::sqlite::SqliteConnection;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time;
+
+#[cfg(test)]
+use crate::db;
+use crate::db::ConnectionSource;
+use crate::db::{directories, plays, songs, users};
+use crate::errors::*;
+use crate::index::{self, CollectionFile, Directory, Song};
+use crate::vfs::{VFSSource, VFS};
+
+const HISTORY_LOOKUP_BUFFER_SIZE: usize = 500; // Songs looked up in each query
+
+// Diesel cannot mix aggregates and plain columns in a select clause
+type PlayStats = (
+	sql_types::Text,
+	sql_types::BigInt,
+	sql_types::Nullable<sql_types::Integer>,
+);
+type PlayCount = (sql_types::Text, sql_types::BigInt);
+type ArtistPlayCount = (sql_types::Nullable<sql_types::Text>, sql_types::BigInt);
+
+#[derive(Insertable)]
+#[table_name = "plays"]
+struct NewPlay {
+	user: i32,
+	path: String,
+	parent: String,
+	artist: Option<String>,
+	played_at: i32,
+	scrobbled: i32,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct AlbumPlays {
+	pub album: Directory,
+	pub play_count: i32,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ArtistPlays {
+	pub artist: String,
+	pub play_count: i32,
+}
+
+fn now() -> Result<i32> {
+	let now = time::SystemTime::now().duration_since(time::UNIX_EPOCH)?;
+	Ok(now.as_secs() as i32)
+}
+
+fn find_user_id(connection: &SqliteConnection, username: &str) -> Result<i32> {
+	let id = users::table
+		.filter(users::name.eq(username))
+		.select(users::id)
+		.get_result(connection)?;
+	Ok(id)
+}
+
+fn load_songs(
+	connection: &SqliteConnection,
+	vfs: &VFS,
+	plays: Vec<(String, i64, Option<i32>)>,
+) -> Result<Vec<Song>> {
+	let paths: Vec<&String> = plays.iter().map(|p| &p.0).collect();
+	let mut real_songs: HashMap<String, Song> = HashMap::new();
+	for chunk in paths[..].chunks(HISTORY_LOOKUP_BUFFER_SIZE) {
+		let chunk_songs: Vec<Song> = songs::table
+			.filter(songs::path.eq_any(chunk))
+			.load(connection)?;
+		for song in chunk_songs {
+			real_songs.insert(song.path.clone(), song);
+		}
+	}
+
+	let songs = plays
+		.into_iter()
+		.filter_map(|(path, play_count, last_played)| {
+			real_songs.remove(&path).map(|mut s| {
+				s.play_count = Some(play_count as i32);
+				s.last_played = last_played;
+				s
+			})
+		})
+		.filter_map(|s| index::virtualize_song(vfs, s))
+		.collect();
+	Ok(songs)
+}
+
+fn record<T>(db: &T, username: &str, track: &Path, scrobbled: bool) -> Result<()>
+where
+	T: ConnectionSource + VFSSource,
+{
+	let vfs = db.get_vfs()?;
+	let real_path = vfs.virtual_to_real(track)?;
+	let real_path_string = real_path.as_path().to_string_lossy().into_owned();
+
+	let connection = db.get_connection();
+	let user = find_user_id(connection.deref(), username)?;
+	// Songs which are not indexed yet are still recorded, without an artist
+	let (parent, artist): (String, Option<String>) = songs::table
+		.filter(songs::path.eq(&real_path_string))
+		.select((songs::parent, songs::artist))
+		.get_result(connection.deref())
+		.optional()?
+		.unwrap_or_else(|| {
+			let parent = real_path.parent().unwrap_or_else(|| Path::new(""));
+			(parent.to_string_lossy().into_owned(), None)
+		});
+
+	// A scrobble completes the play started by the matching now playing notification
+	if scrobbled {
+		let last_play: Option<(i32, String, i32)> = plays::table
+			.filter(plays::user.eq(user))
+			.order(plays::played_at.desc())
+			.then_order_by(plays::id.desc())
+			.select((plays::id, plays::path, plays::scrobbled))
+			.first(connection.deref())
+			.optional()?;
+		if let Some((id, path, 0)) = last_play {
+			if path == real_path_string {
+				diesel::update(plays::table.find(id))
+					.set(plays::scrobbled.eq(1))
+					.execute(connection.deref())?;
+				return Ok(());
+			}
+		}
+	}
+
+	let new_play = NewPlay {
+		user,
+		path: real_path_string,
+		parent,
+		artist,
+		played_at: now()?,
+		scrobbled: scrobbled as i32,
+	};
+	diesel::insert_into(plays::table)
+		.values(&new_play)
+		.execute(connection.deref())?;
+	Ok(())
+}
+
+pub fn now_playing<T>(db: &T, username: &str, track: &Path) -> Result<()>
+where
+	T: ConnectionSource + VFSSource,
+{
+	record(db, username, track, false)
+}
+
+pub fn scrobble<T>(db: &T, username: &str, track: &Path) -> Result<()>
+where
+	T: ConnectionSource + VFSSource,
+{
+	record(db, username, track, true)
+}
+
+pub fn get_listens<T>(db: &T, username: &str, since: i32, until: i32) -> Result<Vec<(PathBuf, i64)>>
+where
+	T: ConnectionSource + VFSSource,
+{
+	let vfs = db.get_vfs()?;
+	let connection = db.get_connection();
+	let user = find_user_id(connection.deref(), username)?;
+	let listens: Vec<(String, i32)> = plays::table
+		.filter(plays::user.eq(user))
+		.filter(plays::scrobbled.ne(0))
+		.filter(plays::played_at.ge(since))
+		.filter(plays::played_at.lt(until))
+		.order(plays::played_at)
+		.select((plays::path, plays::played_at))
+		.load(connection.deref())?;
+	let listens = listens
+		.into_iter()
+		.filter_map(|(path, played_at)| {
+			vfs.real_to_virtual(Path::new(&path))
+				.ok()
+				.map(|p| (p, played_at as i64))
+		})
+		.collect();
+	Ok(listens)
+}
+
+pub fn annotate_songs<'a, T, I>(db: &T, username: &str, songs: I) -> Result<()>
+where
+	T: ConnectionSource + VFSSource,
+	I: IntoIterator<Item = &'a mut Song>,
+{
+	let vfs = db.get_vfs()?;
+	let songs: Vec<(String, &mut Song)> = songs
+		.into_iter()
+		.filter_map(|s| {
+			vfs.virtual_to_real(Path::new(&s.path))
+				.ok()
+				.map(|p| (p.to_string_lossy().into_owned(), s))
+		})
+		.collect();
+
+	let connection = db.get_connection();
+	let user = find_user_id(connection.deref(), username)?;
+	let mut stats: HashMap<String, (i64, Option<i32>)> = HashMap::new();
+	{
+		let paths: Vec<&String> = songs.iter().map(|(p, _)| p).collect();
+		for chunk in paths[..].chunks(HISTORY_LOOKUP_BUFFER_SIZE) {
+			let chunk_stats: Vec<(String, i64, Option<i32>)> = plays::table
+				.filter(plays::user.eq(user))
+				.filter(plays::path.eq_any(chunk))
+				.group_by(plays::path)
+				.select(sql::<PlayStats>("path, COUNT(*), MAX(played_at)"))
+				.load(connection.deref())?;
+			for (path, play_count, last_played) in chunk_stats {
+				stats.insert(path, (play_count, last_played));
+			}
+		}
+	}
+
+	for (path, song) in songs {
+		let (play_count, last_played) = stats.get(&path).cloned().unwrap_or((0, None));
+		song.play_count = Some(play_count as i32);
+		song.last_played = last_played;
+	}
+
+	Ok(())
+}
+
+pub fn annotate_collection_files<T>(
+	db: &T,
+	username: &str,
+	files: &mut [CollectionFile],
+) -> Result<()>
+where
+	T: ConnectionSource + VFSSource,
+{
+	let songs = files.iter_mut().filter_map(|f| match f {
+		CollectionFile::Song(s) => Some(s),
+		_ => None,
+	});
+	annotate_songs(db, username, songs)
+}
+
+pub fn get_recent_songs<T>(db: &T, username: &str, count: i64) -> Result<Vec<Song>>
+where
+	T: ConnectionSource + VFSSource,
+{
+	let vfs = db.get_vfs()?;
+	let connection = db.get_connection();
+	let user = find_user_id(connection.deref(), username)?;
+	let recent_plays = plays::table
+		.filter(plays::user.eq(user))
+		.group_by(plays::path)
+		.select(sql::<PlayStats>("path, COUNT(*), MAX(played_at)"))
+		.order(sql::<sql_types::Integer>("MAX(played_at) DESC"))
+		.limit(count)
+		.load(connection.deref())?;
+	load_songs(connection.deref(), &vfs, recent_plays)
+}
+
+// Plays are counted from `since` (inclusive) to `until` (exclusive), in seconds since epoch
+pub fn get_top_songs<T>(
+	db: &T,
+	username: &str,
+	since: i32,
+	until: i32,
+	count: i64,
+) -> Result<Vec<Song>>
+where
+	T: ConnectionSource + VFSSource,
+{
+	let vfs = db.get_vfs()?;
+	let connection = db.get_connection();
+	let user = find_user_id(connection.deref(), username)?;
+	let top_plays = plays::table
+		.filter(plays::user.eq(user))
+		.filter(plays::played_at.ge(since))
+		.filter(plays::played_at.lt(until))
+		.group_by(plays::path)
+		.select(sql::<PlayStats>("path, COUNT(*), MAX(played_at)"))
+		.order(sql::<sql_types::BigInt>("COUNT(*) DESC"))
+		.limit(count)
+		.load(connection.deref())?;
+	load_songs(connection.deref(), &vfs, top_plays)
+}
+
+pub fn get_top_albums<T>(
+	db: &T,
+	username: &str,
+	since: i32,
+	until: i32,
+	count: i64,
+) -> Result<Vec<AlbumPlays>>
+where
+	T: ConnectionSource + VFSSource,
+{
+	let vfs = db.get_vfs()?;
+	let connection = db.get_connection();
+	let user = find_user_id(connection.deref(), username)?;
+	let top_plays: Vec<(String, i64)> = plays::table
+		.filter(plays::user.eq(user))
+		.filter(plays::played_at.ge(since))
+		.filter(plays::played_at.lt(until))
+		.group_by(plays::parent)
+		.select(sql::<PlayCount>("parent, COUNT(*)"))
+		.order(sql::<sql_types::BigInt>("COUNT(*) DESC"))
+		.limit(count)
+		.load(connection.deref())?;
+
+	let paths: Vec<&String> = top_plays.iter().map(|p| &p.0).collect();
+	let mut real_directories: HashMap<String, Directory> = directories::table
+		.filter(directories::path.eq_any(paths))
+		.load::<Directory>(connection.deref())?
+		.into_iter()
+		.map(|d| (d.path.clone(), d))
+		.collect();
+
+	let albums = top_plays
+		.into_iter()
+		.filter_map(|(path, play_count)| {
+			real_directories
+				.remove(&path)
+				.and_then(|d| index::virtualize_directory(&vfs, d))
+				.map(|album| AlbumPlays {
+					album,
+					play_count: play_count as i32,
+				})
+		})
+		.collect();
+	Ok(albums)
+}
+
+pub fn get_top_artists<T>(
+	db: &T,
+	username: &str,
+	since: i32,
+	until: i32,
+	count: i64,
+) -> Result<Vec<ArtistPlays>>
+where
+	T: ConnectionSource,
+{
+	let connection = db.get_connection();
+	let user = find_user_id(connection.deref(), username)?;
+	let top_plays: Vec<(Option<String>, i64)> = plays::table
+		.filter(plays::user.eq(user))
+		.filter(plays::played_at.ge(since))
+		.filter(plays::played_at.lt(until))
+		.filter(plays::artist.is_not_null())
+		.group_by(plays::artist)
+		.select(sql::<ArtistPlayCount>("artist, COUNT(*)"))
+		.order(sql::<sql_types::BigInt>("COUNT(*) DESC"))
+		.limit(count)
+		.load(connection.deref())?;
+
+	let artists = top_plays
+		.into_iter()
+		.filter_map(|(artist, play_count)| {
+			artist.map(|artist| ArtistPlays {
+				artist,
+				play_count: play_count as i32,
+			})
+		})
+		.collect();
+	Ok(artists)
+}
+
+#[test]
+fn test_play_counts() {
+	let db = db::_get_test_db("play_counts.sqlite");
+	index::update(&db).unwrap();
+
+	let songs = index::flatten(&db, Path::new("root")).unwrap();
+	let first_song = Path::new(&songs[0].path);
+	let second_song = Path::new(&songs[5].path);
+
+	now_playing(&db, "test_user", first_song).unwrap();
+	scrobble(&db, "test_user", first_song).unwrap();
+	scrobble(&db, "test_user", first_song).unwrap();
+	now_playing(&db, "test_user", second_song).unwrap();
+
+	let mut songs = index::flatten(&db, Path::new("root")).unwrap();
+	annotate_songs(&db, "test_user", songs.iter_mut()).unwrap();
+	assert_eq!(songs[0].play_count, Some(2));
+	assert!(songs[0].last_played.is_some());
+	assert_eq!(songs[1].play_count, Some(0));
+	assert_eq!(songs[1].last_played, None);
+	assert_eq!(songs[5].play_count, Some(1));
+
+	let top_songs = get_top_songs(&db, "test_user", 0, i32::max_value(), 10).unwrap();
+	assert_eq!(top_songs.len(), 2);
+	assert_eq!(top_songs[0].path, songs[0].path);
+	assert_eq!(top_songs[0].play_count, Some(2));
+
+	let recent_songs = get_recent_songs(&db, "test_user", 1).unwrap();
+	assert_eq!(recent_songs.len(), 1);
+
+	let top_albums = get_top_albums(&db, "test_user", 0, i32::max_value(), 10).unwrap();
+	assert!(!top_albums.is_empty());
+	assert_eq!(top_albums[0].play_count, 2);
+
+	let top_artists = get_top_artists(&db, "test_user", 0, i32::max_value(), 10).unwrap();
+	assert!(!top_artists.is_empty());
+
+	assert!(
+		get_top_songs(&db, "test_user", i32::max_value(), i32::max_value(), 10)
+			.unwrap()
+			.is_empty()
+	);
+}
+
+#[test]
+fn test_play_time_range() {
+	let db = db::_get_test_db("play_time_range.sqlite");
+	index::update(&db).unwrap();
+
+	let songs = index::flatten(&db, Path::new("root")).unwrap();
+	let first_song = Path::new(&songs[0].path);
+	let second_song = Path::new(&songs[5].path);
+	scrobble(&db, "test_user", first_song).unwrap();
+	scrobble(&db, "test_user", first_song).unwrap();
+	scrobble(&db, "test_user", second_song).unwrap();
+
+	// Spread plays over time
+	{
+		let connection = db.get_connection();
+		let ids: Vec<i32> = plays::table
+			.select(plays::id)
+			.order(plays::id)
+			.load(connection.deref())
+			.unwrap();
+		for (id, played_at) in ids.into_iter().zip(&[1000, 2000, 3000]) {
+			diesel::update(plays::table.find(id))
+				.set(plays::played_at.eq(played_at))
+				.execute(connection.deref())
+				.unwrap();
+		}
+	}
+
+	let top_songs = get_top_songs(&db, "test_user", 1500, 2500, 10).unwrap();
+	assert_eq!(top_songs.len(), 1);
+	assert_eq!(top_songs[0].path, songs[0].path);
+	assert_eq!(top_songs[0].play_count, Some(1));
+
+	let top_songs = get_top_songs(&db, "test_user", 2000, 3000, 10).unwrap();
+	assert_eq!(top_songs.len(), 1);
+	assert_eq!(top_songs[0].path, songs[0].path);
+
+	let top_songs = get_top_songs(&db, "test_user", 0, 3001, 10).unwrap();
+	assert_eq!(top_songs.len(), 2);
+	assert_eq!(top_songs[0].play_count, Some(2));
+
+	let top_artists = get_top_artists(&db, "test_user", 2500, i32::max_value(), 10).unwrap();
+	assert_eq!(top_artists.len(), 1);
+	assert_eq!(top_artists[0].artist, songs[5].artist.clone().unwrap());
+	assert_eq!(top_artists[0].play_count, 1);
+
+	let top_albums = get_top_albums(&db, "test_user", 0, 1000, 10).unwrap();
+	assert!(top_albums.is_empty());
+}
+
+#[test]
+fn test_play_unindexed_song() {
+	let db = db::_get_test_db("play_unindexed_song.sqlite");
+
+	let song = Path::new("root/Khemmis/Hunted/02 - Candlelight.mp3");
+	now_playing(&db, "test_user", song).unwrap();
+	scrobble(&db, "test_user", song).unwrap();
+
+	let listens = get_listens(&db, "test_user", 0, i32::max_value()).unwrap();
+	assert_eq!(listens.len(), 1);
+	assert_eq!(listens[0].0, song);
+}
@@ -3,31 +3,47 @@ use diesel;
 use diesel::dsl::sql;
 use diesel::prelude::*;
 use diesel::sql_types;
-use diesel::sqlite::SqliteConnection;
+use diesel::sqlite::{Sqlite, SqliteConnection};
 use error_chain::bail;
 use log::{error, info};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::path::Path;
-#[cfg(test)]
-use std::path::PathBuf;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
 use std::sync::mpsc::*;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time;
 
+use crate::collation::{self, Collator};
 use crate::config::MiscSettings;
+use crate::cue;
 #[cfg(test)]
 use crate::db;
-use crate::db::{directories, misc_settings, songs};
+use crate::db::{directories, misc_settings, plays, ratings, search_index, songs, users};
 use crate::db::{ConnectionSource, DB};
 use crate::errors;
-use crate::metadata;
+use crate::loudness;
+use crate::lyrics;
+use crate::metadata::{self, SongTags};
+use crate::overrides::Overrides;
+use crate::playlist;
+use crate::suggestions::SuggestionsBuilder;
+use crate::upload;
 use crate::vfs::{VFSSource, VFS};
+use crate::waveform;
 
 const INDEX_BUILDING_INSERT_BUFFER_SIZE: usize = 1000; // Insertions in each transaction
 const INDEX_BUILDING_CLEAN_BUFFER_SIZE: usize = 500; // Insertions in each transaction
+const LOSSLESS_CODECS: [&str; 2] = ["FLAC", "ALAC"];
+const VARIOUS_ARTISTS: &str = "Various Artists";
+const FUZZY_SEARCH_THRESHOLD: i64 = 3; // Searches with fewer results also look for close matches
+const MAX_FUZZY_SEARCH_CANDIDATES: i64 = 10_000;
+const MAX_FUZZY_SEARCH_RESULTS: usize = 100;
+const DISC_FOLDER_PATTERN: &str = r"(?i)^(?:cd|disc|disk)\s*[-_.]?\s*(\d+)$";
 
 no_arg_sql_function!(
 	random,
@@ -94,8 +110,7 @@ pub fn init(db: Arc<DB>) -> Arc<CommandSender> {
 	command_sender
 }
 
-#[derive(Debug, PartialEq, Queryable, QueryableByName, Serialize, Deserialize)]
-#[table_name = "songs"]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct Song {
 	#[serde(skip_serializing, skip_deserializing)]
 	id: i32,
@@ -111,9 +126,105 @@ pub struct Song {
 	pub album: Option<String>,
 	pub artwork: Option<String>,
 	pub duration: Option<i32>,
+	pub replaygain_track_gain: Option<f32>,
+	pub replaygain_track_peak: Option<f32>,
+	pub replaygain_album_gain: Option<f32>,
+	pub replaygain_album_peak: Option<f32>,
+	pub codec: Option<String>,
+	pub bitrate: Option<i32>,
+	pub sample_rate: Option<i32>,
+	pub bit_depth: Option<i32>,
+	pub channels: Option<i32>,
+	pub file_size: Option<i64>,
+	pub has_lyrics: bool,
+	pub genre: Option<String>,
+	// Tracks of a CUE sheet are sections of a larger file
+	#[serde(skip_serializing, skip_deserializing)]
+	pub cue_source: Option<String>,
+	pub cue_start: Option<i32>,
+	pub cue_end: Option<i32>,
+	// Per-user statistics, only filled in by history::annotate_songs
+	pub play_count: Option<i32>,
+	pub last_played: Option<i32>,
+	// Rating read from the file tags, overridden by ratings::annotate_songs
+	pub rating: Option<i32>,
+	pub starred: Option<bool>,
+}
+
+impl Queryable<songs::SqlType, Sqlite> for Song {
+	type Row = (
+		i32,
+		String,
+		String,
+		Option<i32>,
+		Option<i32>,
+		Option<String>,
+		Option<String>,
+		Option<String>,
+		Option<i32>,
+		Option<String>,
+		Option<String>,
+		Option<i32>,
+		Option<i32>,
+		Option<f32>,
+		Option<f32>,
+		Option<f32>,
+		Option<f32>,
+		Option<String>,
+		Option<i32>,
+		Option<i32>,
+		Option<i32>,
+		Option<i32>,
+		Option<i64>,
+		i32,
+		Option<String>,
+		Option<i32>,
+		Option<i32>,
+		Option<String>,
+		Option<String>,
+		Option<String>,
+		Option<String>,
+		Option<String>,
+	);
+
+	fn build(row: Self::Row) -> Self {
+		Song {
+			id: row.0,
+			path: row.1,
+			parent: row.2,
+			track_number: row.3,
+			disc_number: row.4,
+			title: row.5,
+			artist: row.6,
+			album_artist: row.7,
+			year: row.8,
+			album: row.9,
+			artwork: row.10,
+			duration: row.11,
+			replaygain_track_gain: row.13,
+			replaygain_track_peak: row.14,
+			replaygain_album_gain: row.15,
+			replaygain_album_peak: row.16,
+			codec: row.17,
+			bitrate: row.18,
+			sample_rate: row.19,
+			bit_depth: row.20,
+			channels: row.21,
+			file_size: row.22,
+			has_lyrics: row.23 != 0,
+			genre: row.27,
+			cue_source: row.24,
+			cue_start: row.25,
+			cue_end: row.26,
+			play_count: None,
+			last_played: None,
+			rating: row.12,
+			starred: None,
+		}
+	}
 }
 
-#[derive(Debug, PartialEq, Queryable, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct Directory {
 	#[serde(skip_serializing, skip_deserializing)]
 	id: i32,
@@ -125,6 +236,44 @@ pub struct Directory {
 	pub album: Option<String>,
 	pub artwork: Option<String>,
 	pub date_added: i32,
+	// Set on disc folders (eg. CD1) merged into the album of their parent directory
+	pub disc_number: Option<i32>,
+	// Per-user rating, only filled in by ratings::annotate_directories
+	pub rating: Option<i32>,
+	pub starred: Option<bool>,
+}
+
+impl Queryable<directories::SqlType, Sqlite> for Directory {
+	type Row = (
+		i32,
+		String,
+		Option<String>,
+		Option<String>,
+		Option<i32>,
+		Option<String>,
+		Option<String>,
+		i32,
+		Option<i32>,
+		Option<String>,
+		Option<String>,
+		Option<String>,
+	);
+
+	fn build(row: Self::Row) -> Self {
+		Directory {
+			id: row.0,
+			path: row.1,
+			parent: row.2,
+			artist: row.3,
+			year: row.4,
+			album: row.5,
+			artwork: row.6,
+			date_added: row.7,
+			disc_number: row.8,
+			rating: None,
+			starred: None,
+		}
+	}
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
@@ -133,8 +282,11 @@ pub enum CollectionFile {
 	Song(Song),
 }
 
-#[derive(Debug, Insertable)]
+// Also applied as a changeset when a single song is read again, in which case
+// tags which are gone clear their columns
+#[derive(Debug, Insertable, AsChangeset)]
 #[table_name = "songs"]
+#[changeset_options(treat_none_as_null = "true")]
 struct NewSong {
 	path: String,
 	parent: String,
@@ -147,9 +299,100 @@ struct NewSong {
 	album: Option<String>,
 	artwork: Option<String>,
 	duration: Option<i32>,
+	tag_rating: Option<i32>,
+	replaygain_track_gain: Option<f32>,
+	replaygain_track_peak: Option<f32>,
+	replaygain_album_gain: Option<f32>,
+	replaygain_album_peak: Option<f32>,
+	codec: Option<String>,
+	bitrate: Option<i32>,
+	sample_rate: Option<i32>,
+	bit_depth: Option<i32>,
+	channels: Option<i32>,
+	file_size: Option<i64>,
+	has_lyrics: i32,
+	cue_source: Option<String>,
+	cue_start: Option<i32>,
+	cue_end: Option<i32>,
+	genre: Option<String>,
+	sort_path: Option<String>,
+	sort_title: Option<String>,
+	sort_artist: Option<String>,
+	sort_album: Option<String>,
 }
 
-#[derive(Debug, Insertable)]
+impl NewSong {
+	fn new(
+		path: String,
+		parent: String,
+		artwork: Option<String>,
+		tags: SongTags,
+		collator: &Collator,
+	) -> NewSong {
+		let album_artist = get_album_artist(&tags);
+		let sort_key = |sort_tag: &Option<String>, tag: &Option<String>| {
+			sort_tag
+				.as_ref()
+				.or_else(|| tag.as_ref())
+				.map(|t| collator.sort_key(t))
+		};
+		let sort_title = sort_key(&tags.title_sort, &tags.title);
+		let sort_artist = sort_key(&tags.artist_sort, &tags.artist);
+		let sort_album = sort_key(&tags.album_sort, &tags.album);
+		NewSong {
+			sort_path: Some(collator.sort_path(Path::new(&path))),
+			path,
+			parent,
+			disc_number: tags.disc_number.map(|n| n as i32),
+			track_number: tags.track_number.map(|n| n as i32),
+			title: tags.title,
+			duration: tags.duration.map(|n| n as i32),
+			artist: tags.artist,
+			album_artist,
+			album: tags.album,
+			year: tags.year,
+			artwork,
+			tag_rating: tags.rating.map(|n| n as i32),
+			replaygain_track_gain: tags.replaygain_track_gain,
+			replaygain_track_peak: tags.replaygain_track_peak,
+			replaygain_album_gain: tags.replaygain_album_gain,
+			replaygain_album_peak: tags.replaygain_album_peak,
+			codec: tags.codec,
+			bitrate: tags.bitrate.map(|n| n as i32),
+			sample_rate: tags.sample_rate.map(|n| n as i32),
+			bit_depth: tags.bit_depth.map(|n| n as i32),
+			channels: tags.channels.map(|n| n as i32),
+			file_size: tags.file_size.map(|n| n as i64),
+			has_lyrics: i32::from(tags.lyrics.is_some()),
+			cue_source: None,
+			cue_start: None,
+			cue_end: None,
+			genre: tags.genre,
+			sort_title,
+			sort_artist,
+			sort_album,
+		}
+	}
+}
+
+// Songs flagged as part of a compilation are grouped under a single album artist
+fn get_album_artist(tags: &SongTags) -> Option<String> {
+	match tags.album_artist {
+		Some(ref a) if a.eq_ignore_ascii_case(VARIOUS_ARTISTS) => Some(VARIOUS_ARTISTS.to_owned()),
+		Some(ref a) => Some(a.clone()),
+		None if tags.compilation => Some(VARIOUS_ARTISTS.to_owned()),
+		None => None,
+	}
+}
+
+// Sort keys from the tags of a directory's songs, by artist or album name
+fn add_sort_key(keys: &mut HashMap<String, String>, name: &Option<String>, key: &Option<String>) {
+	if let (Some(name), Some(key)) = (name, key) {
+		keys.entry(name.clone()).or_insert_with(|| key.clone());
+	}
+}
+
+#[derive(Clone, Debug, Insertable)]
 #[table_name = "directories"]
 struct NewDirectory {
 	path: String,
@@ -159,29 +402,132 @@ struct NewDirectory {
 	album: Option<String>,
 	artwork: Option<String>,
 	date_added: i32,
+	disc_number: Option<i32>,
+	sort_path: Option<String>,
+	sort_artist: Option<String>,
+	sort_album: Option<String>,
+}
+
+// Album properties of a directory, dropped when its songs disagree about them
+#[derive(Default)]
+struct AlbumTags {
+	artist: Option<String>,
+	album: Option<String>,
+	year: Option<i32>,
+	inconsistent_artist: bool,
+	inconsistent_album: bool,
+	inconsistent_year: bool,
+	compilation: bool,
+	artists: Vec<String>,
+}
+
+impl AlbumTags {
+	fn add(&mut self, artist: &Option<String>, album: &Option<String>, year: Option<i32>) {
+		if year.is_some() {
+			self.inconsistent_year |= self.year.is_some() && self.year != year;
+			self.year = year;
+		}
+		if album.is_some() {
+			self.inconsistent_album |= self.album.is_some() && self.album != *album;
+			self.album = album.clone();
+		}
+		if let Some(artist) = artist {
+			self.inconsistent_artist |=
+				self.artist.is_some() && self.artist.as_ref() != Some(artist);
+			self.compilation |= artist == VARIOUS_ARTISTS;
+			self.artists.push(artist.clone());
+			self.artist = Some(artist.clone());
+		}
+	}
+
+	// Most songs of a compilation are by different artists, while an album with a
+	// few guest appearances is not
+	fn has_distinct_artists(&self) -> bool {
+		let distinct: HashSet<&String> = self.artists.iter().collect();
+		distinct.len() * 2 > self.artists.len()
+	}
+
+	fn resolve(self) -> (Option<String>, Option<String>, Option<i32>) {
+		// Songs from a single album make up a compilation when they are flagged as
+		// such, or when they are mostly by different artists
+		let is_compilation = self.compilation || self.has_distinct_artists();
+		let album = if self.inconsistent_album {
+			None
+		} else {
+			self.album
+		};
+		let year = if self.inconsistent_year {
+			None
+		} else {
+			self.year
+		};
+		let artist = match (self.inconsistent_artist, &album) {
+			(false, _) => self.artist,
+			(true, Some(_)) if is_compilation => Some(VARIOUS_ARTISTS.to_owned()),
+			(true, _) => None,
+		};
+		(artist, album, year)
+	}
+}
+
+// Folded text of a song or directory, matched against search queries.
+// Top-level directories have an empty parent.
+#[derive(Debug, Insertable)]
+#[table_name = "search_index"]
+struct NewSearchEntry {
+	path: String,
+	parent: String,
+	terms: String,
+}
+
+impl NewSearchEntry {
+	fn new(path: &str, parent: Option<&String>, fields: &[&Option<String>]) -> NewSearchEntry {
+		let mut terms = collation::fold(path);
+		for field in fields.iter().filter_map(|f| f.as_ref()) {
+			terms.push('\n');
+			terms.push_str(&collation::fold(field));
+		}
+		NewSearchEntry {
+			path: path.to_owned(),
+			parent: parent.cloned().unwrap_or_default(),
+			terms,
+		}
+	}
 }
 
 struct IndexBuilder<'conn> {
 	new_songs: Vec<NewSong>,
 	new_directories: Vec<NewDirectory>,
+	new_search_entries: Vec<NewSearchEntry>,
+	suggestions: SuggestionsBuilder,
 	connection: &'conn Mutex<SqliteConnection>,
 	album_art_pattern: Regex,
+	disc_folder_pattern: Regex,
+	overrides: Overrides,
+	collator: Collator,
 }
 
 impl<'conn> IndexBuilder<'conn> {
 	fn new(
 		connection: &Mutex<SqliteConnection>,
-		album_art_pattern: Regex,
+		settings: BuilderSettings,
 	) -> Result<IndexBuilder<'_>, errors::Error> {
 		let mut new_songs = Vec::new();
 		let mut new_directories = Vec::new();
+		let mut new_search_entries = Vec::new();
 		new_songs.reserve_exact(INDEX_BUILDING_INSERT_BUFFER_SIZE);
 		new_directories.reserve_exact(INDEX_BUILDING_INSERT_BUFFER_SIZE);
+		new_search_entries.reserve_exact(INDEX_BUILDING_INSERT_BUFFER_SIZE);
 		Ok(IndexBuilder {
 			new_songs,
 			new_directories,
+			new_search_entries,
+			suggestions: SuggestionsBuilder::default(),
 			connection,
-			album_art_pattern,
+			album_art_pattern: settings.album_art_pattern,
+			disc_folder_pattern: Regex::new(DISC_FOLDER_PATTERN)?,
+			overrides: settings.overrides,
+			collator: settings.collator,
 		})
 	}
 
@@ -211,7 +557,40 @@ impl<'conn> IndexBuilder<'conn> {
 		Ok(())
 	}
 
+	fn flush_search_entries(&mut self) -> Result<(), errors::Error> {
+		let connection = self.connection.lock().unwrap();
+		let connection = connection.deref();
+		connection.transaction::<_, errors::Error, _>(|| {
+			diesel::insert_into(search_index::table)
+				.values(&self.new_search_entries)
+				.execute(connection)?;
+			Ok(())
+		})?;
+		self.new_search_entries.clear();
+		Ok(())
+	}
+
+	fn flush_suggestions(&mut self, complete: bool) -> Result<(), errors::Error> {
+		let connection = self.connection.lock().unwrap();
+		self.suggestions.flush(connection.deref(), complete)
+	}
+
+	fn push_search_entry(&mut self, entry: NewSearchEntry) -> Result<(), errors::Error> {
+		if self.new_search_entries.len() >= self.new_search_entries.capacity() {
+			self.flush_search_entries()?;
+		}
+		self.new_search_entries.push(entry);
+		Ok(())
+	}
+
 	fn push_song(&mut self, song: NewSong) -> Result<(), errors::Error> {
+		self.push_search_entry(NewSearchEntry::new(
+			&song.path,
+			Some(&song.parent),
+			&[&song.title, &song.artist, &song.album_artist, &song.album],
+		))?;
+		self.suggestions
+			.add_song(&song.title, &song.artist, &song.album_artist, &song.album);
 		if self.new_songs.len() >= self.new_songs.capacity() {
 			self.flush_songs()?;
 		}
@@ -220,6 +599,11 @@ impl<'conn> IndexBuilder<'conn> {
 	}
 
 	fn push_directory(&mut self, directory: NewDirectory) -> Result<(), errors::Error> {
+		self.push_search_entry(NewSearchEntry::new(
+			&directory.path,
+			directory.parent.as_ref(),
+			&[],
+		))?;
 		if self.new_directories.len() >= self.new_directories.capacity() {
 			self.flush_directories()?;
 		}
@@ -239,13 +623,50 @@ impl<'conn> IndexBuilder<'conn> {
 		Ok(None)
 	}
 
+	// Song from the tags of a file, with its overrides applied
+	fn read_song(
+		&self,
+		file_path: &Path,
+		parent: &str,
+		artwork: Option<String>,
+	) -> Result<NewSong, errors::Error> {
+		let file_path_string = file_path.to_str().ok_or("Invalid song path")?;
+		let mut tags = metadata::read(file_path)?;
+		self.overrides.apply_to_tags(file_path, &mut tags);
+		let mut song = NewSong::new(
+			file_path_string.to_owned(),
+			parent.to_owned(),
+			artwork,
+			tags,
+			&self.collator,
+		);
+		if lyrics::find_sidecar(file_path).is_some() {
+			song.has_lyrics = 1;
+		}
+		Ok(song)
+	}
+
+	// Folders such as CD1 or Disc 2 hold one disc of the album in their parent directory
+	fn get_disc_number(&self, path: &Path) -> Option<i32> {
+		let name = path.file_name()?.to_str()?;
+		let captures = self.disc_folder_pattern.captures(name)?;
+		captures.get(1)?.as_str().parse().ok()
+	}
+
 	fn populate_directory(
 		&mut self,
 		parent: Option<&Path>,
 		path: &Path,
-	) -> Result<(), errors::Error> {
-		// Find artwork
-		let artwork = self.get_artwork(path).unwrap_or(None);
+	) -> Result<NewDirectory, errors::Error> {
+		let disc_number = parent.and_then(|_| self.get_disc_number(path));
+
+		// Find artwork, disc folders fall back to the artwork of their album
+		let mut artwork = self.get_artwork(path).unwrap_or(None);
+		if artwork.is_none() && disc_number.is_some() {
+			if let Some(parent) = parent {
+				artwork = self.get_artwork(parent).unwrap_or(None);
+			}
+		}
 
 		// Extract path and parent path
 		let parent_string = parent.and_then(|p| p.to_str()).map(|s| s.to_owned());
@@ -259,17 +680,14 @@ impl<'conn> IndexBuilder<'conn> {
 			.duration_since(time::UNIX_EPOCH)?
 			.as_secs() as i32;
 
-		let mut directory_album = None;
-		let mut directory_year = None;
-		let mut directory_artist = None;
-		let mut inconsistent_directory_album = false;
-		let mut inconsistent_directory_year = false;
-		let mut inconsistent_directory_artist = false;
+		let mut album_tags = AlbumTags::default();
 
 		// Sub directories
 		let mut sub_directories = Vec::new();
 
-		// Insert content
+		// Songs and CUE sheets
+		let mut song_files = Vec::new();
+		let mut cue_sheets = Vec::new();
 		for file in fs::read_dir(path)? {
 			let file_path = match file {
 				Ok(f) => f.path(),
@@ -281,64 +699,112 @@ impl<'conn> IndexBuilder<'conn> {
 
 			if file_path.is_dir() {
 				sub_directories.push(file_path.to_path_buf());
-				continue;
+			} else if cue::is_cue_sheet(&file_path) {
+				cue_sheets.push(file_path);
+			} else {
+				song_files.push(file_path);
 			}
+		}
 
-			if let Some(file_path_string) = file_path.to_str() {
-				if let Ok(tags) = metadata::read(file_path.as_path()) {
-					if tags.year.is_some() {
-						inconsistent_directory_year |=
-							directory_year.is_some() && directory_year != tags.year;
-						directory_year = tags.year;
-					}
-
-					if tags.album.is_some() {
-						inconsistent_directory_album |=
-							directory_album.is_some() && directory_album != tags.album;
-						directory_album = tags.album.as_ref().cloned();
-					}
-
-					if tags.album_artist.is_some() {
-						inconsistent_directory_artist |=
-							directory_artist.is_some() && directory_artist != tags.album_artist;
-						directory_artist = tags.album_artist.as_ref().cloned();
-					} else if tags.artist.is_some() {
-						inconsistent_directory_artist |=
-							directory_artist.is_some() && directory_artist != tags.artist;
-						directory_artist = tags.artist.as_ref().cloned();
-					}
-
-					let song = NewSong {
-						path: file_path_string.to_owned(),
-						parent: path_string.to_owned(),
-						disc_number: tags.disc_number.map(|n| n as i32),
-						track_number: tags.track_number.map(|n| n as i32),
-						title: tags.title,
-						duration: tags.duration.map(|n| n as i32),
-						artist: tags.artist,
-						album_artist: tags.album_artist,
-						album: tags.album,
-						year: tags.year,
-						artwork: artwork.as_ref().cloned(),
-					};
-
-					self.push_song(song)?;
+		// Files split by a CUE sheet are indexed as one song per track
+		let mut new_songs = Vec::new();
+		let mut cue_sources = Vec::new();
+		for sheet_path in cue_sheets {
+			let sheet = match cue::read(&sheet_path) {
+				Ok(s) => s,
+				Err(e) => {
+					error!("Could not read CUE sheet {}: {}", sheet_path.display(), e);
+					continue;
 				}
+			};
+			let virtual_songs =
+				cue::get_songs(&sheet_path, &sheet, |source| metadata::read(source).ok());
+			for mut virtual_song in virtual_songs {
+				self.overrides
+					.apply_to_tags(Path::new(&virtual_song.path), &mut virtual_song.tags);
+				let mut song = NewSong::new(
+					virtual_song.path,
+					path_string.to_owned(),
+					artwork.as_ref().cloned(),
+					virtual_song.tags,
+					&self.collator,
+				);
+				song.cue_source = Some(virtual_song.source.to_string_lossy().into_owned());
+				song.cue_start = Some(virtual_song.start as i32);
+				song.cue_end = virtual_song.end.map(|n| n as i32);
+				cue_sources.push(virtual_song.source);
+				new_songs.push(song);
 			}
 		}
 
-		// Insert directory
-		if inconsistent_directory_year {
-			directory_year = None;
+		for file_path in song_files {
+			if cue_sources.contains(&file_path) {
+				continue;
+			}
+			if let Ok(song) = self.read_song(&file_path, path_string, artwork.as_ref().cloned()) {
+				new_songs.push(song);
+			}
 		}
-		if inconsistent_directory_album {
-			directory_album = None;
+
+		// Sort keys of the artists and albums found so far
+		let mut artist_sort_keys = HashMap::new();
+		let mut album_sort_keys = HashMap::new();
+
+		// Insert content
+		for mut song in new_songs {
+			if song.disc_number.is_none() {
+				song.disc_number = disc_number;
+			}
+			let artist = song.album_artist.as_ref().or_else(|| song.artist.as_ref());
+			album_tags.add(&artist.cloned(), &song.album, song.year);
+			add_sort_key(&mut artist_sort_keys, &song.artist, &song.sort_artist);
+			add_sort_key(&mut album_sort_keys, &song.album, &song.sort_album);
+			self.push_song(song)?;
 		}
-		if inconsistent_directory_artist {
-			directory_artist = None;
+
+		// Populate subdirectories, disc folders are merged into this album
+		for sub_directory in sub_directories {
+			let sub_directory = self.populate_directory(Some(path), &sub_directory)?;
+			if sub_directory.disc_number.is_some() {
+				album_tags.add(
+					&sub_directory.artist,
+					&sub_directory.album,
+					sub_directory.year,
+				);
+				add_sort_key(
+					&mut artist_sort_keys,
+					&sub_directory.artist,
+					&sub_directory.sort_artist,
+				);
+				add_sort_key(
+					&mut album_sort_keys,
+					&sub_directory.album,
+					&sub_directory.sort_album,
+				);
+				if artwork.is_none() {
+					artwork = sub_directory.artwork;
+				}
+			}
 		}
 
+		// Insert directory
+		let (mut directory_artist, mut directory_album, mut directory_year) = album_tags.resolve();
+		self.overrides.apply_to_directory(
+			path,
+			&mut directory_artist,
+			&mut directory_album,
+			&mut directory_year,
+		);
+		let collator = &self.collator;
+		let sort_key = |name: &Option<String>, keys: &HashMap<String, String>| {
+			name.as_ref()
+				.map(|n| keys.get(n).cloned().unwrap_or_else(|| collator.sort_key(n)))
+		};
+
 		let directory = NewDirectory {
+			sort_path: Some(collator.sort_path(path)),
+			sort_artist: sort_key(&directory_artist, &artist_sort_keys),
+			sort_album: sort_key(&directory_album, &album_sort_keys),
 			path: path_string.to_owned(),
 			parent: parent_string,
 			artwork,
@@ -346,18 +812,21 @@ impl<'conn> IndexBuilder<'conn> {
 			artist: directory_artist,
 			year: directory_year,
 			date_added: created,
+			disc_number,
 		};
-		self.push_directory(directory)?;
-
-		// Populate subdirectories
-		for sub_directory in sub_directories {
-			self.populate_directory(Some(path), &sub_directory)?;
-		}
-
-		Ok(())
+		self.push_directory(directory.clone())?;
+		Ok(directory)
 	}
 }
 
+// Title, artist, album artist and album of a song
+type SongSuggestionFields = (
+	Option<String>,
+	Option<String>,
+	Option<String>,
+	Option<String>,
+);
+
 fn clean<T>(db: &T) -> Result<(), errors::Error>
 where
 	T: ConnectionSource + VFSSource,
@@ -365,26 +834,52 @@ where
 	let vfs = db.get_vfs()?;
 
 	{
-		let all_songs: Vec<String>;
+		let all_songs: Vec<(String, Option<String>)>;
 		{
 			let connection = db.get_connection();
-			all_songs = songs::table.select(songs::path).load(connection.deref())?;
+			all_songs = songs::table
+				.select((songs::path, songs::cue_source))
+				.load(connection.deref())?;
 		}
 
 		let missing_songs = all_songs
 			.into_iter()
-			.filter(|ref song_path| {
-				let path = Path::new(&song_path);
-				!path.exists() || vfs.real_to_virtual(path).is_err()
+			.filter(|(song_path, cue_source)| match cue_source {
+				Some(cue_source) => {
+					let sheet_path =
+						cue::get_sheet_path(&song_path).unwrap_or_else(|| Path::new(""));
+					!Path::new(cue_source).exists()
+						|| !sheet_path.exists()
+						|| vfs.real_to_virtual(sheet_path).is_err()
+				}
+				None => {
+					let path = Path::new(&song_path);
+					!path.exists() || vfs.real_to_virtual(path).is_err()
+				}
 			})
+			.map(|(song_path, _)| song_path)
 			.collect::<Vec<_>>();
 
 		{
 			let connection = db.get_connection();
+			let mut suggestions = SuggestionsBuilder::default();
 			for chunk in missing_songs[..].chunks(INDEX_BUILDING_CLEAN_BUFFER_SIZE) {
+				let removed_songs: Vec<SongSuggestionFields> = songs::table
+					.filter(songs::path.eq_any(chunk))
+					.select((
+						songs::title,
+						songs::artist,
+						songs::album_artist,
+						songs::album,
+					))
+					.load(connection.deref())?;
+				for (title, artist, album_artist, album) in &removed_songs {
+					suggestions.remove_song(title, artist, album_artist, album);
+				}
 				diesel::delete(songs::table.filter(songs::path.eq_any(chunk)))
 					.execute(connection.deref())?;
 			}
+			suggestions.flush(connection.deref(), false)?;
 		}
 	}
 
@@ -414,33 +909,165 @@ where
 		}
 	}
 
+	{
+		// Search entries of songs and directories which are gone
+		let connection = db.get_connection();
+		diesel::delete(
+			search_index::table
+				.filter(search_index::path.ne_all(songs::table.select(songs::path)))
+				.filter(search_index::path.ne_all(directories::table.select(directories::path))),
+		)
+		.execute(connection.deref())?;
+	}
+
 	Ok(())
 }
 
+struct BuilderSettings {
+	album_art_pattern: Regex,
+	overrides: Overrides,
+	collator: Collator,
+}
+
+fn get_builder_settings<T>(db: &T) -> Result<BuilderSettings, errors::Error>
+where
+	T: ConnectionSource,
+{
+	let connection = db.get_connection();
+	let settings: MiscSettings = misc_settings::table.get_result(connection.deref())?;
+	Ok(BuilderSettings {
+		album_art_pattern: Regex::new(&settings.index_album_art_pattern)?,
+		overrides: Overrides::load(connection.deref())?,
+		collator: Collator::new(&settings.index_sort_articles),
+	})
+}
+
 fn populate<T>(db: &T) -> Result<(), errors::Error>
 where
 	T: ConnectionSource + VFSSource,
 {
 	let vfs = db.get_vfs()?;
 	let mount_points = vfs.get_mount_points();
+	let settings = get_builder_settings(db)?;
+
+	let connection_mutex = db.get_connection_mutex();
+	let mut builder = IndexBuilder::new(connection_mutex.deref(), settings)?;
+	for target in mount_points.values() {
+		builder.populate_directory(None, target.as_path())?;
+	}
+	builder.flush_songs()?;
+	builder.flush_directories()?;
+	builder.flush_search_entries()?;
+	builder.flush_suggestions(true)?;
+	Ok(())
+}
+
+// Indexes the content of a single directory without waiting for the next full
+// update, along with any of its parents which are not in the index yet
+pub fn update_directory<T>(db: &T, real_path: &Path) -> Result<(), errors::Error>
+where
+	T: ConnectionSource + VFSSource,
+{
+	let vfs = db.get_vfs()?;
+	vfs.real_to_virtual(real_path)?;
 
-	let album_art_pattern;
+	let mut target = real_path;
+	let mut parent = real_path
+		.parent()
+		.filter(|p| vfs.real_to_virtual(p).is_ok());
 	{
 		let connection = db.get_connection();
-		let settings: MiscSettings = misc_settings::table.get_result(connection.deref())?;
-		album_art_pattern = Regex::new(&settings.index_album_art_pattern)?;
+		while let Some(p) = parent {
+			let indexed: i64 = directories::table
+				.filter(directories::path.eq(p.to_string_lossy()))
+				.count()
+				.get_result(connection.deref())?;
+			if indexed > 0 {
+				break;
+			}
+			target = p;
+			parent = p.parent().filter(|p| vfs.real_to_virtual(p).is_ok());
+		}
 	}
 
+	let settings = get_builder_settings(db)?;
 	let connection_mutex = db.get_connection_mutex();
-	let mut builder = IndexBuilder::new(connection_mutex.deref(), album_art_pattern)?;
-	for target in mount_points.values() {
-		builder.populate_directory(None, target.as_path())?;
-	}
+	let mut builder = IndexBuilder::new(connection_mutex.deref(), settings)?;
+	builder.populate_directory(parent, target)?;
 	builder.flush_songs()?;
 	builder.flush_directories()?;
+	builder.flush_search_entries()?;
+	builder.flush_suggestions(false)?;
 	Ok(())
 }
 
+// Reads the tags of a single song file back into the index, along with its search
+// entry and suggestions, the same way a full update would
+pub fn update_song<T>(db: &T, real_path: &Path) -> Result<(), errors::Error>
+where
+	T: ConnectionSource + VFSSource,
+{
+	let vfs = db.get_vfs()?;
+	vfs.real_to_virtual(real_path)?;
+	let real_path_string = real_path.to_string_lossy().into_owned();
+
+	// Artwork and disc number come from the parent directory
+	let (parent, artwork, disc_number): (String, Option<String>, Option<i32>) = {
+		let connection = db.get_connection();
+		let parent: String = songs::table
+			.filter(songs::path.eq(&real_path_string))
+			.select(songs::parent)
+			.get_result(connection.deref())?;
+		let (artwork, disc_number) = directories::table
+			.filter(directories::path.eq(&parent))
+			.select((directories::artwork, directories::disc_number))
+			.get_result(connection.deref())
+			.optional()?
+			.unwrap_or((None, None));
+		(parent, artwork, disc_number)
+	};
+
+	let settings = get_builder_settings(db)?;
+	let connection_mutex = db.get_connection_mutex();
+	let builder = IndexBuilder::new(connection_mutex.deref(), settings)?;
+	let mut song = builder.read_song(real_path, &parent, artwork)?;
+	if song.disc_number.is_none() {
+		song.disc_number = disc_number;
+	}
+
+	// The row is updated in place, so that its id is kept
+	let search_entry = NewSearchEntry::new(
+		&song.path,
+		Some(&song.parent),
+		&[&song.title, &song.artist, &song.album_artist, &song.album],
+	);
+	let connection = db.get_connection();
+	let connection = connection.deref();
+	connection.transaction::<_, errors::Error, _>(|| {
+		// Suggestions for the previous tags are recounted along with the new ones
+		let mut suggestions = SuggestionsBuilder::default();
+		let (title, artist, album_artist, album): SongSuggestionFields = songs::table
+			.filter(songs::path.eq(&real_path_string))
+			.select((
+				songs::title,
+				songs::artist,
+				songs::album_artist,
+				songs::album,
+			))
+			.get_result(connection)?;
+		suggestions.remove_song(&title, &artist, &album_artist, &album);
+		suggestions.add_song(&song.title, &song.artist, &song.album_artist, &song.album);
+
+		diesel::update(songs::table.filter(songs::path.eq(&real_path_string)))
+			.set(&song)
+			.execute(connection)?;
+		diesel::update(search_index::table.filter(search_index::path.eq(&real_path_string)))
+			.set(search_index::terms.eq(&search_entry.terms))
+			.execute(connection)?;
+		suggestions.flush(connection, false)
+	})
+}
+
 pub fn update<T>(db: &T) -> Result<(), errors::Error>
 where
 	T: ConnectionSource + VFSSource,
@@ -449,6 +1076,7 @@ where
 	info!("Beginning library index update");
 	clean(db)?;
 	populate(db)?;
+	playlist::relink(db)?;
 	info!(
 		"Library index update took {} seconds",
 		start.elapsed().as_secs()
@@ -479,6 +1107,34 @@ where
 		// Do the update
 		if let Err(e) = update(db) {
 			error!("Error while updating index: {}", e);
+			continue;
+		}
+
+		// Measure loudness of new songs
+		match loudness::is_enabled(db) {
+			Ok(true) => {
+				if let Err(e) = loudness::analyze(db) {
+					error!("Error while analyzing loudness: {}", e);
+				}
+			}
+			Ok(false) => (),
+			Err(e) => error!("Could not read loudness analysis setting: {}", e),
+		}
+
+		// Generate waveforms of new songs
+		match waveform::is_enabled(db) {
+			Ok(true) => {
+				if let Err(e) = waveform::precompute(db) {
+					error!("Error while generating waveforms: {}", e);
+				}
+			}
+			Ok(false) => (),
+			Err(e) => error!("Could not read waveform generation setting: {}", e),
+		}
+
+		// Clean up uploads which were never completed
+		if let Err(e) = upload::remove_stale_partials() {
+			error!("Error while removing abandoned uploads: {}", e);
 		}
 	}
 }
@@ -526,7 +1182,7 @@ pub fn virtualize_song(vfs: &VFS, mut song: Song) -> Option<Song> {
 	Some(song)
 }
 
-fn virtualize_directory(vfs: &VFS, mut directory: Directory) -> Option<Directory> {
+pub fn virtualize_directory(vfs: &VFS, mut directory: Directory) -> Option<Directory> {
 	directory.path = match vfs.real_to_virtual(Path::new(&directory.path)) {
 		Ok(p) => p.to_string_lossy().into_owned(),
 		_ => return None,
@@ -540,7 +1196,124 @@ fn virtualize_directory(vfs: &VFS, mut directory: Directory) -> Option<Directory
 	Some(directory)
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SortKey {
+	Path,
+	Title,
+	Artist,
+	Album,
+	Year,
+	Track,
+	Duration,
+	DateAdded,
+}
+
+impl SortKey {
+	pub fn from_name(name: &str) -> Option<SortKey> {
+		match name {
+			"path" => Some(SortKey::Path),
+			"title" => Some(SortKey::Title),
+			"artist" => Some(SortKey::Artist),
+			"album" => Some(SortKey::Album),
+			"year" => Some(SortKey::Year),
+			"track" => Some(SortKey::Track),
+			"duration" => Some(SortKey::Duration),
+			"date_added" => Some(SortKey::DateAdded),
+			_ => None,
+		}
+	}
+
+	// Keys which do not apply to songs or directories leave them sorted by path
+	fn song_columns(self) -> &'static [&'static str] {
+		match self {
+			SortKey::Path | SortKey::DateAdded => &["sort_path"],
+			SortKey::Title => &["sort_title"],
+			SortKey::Artist => &["sort_artist"],
+			SortKey::Album => &["sort_album", "disc_number", "track_number"],
+			SortKey::Year => &["year"],
+			SortKey::Track => &["disc_number", "track_number"],
+			SortKey::Duration => &["duration"],
+		}
+	}
+
+	fn directory_columns(self) -> &'static [&'static str] {
+		match self {
+			SortKey::Path | SortKey::Title | SortKey::Track | SortKey::Duration => &["sort_path"],
+			SortKey::Artist => &["sort_artist"],
+			SortKey::Album => &["sort_album"],
+			SortKey::Year => &["year"],
+			SortKey::DateAdded => &["date_added"],
+		}
+	}
+}
+
+// Window into a larger set of results, and the order it is taken in
+#[derive(Clone, Debug, Default)]
+pub struct Page {
+	pub offset: i64,
+	pub limit: Option<i64>,
+	pub sort: Option<SortKey>,
+	pub descending: bool,
+}
+
+impl Page {
+	fn get_order(&self, columns: Option<&[&str]>, default: &str) -> String {
+		let columns = match columns {
+			Some(c) => c,
+			None => return default.to_owned(),
+		};
+		let direction = if self.descending { "DESC" } else { "ASC" };
+		let mut terms: Vec<String> = columns
+			.iter()
+			.map(|c| format!("{} {}", c, direction))
+			.collect();
+		terms.push("sort_path ASC".to_owned());
+		terms.join(", ")
+	}
+
+	fn get_song_order(&self, default: &str) -> String {
+		self.get_order(self.sort.map(SortKey::song_columns), default)
+	}
+
+	fn get_directory_order(&self, default: &str) -> String {
+		self.get_order(self.sort.map(SortKey::directory_columns), default)
+	}
+
+	// SQLite treats negative limits as no limit at all
+	fn get_limit(&self) -> i64 {
+		self.limit.unwrap_or(-1)
+	}
+
+	// Rest of this page after a first set of `count` results, `taken` of which were on the page
+	fn continued(&self, count: i64, taken: usize) -> Page {
+		Page {
+			offset: (self.offset - count).max(0),
+			limit: self.limit.map(|l| (l - taken as i64).max(0)),
+			..self.clone()
+		}
+	}
+}
+
+// One page of results, along with the size of the whole set
+#[derive(Debug)]
+pub struct Paged<T> {
+	pub items: Vec<T>,
+	pub total: i64,
+}
+
 pub fn browse<T, P>(db: &T, virtual_path: P) -> Result<Vec<CollectionFile>, errors::Error>
+where
+	T: ConnectionSource + VFSSource,
+	P: AsRef<Path>,
+{
+	Ok(browse_page(db, virtual_path, &Page::default())?.items)
+}
+
+pub fn browse_page<T, P>(
+	db: &T,
+	virtual_path: P,
+	page: &Page,
+) -> Result<Paged<CollectionFile>, errors::Error>
 where
 	T: ConnectionSource + VFSSource,
 	P: AsRef<Path>,
@@ -548,44 +1321,104 @@ where
 	let mut output = Vec::new();
 	let vfs = db.get_vfs()?;
 	let connection = db.get_connection();
+	let directory_order = page.get_directory_order("sort_path ASC");
 
 	if virtual_path.as_ref().components().count() == 0 {
 		// Browse top-level
+		let total: i64 = directories::table
+			.filter(directories::parent.is_null())
+			.count()
+			.get_result(connection.deref())?;
 		let real_directories: Vec<Directory> = directories::table
 			.filter(directories::parent.is_null())
+			.order(sql::<sql_types::Bool>(&directory_order))
+			.limit(page.get_limit())
+			.offset(page.offset)
 			.load(connection.deref())?;
 		let virtual_directories = real_directories
 			.into_iter()
 			.filter_map(|s| virtualize_directory(&vfs, s));
 		output.extend(virtual_directories.map(CollectionFile::Directory));
+		Ok(Paged {
+			items: output,
+			total,
+		})
 	} else {
 		// Browse sub-directory
 		let real_path = vfs.virtual_to_real(virtual_path)?;
 		let real_path_string = real_path.as_path().to_string_lossy().into_owned();
 
+		// Directories come first, songs fill the rest of the page
+		let directory_count: i64 = directories::table
+			.filter(directories::parent.eq(&real_path_string))
+			.count()
+			.get_result(connection.deref())?;
 		let real_directories: Vec<Directory> = directories::table
 			.filter(directories::parent.eq(&real_path_string))
-			.order(sql::<sql_types::Bool>("path COLLATE NOCASE ASC"))
+			.order(sql::<sql_types::Bool>(&directory_order))
+			.limit(page.get_limit())
+			.offset(page.offset)
 			.load(connection.deref())?;
+		let song_page = page.continued(directory_count, real_directories.len());
 		let virtual_directories = real_directories
 			.into_iter()
 			.filter_map(|s| virtualize_directory(&vfs, s));
 		output.extend(virtual_directories.map(CollectionFile::Directory));
 
+		let song_count: i64 = songs::table
+			.filter(songs::parent.eq(&real_path_string))
+			.count()
+			.get_result(connection.deref())?;
 		let real_songs: Vec<Song> = songs::table
 			.filter(songs::parent.eq(&real_path_string))
-			.order(sql::<sql_types::Bool>("path COLLATE NOCASE ASC"))
+			.order(sql::<sql_types::Bool>(
+				&song_page.get_song_order("sort_path ASC"),
+			))
+			.limit(song_page.get_limit())
+			.offset(song_page.offset)
 			.load(connection.deref())?;
 		let virtual_songs = real_songs
 			.into_iter()
 			.filter_map(|s| virtualize_song(&vfs, s));
 		output.extend(virtual_songs.map(CollectionFile::Song));
+
+		Ok(Paged {
+			items: output,
+			total: directory_count + song_count,
+		})
 	}
+}
 
-	Ok(output)
+// LIKE pattern matching everything below a directory, but not its siblings sharing a prefix
+// with it. Wildcards within the path are escaped with backslashes.
+pub fn get_descendants_pattern(real_path: &Path) -> String {
+	let escape = |s: &str| {
+		s.replace('\\', "\\\\")
+			.replace('%', "\\%")
+			.replace('_', "\\_")
+	};
+	let real_path = real_path.to_string_lossy();
+	let directory = real_path.trim_end_matches(std::path::MAIN_SEPARATOR);
+	format!(
+		"{}{}%",
+		escape(directory),
+		escape(&std::path::MAIN_SEPARATOR.to_string())
+	)
 }
 
 pub fn flatten<T, P>(db: &T, virtual_path: P) -> Result<Vec<Song>, errors::Error>
+where
+	T: ConnectionSource + VFSSource,
+	P: AsRef<Path>,
+{
+	Ok(flatten_page(db, virtual_path, &Page::default())?.items)
+}
+
+pub fn flatten_page<T, P>(
+	db: &T,
+	virtual_path: P,
+	page: &Page,
+) -> Result<Paged<Song>, errors::Error>
 where
 	T: ConnectionSource + VFSSource,
 	P: AsRef<Path>,
@@ -594,21 +1427,48 @@ where
 	let vfs = db.get_vfs()?;
 	let connection = db.get_connection();
 
-	let real_songs: Vec<Song> = if virtual_path.as_ref().parent() != None {
+	let (directory, descendants) = if virtual_path.as_ref().parent() != None {
 		let real_path = vfs.virtual_to_real(virtual_path)?;
-		let like_path = real_path.as_path().to_string_lossy().into_owned() + "%";
-		songs
-			.filter(path.like(&like_path))
-			.order(path)
-			.load(connection.deref())?
+		(
+			real_path.to_string_lossy().into_owned(),
+			get_descendants_pattern(&real_path),
+		)
 	} else {
-		songs.order(path).load(connection.deref())?
+		(String::new(), "%".to_owned())
 	};
 
+	let total: i64 = songs
+		.filter(path.eq(&directory).or(path.like(&descendants).escape('\\')))
+		.count()
+		.get_result(connection.deref())?;
+	let real_songs: Vec<Song> = songs
+		.filter(path.eq(&directory).or(path.like(&descendants).escape('\\')))
+		.order(sql::<sql_types::Bool>(&page.get_song_order("sort_path")))
+		.limit(page.get_limit())
+		.offset(page.offset)
+		.load(connection.deref())?;
+
 	let virtual_songs = real_songs
 		.into_iter()
 		.filter_map(|s| virtualize_song(&vfs, s));
-	Ok(virtual_songs.collect::<Vec<_>>())
+	Ok(Paged {
+		items: virtual_songs.collect::<Vec<_>>(),
+		total,
+	})
+}
+
+pub fn count_albums<T>(db: &T) -> Result<i64, errors::Error>
+where
+	T: ConnectionSource,
+{
+	use self::directories::dsl::*;
+	let connection = db.get_connection();
+	let count = directories
+		.filter(album.is_not_null())
+		.filter(disc_number.is_null())
+		.count()
+		.get_result(connection.deref())?;
+	Ok(count)
 }
 
 pub fn get_random_albums<T>(db: &T, count: i64) -> Result<Vec<Directory>, errors::Error>
@@ -620,6 +1480,7 @@ where
 	let connection = db.get_connection();
 	let real_directories = directories
 		.filter(album.is_not_null())
+		.filter(disc_number.is_null())
 		.limit(count)
 		.order(random)
 		.load(connection.deref())?;
@@ -629,7 +1490,11 @@ where
 	Ok(virtual_directories.collect::<Vec<_>>())
 }
 
-pub fn get_recent_albums<T>(db: &T, count: i64) -> Result<Vec<Directory>, errors::Error>
+pub fn get_recent_albums<T>(
+	db: &T,
+	offset: i64,
+	count: i64,
+) -> Result<Vec<Directory>, errors::Error>
 where
 	T: ConnectionSource + VFSSource,
 {
@@ -638,8 +1503,10 @@ where
 	let connection = db.get_connection();
 	let real_directories: Vec<Directory> = directories
 		.filter(album.is_not_null())
+		.filter(disc_number.is_null())
 		.order(date_added.desc())
 		.limit(count)
+		.offset(offset)
 		.load(connection.deref())?;
 	let virtual_directories = real_directories
 		.into_iter()
@@ -647,52 +1514,567 @@ where
 	Ok(virtual_directories.collect::<Vec<_>>())
 }
 
-pub fn search<T>(db: &T, query: &str) -> Result<Vec<CollectionFile>, errors::Error>
-where
-	T: ConnectionSource + VFSSource,
-{
-	let vfs = db.get_vfs()?;
-	let connection = db.get_connection();
-	let like_test = format!("%{}%", query);
-	let mut output = Vec::new();
-
-	// Find dirs with matching path and parent not matching
-	{
-		use self::directories::dsl::*;
-		let real_directories: Vec<Directory> = directories
-			.filter(path.like(&like_test))
-			.filter(parent.not_like(&like_test))
-			.load(connection.deref())?;
-
-		let virtual_directories = real_directories
-			.into_iter()
-			.filter_map(|s| virtualize_directory(&vfs, s));
+// Restricts search results to songs with the given audio properties
+#[derive(Debug, Default)]
+pub struct SearchFilters {
+	pub codec: Option<String>,
+	pub lossless: Option<bool>,
+	pub min_sample_rate: Option<i32>,
+	pub min_bit_depth: Option<i32>,
+	pub min_bitrate: Option<i32>,
+	pub has_lyrics: Option<bool>,
+}
 
-		output.extend(virtual_directories.map(CollectionFile::Directory));
+impl SearchFilters {
+	fn is_empty(&self) -> bool {
+		self.codec.is_none()
+			&& self.lossless.is_none()
+			&& self.min_sample_rate.is_none()
+			&& self.min_bit_depth.is_none()
+			&& self.min_bitrate.is_none()
+			&& self.has_lyrics.is_none()
 	}
+}
 
-	// Find songs with matching title/album/artist and non-matching parent
-	{
-		use self::songs::dsl::*;
-		let real_songs: Vec<Song> = songs
-			.filter(
-				path.like(&like_test)
-					.or(title.like(&like_test))
-					.or(album.like(&like_test))
-					.or(artist.like(&like_test))
-					.or(album_artist.like(&like_test)),
-			)
-			.filter(parent.not_like(&like_test))
+// Songs and directories matching the query, unless their parent directory already does
+fn get_search_results_query(
+	like_test: &str,
+) -> search_index::BoxedQuery<'_, Sqlite, sql_types::Text> {
+	use self::search_index::dsl::*;
+	let matching_paths = search_index
+		.filter(terms.like(like_test))
+		.select(path)
+		.into_boxed();
+	search_index
+		.filter(terms.like(like_test))
+		.filter(parent.ne_all(matching_paths))
+		.select(path)
+		.into_boxed()
+}
+
+fn filter_search_songs<'a>(
+	mut query: songs::BoxedQuery<'a, Sqlite>,
+	filters: &'a SearchFilters,
+) -> songs::BoxedQuery<'a, Sqlite> {
+	use self::songs::dsl::*;
+	if let Some(ref c) = filters.codec {
+		query = query.filter(codec.like(c));
+	}
+	match filters.lossless {
+		Some(true) => query = query.filter(codec.eq_any(LOSSLESS_CODECS.to_vec())),
+		Some(false) => {
+			query = query.filter(codec.is_null().or(codec.ne_all(LOSSLESS_CODECS.to_vec())))
+		}
+		None => (),
+	}
+	if let Some(r) = filters.min_sample_rate {
+		query = query.filter(sample_rate.ge(r));
+	}
+	if let Some(d) = filters.min_bit_depth {
+		query = query.filter(bit_depth.ge(d));
+	}
+	if let Some(b) = filters.min_bitrate {
+		query = query.filter(bitrate.ge(b));
+	}
+	if let Some(l) = filters.has_lyrics {
+		query = query.filter(has_lyrics.eq(i32::from(l)));
+	}
+	query
+}
+
+fn get_search_songs_query<'a>(
+	like_test: &'a str,
+	filters: &'a SearchFilters,
+) -> songs::BoxedQuery<'a, Sqlite> {
+	let query = songs::table
+		.filter(songs::path.eq_any(get_search_results_query(like_test)))
+		.into_boxed();
+	filter_search_songs(query, filters)
+}
+
+// Ranks every song and directory by how closely it matches the query, allowing for typos.
+// Results are ordered by relevance rather than by the sort order of the page.
+fn fuzzy_search(
+	connection: &SqliteConnection,
+	vfs: &VFS,
+	query: &str,
+	filters: &SearchFilters,
+	page: &Page,
+) -> Result<Paged<CollectionFile>, errors::Error> {
+	let query = collation::fold(query);
+	let query_words: Vec<&str> = collation::split_words(&query).collect();
+	if query_words.is_empty() {
+		return Ok(Paged {
+			items: Vec::new(),
+			total: 0,
+		});
+	}
+
+	// Close matches contain a fragment of each query word, which rules out most
+	// entries before they are ranked
+	let mut candidates = search_index::table
+		.select((
+			search_index::path,
+			search_index::parent,
+			search_index::terms,
+		))
+		.order(search_index::id)
+		.limit(MAX_FUZZY_SEARCH_CANDIDATES)
+		.into_boxed();
+	for query_word in &query_words {
+		let mut condition: Box<
+			dyn BoxableExpression<search_index::table, Sqlite, SqlType = sql_types::Bool>,
+		> = Box::new(false.into_sql::<sql_types::Bool>());
+		for fragment in collation::get_fuzzy_fragments(query_word) {
+			let like_test = format!("%{}%", fragment);
+			condition = Box::new(condition.or(search_index::terms.like(like_test)));
+		}
+		candidates = candidates.filter(condition);
+	}
+	let entries: Vec<(String, String, String)> = candidates.load(connection)?;
+	let mut matches: Vec<(usize, String, String)> = entries
+		.into_iter()
+		.filter_map(|(path, parent, terms)| {
+			collation::fuzzy_distance(&query_words, &terms).map(|d| (d, path, parent))
+		})
+		.collect();
+	let matching_paths: HashSet<String> = matches.iter().map(|m| m.1.clone()).collect();
+	matches.retain(|m| !matching_paths.contains(&m.2));
+	matches.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| a.1.cmp(&b.1)));
+	matches.truncate(MAX_FUZZY_SEARCH_RESULTS);
+	let paths: Vec<String> = matches.into_iter().map(|m| m.1).collect();
+
+	let mut results: HashMap<String, CollectionFile> = HashMap::new();
+	if filters.is_empty() {
+		let real_directories: Vec<Directory> = directories::table
+			.filter(directories::path.eq_any(&paths))
+			.load(connection)?;
+		for directory in real_directories {
+			results.insert(directory.path.clone(), CollectionFile::Directory(directory));
+		}
+	}
+	let query = songs::table.filter(songs::path.eq_any(&paths)).into_boxed();
+	let real_songs: Vec<Song> = filter_search_songs(query, filters).load(connection)?;
+	for song in real_songs {
+		results.insert(song.path.clone(), CollectionFile::Song(song));
+	}
+
+	let total = results.len() as i64;
+	let items = paths
+		.iter()
+		.filter_map(|p| results.remove(p))
+		.skip(page.offset.max(0) as usize)
+		.take(page.limit.map_or(usize::MAX, |l| l.max(0) as usize))
+		.filter_map(|f| match f {
+			CollectionFile::Directory(d) => {
+				virtualize_directory(vfs, d).map(CollectionFile::Directory)
+			}
+			CollectionFile::Song(s) => virtualize_song(vfs, s).map(CollectionFile::Song),
+		})
+		.collect();
+	Ok(Paged { items, total })
+}
+
+pub fn search<T>(
+	db: &T,
+	query: &str,
+	filters: &SearchFilters,
+) -> Result<Vec<CollectionFile>, errors::Error>
+where
+	T: ConnectionSource + VFSSource,
+{
+	Ok(search_page(db, query, filters, &Page::default())?.items)
+}
+
+pub fn search_page<T>(
+	db: &T,
+	query: &str,
+	filters: &SearchFilters,
+	page: &Page,
+) -> Result<Paged<CollectionFile>, errors::Error>
+where
+	T: ConnectionSource + VFSSource,
+{
+	let vfs = db.get_vfs()?;
+	let connection = db.get_connection();
+	let like_test = format!("%{}%", collation::fold(query));
+	let mut output = Vec::new();
+	let mut song_page = page.clone();
+	let mut directory_count = 0;
+
+	// Find dirs with matching path and parent not matching
+	// Directories have no audio properties, so they are left out when filtering on those
+	if filters.is_empty() {
+		use self::directories::dsl::*;
+		directory_count = directories
+			.filter(path.eq_any(get_search_results_query(&like_test)))
+			.count()
+			.get_result(connection.deref())?;
+		let real_directories: Vec<Directory> = directories
+			.filter(path.eq_any(get_search_results_query(&like_test)))
+			.order(sql::<sql_types::Bool>(
+				&page.get_directory_order("sort_path ASC"),
+			))
+			.limit(page.get_limit())
+			.offset(page.offset)
 			.load(connection.deref())?;
+		song_page = page.continued(directory_count, real_directories.len());
 
-		let virtual_songs = real_songs
+		let virtual_directories = real_directories
 			.into_iter()
-			.filter_map(|s| virtualize_song(&vfs, s));
+			.filter_map(|s| virtualize_directory(&vfs, s));
 
-		output.extend(virtual_songs.map(CollectionFile::Song));
+		output.extend(virtual_directories.map(CollectionFile::Directory));
+	}
+
+	// Songs fill the rest of the page
+	let song_count: i64 = get_search_songs_query(&like_test, filters)
+		.count()
+		.get_result(connection.deref())?;
+	let real_songs: Vec<Song> = get_search_songs_query(&like_test, filters)
+		.order(sql::<sql_types::Bool>(
+			&song_page.get_song_order("sort_path ASC"),
+		))
+		.limit(song_page.get_limit())
+		.offset(song_page.offset)
+		.load(connection.deref())?;
+
+	let virtual_songs = real_songs
+		.into_iter()
+		.filter_map(|s| virtualize_song(&vfs, s));
+
+	output.extend(virtual_songs.map(CollectionFile::Song));
+
+	// Queries with typos in them find little to nothing
+	let total = directory_count + song_count;
+	if total < FUZZY_SEARCH_THRESHOLD {
+		let fuzzy_results = fuzzy_search(connection.deref(), &vfs, query, filters, page)?;
+		if fuzzy_results.total > total {
+			return Ok(fuzzy_results);
+		}
+	}
+
+	Ok(Paged {
+		items: output,
+		total,
+	})
+}
+
+type TagCounts<ST> = (ST, sql_types::BigInt, sql_types::BigInt);
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Genre {
+	pub name: String,
+	pub song_count: i64,
+	pub album_count: i64,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Year {
+	pub year: i32,
+	pub song_count: i64,
+	pub album_count: i64,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Decade {
+	pub decade: i32,
+	pub song_count: i64,
+	pub album_count: i64,
+}
+
+// Selects songs by genre and by years, bounds included
+#[derive(Debug, Default)]
+pub struct TagFilters {
+	pub genre: Option<String>,
+	pub from_year: Option<i32>,
+	pub to_year: Option<i32>,
+}
+
+fn get_tag_songs_query(filters: &TagFilters) -> songs::BoxedQuery<'_, Sqlite> {
+	use self::songs::dsl::*;
+	let mut query = songs.into_boxed();
+	if let Some(ref g) = filters.genre {
+		query = query.filter(genre.eq(g));
+	}
+	if let Some(y) = filters.from_year {
+		query = query.filter(year.ge(y));
+	}
+	if let Some(y) = filters.to_year {
+		query = query.filter(year.le(y));
+	}
+	query
+}
+
+pub fn get_genres<T>(db: &T) -> Result<Vec<Genre>, errors::Error>
+where
+	T: ConnectionSource,
+{
+	use self::songs::dsl::*;
+	let connection = db.get_connection();
+	let counts: Vec<(Option<String>, i64, i64)> = songs
+		.filter(genre.is_not_null())
+		.group_by(genre)
+		.select(sql::<TagCounts<sql_types::Nullable<sql_types::Text>>>(
+			"genre, COUNT(*), COUNT(DISTINCT parent)",
+		))
+		.order(sql::<sql_types::Text>("genre COLLATE NOCASE ASC"))
+		.load(connection.deref())?;
+	let genres = counts
+		.into_iter()
+		.filter_map(|(name, song_count, album_count)| {
+			name.map(|name| Genre {
+				name,
+				song_count,
+				album_count,
+			})
+		})
+		.collect();
+	Ok(genres)
+}
+
+pub fn get_years<T>(db: &T) -> Result<Vec<Year>, errors::Error>
+where
+	T: ConnectionSource,
+{
+	use self::songs::dsl::*;
+	let connection = db.get_connection();
+	let counts: Vec<(Option<i32>, i64, i64)> = songs
+		.filter(year.is_not_null())
+		.group_by(year)
+		.select(sql::<TagCounts<sql_types::Nullable<sql_types::Integer>>>(
+			"year, COUNT(*), COUNT(DISTINCT parent)",
+		))
+		.order(year.asc())
+		.load(connection.deref())?;
+	let years = counts
+		.into_iter()
+		.filter_map(|(y, song_count, album_count)| {
+			y.map(|year_value| Year {
+				year: year_value,
+				song_count,
+				album_count,
+			})
+		})
+		.collect();
+	Ok(years)
+}
+
+pub fn get_decades<T>(db: &T) -> Result<Vec<Decade>, errors::Error>
+where
+	T: ConnectionSource,
+{
+	use self::songs::dsl::*;
+	let connection = db.get_connection();
+	let counts: Vec<(i32, i64, i64)> = songs
+		.filter(year.is_not_null())
+		.group_by(sql::<sql_types::Integer>("year / 10"))
+		.select(sql::<TagCounts<sql_types::Integer>>(
+			"(year / 10) * 10, COUNT(*), COUNT(DISTINCT parent)",
+		))
+		.order(sql::<sql_types::Integer>("year / 10 ASC"))
+		.load(connection.deref())?;
+	let decades = counts
+		.into_iter()
+		.map(|(decade, song_count, album_count)| Decade {
+			decade,
+			song_count,
+			album_count,
+		})
+		.collect();
+	Ok(decades)
+}
+
+// Directories holding at least one of the selected songs, directly or within a disc folder
+fn get_tag_albums_query(filters: &TagFilters) -> directories::BoxedQuery<'_, Sqlite> {
+	use self::directories::dsl::*;
+	let disc_folder_parents = directories
+		.filter(disc_number.is_not_null())
+		.filter(path.eq_any(get_tag_songs_query(filters).select(songs::parent)))
+		.select(parent)
+		.into_boxed();
+	directories
+		.filter(disc_number.is_null())
+		.filter(
+			path.eq_any(get_tag_songs_query(filters).select(songs::parent))
+				.or(path.nullable().eq_any(disc_folder_parents)),
+		)
+		.into_boxed()
+}
+
+pub fn get_tag_albums<T>(
+	db: &T,
+	filters: &TagFilters,
+	page: &Page,
+) -> Result<Paged<Directory>, errors::Error>
+where
+	T: ConnectionSource + VFSSource,
+{
+	let vfs = db.get_vfs()?;
+	let connection = db.get_connection();
+	let total: i64 = get_tag_albums_query(filters)
+		.count()
+		.get_result(connection.deref())?;
+	let real_directories: Vec<Directory> = get_tag_albums_query(filters)
+		.order(sql::<sql_types::Bool>(&page.get_directory_order(
+			"sort_artist ASC, year ASC, sort_album ASC",
+		)))
+		.limit(page.get_limit())
+		.offset(page.offset)
+		.load(connection.deref())?;
+	let virtual_directories = real_directories
+		.into_iter()
+		.filter_map(|d| virtualize_directory(&vfs, d));
+	Ok(Paged {
+		items: virtual_directories.collect(),
+		total,
+	})
+}
+
+pub fn get_tag_songs<T>(
+	db: &T,
+	filters: &TagFilters,
+	page: &Page,
+) -> Result<Paged<Song>, errors::Error>
+where
+	T: ConnectionSource + VFSSource,
+{
+	let vfs = db.get_vfs()?;
+	let connection = db.get_connection();
+	let total: i64 = get_tag_songs_query(filters)
+		.count()
+		.get_result(connection.deref())?;
+	let real_songs: Vec<Song> = get_tag_songs_query(filters)
+		.order(sql::<sql_types::Bool>(&page.get_song_order("sort_path")))
+		.limit(page.get_limit())
+		.offset(page.offset)
+		.load(connection.deref())?;
+	let virtual_songs = real_songs
+		.into_iter()
+		.filter_map(|s| virtualize_song(&vfs, s));
+	Ok(Paged {
+		items: virtual_songs.collect(),
+		total,
+	})
+}
+
+// Restricts random songs to a part of the collection, or to a user's listening habits
+#[derive(Debug, Default)]
+pub struct RandomFilters {
+	pub tags: TagFilters,
+	pub mount: Option<String>,
+	pub unplayed: bool,
+	pub min_rating: Option<i32>,
+}
+
+fn get_random_position(seed: u64, song_id: i32) -> u64 {
+	let mut hasher = DefaultHasher::new();
+	seed.hash(&mut hasher);
+	song_id.hash(&mut hasher);
+	hasher.finish()
+}
+
+// Each song's position only depends on the seed and the song itself, so pages
+// drawn with the same seed never overlap and songs keep their relative order
+// whatever the filters
+pub fn get_random_songs<T>(
+	db: &T,
+	username: &str,
+	filters: &RandomFilters,
+	seed: u64,
+	offset: i64,
+	count: i64,
+) -> Result<Paged<Song>, errors::Error>
+where
+	T: ConnectionSource + VFSSource,
+{
+	let vfs = db.get_vfs()?;
+	let connection = db.get_connection();
+	let user: i32 = users::table
+		.filter(users::name.eq(username))
+		.select(users::id)
+		.get_result(connection.deref())?;
+
+	let mut query = get_tag_songs_query(&filters.tags);
+	if let Some(ref name) = filters.mount {
+		let real_path = vfs.virtual_to_real(Path::new(name))?;
+		let directory = real_path.to_string_lossy().into_owned();
+		query = query.filter(
+			songs::path.eq(directory).or(songs::path
+				.like(get_descendants_pattern(&real_path))
+				.escape('\\')),
+		);
+	}
+	if filters.unplayed {
+		let played = plays::table
+			.filter(plays::user.eq(user))
+			.select(plays::path);
+		query = query.filter(songs::path.ne_all(played));
 	}
+	if let Some(min_rating) = filters.min_rating {
+		// Ratings from the user take precedence over the ones found in tags
+		let user_ratings = ratings::table
+			.filter(ratings::user.eq(user))
+			.filter(ratings::kind.eq("song"))
+			.filter(ratings::rating.is_not_null());
+		let rated = user_ratings
+			.filter(ratings::rating.ge(min_rating))
+			.select(ratings::target);
+		let all_rated = user_ratings.select(ratings::target);
+		query = query.filter(
+			songs::path.eq_any(rated).or(songs::tag_rating
+				.ge(min_rating)
+				.and(songs::path.ne_all(all_rated))),
+		);
+	}
+
+	let mut ids: Vec<i32> = query.select(songs::id).load(connection.deref())?;
+	let total = ids.len() as i64;
+	ids.sort_by_key(|id| (get_random_position(seed, *id), *id));
+	let page_ids: Vec<i32> = ids
+		.into_iter()
+		.skip(offset.max(0) as usize)
+		.take(count.max(0) as usize)
+		.collect();
+
+	let mut real_songs: HashMap<i32, Song> = songs::table
+		.filter(songs::id.eq_any(&page_ids))
+		.load::<Song>(connection.deref())?
+		.into_iter()
+		.map(|s| (s.id, s))
+		.collect();
+	let virtual_songs = page_ids
+		.iter()
+		.filter_map(|i| real_songs.remove(i))
+		.filter_map(|s| virtualize_song(&vfs, s));
+	Ok(Paged {
+		items: virtual_songs.collect(),
+		total,
+	})
+}
 
-	Ok(output)
+// Source file and section in milliseconds of a song coming from a CUE sheet
+pub fn get_cue_range<T>(
+	db: &T,
+	real_path: &Path,
+) -> Result<Option<(PathBuf, u32, Option<u32>)>, errors::Error>
+where
+	T: ConnectionSource,
+{
+	let connection = db.get_connection();
+	let range: Option<(Option<String>, Option<i32>, Option<i32>)> = songs::table
+		.filter(songs::path.eq(real_path.to_string_lossy()))
+		.select((songs::cue_source, songs::cue_start, songs::cue_end))
+		.get_result(connection.deref())
+		.optional()?;
+	Ok(match range {
+		Some((Some(source), start, end)) => Some((
+			PathBuf::from(source),
+			start.unwrap_or(0) as u32,
+			end.map(|n| n as u32),
+		)),
+		_ => None,
+	})
 }
 
 pub fn get_song<T>(db: &T, virtual_path: &Path) -> Result<Song, errors::Error>
@@ -765,6 +2147,8 @@ fn test_metadata() {
 		song.artwork,
 		Some(artwork_path.to_string_lossy().into_owned())
 	);
+	assert_eq!(song.codec, Some("MP3".to_owned()));
+	assert!(song.file_size.unwrap() > 0);
 }
 
 #[test]
@@ -817,6 +2201,60 @@ fn test_flatten() {
 	assert_eq!(results[0].title, Some("Above The Water".to_owned()));
 }
 
+#[test]
+fn test_flatten_page() {
+	let db = db::_get_test_db("flatten_page.sqlite");
+	update(&db).unwrap();
+
+	let page = Page {
+		offset: 10,
+		limit: Some(5),
+		..Default::default()
+	};
+	let results = flatten_page(&db, Path::new("root"), &page).unwrap();
+	assert_eq!(results.total, 12);
+	assert_eq!(results.items.len(), 2);
+
+	let page = Page {
+		limit: Some(3),
+		sort: Some(SortKey::Title),
+		descending: true,
+		..Default::default()
+	};
+	let results = flatten_page(&db, Path::new("root"), &page).unwrap();
+	assert_eq!(results.items.len(), 3);
+	let titles: Vec<_> = results.items.iter().map(|s| s.title.clone()).collect();
+	let mut sorted_titles = titles.clone();
+	sorted_titles.sort_by(|a, b| b.cmp(a));
+	assert_eq!(titles, sorted_titles);
+}
+
+#[test]
+fn test_browse_page() {
+	let db = db::_get_test_db("browse_page.sqlite");
+	update(&db).unwrap();
+
+	// Pages run over directories first, then songs
+	let path = Path::new("root/Khemmis/Hunted");
+	let all = browse(&db, path).unwrap();
+	let page = Page {
+		offset: 2,
+		limit: Some(2),
+		..Default::default()
+	};
+	let results = browse_page(&db, path, &page).unwrap();
+	assert_eq!(results.total, all.len() as i64);
+	assert_eq!(&results.items[..], &all[2..4]);
+
+	let page = Page {
+		offset: 1,
+		..Default::default()
+	};
+	let results = browse_page(&db, Path::new("root"), &page).unwrap();
+	assert_eq!(results.total, 2);
+	assert_eq!(results.items.len(), 1);
+}
+
 #[test]
 fn test_random() {
 	let db = db::_get_test_db("random.sqlite");
@@ -829,11 +2267,162 @@ fn test_random() {
 fn test_recent() {
 	let db = db::_get_test_db("recent.sqlite");
 	update(&db).unwrap();
-	let results = get_recent_albums(&db, 2).unwrap();
+	let results = get_recent_albums(&db, 0, 2).unwrap();
 	assert_eq!(results.len(), 2);
 	assert!(results[0].date_added >= results[1].date_added);
 }
 
+#[test]
+fn test_genres_and_years() {
+	let db = db::_get_test_db("genres_and_years.sqlite");
+	update(&db).unwrap();
+	{
+		let connection = db.get_connection();
+		diesel::update(songs::table.filter(songs::path.like("%Khemmis%")))
+			.set((songs::genre.eq("Doom Metal"), songs::year.eq(2016)))
+			.execute(connection.deref())
+			.unwrap();
+		diesel::update(songs::table.filter(songs::path.like("%Tobokegao%")))
+			.set((songs::genre.eq("Electronic"), songs::year.eq(2011)))
+			.execute(connection.deref())
+			.unwrap();
+	}
+
+	let genres = get_genres(&db).unwrap();
+	assert_eq!(
+		genres,
+		vec![
+			Genre {
+				name: "Doom Metal".into(),
+				song_count: 5,
+				album_count: 1,
+			},
+			Genre {
+				name: "Electronic".into(),
+				song_count: 7,
+				album_count: 1,
+			},
+		]
+	);
+	assert_eq!(get_years(&db).unwrap().len(), 2);
+	let decades = get_decades(&db).unwrap();
+	assert_eq!(decades.len(), 1);
+	assert_eq!(decades[0].decade, 2010);
+	assert_eq!(decades[0].song_count, 12);
+
+	let doom = TagFilters {
+		genre: Some("Doom Metal".into()),
+		..Default::default()
+	};
+	let albums = get_tag_albums(&db, &doom, &Page::default()).unwrap();
+	assert_eq!(albums.total, 1);
+	assert!(albums.items[0].path.ends_with("Hunted"));
+	assert_eq!(
+		get_tag_songs(&db, &doom, &Page::default()).unwrap().total,
+		5
+	);
+
+	let early_2010s = TagFilters {
+		from_year: Some(2010),
+		to_year: Some(2012),
+		..Default::default()
+	};
+	let songs = get_tag_songs(&db, &early_2010s, &Page::default()).unwrap();
+	assert_eq!(songs.total, 7);
+	assert!(songs.items.iter().all(|s| s.year == Some(2011)));
+}
+
+#[test]
+fn test_random_songs() {
+	use crate::history;
+	use crate::ratings;
+
+	let db = db::_get_test_db("random_songs.sqlite");
+	update(&db).unwrap();
+
+	let filters = RandomFilters::default();
+	let first_page = get_random_songs(&db, "test_user", &filters, 42, 0, 8).unwrap();
+	let second_page = get_random_songs(&db, "test_user", &filters, 42, 8, 8).unwrap();
+	assert_eq!(first_page.total, 12);
+	assert_eq!(first_page.items.len(), 8);
+	assert_eq!(second_page.items.len(), 4);
+	let mut paths: Vec<&String> = first_page
+		.items
+		.iter()
+		.chain(second_page.items.iter())
+		.map(|s| &s.path)
+		.collect();
+	paths.sort();
+	paths.dedup();
+	assert_eq!(paths.len(), 12);
+
+	// Seeds give the same order every time
+	let again = get_random_songs(&db, "test_user", &filters, 42, 0, 8).unwrap();
+	assert_eq!(again.items, first_page.items);
+
+	let khemmis = RandomFilters {
+		mount: Some("root/Khemmis".into()),
+		..Default::default()
+	};
+	let songs = get_random_songs(&db, "test_user", &khemmis, 0, 0, 20).unwrap();
+	assert_eq!(songs.total, 5);
+
+	let played = &first_page.items[0].path;
+	history::scrobble(&db, "test_user", Path::new(played)).unwrap();
+	let unplayed = RandomFilters {
+		unplayed: true,
+		..Default::default()
+	};
+	let songs = get_random_songs(&db, "test_user", &unplayed, 0, 0, 20).unwrap();
+	assert_eq!(songs.total, 11);
+	assert!(songs.items.iter().all(|s| &s.path != played));
+
+	let rated = &first_page.items[1].path;
+	ratings::set_rating(&db, "test_user", ratings::Kind::Song, rated, Some(4), None).unwrap();
+	let four_stars = RandomFilters {
+		min_rating: Some(4),
+		..Default::default()
+	};
+	let songs = get_random_songs(&db, "test_user", &four_stars, 0, 0, 20).unwrap();
+	assert_eq!(songs.total, 1);
+	assert_eq!(&songs.items[0].path, rated);
+}
+
+#[test]
+fn test_random_songs_positions() {
+	let (db, collection_path) =
+		db::_get_test_db_with_collection("random_songs_positions.sqlite", "random_songs_positions");
+	for directory in &["Khemmis", "Khemmis Tribute", "Khem_is"] {
+		for name in &["a.mp3", "b.mp3", "c.mp3"] {
+			let path = collection_path.join(directory).join(name);
+			fs::create_dir_all(path.parent().unwrap()).unwrap();
+			fs::copy("test/sample.mp3", path).unwrap();
+		}
+	}
+	update(&db).unwrap();
+
+	// Directories sharing a prefix with the mount are left out
+	let khemmis = RandomFilters {
+		mount: Some("root/Khemmis".into()),
+		..Default::default()
+	};
+	let songs = get_random_songs(&db, "test_user", &khemmis, 0, 0, 20).unwrap();
+	assert_eq!(songs.total, 3);
+
+	// Filters do not move the remaining songs around
+	let filters = RandomFilters::default();
+	let all_songs = get_random_songs(&db, "test_user", &filters, 42, 0, 20).unwrap();
+	let khemmis_songs = get_random_songs(&db, "test_user", &khemmis, 42, 0, 20).unwrap();
+	let expected: Vec<&String> = all_songs
+		.items
+		.iter()
+		.map(|s| &s.path)
+		.filter(|p| p.starts_with("root/Khemmis/"))
+		.collect();
+	let actual: Vec<&String> = khemmis_songs.items.iter().map(|s| &s.path).collect();
+	assert_eq!(actual, expected);
+}
+
 #[test]
 fn test_get_song() {
 	let db = db::_get_test_db("get_song.sqlite");
@@ -848,3 +2437,304 @@ fn test_get_song() {
 	let song = get_song(&db, &song_path).unwrap();
 	assert_eq!(song.title.unwrap(), "Candlelight");
 }
+
+#[test]
+fn test_cue_sheets() {
+	let (db, collection_path) = db::_get_test_db_with_collection("cue_sheets.sqlite", "cue_sheets");
+	for file in &["Hunted.cue", "Hunted.flac"] {
+		fs::copy(Path::new("test/cue").join(file), collection_path.join(file)).unwrap();
+	}
+	update(&db).unwrap();
+
+	// Tracks of the sheet replace the file they come from
+	let songs = flatten(&db, Path::new("root")).unwrap();
+	let paths: Vec<&str> = songs.iter().map(|s| s.path.as_str()).collect();
+	assert_eq!(
+		paths,
+		vec![
+			"root/Hunted.cue#01",
+			"root/Hunted.cue#02",
+			"root/Hunted.cue#03"
+		]
+	);
+	assert_eq!(songs[1].title, Some("Candlelight".into()));
+	assert_eq!(songs[1].artist, Some("Khemmis".into()));
+	assert_eq!(songs[1].album, Some("Hunted".into()));
+	assert_eq!(songs[1].track_number, Some(2));
+	assert_eq!(songs[1].duration, Some(1));
+
+	let song = get_song(&db, Path::new("root/Hunted.cue#02")).unwrap();
+	assert_eq!(song.title, Some("Candlelight".into()));
+
+	let vfs = db.get_vfs().unwrap();
+	let real_path = vfs
+		.virtual_to_real(Path::new("root/Hunted.cue#02"))
+		.unwrap();
+	let (source, start, end) = get_cue_range(&db, &real_path).unwrap().unwrap();
+	assert_eq!(source, collection_path.join("Hunted.flac"));
+	assert_eq!((start, end), (1493, Some(3000)));
+	assert!(cue::extract(&source, start, end).unwrap().exists());
+}
+
+#[test]
+fn test_search_filters() {
+	let db = db::_get_test_db("search_filters.sqlite");
+	update(&db).unwrap();
+
+	let mp3 = SearchFilters {
+		codec: Some("mp3".to_owned()),
+		..Default::default()
+	};
+	let lossless = SearchFilters {
+		lossless: Some(true),
+		..Default::default()
+	};
+
+	assert_eq!(search(&db, "door", &mp3).unwrap().len(), 1);
+	assert!(search(&db, "door", &lossless).unwrap().is_empty());
+
+	// Songs of unknown codec are not lossless
+	let lossy = SearchFilters {
+		lossless: Some(false),
+		..Default::default()
+	};
+	assert_eq!(search(&db, "door", &lossy).unwrap().len(), 1);
+	{
+		let connection = db.get_connection();
+		diesel::update(songs::table)
+			.set(songs::codec.eq(None::<String>))
+			.execute(connection.deref())
+			.unwrap();
+	}
+	assert_eq!(search(&db, "door", &lossy).unwrap().len(), 1);
+	assert!(search(&db, "door", &lossless).unwrap().is_empty());
+
+	let with_lyrics = SearchFilters {
+		has_lyrics: Some(true),
+		..Default::default()
+	};
+	let without_lyrics = SearchFilters {
+		has_lyrics: Some(false),
+		..Default::default()
+	};
+	assert_eq!(search(&db, "Candlelight", &with_lyrics).unwrap().len(), 1);
+	assert!(search(&db, "Candlelight", &without_lyrics)
+		.unwrap()
+		.is_empty());
+	assert!(search(&db, "door", &with_lyrics).unwrap().is_empty());
+
+	// Directories are left out when filtering on audio properties
+	assert_eq!(
+		search(&db, "Hunted", &SearchFilters::default())
+			.unwrap()
+			.len(),
+		1
+	);
+	assert!(search(&db, "Hunted", &mp3).unwrap().is_empty());
+}
+
+#[test]
+fn test_search_folding_and_typos() {
+	let db = db::_get_test_db("search_folding.sqlite");
+	update(&db).unwrap();
+	let filters = SearchFilters::default();
+
+	// Case and accents are ignored
+	let results = search(&db, "KHÉMMIS", &filters).unwrap();
+	assert_eq!(results.len(), 1);
+	match results[0] {
+		CollectionFile::Directory(ref d) => assert_eq!(d.path, "root/Khemmis"),
+		_ => panic!("Expected directory"),
+	}
+
+	// Close matches are found when nothing matches exactly
+	let results = search(&db, "candlelght", &filters).unwrap();
+	assert_eq!(results.len(), 1);
+	match results[0] {
+		CollectionFile::Song(ref s) => assert_eq!(s.title, Some("Candlelight".into())),
+		_ => panic!("Expected song"),
+	}
+	assert!(search(&db, "zzzzzzzz", &filters).unwrap().is_empty());
+}
+
+#[test]
+fn test_disc_folders_and_compilations() {
+	use crate::overrides::{self, MetadataOverride};
+
+	let (db, collection_path) =
+		db::_get_test_db_with_collection("disc_folders.sqlite", "disc_folders");
+	for disc in &["CD1", "Disc 2"] {
+		let disc_path = collection_path.join("Album").join(disc);
+		fs::create_dir_all(&disc_path).unwrap();
+		let song_path = disc_path.join("01.mp3");
+		fs::copy("test/sample.mp3", &song_path).unwrap();
+		let edits = metadata::TagEdits {
+			disc_number: Some(0),
+			..Default::default()
+		};
+		metadata::write(&song_path, &edits).unwrap();
+	}
+
+	let disc_override = MetadataOverride {
+		path: "root/Album/Disc 2".into(),
+		album_artist: Some("Someone Else".into()),
+		..Default::default()
+	};
+	overrides::set(&db, &disc_override).unwrap();
+	update(&db).unwrap();
+
+	// Songs get the disc number of their folder
+	let songs = flatten(&db, Path::new("root/Album")).unwrap();
+	let disc_numbers: Vec<Option<i32>> = songs.iter().map(|s| s.disc_number).collect();
+	assert_eq!(disc_numbers, vec![Some(1), Some(2)]);
+
+	// Disc folders are merged into a single album by various artists
+	assert_eq!(count_albums(&db).unwrap(), 1);
+	let albums = get_recent_albums(&db, 0, 10).unwrap();
+	assert_eq!(albums.len(), 1);
+	assert_eq!(albums[0].path, "root/Album");
+	assert_eq!(albums[0].album, Some("TEST ALBUM".into()));
+	assert_eq!(albums[0].artist, Some(VARIOUS_ARTISTS.into()));
+	assert_eq!(albums[0].disc_number, None);
+
+	let tags = SongTags {
+		compilation: true,
+		..Default::default()
+	};
+	assert_eq!(get_album_artist(&tags), Some(VARIOUS_ARTISTS.into()));
+	let tags = SongTags {
+		album_artist: Some("various artists".into()),
+		..Default::default()
+	};
+	assert_eq!(get_album_artist(&tags), Some(VARIOUS_ARTISTS.into()));
+}
+
+#[test]
+fn test_album_tags() {
+	let album = Some("Album".to_owned());
+	let resolve = |artists: &[&str]| {
+		let mut album_tags = AlbumTags::default();
+		for artist in artists {
+			album_tags.add(&Some(artist.to_string()), &album, None);
+		}
+		album_tags.resolve().0
+	};
+
+	assert_eq!(resolve(&["A", "A", "A"]), Some("A".into()));
+
+	// Guest appearances do not make an album a compilation
+	assert_eq!(resolve(&["A", "A", "A", "B"]), None);
+
+	// Songs flagged as part of a compilation, or mostly by different artists, do
+	assert_eq!(
+		resolve(&["A", "A", VARIOUS_ARTISTS]),
+		Some(VARIOUS_ARTISTS.into())
+	);
+	assert_eq!(resolve(&["A", "B", "C", "C"]), Some(VARIOUS_ARTISTS.into()));
+}
+
+#[test]
+fn test_sort_names() {
+	let (db, collection_path) = db::_get_test_db_with_collection("sort_names.sqlite", "sort_names");
+	for artist in &["The Zombies", "Blur", "Ärzte"] {
+		let artist_path = collection_path.join(artist);
+		fs::create_dir_all(&artist_path).unwrap();
+		for song in &["Track 10.mp3", "Track 2.mp3"] {
+			fs::copy("test/sample.mp3", artist_path.join(song)).unwrap();
+		}
+	}
+
+	update(&db).unwrap();
+
+	// Articles and accents are ignored
+	let paths: Vec<String> = browse(&db, Path::new("root"))
+		.unwrap()
+		.into_iter()
+		.filter_map(|f| match f {
+			CollectionFile::Directory(d) => Some(d.path),
+			CollectionFile::Song(_) => None,
+		})
+		.collect();
+	assert_eq!(paths, vec!["root/Ärzte", "root/Blur", "root/The Zombies"]);
+
+	// Numbers are in numeric order
+	let paths: Vec<String> = flatten(&db, Path::new("root/Blur"))
+		.unwrap()
+		.into_iter()
+		.map(|s| s.path)
+		.collect();
+	assert_eq!(
+		paths,
+		vec!["root/Blur/Track 2.mp3", "root/Blur/Track 10.mp3"]
+	);
+}
+
+#[test]
+fn test_suggestions_follow_changes() {
+	use crate::suggestions::{self, Suggestions};
+
+	let (db, collection_path) =
+		db::_get_test_db_with_collection("suggestions_changes.sqlite", "suggestions_changes");
+	let song_path = collection_path.join("sample.mp3");
+	fs::copy("test/sample.mp3", &song_path).unwrap();
+	update(&db).unwrap();
+	let suggestions = suggestions::suggest(&db, "test title", 5).unwrap();
+	assert_eq!(suggestions.titles, vec!["TEST TITLE".to_owned()]);
+
+	// Edited songs replace their previous values
+	let edits = metadata::TagEdits {
+		title: Some("NEW TITLE".into()),
+		..Default::default()
+	};
+	metadata::write(&song_path, &edits).unwrap();
+	update_song(&db, &song_path).unwrap();
+	let suggestions = suggestions::suggest(&db, "test title", 5).unwrap();
+	assert!(suggestions.titles.is_empty());
+	let suggestions = suggestions::suggest(&db, "new", 5).unwrap();
+	assert_eq!(suggestions.titles, vec!["NEW TITLE".to_owned()]);
+
+	// Removed songs take their values with them
+	fs::remove_file(&song_path).unwrap();
+	clean(&db).unwrap();
+	assert_eq!(
+		suggestions::suggest(&db, "new", 5).unwrap(),
+		Suggestions::default()
+	);
+	assert_eq!(
+		suggestions::suggest(&db, "test", 5).unwrap(),
+		Suggestions::default()
+	);
+}
+
+#[test]
+fn test_update_song() {
+	let (db, collection_path) =
+		db::_get_test_db_with_collection("update_song.sqlite", "update_song");
+	let song_path = collection_path.join("sample.mp3");
+	fs::copy("test/sample.mp3", &song_path).unwrap();
+	update(&db).unwrap();
+	let song_path_string = song_path.to_string_lossy().into_owned();
+	let get_row = || -> (i32, Option<String>, Option<String>) {
+		let connection = db.get_connection();
+		songs::table
+			.filter(songs::path.eq(&song_path_string))
+			.select((songs::id, songs::title, songs::album))
+			.get_result(connection.deref())
+			.unwrap()
+	};
+	let (id, _, album) = get_row();
+	assert!(album.is_some());
+
+	// The row is edited in place, and cleared tags clear their columns
+	let edits = metadata::TagEdits {
+		title: Some("NEW TITLE".into()),
+		album: Some("".into()),
+		..Default::default()
+	};
+	metadata::write(&song_path, &edits).unwrap();
+	update_song(&db, &song_path).unwrap();
+	assert_eq!(get_row(), (id, Some("NEW TITLE".to_owned()), None));
+
+	let results = search(&db, "new title", &SearchFilters::default()).unwrap();
+	assert_eq!(results.len(), 1);
+}
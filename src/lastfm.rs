@@ -1,15 +1,28 @@
-use rustfm_scrobble::{Scrobble, Scrobbler};
+use core::ops::Deref;
+use crypto::digest::Digest;
+use crypto::md5::Md5;
+use diesel::prelude::*;
+use error_chain::bail;
+use reqwest;
+use rustfm_scrobble::Scrobbler;
 use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
 use std::path::Path;
 
-use crate::db::ConnectionSource;
+#[cfg(test)]
+use crate::db;
+use crate::db::{misc_settings, songs, ConnectionSource};
 use crate::errors;
 use crate::index;
+use crate::ratings;
+use crate::scrobble_queue::{self, QueuedScrobble, Service};
 use crate::user;
 use crate::vfs::VFSSource;
 
 const LASTFM_API_KEY: &str = "02b96c939a2b451c31dfd67add1f696e";
 const LASTFM_API_SECRET: &str = "0f25a80ceef4b470b5cb97d99d4b3420";
+pub const LASTFM_MAX_SCROBBLES_PER_REQUEST: usize = 50;
+const LASTFM_LOVED_TRACKS_PER_PAGE: usize = 1000;
 
 #[derive(Debug, Deserialize)]
 struct AuthResponseSessionName {
@@ -42,16 +55,155 @@ struct AuthResponse {
 	pub session: AuthResponseSession,
 }
 
-fn scrobble_from_path<T>(db: &T, track: &Path) -> Result<Scrobble, errors::Error>
+#[derive(Debug, Deserialize)]
+struct LovedTrackArtist {
+	name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct LovedTrack {
+	name: String,
+	artist: LovedTrackArtist,
+}
+
+// Last.fm returns a single object instead of an array when there is only one track
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum LovedTrackList {
+	Many(Vec<LovedTrack>),
+	One(LovedTrack),
+}
+
+#[derive(Debug, Deserialize)]
+struct LovedTracksAttributes {
+	#[serde(rename = "totalPages")]
+	total_pages: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct LovedTracks {
+	track: Option<LovedTrackList>,
+	#[serde(rename = "@attr")]
+	attributes: LovedTracksAttributes,
+}
+
+#[derive(Debug, Deserialize)]
+struct LovedTracksResponse {
+	lovedtracks: LovedTracks,
+}
+
+fn get_api_url<T>(db: &T) -> Result<String, errors::Error>
 where
-	T: ConnectionSource + VFSSource,
+	T: ConnectionSource,
 {
-	let song = index::get_song(db, track)?;
-	Ok(Scrobble::new(
-		song.artist.unwrap_or_else(|| "".into()),
-		song.title.unwrap_or_else(|| "".into()),
-		song.album.unwrap_or_else(|| "".into()),
-	))
+	let connection = db.get_connection();
+	let url = misc_settings::table
+		.select(misc_settings::lastfm_url)
+		.get_result(connection.deref())?;
+	Ok(url)
+}
+
+// Signs and sends a write request to the Last.fm API.
+// Calls which rustfm_scrobble does not support (eg. batch scrobbling) go through here.
+fn call_signed<T>(
+	db: &T,
+	method: &str,
+	session_key: &str,
+	mut params: BTreeMap<String, String>,
+) -> Result<(), errors::Error>
+where
+	T: ConnectionSource,
+{
+	let url = get_api_url(db)?;
+	params.insert("method".into(), method.into());
+	params.insert("api_key".into(), LASTFM_API_KEY.into());
+	params.insert("sk".into(), session_key.into());
+
+	let mut hasher = Md5::new();
+	for (key, value) in &params {
+		hasher.input_str(key);
+		hasher.input_str(value);
+	}
+	hasher.input_str(LASTFM_API_SECRET);
+	params.insert("api_sig".into(), hasher.result_str());
+	params.insert("format".into(), "json".into());
+
+	let client = reqwest::Client::new();
+	let res = client.post(url.as_str()).form(&params).send()?;
+	if !res.status().is_success() {
+		bail!(errors::ErrorKind::LastFMRequestFailed(
+			res.status().as_u16()
+		));
+	}
+	Ok(())
+}
+
+fn get_loved_tracks_page(
+	url: &str,
+	lastfm_username: &str,
+	page: usize,
+) -> Result<(Vec<LovedTrack>, usize), errors::Error> {
+	let client = reqwest::Client::new();
+	let mut res = client
+		.get(url)
+		.query(&[
+			("method", "user.getLovedTracks"),
+			("api_key", LASTFM_API_KEY),
+			("user", lastfm_username),
+			("limit", &LASTFM_LOVED_TRACKS_PER_PAGE.to_string()),
+			("page", &page.to_string()),
+			("format", "json"),
+		])
+		.send()?;
+	if !res.status().is_success() {
+		bail!(errors::ErrorKind::LastFMRequestFailed(
+			res.status().as_u16()
+		));
+	}
+	let response: LovedTracksResponse = res.json()?;
+	let total_pages = response
+		.lovedtracks
+		.attributes
+		.total_pages
+		.parse()
+		.unwrap_or(0);
+	let tracks = match response.lovedtracks.track {
+		Some(LovedTrackList::Many(tracks)) => tracks,
+		Some(LovedTrackList::One(track)) => vec![track],
+		None => Vec::new(),
+	};
+	Ok((tracks, total_pages))
+}
+
+// Finds the real path of local songs matching (artist, title) pairs, ignoring case
+fn find_loved_songs<T>(db: &T, loved: &[(String, String)]) -> Result<Vec<String>, errors::Error>
+where
+	T: ConnectionSource,
+{
+	let connection = db.get_connection();
+	let candidates: Vec<(String, Option<String>, Option<String>)> = songs::table
+		.filter(songs::artist.is_not_null())
+		.filter(songs::title.is_not_null())
+		.select((songs::path, songs::artist, songs::title))
+		.load(connection.deref())?;
+
+	let mut library: HashMap<(String, String), Vec<String>> = HashMap::new();
+	for (path, artist, title) in candidates {
+		if let (Some(artist), Some(title)) = (artist, title) {
+			library
+				.entry((artist.to_lowercase(), title.to_lowercase()))
+				.or_insert_with(Vec::new)
+				.push(path);
+		}
+	}
+
+	let mut paths = Vec::new();
+	for (artist, title) in loved {
+		if let Some(matches) = library.remove(&(artist.to_lowercase(), title.to_lowercase())) {
+			paths.extend(matches);
+		}
+	}
+	Ok(paths)
 }
 
 pub fn link<T>(db: &T, username: &str, token: &str) -> Result<(), errors::Error>
@@ -68,29 +220,202 @@ pub fn unlink<T>(db: &T, username: &str) -> Result<(), errors::Error>
 where
 	T: ConnectionSource + VFSSource,
 {
+	scrobble_queue::clear(db, username, Service::LastFM)?;
 	user::lastfm_unlink(db, username)
 }
 
-pub fn scrobble<T>(db: &T, username: &str, track: &Path) -> Result<(), errors::Error>
+pub fn now_playing<T>(db: &T, username: &str, track: &Path) -> Result<(), errors::Error>
+where
+	T: ConnectionSource + VFSSource,
+{
+	let song = index::get_song(db, track)?;
+	let session_key = user::get_lastfm_session_key(db, username)?;
+	let mut params = BTreeMap::new();
+	params.insert("artist".into(), song.artist.unwrap_or_else(|| "".into()));
+	params.insert("track".into(), song.title.unwrap_or_else(|| "".into()));
+	if let Some(album) = song.album {
+		params.insert("album".into(), album);
+	}
+	if let Some(duration) = song.duration {
+		params.insert("duration".into(), duration.to_string());
+	}
+	call_signed(db, "track.updateNowPlaying", &session_key, params)
+}
+
+pub fn submit_scrobbles<T>(
+	db: &T,
+	username: &str,
+	scrobbles: &[QueuedScrobble],
+) -> Result<(), errors::Error>
+where
+	T: ConnectionSource,
+{
+	let session_key = user::get_lastfm_session_key(db, username)?;
+	let mut params = BTreeMap::new();
+	for (i, scrobble) in scrobbles.iter().enumerate() {
+		let field = |name: &str| format!("{}[{}]", name, i);
+		params.insert(
+			field("artist"),
+			scrobble.artist.clone().unwrap_or_else(|| "".into()),
+		);
+		params.insert(
+			field("track"),
+			scrobble.title.clone().unwrap_or_else(|| "".into()),
+		);
+		params.insert(field("timestamp"), scrobble.played_at.to_string());
+		if let Some(ref album) = scrobble.album {
+			params.insert(field("album"), album.clone());
+		}
+		if let Some(track_number) = scrobble.track_number {
+			params.insert(field("trackNumber"), track_number.to_string());
+		}
+		if let Some(duration) = scrobble.duration {
+			params.insert(field("duration"), duration.to_string());
+		}
+	}
+	call_signed(db, "track.scrobble", &session_key, params)
+}
+
+pub fn love<T>(db: &T, username: &str, track: &Path) -> Result<(), errors::Error>
 where
 	T: ConnectionSource + VFSSource,
 {
-	let mut scrobbler = Scrobbler::new(LASTFM_API_KEY.into(), LASTFM_API_SECRET.into());
-	let scrobble = scrobble_from_path(db, track)?;
-	let auth_token = user::get_lastfm_session_key(db, username)?;
-	scrobbler.authenticate_with_session_key(auth_token);
-	scrobbler.scrobble(scrobble)?;
-	Ok(())
+	let song = index::get_song(db, track)?;
+	let session_key = user::get_lastfm_session_key(db, username)?;
+	let mut params = BTreeMap::new();
+	params.insert("artist".into(), song.artist.unwrap_or_else(|| "".into()));
+	params.insert("track".into(), song.title.unwrap_or_else(|| "".into()));
+	call_signed(db, "track.love", &session_key, params)
 }
 
-pub fn now_playing<T>(db: &T, username: &str, track: &Path) -> Result<(), errors::Error>
+pub fn unlove<T>(db: &T, username: &str, track: &Path) -> Result<(), errors::Error>
 where
 	T: ConnectionSource + VFSSource,
 {
-	let mut scrobbler = Scrobbler::new(LASTFM_API_KEY.into(), LASTFM_API_SECRET.into());
-	let scrobble = scrobble_from_path(db, track)?;
-	let auth_token = user::get_lastfm_session_key(db, username)?;
-	scrobbler.authenticate_with_session_key(auth_token);
-	scrobbler.now_playing(scrobble)?;
-	Ok(())
+	let song = index::get_song(db, track)?;
+	let session_key = user::get_lastfm_session_key(db, username)?;
+	let mut params = BTreeMap::new();
+	params.insert("artist".into(), song.artist.unwrap_or_else(|| "".into()));
+	params.insert("track".into(), song.title.unwrap_or_else(|| "".into()));
+	call_signed(db, "track.unlove", &session_key, params)
+}
+
+// Stars every local song matching one of the user's loved tracks on Last.fm
+pub fn import_loved_tracks<T>(db: &T, username: &str) -> Result<usize, errors::Error>
+where
+	T: ConnectionSource,
+{
+	let url = get_api_url(db)?;
+	let lastfm_username = user::get_lastfm_username(db, username)?;
+	let mut loved = Vec::new();
+	let mut page = 1;
+	loop {
+		let (tracks, total_pages) = get_loved_tracks_page(&url, &lastfm_username, page)?;
+		loved.extend(tracks.into_iter().map(|t| (t.artist.name, t.name)));
+		if page >= total_pages {
+			break;
+		}
+		page += 1;
+	}
+
+	let paths = find_loved_songs(db, &loved)?;
+	ratings::star_songs(db, username, &paths)?;
+	Ok(paths.len())
+}
+
+#[test]
+fn test_find_loved_songs() {
+	let db = db::_get_test_db("lastfm_loved.sqlite");
+	index::update(&db).unwrap();
+
+	let loved = vec![
+		("khemmis".to_owned(), "above the water".to_owned()),
+		("Khemmis".to_owned(), "Not In The Collection".to_owned()),
+	];
+	let paths = find_loved_songs(&db, &loved).unwrap();
+	assert_eq!(paths.len(), 1);
+	assert!(paths[0].ends_with("01 - Above The Water.mp3"));
+
+	ratings::star_songs(&db, "test_user", &paths).unwrap();
+	let starred = ratings::get_starred(&db, "test_user").unwrap();
+	assert_eq!(starred.songs.len(), 1);
+	assert_eq!(starred.songs[0].title, Some("Above The Water".to_owned()));
+}
+
+#[test]
+fn test_love_and_import() {
+	use crate::test;
+
+	let db = db::_get_test_db("lastfm_love.sqlite");
+	index::update(&db).unwrap();
+	user::lastfm_link(&db, "test_user", "lastfm_user", "some-session-key").unwrap();
+
+	let first_page = r#"{"lovedtracks": {
+		"track": [
+			{"name": "Above The Water", "artist": {"name": "Khemmis"}},
+			{"name": "Not In The Collection", "artist": {"name": "Khemmis"}}
+		],
+		"@attr": {"user": "lastfm_user", "page": "1", "totalPages": "2"}
+	}}"#;
+	let second_page = r#"{"lovedtracks": {
+		"track": {"name": "candlelight", "artist": {"name": "KHEMMIS"}},
+		"@attr": {"user": "lastfm_user", "page": "2", "totalPages": "2"}
+	}}"#;
+	let (url, requests) = test::start_test_server_with_responses(vec![
+		(200, "{}".to_owned()),
+		(200, "{}".to_owned()),
+		(200, first_page.to_owned()),
+		(200, second_page.to_owned()),
+		(200, first_page.to_owned()),
+		(200, second_page.to_owned()),
+	]);
+	{
+		let connection = db.get_connection();
+		diesel::update(misc_settings::table)
+			.set(misc_settings::lastfm_url.eq(&url))
+			.execute(connection.deref())
+			.unwrap();
+	}
+
+	let songs = index::flatten(&db, Path::new("root")).unwrap();
+	let track = Path::new(&songs[0].path);
+
+	love(&db, "test_user", track).unwrap();
+	let request = requests.recv().unwrap();
+	assert!(request.starts_with("POST /"));
+	assert!(request.contains("method=track.love"));
+	assert!(request.contains("sk=some-session-key"));
+	assert!(request.contains("track=Above+The+Water"));
+	assert!(request.contains("api_sig="));
+
+	unlove(&db, "test_user", track).unwrap();
+	let request = requests.recv().unwrap();
+	assert!(request.contains("method=track.unlove"));
+
+	let (tracks, total_pages) = get_loved_tracks_page(&url, "lastfm_user", 1).unwrap();
+	assert_eq!(total_pages, 2);
+	assert_eq!(tracks.len(), 2);
+	assert_eq!(tracks[0].name, "Above The Water");
+	assert_eq!(tracks[0].artist.name, "Khemmis");
+	let request = requests.recv().unwrap();
+	assert!(request.starts_with("GET /?"));
+	assert!(request.contains("method=user.getLovedTracks"));
+	assert!(request.contains("user=lastfm_user"));
+	assert!(request.contains("page=1"));
+
+	let (tracks, total_pages) = get_loved_tracks_page(&url, "lastfm_user", 2).unwrap();
+	assert_eq!(total_pages, 2);
+	assert_eq!(tracks.len(), 1);
+	assert_eq!(tracks[0].name, "candlelight");
+	let request = requests.recv().unwrap();
+	assert!(request.contains("page=2"));
+
+	assert_eq!(import_loved_tracks(&db, "test_user").unwrap(), 2);
+	let starred = ratings::get_starred(&db, "test_user").unwrap();
+	let mut titles: Vec<_> = starred.songs.into_iter().filter_map(|s| s.title).collect();
+	titles.sort();
+	assert_eq!(
+		titles,
+		vec!["Above The Water".to_owned(), "Candlelight".to_owned()]
+	);
 }
@@ -0,0 +1,299 @@
+use core::ops::Deref;
+use diesel::prelude::*;
+use error_chain::bail;
+use reqwest;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time;
+
+#[cfg(test)]
+use crate::db;
+use crate::db::misc_settings;
+use crate::db::ConnectionSource;
+use crate::errors::*;
+use crate::history;
+use crate::index;
+use crate::scrobble_queue::{self, QueuedScrobble, Service};
+#[cfg(test)]
+use crate::test;
+use crate::user;
+use crate::vfs::VFSSource;
+
+pub const LISTENBRAINZ_MAX_LISTENS_PER_REQUEST: usize = 100;
+
+#[derive(Debug, Serialize)]
+struct AdditionalInfo {
+	#[serde(skip_serializing_if = "Option::is_none")]
+	tracknumber: Option<i32>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	duration: Option<i32>,
+	media_player: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+struct TrackMetadata {
+	artist_name: String,
+	track_name: String,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	release_name: Option<String>,
+	additional_info: AdditionalInfo,
+}
+
+#[derive(Debug, Serialize)]
+struct Listen {
+	#[serde(skip_serializing_if = "Option::is_none")]
+	listened_at: Option<i64>,
+	track_metadata: TrackMetadata,
+}
+
+#[derive(Debug, Serialize)]
+struct Submission {
+	listen_type: &'static str,
+	payload: Vec<Listen>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ValidateTokenResponse {
+	valid: bool,
+}
+
+fn now() -> Result<i32> {
+	let now = time::SystemTime::now().duration_since(time::UNIX_EPOCH)?;
+	Ok(now.as_secs() as i32)
+}
+
+fn get_api_url<T>(db: &T, endpoint: &str) -> Result<String>
+where
+	T: ConnectionSource,
+{
+	let connection = db.get_connection();
+	let base_url: String = misc_settings::table
+		.select(misc_settings::listenbrainz_url)
+		.get_result(connection.deref())?;
+	Ok(format!("{}/1/{}", base_url.trim_end_matches('/'), endpoint))
+}
+
+fn listen_from_path<T>(db: &T, track: &Path, listened_at: Option<i64>) -> Result<Listen>
+where
+	T: ConnectionSource + VFSSource,
+{
+	let song = index::get_song(db, track)?;
+	Ok(Listen {
+		listened_at,
+		track_metadata: TrackMetadata {
+			artist_name: song.artist.unwrap_or_else(|| "".into()),
+			track_name: song.title.unwrap_or_else(|| "".into()),
+			release_name: song.album,
+			additional_info: AdditionalInfo {
+				tracknumber: song.track_number,
+				duration: song.duration,
+				media_player: "Polaris",
+			},
+		},
+	})
+}
+
+fn submit<T>(db: &T, username: &str, submission: &Submission) -> Result<()>
+where
+	T: ConnectionSource,
+{
+	let url = get_api_url(db, "submit-listens")?;
+	let token = user::get_listenbrainz_token(db, username)?;
+	let client = reqwest::Client::new();
+	let res = client
+		.post(url.as_str())
+		.header("Authorization", format!("Token {}", token))
+		.json(submission)
+		.send()?;
+	if !res.status().is_success() {
+		bail!(ErrorKind::ListenBrainzRequestFailed(res.status().as_u16()));
+	}
+	Ok(())
+}
+
+pub fn link<T>(db: &T, username: &str, token: &str) -> Result<()>
+where
+	T: ConnectionSource,
+{
+	let url = get_api_url(db, "validate-token")?;
+	let client = reqwest::Client::new();
+	let mut res = client
+		.get(url.as_str())
+		.header("Authorization", format!("Token {}", token))
+		.send()?;
+	if !res.status().is_success() {
+		bail!(ErrorKind::ListenBrainzRequestFailed(res.status().as_u16()));
+	}
+	let validation: ValidateTokenResponse = res.json()?;
+	if !validation.valid {
+		bail!(ErrorKind::MissingListenBrainzCredentials);
+	}
+
+	user::listenbrainz_link(db, username, token, now()?)
+}
+
+pub fn unlink<T>(db: &T, username: &str) -> Result<()>
+where
+	T: ConnectionSource,
+{
+	scrobble_queue::clear(db, username, Service::ListenBrainz)?;
+	user::listenbrainz_unlink(db, username)
+}
+
+// Queues the plays recorded before the account was linked, except for the ones
+// imported by previous calls
+pub fn import<T>(db: &T, username: &str) -> Result<usize>
+where
+	T: ConnectionSource + VFSSource,
+{
+	let (since, until) = user::get_listenbrainz_import_range(db, username)?;
+	let listens = history::get_listens(db, username, since, until)?;
+	scrobble_queue::enqueue_plays(db, username, Service::ListenBrainz, &listens)?;
+	user::set_listenbrainz_imported_until(db, username, until)?;
+	Ok(listens.len())
+}
+
+pub fn now_playing<T>(db: &T, username: &str, track: &Path) -> Result<()>
+where
+	T: ConnectionSource + VFSSource,
+{
+	let submission = Submission {
+		listen_type: "playing_now",
+		payload: vec![listen_from_path(db, track, None)?],
+	};
+	submit(db, username, &submission)
+}
+
+pub fn submit_listens<T>(db: &T, username: &str, scrobbles: &[QueuedScrobble]) -> Result<()>
+where
+	T: ConnectionSource,
+{
+	let payload = scrobbles
+		.iter()
+		.map(|s| Listen {
+			listened_at: Some(s.played_at as i64),
+			track_metadata: TrackMetadata {
+				artist_name: s.artist.clone().unwrap_or_else(|| "".into()),
+				track_name: s.title.clone().unwrap_or_else(|| "".into()),
+				release_name: s.album.clone(),
+				additional_info: AdditionalInfo {
+					tracknumber: s.track_number,
+					duration: s.duration,
+					media_player: "Polaris",
+				},
+			},
+		})
+		.collect::<Vec<_>>();
+	let submission = Submission {
+		listen_type: if payload.len() == 1 {
+			"single"
+		} else {
+			"import"
+		},
+		payload,
+	};
+	submit(db, username, &submission)
+}
+
+#[test]
+fn test_submit_listens() {
+	use std::path::PathBuf;
+
+	let db = db::_get_test_db("listenbrainz_submit.sqlite");
+	index::update(&db).unwrap();
+
+	let (url, requests) = test::start_test_server(4);
+	{
+		let connection = db.get_connection();
+		diesel::update(misc_settings::table)
+			.set(misc_settings::listenbrainz_url.eq(&url))
+			.execute(connection.deref())
+			.unwrap();
+	}
+
+	let songs = index::flatten(&db, Path::new("root")).unwrap();
+	let track = Path::new(&songs[0].path);
+
+	assert!(now_playing(&db, "test_user", track).is_err());
+
+	link(&db, "test_user", "some-token").unwrap();
+	let request = requests.recv().unwrap();
+	assert!(request.starts_with("GET /1/validate-token"));
+	assert!(request.contains("Token some-token"));
+	assert!(user::is_listenbrainz_linked(&db, "test_user").unwrap());
+
+	now_playing(&db, "test_user", track).unwrap();
+	let request = requests.recv().unwrap();
+	assert!(request.starts_with("POST /1/submit-listens"));
+	assert!(request.contains(r#""listen_type":"playing_now""#));
+	assert!(request.contains(r#""track_name":"Above The Water""#));
+	assert!(!request.contains("listened_at"));
+
+	scrobble_queue::enqueue(&db, "test_user", track).unwrap();
+	scrobble_queue::process(&db).unwrap();
+	let request = requests.recv().unwrap();
+	assert!(request.contains(r#""listen_type":"single""#));
+	assert!(request.contains(r#""listened_at":"#));
+
+	let listens = vec![
+		(PathBuf::from(&songs[0].path), 1_500_000_000),
+		(PathBuf::from(&songs[1].path), 1_500_000_300),
+	];
+	scrobble_queue::enqueue_plays(&db, "test_user", Service::ListenBrainz, &listens).unwrap();
+	scrobble_queue::process(&db).unwrap();
+	let request = requests.recv().unwrap();
+	assert!(request.contains(r#""listen_type":"import""#));
+	assert!(request.contains(r#""listened_at":1500000300"#));
+
+	unlink(&db, "test_user").unwrap();
+	assert!(!user::is_listenbrainz_linked(&db, "test_user").unwrap());
+}
+
+#[test]
+fn test_import() {
+	use crate::db::{plays, scrobble_queue};
+
+	let db = db::_get_test_db("listenbrainz_import.sqlite");
+	index::update(&db).unwrap();
+
+	let songs = index::flatten(&db, Path::new("root")).unwrap();
+	let set_play_times = |played_at: &[i32]| {
+		let connection = db.get_connection();
+		let ids: Vec<i32> = plays::table
+			.select(plays::id)
+			.order(plays::id)
+			.load(connection.deref())
+			.unwrap();
+		for (id, played_at) in ids.into_iter().zip(played_at) {
+			diesel::update(plays::table.find(id))
+				.set(plays::played_at.eq(played_at))
+				.execute(connection.deref())
+				.unwrap();
+		}
+	};
+	let count_queued = || -> i64 {
+		let connection = db.get_connection();
+		scrobble_queue::table
+			.count()
+			.get_result(connection.deref())
+			.unwrap()
+	};
+
+	history::scrobble(&db, "test_user", Path::new(&songs[0].path)).unwrap();
+	history::scrobble(&db, "test_user", Path::new(&songs[1].path)).unwrap();
+	history::scrobble(&db, "test_user", Path::new(&songs[2].path)).unwrap();
+	set_play_times(&[1000, 2000, 4000]);
+
+	assert!(import(&db, "test_user").is_err());
+	user::listenbrainz_link(&db, "test_user", "some-token", 3000).unwrap();
+
+	// Plays are only imported once, and the ones made while linked are left alone
+	assert_eq!(import(&db, "test_user").unwrap(), 2);
+	assert_eq!(import(&db, "test_user").unwrap(), 0);
+	assert_eq!(count_queued(), 2);
+
+	// Relinking imports the plays made in between
+	user::listenbrainz_link(&db, "test_user", "some-token", 5000).unwrap();
+	assert_eq!(import(&db, "test_user").unwrap(), 1);
+	assert_eq!(count_queued(), 3);
+}
@@ -0,0 +1,314 @@
+use core::ops::Deref;
+use diesel;
+use diesel::prelude::*;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time;
+
+#[cfg(test)]
+use crate::db;
+use crate::db::ConnectionSource;
+use crate::db::{loudness, misc_settings, songs};
+use crate::decoding;
+use crate::errors::*;
+#[cfg(test)]
+use crate::index;
+use crate::index::{CollectionFile, Song};
+use crate::vfs::VFSSource;
+
+mod r128;
+
+const LOUDNESS_LOOKUP_BUFFER_SIZE: usize = 500; // Songs looked up in each query
+const REPLAYGAIN_REFERENCE_LUFS: f32 = -18.0;
+
+static ANALYSIS_RUNNING: AtomicBool = AtomicBool::new(false);
+static ANALYSIS_DONE: AtomicUsize = AtomicUsize::new(0);
+static ANALYSIS_TOTAL: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Progress {
+	pub running: bool,
+	pub analyzed_songs: usize,
+	pub total_songs: usize,
+}
+
+#[derive(Insertable)]
+#[table_name = "loudness"]
+struct NewLoudness {
+	path: String,
+	integrated: Option<f32>,
+	true_peak: f32,
+	modified: i32,
+}
+
+pub fn get_progress() -> Progress {
+	Progress {
+		running: ANALYSIS_RUNNING.load(Ordering::SeqCst),
+		analyzed_songs: ANALYSIS_DONE.load(Ordering::SeqCst),
+		total_songs: ANALYSIS_TOTAL.load(Ordering::SeqCst),
+	}
+}
+
+pub fn is_enabled<T>(db: &T) -> Result<bool>
+where
+	T: ConnectionSource,
+{
+	let connection = db.get_connection();
+	let enabled: i32 = misc_settings::table
+		.select(misc_settings::analyze_loudness)
+		.get_result(connection.deref())?;
+	Ok(enabled != 0)
+}
+
+fn get_modified(path: &Path) -> Result<i32> {
+	let modified = fs::metadata(path)?
+		.modified()?
+		.duration_since(time::UNIX_EPOCH)?;
+	Ok(modified.as_secs() as i32)
+}
+
+fn measure(path: &Path) -> Result<r128::Meter> {
+	let mut meter: Option<r128::Meter> = None;
+	let mut stream_format = None;
+	decoding::decode(path, |format, samples| {
+		if stream_format.is_none() {
+			stream_format = Some(format);
+			meter = Some(r128::Meter::new(format.sample_rate, format.channels));
+		}
+		// Changes of format midway through a file are not supported
+		if stream_format == Some(format) {
+			if let Some(ref mut meter) = meter {
+				meter.add_samples(samples);
+			}
+		}
+	})?;
+	meter.ok_or_else(|| "No audio data".into())
+}
+
+// Measures all songs in an album directory, which is needed to compute album loudness
+fn analyze_album(parent: &str, tracks: &[(String, i32)]) -> Vec<NewLoudness> {
+	let mut results = Vec::new();
+	let mut album_blocks = Vec::new();
+	let mut album_peak = 0.0;
+	let mut album_modified = 0;
+
+	for (path, modified) in tracks {
+		match measure(Path::new(path)) {
+			Ok(meter) => {
+				album_blocks.extend_from_slice(meter.blocks());
+				album_peak = meter.true_peak().max(album_peak);
+				album_modified = album_modified.max(*modified);
+				results.push(NewLoudness {
+					path: path.clone(),
+					integrated: meter.integrated_loudness().map(|l| l as f32),
+					true_peak: meter.true_peak() as f32,
+					modified: *modified,
+				});
+			}
+			Err(e) => error!("Could not analyze loudness of {}: {}", path, e),
+		}
+		ANALYSIS_DONE.fetch_add(1, Ordering::SeqCst);
+	}
+
+	if !results.is_empty() {
+		results.push(NewLoudness {
+			path: parent.to_owned(),
+			integrated: r128::integrated_loudness(album_blocks.iter()).map(|l| l as f32),
+			true_peak: album_peak as f32,
+			modified: album_modified,
+		});
+	}
+
+	results
+}
+
+fn run_analysis<T>(db: &T) -> Result<()>
+where
+	T: ConnectionSource,
+{
+	let tracks: Vec<(String, String)>;
+	let analyzed: HashMap<String, i32>;
+	{
+		let connection = db.get_connection();
+		tracks = songs::table
+			.select((songs::path, songs::parent))
+			.order((songs::parent, songs::path))
+			.load(connection.deref())?;
+		analyzed = loudness::table
+			.select((loudness::path, loudness::modified))
+			.load::<(String, i32)>(connection.deref())?
+			.into_iter()
+			.collect();
+	}
+
+	// Albums are analyzed again as a whole when any of their songs changed
+	let mut albums: Vec<(String, Vec<(String, i32)>, bool)> = Vec::new();
+	for (path, parent) in tracks {
+		if !decoding::can_decode(Path::new(&path)) {
+			continue;
+		}
+		let modified = match get_modified(Path::new(&path)) {
+			Ok(m) => m,
+			Err(_) => continue,
+		};
+		let outdated = analyzed.get(&path) != Some(&modified);
+		match albums.last_mut() {
+			Some((album, tracks, album_outdated)) if *album == parent => {
+				tracks.push((path, modified));
+				*album_outdated |= outdated;
+			}
+			_ => {
+				let album_outdated = outdated || !analyzed.contains_key(&parent);
+				albums.push((parent, vec![(path, modified)], album_outdated));
+			}
+		}
+	}
+	albums.retain(|(_, _, outdated)| *outdated);
+
+	let total = albums.iter().map(|(_, tracks, _)| tracks.len()).sum();
+	ANALYSIS_TOTAL.store(total, Ordering::SeqCst);
+	if total > 0 {
+		info!("Analyzing loudness of {} songs", total);
+	}
+
+	for (parent, tracks, _) in albums {
+		let results = analyze_album(&parent, &tracks);
+		let connection = db.get_connection();
+		diesel::insert_into(loudness::table)
+			.values(&results)
+			.execute(connection.deref())?;
+	}
+
+	// Forget about songs and albums which left the collection
+	let connection = db.get_connection();
+	diesel::delete(
+		loudness::table
+			.filter(loudness::path.ne_all(songs::table.select(songs::path)))
+			.filter(loudness::path.ne_all(songs::table.select(songs::parent))),
+	)
+	.execute(connection.deref())?;
+
+	Ok(())
+}
+
+// Computes integrated loudness and true peak of songs and albums which are not up to date
+pub fn analyze<T>(db: &T) -> Result<()>
+where
+	T: ConnectionSource,
+{
+	let start = time::Instant::now();
+	ANALYSIS_DONE.store(0, Ordering::SeqCst);
+	ANALYSIS_TOTAL.store(0, Ordering::SeqCst);
+	ANALYSIS_RUNNING.store(true, Ordering::SeqCst);
+	let result = run_analysis(db);
+	ANALYSIS_RUNNING.store(false, Ordering::SeqCst);
+	info!(
+		"Loudness analysis took {} seconds",
+		start.elapsed().as_secs()
+	);
+	result
+}
+
+// Fills in ReplayGain values of songs without ReplayGain tags
+pub fn annotate_songs<'a, T, I>(db: &T, songs: I) -> Result<()>
+where
+	T: ConnectionSource + VFSSource,
+	I: IntoIterator<Item = &'a mut Song>,
+{
+	let vfs = db.get_vfs()?;
+	let songs: Vec<(String, &mut Song)> = songs
+		.into_iter()
+		.filter(|s| s.replaygain_track_gain.is_none() || s.replaygain_album_gain.is_none())
+		.filter_map(|s| {
+			vfs.virtual_to_real(Path::new(&s.path))
+				.ok()
+				.map(|p| (p.to_string_lossy().into_owned(), s))
+		})
+		.collect();
+
+	let mut paths: Vec<&String> = Vec::new();
+	for (path, song) in &songs {
+		paths.push(path);
+		paths.push(&song.parent);
+	}
+
+	let connection = db.get_connection();
+	let mut measurements: HashMap<String, (Option<f32>, f32)> = HashMap::new();
+	for chunk in paths[..].chunks(LOUDNESS_LOOKUP_BUFFER_SIZE) {
+		let chunk_measurements: Vec<(String, Option<f32>, f32)> = loudness::table
+			.filter(loudness::path.eq_any(chunk))
+			.select((loudness::path, loudness::integrated, loudness::true_peak))
+			.load(connection.deref())?;
+		for (path, integrated, true_peak) in chunk_measurements {
+			measurements.insert(path, (integrated, true_peak));
+		}
+	}
+
+	let gain = |integrated: Option<f32>| integrated.map(|l| REPLAYGAIN_REFERENCE_LUFS - l);
+	for (path, song) in songs {
+		if song.replaygain_track_gain.is_none() {
+			if let Some((integrated, true_peak)) = measurements.get(&path) {
+				song.replaygain_track_gain = gain(*integrated);
+				song.replaygain_track_peak = Some(*true_peak);
+			}
+		}
+		if song.replaygain_album_gain.is_none() {
+			if let Some((integrated, true_peak)) = measurements.get(&song.parent) {
+				song.replaygain_album_gain = gain(*integrated);
+				song.replaygain_album_peak = Some(*true_peak);
+			}
+		}
+	}
+
+	Ok(())
+}
+
+pub fn annotate_collection_files<T>(db: &T, files: &mut [CollectionFile]) -> Result<()>
+where
+	T: ConnectionSource + VFSSource,
+{
+	let songs = files.iter_mut().filter_map(|f| match f {
+		CollectionFile::Song(s) => Some(s),
+		_ => None,
+	});
+	annotate_songs(db, songs)
+}
+
+#[test]
+fn test_analyze() {
+	let db = db::_get_test_db("loudness.sqlite");
+	index::update(&db).unwrap();
+	analyze(&db).unwrap();
+
+	let progress = get_progress();
+	assert!(!progress.running);
+	assert_eq!(progress.analyzed_songs, progress.total_songs);
+
+	// Nothing changed since the previous analysis
+	analyze(&db).unwrap();
+	assert_eq!(get_progress().total_songs, 0);
+
+	let mut songs = index::flatten(&db, Path::new("root")).unwrap();
+	annotate_songs(&db, songs.iter_mut()).unwrap();
+	assert!(songs.iter().all(|s| s.replaygain_track_gain.is_some()));
+	assert!(songs.iter().all(|s| s.replaygain_album_gain.is_some()));
+
+	// Khemmis songs are copies of the same file, measuring -8.13 LUFS with a 0.732 true peak
+	let khemmis = songs
+		.iter()
+		.filter(|s| s.artist == Some("Khemmis".to_owned()))
+		.collect::<Vec<_>>();
+	assert_eq!(khemmis.len(), 5);
+	for song in khemmis {
+		let track_gain = song.replaygain_track_gain.unwrap();
+		let album_gain = song.replaygain_album_gain.unwrap();
+		assert!((track_gain + 9.87).abs() < 0.05, "{}", track_gain);
+		assert!((album_gain + 9.87).abs() < 0.05, "{}", album_gain);
+		assert!((song.replaygain_track_peak.unwrap() - 0.732).abs() < 0.005);
+		assert!((song.replaygain_album_peak.unwrap() - 0.732).abs() < 0.005);
+	}
+}
@@ -0,0 +1,287 @@
+use std::f64::consts::PI;
+
+// Loudness measurement as described in ITU-R BS.1770-4 and EBU R128
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+const RELATIVE_GATE_LU: f64 = -10.0;
+const BLOCK_SEGMENTS: usize = 4; // 400ms blocks made of 100ms segments (75% overlap)
+const TRUE_PEAK_TAPS: usize = 12; // Interpolation filter taps per phase
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Biquad {
+	b: [f64; 3],
+	a: [f64; 3],
+	z: [f64; 2],
+}
+
+impl Biquad {
+	fn process(&mut self, x: f64) -> f64 {
+		let y = self.b[0] * x + self.z[0];
+		self.z[0] = self.b[1] * x - self.a[1] * y + self.z[1];
+		self.z[1] = self.b[2] * x - self.a[2] * y;
+		y
+	}
+}
+
+// High shelf modeling the acoustic effect of the head, followed by a high pass filter
+fn k_weighting_filters(sample_rate: f64) -> (Biquad, Biquad) {
+	let f0 = 1681.974_450_955_533;
+	let gain = 3.999_843_853_973_347;
+	let q = 0.707_175_236_955_419_6;
+	let k = (PI * f0 / sample_rate).tan();
+	let vh = 10f64.powf(gain / 20.0);
+	let vb = vh.powf(0.499_666_774_154_541_6);
+	let a0 = 1.0 + k / q + k * k;
+	let shelf = Biquad {
+		b: [
+			(vh + vb * k / q + k * k) / a0,
+			2.0 * (k * k - vh) / a0,
+			(vh - vb * k / q + k * k) / a0,
+		],
+		a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+		z: [0.0; 2],
+	};
+
+	let f0 = 38.135_470_876_024_44;
+	let q = 0.500_327_037_323_877_3;
+	let k = (PI * f0 / sample_rate).tan();
+	let a0 = 1.0 + k / q + k * k;
+	let high_pass = Biquad {
+		b: [1.0, -2.0, 1.0],
+		a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+		z: [0.0; 2],
+	};
+
+	(shelf, high_pass)
+}
+
+// Surround channels weigh more, LFE is ignored (assuming L, R, C, LFE, Ls, Rs)
+fn channel_weight(channels: usize, channel: usize) -> f64 {
+	match (channels, channel) {
+		(6, 3) => 0.0,
+		(6, 4) | (6, 5) => 1.41,
+		_ => 1.0,
+	}
+}
+
+fn windowed_sinc(x: f64, half_width: f64) -> f64 {
+	if x.abs() >= half_width {
+		return 0.0;
+	}
+	let window = 0.5 * (1.0 + (PI * x / half_width).cos());
+	if x == 0.0 {
+		window
+	} else {
+		window * (PI * x).sin() / (PI * x)
+	}
+}
+
+// Energy of a 400ms block, as a mean square of the weighted channels
+pub type Block = f64;
+
+pub fn block_loudness(energy: f64) -> f64 {
+	-0.691 + 10.0 * energy.log10()
+}
+
+// Gated loudness of a set of blocks, which may come from several tracks
+pub fn integrated_loudness<'a, I>(blocks: I) -> Option<f64>
+where
+	I: IntoIterator<Item = &'a Block> + Clone,
+{
+	let gated_mean = |threshold: f64| {
+		let (sum, count) = blocks
+			.clone()
+			.into_iter()
+			.filter(|b| block_loudness(**b) > threshold)
+			.fold((0.0, 0), |(sum, count), b| (sum + b, count + 1));
+		if count == 0 {
+			None
+		} else {
+			Some(sum / count as f64)
+		}
+	};
+	let relative_threshold = block_loudness(gated_mean(ABSOLUTE_GATE_LUFS)?) + RELATIVE_GATE_LU;
+	gated_mean(relative_threshold.max(ABSOLUTE_GATE_LUFS)).map(block_loudness)
+}
+
+pub struct Meter {
+	channels: usize,
+	filters: Vec<(Biquad, Biquad)>,
+	weights: Vec<f64>,
+	segment_length: usize,
+	segment_position: usize,
+	segment_energy: f64,
+	segments: Vec<f64>,
+	blocks: Vec<Block>,
+	oversampling: usize,
+	interpolation: Vec<Vec<f64>>,
+	history: Vec<Vec<f64>>,
+	history_position: usize,
+	true_peak: f64,
+}
+
+impl Meter {
+	pub fn new(sample_rate: u32, channels: usize) -> Meter {
+		let oversampling = if sample_rate < 96_000 {
+			4
+		} else if sample_rate < 192_000 {
+			2
+		} else {
+			1
+		};
+		let half_width = TRUE_PEAK_TAPS as f64 / 2.0;
+		let interpolation = (0..oversampling)
+			.map(|phase| {
+				(0..TRUE_PEAK_TAPS)
+					.map(|tap| {
+						let offset =
+							half_width - 1.0 - tap as f64 + phase as f64 / oversampling as f64;
+						windowed_sinc(offset, half_width)
+					})
+					.collect()
+			})
+			.collect();
+
+		Meter {
+			channels,
+			filters: (0..channels)
+				.map(|_| k_weighting_filters(f64::from(sample_rate)))
+				.collect(),
+			weights: (0..channels).map(|c| channel_weight(channels, c)).collect(),
+			segment_length: (sample_rate / 10).max(1) as usize,
+			segment_position: 0,
+			segment_energy: 0.0,
+			segments: Vec::new(),
+			blocks: Vec::new(),
+			oversampling,
+			interpolation,
+			history: vec![vec![0.0; TRUE_PEAK_TAPS]; channels],
+			history_position: 0,
+			true_peak: 0.0,
+		}
+	}
+
+	// Samples are interleaved, between -1.0 and 1.0
+	pub fn add_samples(&mut self, samples: &[f32]) {
+		for frame in samples.chunks_exact(self.channels) {
+			for (channel, sample) in frame.iter().enumerate() {
+				let x = f64::from(*sample);
+				let (shelf, high_pass) = &mut self.filters[channel];
+				let y = high_pass.process(shelf.process(x));
+				self.segment_energy += self.weights[channel] * y * y;
+				self.history[channel][self.history_position] = x;
+				self.update_true_peak(channel);
+			}
+			self.history_position = (self.history_position + 1) % TRUE_PEAK_TAPS;
+
+			self.segment_position += 1;
+			if self.segment_position == self.segment_length {
+				self.end_segment();
+			}
+		}
+	}
+
+	fn update_true_peak(&mut self, channel: usize) {
+		let history = &self.history[channel];
+		let newest = self.history_position;
+		for phase in 0..self.oversampling {
+			let coefficients = &self.interpolation[phase];
+			let mut y = 0.0;
+			for (tap, coefficient) in coefficients.iter().enumerate() {
+				// Tap 0 is the oldest sample in the history
+				let index = (newest + 1 + tap) % TRUE_PEAK_TAPS;
+				y += coefficient * history[index];
+			}
+			self.true_peak = self.true_peak.max(y.abs());
+		}
+		self.true_peak = self.true_peak.max(history[newest].abs());
+	}
+
+	fn end_segment(&mut self) {
+		self.segments
+			.push(self.segment_energy / self.segment_length as f64);
+		self.segment_energy = 0.0;
+		self.segment_position = 0;
+		if self.segments.len() >= BLOCK_SEGMENTS {
+			let block = &self.segments[self.segments.len() - BLOCK_SEGMENTS..];
+			self.blocks
+				.push(block.iter().sum::<f64>() / BLOCK_SEGMENTS as f64);
+		}
+	}
+
+	pub fn blocks(&self) -> &[Block] {
+		&self.blocks
+	}
+
+	// Integrated loudness in LUFS, None for silent or very short tracks
+	pub fn integrated_loudness(&self) -> Option<f64> {
+		integrated_loudness(self.blocks.iter())
+	}
+
+	// Linear peak amplitude of the reconstructed signal
+	pub fn true_peak(&self) -> f64 {
+		self.true_peak
+	}
+}
+
+#[cfg(test)]
+fn sine(
+	sample_rate: u32,
+	channels: usize,
+	frequency: f64,
+	amplitude: f64,
+	seconds: f64,
+) -> Vec<f32> {
+	let frames = (f64::from(sample_rate) * seconds) as usize;
+	let mut samples = Vec::with_capacity(frames * channels);
+	for i in 0..frames {
+		let t = i as f64 / f64::from(sample_rate);
+		let value = amplitude * (2.0 * PI * frequency * t).sin();
+		for _ in 0..channels {
+			samples.push(value as f32);
+		}
+	}
+	samples
+}
+
+#[test]
+fn test_sine_loudness() {
+	// A stereo 1kHz sine at -20dBFS measures -20 LUFS
+	let mut meter = Meter::new(48000, 2);
+	meter.add_samples(&sine(48000, 2, 1000.0, 0.1, 10.0));
+	let loudness = meter.integrated_loudness().unwrap();
+	assert!((loudness + 20.0).abs() < 0.1, "{}", loudness);
+	assert!((meter.true_peak() - 0.1).abs() < 0.002);
+
+	let mut meter = Meter::new(44100, 1);
+	meter.add_samples(&sine(44100, 1, 1000.0, 0.5, 5.0));
+	let loudness = meter.integrated_loudness().unwrap();
+	assert!((loudness + 9.03).abs() < 0.1, "{}", loudness);
+}
+
+#[test]
+fn test_gating() {
+	let mut meter = Meter::new(48000, 2);
+	meter.add_samples(&vec![0.0; 48000 * 2 * 10]);
+	assert_eq!(meter.integrated_loudness(), None);
+
+	// Quiet passages do not drag the loudness down
+	meter.add_samples(&sine(48000, 2, 1000.0, 0.1, 10.0));
+	meter.add_samples(&sine(48000, 2, 1000.0, 0.001, 10.0));
+	let loudness = meter.integrated_loudness().unwrap();
+	assert!((loudness + 20.0).abs() < 0.2, "{}", loudness);
+}
+
+#[test]
+fn test_true_peak() {
+	// Samples never hit the peak of a sine at a quarter of the sample rate, phased by 45 degrees
+	let mut samples = Vec::new();
+	for i in 0..48000 {
+		let value = 0.5 * (PI / 2.0 * i as f64 + PI / 4.0).sin();
+		samples.push(value as f32);
+	}
+	let mut meter = Meter::new(48000, 1);
+	meter.add_samples(&samples);
+	assert!(meter.true_peak() > 0.49, "{}", meter.true_peak());
+	let sample_peak = samples.iter().fold(0.0f32, |m, s| m.max(s.abs()));
+	assert!(sample_peak < 0.36);
+}
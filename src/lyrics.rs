@@ -0,0 +1,189 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::errors::*;
+use crate::metadata;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Line {
+	pub time: Option<u32>, // Milliseconds since the start of the song
+	pub text: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Lyrics {
+	pub synchronized: bool,
+	pub lines: Vec<Line>,
+}
+
+impl Lyrics {
+	pub fn new(mut lines: Vec<Line>) -> Option<Lyrics> {
+		let synchronized = lines.iter().any(|l| l.time.is_some());
+		if synchronized {
+			lines.retain(|l| l.time.is_some());
+			lines.sort_by_key(|l| l.time);
+		}
+		if lines.iter().all(|l| l.text.trim().is_empty()) {
+			return None;
+		}
+		Some(Lyrics {
+			synchronized,
+			lines,
+		})
+	}
+}
+
+// Timestamps look like mm:ss, mm:ss.xx or mm:ss.xxx
+fn parse_timestamp(tag: &str) -> Option<u32> {
+	let mut parts = tag.splitn(2, ':');
+	let minutes = parts.next()?.trim().parse::<u32>().ok()?;
+	let seconds = parts.next()?.trim().replace(':', ".");
+	let mut parts = seconds.splitn(2, '.');
+	let whole_seconds = parts.next()?.parse::<u32>().ok()?;
+	let fraction = match parts.next() {
+		Some(f) if f.chars().all(|c| c.is_ascii_digit()) && !f.is_empty() => {
+			let digits = &f[..f.len().min(3)];
+			digits.parse::<u32>().ok()? * 10u32.pow(3 - digits.len() as u32)
+		}
+		Some(_) => return None,
+		None => 0,
+	};
+	minutes
+		.checked_mul(60_000)?
+		.checked_add(whole_seconds.checked_mul(1000)?)?
+		.checked_add(fraction)
+}
+
+// Plain text lyrics are accepted too, as lines without a timestamp
+pub fn parse_lrc(content: &str) -> Option<Lyrics> {
+	let mut lines = Vec::new();
+	let mut offset: i64 = 0;
+
+	for line in content.lines() {
+		let mut text = line.trim();
+		let mut times = Vec::new();
+		let mut has_tags = false;
+		while text.starts_with('[') {
+			let end = match text.find(']') {
+				Some(e) => e,
+				None => break,
+			};
+			let tag = &text[1..end];
+			if let Some(time) = parse_timestamp(tag) {
+				times.push(time);
+			} else if tag.starts_with("offset:") {
+				offset = tag["offset:".len()..].trim().parse().unwrap_or(0);
+			}
+			has_tags = true;
+			text = text[end + 1..].trim_start();
+		}
+
+		if times.is_empty() {
+			// Skip ID tags such as [ar:Artist]
+			if !has_tags {
+				lines.push(Line {
+					time: None,
+					text: text.to_owned(),
+				});
+			}
+			continue;
+		}
+		for time in times {
+			lines.push(Line {
+				time: Some(time),
+				text: text.to_owned(),
+			});
+		}
+	}
+
+	// A positive offset makes lyrics appear sooner
+	for line in &mut lines {
+		line.time = line.time.map(|t| {
+			let time = i64::from(t).saturating_sub(offset);
+			time.max(0).min(i64::from(u32::max_value())) as u32
+		});
+	}
+
+	Lyrics::new(lines)
+}
+
+pub fn find_sidecar(song_path: &Path) -> Option<PathBuf> {
+	let sidecar = song_path.with_extension("lrc");
+	if sidecar.is_file() {
+		Some(sidecar)
+	} else {
+		None
+	}
+}
+
+// Sidecar files take precedence over lyrics embedded in the song
+pub fn read(song_path: &Path) -> Result<Option<Lyrics>> {
+	if let Some(sidecar) = find_sidecar(song_path) {
+		let content = fs::read(sidecar)?;
+		return Ok(parse_lrc(&String::from_utf8_lossy(&content)));
+	}
+	Ok(metadata::read(song_path)?.lyrics)
+}
+
+#[test]
+fn test_parse_timestamp() {
+	assert_eq!(parse_timestamp("01:02"), Some(62_000));
+	assert_eq!(parse_timestamp("01:02.5"), Some(62_500));
+	assert_eq!(parse_timestamp("01:02.34"), Some(62_340));
+	assert_eq!(parse_timestamp("01:02.345"), Some(62_345));
+	assert_eq!(parse_timestamp("01:02:34"), Some(62_340));
+	assert_eq!(parse_timestamp("ar:Artist"), None);
+	assert_eq!(parse_timestamp("71582:47.295"), Some(u32::max_value()));
+	assert_eq!(parse_timestamp("71582:47.296"), None);
+	assert_eq!(parse_timestamp("4294967295:00"), None);
+	assert_eq!(parse_timestamp("00:4294967295"), None);
+}
+
+#[test]
+fn test_parse_lrc() {
+	let content = "[ar:Khemmis]\n[offset:+500]\n[00:12.00]First line\n[00:17.20][01:02.00]Chorus\n[00:15.30]Second line\n";
+	let lyrics = parse_lrc(content).unwrap();
+	assert!(lyrics.synchronized);
+	assert_eq!(
+		lyrics.lines,
+		vec![
+			Line {
+				time: Some(11_500),
+				text: "First line".to_owned()
+			},
+			Line {
+				time: Some(14_800),
+				text: "Second line".to_owned()
+			},
+			Line {
+				time: Some(16_700),
+				text: "Chorus".to_owned()
+			},
+			Line {
+				time: Some(61_500),
+				text: "Chorus".to_owned()
+			},
+		]
+	);
+
+	let lyrics = parse_lrc("First line\n\nSecond line").unwrap();
+	assert!(!lyrics.synchronized);
+	assert_eq!(lyrics.lines.len(), 3);
+	assert_eq!(lyrics.lines[2].text, "Second line");
+
+	assert_eq!(parse_lrc("[ar:Khemmis]\n\n"), None);
+
+	let lyrics = parse_lrc("[offset:-9223372036854775808]\n[00:01.00]Line").unwrap();
+	assert_eq!(lyrics.lines[0].time, Some(u32::max_value()));
+}
+
+#[test]
+fn test_read_sidecar() {
+	let song_path = Path::new("test/collection/Khemmis/Hunted/02 - Candlelight.mp3");
+	let lyrics = read(song_path).unwrap().unwrap();
+	assert!(lyrics.synchronized);
+	assert_eq!(lyrics.lines[0].time, Some(0));
+
+	assert_eq!(find_sidecar(Path::new("test/sample.mp3")), None);
+}
@@ -28,24 +28,40 @@ use std::sync::Arc;
 mod api;
 #[cfg(test)]
 mod api_tests;
+mod archive;
+mod collation;
 mod config;
+mod cue;
 mod db;
 mod ddns;
+mod decoding;
 mod errors;
+mod history;
 mod index;
 mod lastfm;
+mod listenbrainz;
+mod loudness;
+mod lyrics;
 mod metadata;
+mod overrides;
 mod playlist;
+mod ratings;
+mod scrobble_queue;
 mod serve;
 mod server;
+mod share_links;
+mod suggestions;
 mod swagger;
+mod tag_editor;
 #[cfg(test)]
 mod test;
 mod thumbnails;
 mod ui;
+mod upload;
 mod user;
 mod utils;
 mod vfs;
+mod waveform;
 mod web;
 
 fn log_config() -> simplelog::Config {
@@ -265,6 +281,12 @@ fn run() -> Result<()> {
 		ddns::run(db_ddns.deref());
 	});
 
+	// Start submitting queued scrobbles
+	let db_scrobble_queue = db.clone();
+	std::thread::spawn(move || {
+		scrobble_queue::run(db_scrobble_queue.deref());
+	});
+
 	// Send readiness notification
 	notify_ready();
 
@@ -0,0 +1,772 @@
+use ape;
+use error_chain::bail;
+use id3;
+use lewton::inside_ogg::OggStreamReader;
+use metaflac;
+use mp3_duration;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+use crate::errors::*;
+use crate::lyrics::{self, Line, Lyrics};
+use crate::utils;
+use crate::utils::AudioFormat;
+
+mod mp4;
+mod mpeg;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Field {
+	Title,
+	Artist,
+	Album,
+	Year,
+	TrackNumber,
+	DiscNumber,
+	Genre,
+}
+
+// Tag changes written back to files. Fields left out are not modified, empty strings
+// and zeroes remove the corresponding tag.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TagEdits {
+	pub title: Option<String>,
+	pub artist: Option<String>,
+	pub album: Option<String>,
+	pub year: Option<i32>,
+	pub track_number: Option<u32>,
+	pub disc_number: Option<u32>,
+	pub genre: Option<String>,
+}
+
+impl TagEdits {
+	// New value of each edited field, None when the field should be removed
+	fn changes(&self) -> Vec<(Field, Option<String>)> {
+		let text = |v: &Option<String>| {
+			v.as_ref()
+				.map(|v| Some(v.clone()).filter(|v| !v.is_empty()))
+		};
+		let number = |v: Option<i64>| v.map(|v| Some(v.to_string()).filter(|_| v != 0));
+		vec![
+			(Field::Title, text(&self.title)),
+			(Field::Artist, text(&self.artist)),
+			(Field::Album, text(&self.album)),
+			(Field::Year, number(self.year.map(i64::from))),
+			(Field::TrackNumber, number(self.track_number.map(i64::from))),
+			(Field::DiscNumber, number(self.disc_number.map(i64::from))),
+			(Field::Genre, text(&self.genre)),
+		]
+		.into_iter()
+		.filter_map(|(field, change)| change.map(|c| (field, c)))
+		.collect()
+	}
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SongTags {
+	pub disc_number: Option<u32>,
+	pub track_number: Option<u32>,
+	pub title: Option<String>,
+	pub duration: Option<u32>,
+	pub artist: Option<String>,
+	pub album_artist: Option<String>,
+	pub album: Option<String>,
+	pub title_sort: Option<String>,
+	pub artist_sort: Option<String>,
+	pub album_sort: Option<String>,
+	pub year: Option<i32>,
+	pub genre: Option<String>,
+	pub compilation: bool,
+	pub rating: Option<u32>,                // 1 to 5 stars
+	pub replaygain_track_gain: Option<f32>, // dB
+	pub replaygain_track_peak: Option<f32>,
+	pub replaygain_album_gain: Option<f32>, // dB
+	pub replaygain_album_peak: Option<f32>,
+	pub codec: Option<String>,
+	pub bitrate: Option<u32>, // kbps
+	pub sample_rate: Option<u32>,
+	pub bit_depth: Option<u32>,
+	pub channels: Option<u32>,
+	pub file_size: Option<u64>,
+	pub lyrics: Option<Lyrics>,
+}
+
+impl SongTags {
+	// Stores the value of a REPLAYGAIN_* tag, other keys are ignored
+	fn add_replaygain(&mut self, key: &str, value: &str) {
+		let field = match key.to_uppercase().as_str() {
+			"REPLAYGAIN_TRACK_GAIN" => &mut self.replaygain_track_gain,
+			"REPLAYGAIN_TRACK_PEAK" => &mut self.replaygain_track_peak,
+			"REPLAYGAIN_ALBUM_GAIN" => &mut self.replaygain_album_gain,
+			"REPLAYGAIN_ALBUM_PEAK" => &mut self.replaygain_album_peak,
+			_ => return,
+		};
+		*field = parse_replaygain(value);
+	}
+}
+
+// Compilation flags are written as "1" by most taggers
+fn parse_flag(value: &str) -> bool {
+	match value.trim() {
+		"1" => true,
+		v => v.eq_ignore_ascii_case("true"),
+	}
+}
+
+// Gains are written as "-6.48 dB", peaks as "0.988312"
+fn parse_replaygain(value: &str) -> Option<f32> {
+	let value = value.trim();
+	let number = if value.to_lowercase().ends_with("db") {
+		&value[..value.len() - 2]
+	} else {
+		value
+	};
+	number.trim().parse::<f32>().ok().filter(|v| v.is_finite())
+}
+
+pub fn read(path: &Path) -> Result<SongTags> {
+	let mut tags = match utils::get_audio_format(path) {
+		Some(AudioFormat::FLAC) => read_flac(path)?,
+		Some(AudioFormat::MP3) => read_id3(path)?,
+		Some(AudioFormat::MP4) => mp4::read(path)?,
+		Some(AudioFormat::MPC) => read_ape(path)?,
+		Some(AudioFormat::OGG) => read_vorbis(path)?,
+		_ => bail!("Unsupported file format for reading metadata"),
+	};
+
+	let file_size = fs::metadata(path)?.len();
+	tags.file_size = Some(file_size);
+	if tags.bitrate.is_none() {
+		tags.bitrate = tags
+			.duration
+			.filter(|d| *d > 0)
+			.map(|d| (file_size * 8 / u64::from(d) / 1000) as u32);
+	}
+
+	Ok(tags)
+}
+
+fn read_id3(path: &Path) -> Result<SongTags> {
+	let tag = id3::Tag::read_from_path(&path)?;
+	let duration = mp3_duration::from_path(&path)
+		.map(|d| d.as_secs() as u32)
+		.ok();
+
+	let artist = tag.artist().map(|s| s.to_string());
+	let album_artist = tag.album_artist().map(|s| s.to_string());
+	let album = tag.album().map(|s| s.to_string());
+	let title = tag.title().map(|s| s.to_string());
+	let genre = tag.genre().map(|s| s.to_string());
+	let disc_number = tag.disc();
+	let track_number = tag.track();
+	let year = tag
+		.year()
+		.map(|y| y as i32)
+		.or_else(|| tag.date_released().and_then(|d| Some(d.year)))
+		.or_else(|| tag.date_recorded().and_then(|d| Some(d.year)));
+	let rating = tag.get("POPM").and_then(|f| match f.content() {
+		id3::Content::Unknown(data) => read_popm_rating(data),
+		_ => None,
+	});
+	let read_text = |id| {
+		tag.get(id)
+			.and_then(|f| f.content().text())
+			.map(|s| s.to_string())
+	};
+	let compilation = read_text("TCMP").map_or(false, |c| parse_flag(&c));
+
+	let mut tags = SongTags {
+		artist,
+		album_artist,
+		album,
+		title,
+		duration,
+		disc_number,
+		track_number,
+		year,
+		title_sort: read_text("TSOT"),
+		artist_sort: read_text("TSOP"),
+		album_sort: read_text("TSOA"),
+		genre,
+		compilation,
+		rating,
+		codec: Some("MP3".into()),
+		..Default::default()
+	};
+
+	// Tags and artwork at the start of the file do not count towards the bitrate
+	if let Ok(header) = mpeg::read_frame_header(path) {
+		let audio_size = fs::metadata(path)?.len().saturating_sub(header.audio_start);
+		tags.bitrate = match duration {
+			Some(d) if d > 0 => Some((audio_size * 8 / u64::from(d) / 1000) as u32),
+			_ => Some(header.bitrate),
+		};
+		tags.sample_rate = Some(header.sample_rate);
+		tags.channels = Some(header.channels);
+	}
+
+	for frame in tag.frames().filter(|f| f.id() == "TXXX") {
+		if let id3::Content::ExtendedText(text) = frame.content() {
+			tags.add_replaygain(&text.key, &text.value);
+		}
+	}
+
+	// Synchronized lyrics are preferred over plain ones
+	tags.lyrics = tag
+		.get("SYLT")
+		.and_then(|f| match f.content() {
+			id3::Content::Unknown(data) => read_sylt(data),
+			_ => None,
+		})
+		.or_else(|| {
+			tag.get("USLT").and_then(|f| match f.content() {
+				id3::Content::Lyrics(l) => lyrics::parse_lrc(&l.text),
+				_ => None,
+			})
+		});
+
+	Ok(tags)
+}
+
+// POPM frames hold an email, a 0-255 rating and a play counter
+fn read_popm_rating(data: &[u8]) -> Option<u32> {
+	let email_end = data.iter().position(|b| *b == 0)?;
+	match *data.get(email_end + 1)? {
+		0 => None,
+		1..=31 => Some(1),
+		32..=95 => Some(2),
+		96..=159 => Some(3),
+		160..=223 => Some(4),
+		_ => Some(5),
+	}
+}
+
+// Reads a string in one of the ID3 text encodings, up to its null terminator
+fn read_id3_string(data: &[u8], encoding: u8) -> Option<(String, &[u8])> {
+	match encoding {
+		0 | 3 => {
+			let end = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+			let text = if encoding == 0 {
+				data[..end].iter().map(|b| char::from(*b)).collect()
+			} else {
+				String::from_utf8_lossy(&data[..end]).into_owned()
+			};
+			Some((text, data.get(end + 1..).unwrap_or(&[])))
+		}
+		1 | 2 => {
+			let end = data
+				.chunks(2)
+				.position(|c| c == [0, 0])
+				.map(|p| p * 2)
+				.unwrap_or(data.len() & !1);
+			let mut bytes = &data[..end];
+			let mut little_endian = false;
+			if encoding == 1 && bytes.len() >= 2 {
+				match (bytes[0], bytes[1]) {
+					(0xFF, 0xFE) => little_endian = true,
+					(0xFE, 0xFF) => (),
+					_ => return None,
+				}
+				bytes = &bytes[2..];
+			}
+			let units: Vec<u16> = bytes
+				.chunks_exact(2)
+				.map(|c| {
+					if little_endian {
+						u16::from_le_bytes([c[0], c[1]])
+					} else {
+						u16::from_be_bytes([c[0], c[1]])
+					}
+				})
+				.collect();
+			Some((
+				String::from_utf16_lossy(&units),
+				data.get(end + 2..).unwrap_or(&[]),
+			))
+		}
+		_ => None,
+	}
+}
+
+// SYLT frames hold an encoding, a language, a timestamp format, a content type and a
+// description, followed by pairs of null terminated text and big-endian u32 timestamp
+fn read_sylt(data: &[u8]) -> Option<Lyrics> {
+	let encoding = *data.get(0)?;
+	// Timestamps in MPEG frames are not supported, only milliseconds
+	if *data.get(4)? != 2 {
+		return None;
+	}
+	let (_, mut data) = read_id3_string(data.get(6..)?, encoding)?;
+	let mut lines = Vec::new();
+	while !data.is_empty() {
+		let (text, rest) = read_id3_string(data, encoding)?;
+		let time = rest.get(0..4)?;
+		lines.push(Line {
+			time: Some(u32::from_be_bytes([time[0], time[1], time[2], time[3]])),
+			text: text.trim_start_matches('\n').to_owned(),
+		});
+		data = &rest[4..];
+	}
+	Lyrics::new(lines)
+}
+
+// RATING comments are either in stars or a percentage
+fn read_vorbis_rating(value: &str) -> Option<u32> {
+	let rating = value.trim().parse::<u32>().ok()?;
+	match rating {
+		0 => None,
+		1..=5 => Some(rating),
+		_ => Some(((rating.min(100) + 10) / 20).max(1)),
+	}
+}
+
+fn read_ape_string(item: &ape::Item) -> Option<String> {
+	match item.value {
+		ape::ItemValue::Text(ref s) => Some(s.clone()),
+		_ => None,
+	}
+}
+
+fn read_ape_i32(item: &ape::Item) -> Option<i32> {
+	match item.value {
+		ape::ItemValue::Text(ref s) => s.parse::<i32>().ok(),
+		_ => None,
+	}
+}
+
+fn read_ape_x_of_y(item: &ape::Item) -> Option<u32> {
+	match item.value {
+		ape::ItemValue::Text(ref s) => {
+			let format = Regex::new(r#"^\d+"#).unwrap();
+			if let Some(m) = format.find(s) {
+				s[m.start()..m.end()].parse().ok()
+			} else {
+				None
+			}
+		}
+		_ => None,
+	}
+}
+
+fn read_ape(path: &Path) -> Result<SongTags> {
+	let tag = ape::read(path)?;
+	let artist = tag.item("Artist").and_then(read_ape_string);
+	let album = tag.item("Album").and_then(read_ape_string);
+	let album_artist = tag.item("Album artist").and_then(read_ape_string);
+	let title = tag.item("Title").and_then(read_ape_string);
+	let year = tag.item("Year").and_then(read_ape_i32);
+	let genre = tag.item("Genre").and_then(read_ape_string);
+	let compilation = tag
+		.item("Compilation")
+		.and_then(read_ape_string)
+		.map_or(false, |c| parse_flag(&c));
+	let disc_number = tag.item("Disc").and_then(read_ape_x_of_y);
+	let track_number = tag.item("Track").and_then(read_ape_x_of_y);
+	let mut tags = SongTags {
+		artist,
+		album_artist,
+		album,
+		title,
+		duration: None,
+		disc_number,
+		track_number,
+		year,
+		title_sort: tag.item("TitleSort").and_then(read_ape_string),
+		artist_sort: tag.item("ArtistSort").and_then(read_ape_string),
+		album_sort: tag.item("AlbumSort").and_then(read_ape_string),
+		genre,
+		compilation,
+		codec: Some("Musepack".into()),
+		..Default::default()
+	};
+
+	for key in &[
+		"REPLAYGAIN_TRACK_GAIN",
+		"REPLAYGAIN_TRACK_PEAK",
+		"REPLAYGAIN_ALBUM_GAIN",
+		"REPLAYGAIN_ALBUM_PEAK",
+	] {
+		if let Some(value) = tag.item(key).and_then(read_ape_string) {
+			tags.add_replaygain(key, &value);
+		}
+	}
+
+	tags.lyrics = tag
+		.item("Lyrics")
+		.and_then(read_ape_string)
+		.and_then(|l| lyrics::parse_lrc(&l));
+
+	Ok(tags)
+}
+
+fn read_vorbis(path: &Path) -> Result<SongTags> {
+	let file = fs::File::open(path)?;
+	let source = OggStreamReader::new(file)?;
+
+	let mut tags = SongTags {
+		codec: Some("Vorbis".into()),
+		sample_rate: Some(source.ident_hdr.audio_sample_rate),
+		channels: Some(u32::from(source.ident_hdr.audio_channels)),
+		bitrate: Some(source.ident_hdr.bitrate_nominal)
+			.filter(|b| *b > 0)
+			.map(|b| b as u32 / 1000),
+		..Default::default()
+	};
+
+	for (key, value) in source.comment_hdr.comment_list {
+		match key.as_str() {
+			"TITLE" => tags.title = Some(value),
+			"ALBUM" => tags.album = Some(value),
+			"ARTIST" => tags.artist = Some(value),
+			"ALBUMARTIST" => tags.album_artist = Some(value),
+			"TITLESORT" => tags.title_sort = Some(value),
+			"ARTISTSORT" => tags.artist_sort = Some(value),
+			"ALBUMSORT" => tags.album_sort = Some(value),
+			"TRACKNUMBER" => tags.track_number = value.parse::<u32>().ok(),
+			"DISCNUMBER" => tags.disc_number = value.parse::<u32>().ok(),
+			"DATE" => tags.year = value.parse::<i32>().ok(),
+			"GENRE" => tags.genre = Some(value),
+			"COMPILATION" => tags.compilation = parse_flag(&value),
+			"RATING" => tags.rating = read_vorbis_rating(&value),
+			"LYRICS" | "UNSYNCEDLYRICS" => tags.lyrics = lyrics::parse_lrc(&value),
+			_ => tags.add_replaygain(&key, &value),
+		}
+	}
+
+	Ok(tags)
+}
+
+fn read_flac(path: &Path) -> Result<SongTags> {
+	let tag = metaflac::Tag::read_from_path(path)?;
+	let vorbis = tag.vorbis_comments().ok_or("Missing Vorbis comments")?;
+	let disc_number = vorbis
+		.get("DISCNUMBER")
+		.and_then(|d| d[0].parse::<u32>().ok());
+	let year = vorbis.get("DATE").and_then(|d| d[0].parse::<i32>().ok());
+	let rating = vorbis.get("RATING").and_then(|r| read_vorbis_rating(&r[0]));
+	let compilation = vorbis
+		.get("COMPILATION")
+		.map_or(false, |c| parse_flag(&c[0]));
+	let streaminfo = tag.get_blocks(metaflac::BlockType::StreamInfo);
+	let streaminfo = match streaminfo.first() {
+		Some(&&metaflac::Block::StreamInfo(ref s)) => Some(s),
+		_ => None,
+	};
+	let duration = streaminfo.map(|s| (s.total_samples as u32 / s.sample_rate) as u32);
+	let bitrate = streaminfo.filter(|s| s.total_samples > 0).map(|s| {
+		let file_size = fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+		(file_size * 8 * u64::from(s.sample_rate) / s.total_samples / 1000) as u32
+	});
+
+	let mut tags = SongTags {
+		artist: vorbis.artist().map(|v| v[0].clone()),
+		album_artist: vorbis.album_artist().map(|v| v[0].clone()),
+		album: vorbis.album().map(|v| v[0].clone()),
+		title: vorbis.title().map(|v| v[0].clone()),
+		duration,
+		disc_number,
+		track_number: vorbis.track(),
+		year,
+		genre: vorbis.genre().map(|v| v[0].clone()),
+		compilation,
+		rating,
+		codec: Some("FLAC".into()),
+		bitrate,
+		sample_rate: streaminfo.map(|s| s.sample_rate),
+		bit_depth: streaminfo.map(|s| u32::from(s.bits_per_sample)),
+		channels: streaminfo.map(|s| u32::from(s.num_channels)),
+		..Default::default()
+	};
+
+	for (key, values) in &vorbis.comments {
+		if let Some(value) = values.first() {
+			match key.as_str() {
+				"TITLESORT" => tags.title_sort = Some(value.clone()),
+				"ARTISTSORT" => tags.artist_sort = Some(value.clone()),
+				"ALBUMSORT" => tags.album_sort = Some(value.clone()),
+				"LYRICS" | "UNSYNCEDLYRICS" => tags.lyrics = lyrics::parse_lrc(value),
+				_ => tags.add_replaygain(key, value),
+			}
+		}
+	}
+
+	Ok(tags)
+}
+
+// MP4 and Ogg tags cannot be written without rewriting the whole file
+pub fn is_writable(path: &Path) -> bool {
+	match utils::get_audio_format(path) {
+		Some(AudioFormat::FLAC) | Some(AudioFormat::MP3) | Some(AudioFormat::MPC) => true,
+		_ => false,
+	}
+}
+
+pub fn write(path: &Path, edits: &TagEdits) -> Result<()> {
+	match utils::get_audio_format(path) {
+		Some(AudioFormat::FLAC) => write_flac(path, edits),
+		Some(AudioFormat::MP3) => write_id3(path, edits),
+		Some(AudioFormat::MPC) => write_ape(path, edits),
+		_ => bail!(ErrorKind::UnsupportedTagWriting(
+			path.to_string_lossy().into_owned()
+		)),
+	}
+}
+
+fn write_id3(path: &Path, edits: &TagEdits) -> Result<()> {
+	// Tags which fail to parse are left alone rather than replaced by an empty one
+	let mut tag = match id3::Tag::read_from_path(&path) {
+		Ok(tag) => tag,
+		Err(id3::Error {
+			kind: id3::ErrorKind::NoTag,
+			..
+		}) => id3::Tag::new(),
+		Err(e) => return Err(e.into()),
+	};
+	for (field, value) in edits.changes() {
+		let id = match field {
+			Field::Title => "TIT2",
+			Field::Artist => "TPE1",
+			Field::Album => "TALB",
+			Field::Year => {
+				tag.remove("TYER");
+				"TDRC"
+			}
+			Field::TrackNumber => "TRCK",
+			Field::DiscNumber => "TPOS",
+			Field::Genre => "TCON",
+		};
+		tag.remove(id);
+		if let Some(value) = value {
+			tag.add_frame(id3::Frame::with_content(id, id3::Content::Text(value)));
+		}
+	}
+	// ID3v2.2 tags can be read but not written
+	let version = match tag.version() {
+		id3::Version::Id3v22 => id3::Version::Id3v23,
+		v => v,
+	};
+	tag.write_to_path(&path, version)?;
+	Ok(())
+}
+
+fn write_ape(path: &Path, edits: &TagEdits) -> Result<()> {
+	let mut tag = match ape::read(path) {
+		Ok(tag) => tag,
+		Err(ape::Error::TagNotFound) => ape::Tag::new(),
+		Err(e) => return Err(e.into()),
+	};
+	for (field, value) in edits.changes() {
+		let key = match field {
+			Field::Title => "Title",
+			Field::Artist => "Artist",
+			Field::Album => "Album",
+			Field::Year => "Year",
+			Field::TrackNumber => "Track",
+			Field::DiscNumber => "Disc",
+			Field::Genre => "Genre",
+		};
+		tag.remove_item(key);
+		if let Some(value) = value {
+			tag.set_item(ape::Item::from_text(key, value)?);
+		}
+	}
+	ape::write(&tag, path)?;
+	Ok(())
+}
+
+fn write_flac(path: &Path, edits: &TagEdits) -> Result<()> {
+	let mut tag = metaflac::Tag::read_from_path(path)?;
+	let vorbis = tag.vorbis_comments_mut();
+	for (field, value) in edits.changes() {
+		let key = match field {
+			Field::Title => "TITLE",
+			Field::Artist => "ARTIST",
+			Field::Album => "ALBUM",
+			Field::Year => "DATE",
+			Field::TrackNumber => "TRACKNUMBER",
+			Field::DiscNumber => "DISCNUMBER",
+			Field::Genre => "GENRE",
+		};
+		match value {
+			Some(value) => vorbis.set(key, vec![value]),
+			None => vorbis.remove(key),
+		}
+	}
+	tag.save()?;
+	Ok(())
+}
+
+#[test]
+fn test_read_metadata() {
+	let sample_tags = SongTags {
+		disc_number: Some(3),
+		track_number: Some(1),
+		title: Some("TEST TITLE".into()),
+		artist: Some("TEST ARTIST".into()),
+		album_artist: Some("TEST ALBUM ARTIST".into()),
+		album: Some("TEST ALBUM".into()),
+		duration: None,
+		year: Some(2016),
+		sample_rate: Some(44100),
+		channels: Some(1),
+		..Default::default()
+	};
+	let flac_sample_tag = SongTags {
+		duration: Some(0),
+		codec: Some("FLAC".into()),
+		bitrate: Some(684),
+		bit_depth: Some(16),
+		file_size: Some(39532),
+		..sample_tags.clone()
+	};
+	let mp3_sample_tag = SongTags {
+		duration: Some(0),
+		codec: Some("MP3".into()),
+		bitrate: Some(320),
+		file_size: Some(24142),
+		..sample_tags.clone()
+	};
+	let ogg_sample_tag = SongTags {
+		codec: Some("Vorbis".into()),
+		bitrate: Some(239),
+		file_size: Some(15760),
+		..sample_tags.clone()
+	};
+	assert_eq!(read(Path::new("test/sample.mp3")).unwrap(), mp3_sample_tag);
+	assert_eq!(read(Path::new("test/sample.ogg")).unwrap(), ogg_sample_tag);
+	assert_eq!(
+		read(Path::new("test/sample.flac")).unwrap(),
+		flac_sample_tag
+	);
+}
+
+#[test]
+fn test_read_ratings() {
+	assert_eq!(
+		read_popm_rating(b"someone@example.com\0\xff\0\0\0\x01"),
+		Some(5)
+	);
+	assert_eq!(read_popm_rating(b"\0\x01"), Some(1));
+	assert_eq!(read_popm_rating(b"\0\x80"), Some(3));
+	assert_eq!(read_popm_rating(b"\0\0"), None);
+	assert_eq!(read_popm_rating(b"no rating"), None);
+	assert_eq!(read_vorbis_rating("4"), Some(4));
+	assert_eq!(read_vorbis_rating("60"), Some(3));
+	assert_eq!(read_vorbis_rating("100"), Some(5));
+	assert_eq!(read_vorbis_rating("0"), None);
+	assert_eq!(read_vorbis_rating("great"), None);
+}
+
+#[test]
+fn test_parse_replaygain() {
+	assert_eq!(parse_replaygain("-6.48 dB"), Some(-6.48));
+	assert_eq!(parse_replaygain("+2.10 DB"), Some(2.1));
+	assert_eq!(parse_replaygain("0.988312"), Some(0.988312));
+	assert_eq!(parse_replaygain("loud"), None);
+
+	let mut tags = read(Path::new("test/sample.ogg")).unwrap();
+	tags.add_replaygain("replaygain_album_gain", "-3.5 dB");
+	tags.add_replaygain("REPLAYGAIN_REFERENCE_LOUDNESS", "89.0 dB");
+	assert_eq!(tags.replaygain_album_gain, Some(-3.5));
+	assert_eq!(tags.replaygain_track_gain, None);
+}
+
+#[test]
+fn test_read_sylt() {
+	let mut data = vec![3, b'e', b'n', b'g', 2, 1, 0];
+	data.extend_from_slice(b"First line\0");
+	data.extend_from_slice(&1500u32.to_be_bytes());
+	data.extend_from_slice(b"\nSecond line\0");
+	data.extend_from_slice(&500u32.to_be_bytes());
+	let lyrics = read_sylt(&data).unwrap();
+	assert!(lyrics.synchronized);
+	assert_eq!(
+		lyrics.lines,
+		vec![
+			Line {
+				time: Some(500),
+				text: "Second line".into()
+			},
+			Line {
+				time: Some(1500),
+				text: "First line".into()
+			},
+		]
+	);
+
+	// UTF-16 with byte order marks, then timestamps in MPEG frames
+	let mut data = vec![1, b'e', b'n', b'g', 2, 1, 0xFF, 0xFE, 0, 0];
+	data.extend_from_slice(&[0xFF, 0xFE, b'H', 0, b'i', 0, 0, 0]);
+	data.extend_from_slice(&100u32.to_be_bytes());
+	assert_eq!(read_sylt(&data).unwrap().lines[0].text, "Hi");
+	data[4] = 1;
+	assert_eq!(read_sylt(&data), None);
+}
+
+#[test]
+fn test_write_metadata() {
+	let mut copy_path = utils::get_data_root().unwrap();
+	copy_path.push("test_write_metadata.mp3");
+	fs::copy("test/sample.mp3", &copy_path).unwrap();
+
+	let edits = TagEdits {
+		title: Some("NEW TITLE".into()),
+		album: Some("".into()),
+		year: Some(2019),
+		genre: Some("Doom Metal".into()),
+		..Default::default()
+	};
+	write(&copy_path, &edits).unwrap();
+	let tags = read(&copy_path).unwrap();
+	assert_eq!(tags.title, Some("NEW TITLE".into()));
+	assert_eq!(tags.artist, Some("TEST ARTIST".into()));
+	assert_eq!(tags.album, None);
+	assert_eq!(tags.year, Some(2019));
+	assert_eq!(tags.genre, Some("Doom Metal".into()));
+	fs::remove_file(&copy_path).unwrap();
+
+	assert!(is_writable(Path::new("test/sample.flac")));
+	assert!(!is_writable(Path::new("test/sample.ogg")));
+	assert!(write(Path::new("test/sample.ogg"), &edits).is_err());
+}
+
+#[test]
+fn test_write_metadata_without_tags() {
+	let mut path = utils::get_data_root().unwrap();
+	path.push("test_write_metadata_without_tags.mp3");
+	fs::write(&path, vec![0; 100]).unwrap();
+
+	let edits = TagEdits {
+		title: Some("NEW TITLE".into()),
+		..Default::default()
+	};
+	write(&path, &edits).unwrap();
+	let tag = id3::Tag::read_from_path(&path).unwrap();
+	assert_eq!(tag.title(), Some("NEW TITLE"));
+	fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_write_metadata_keeps_unreadable_tags() {
+	let mut path = utils::get_data_root().unwrap();
+	path.push("test_write_metadata_keeps_unreadable_tags.mpc");
+
+	// APE footer announcing a tag larger than the whole file
+	let mut content = vec![0; 100];
+	content.extend_from_slice(b"APETAGEX");
+	content.extend_from_slice(&2000u32.to_le_bytes());
+	content.extend_from_slice(&100_000u32.to_le_bytes());
+	content.extend_from_slice(&1u32.to_le_bytes());
+	content.extend_from_slice(&[0; 12]);
+	fs::write(&path, &content).unwrap();
+
+	let edits = TagEdits {
+		title: Some("NEW TITLE".into()),
+		..Default::default()
+	};
+	assert!(write(&path, &edits).is_err());
+	assert_eq!(fs::read(&path).unwrap(), content);
+	fs::remove_file(&path).unwrap();
+}
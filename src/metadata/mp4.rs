@@ -0,0 +1,331 @@
+use error_chain::bail;
+use std::convert::TryInto;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use super::SongTags;
+use crate::errors::*;
+use crate::lyrics;
+
+// Atoms are laid out as a big-endian u32 size, a four character type and a payload.
+// Metadata lives under moov/udta/meta/ilst, each tag holding its value in a data atom.
+struct Atom<'a> {
+	kind: &'a [u8],
+	payload: &'a [u8],
+}
+
+fn read_u16(data: &[u8]) -> Option<u16> {
+	data.get(0..2)
+		.map(|b| u16::from_be_bytes(b.try_into().unwrap()))
+}
+
+fn read_u32(data: &[u8]) -> Option<u32> {
+	data.get(0..4)
+		.map(|b| u32::from_be_bytes(b.try_into().unwrap()))
+}
+
+fn read_u64(data: &[u8]) -> Option<u64> {
+	data.get(0..8)
+		.map(|b| u64::from_be_bytes(b.try_into().unwrap()))
+}
+
+fn read_atoms(mut data: &[u8]) -> Vec<Atom<'_>> {
+	let mut atoms = Vec::new();
+	while data.len() >= 8 {
+		let (header_size, size) = match read_u32(data).unwrap() {
+			0 => (8, data.len() as u64),
+			1 => match read_u64(&data[8..]) {
+				Some(s) => (16, s),
+				None => break,
+			},
+			s => (8, s as u64),
+		};
+		if size < header_size as u64 || size > data.len() as u64 {
+			break;
+		}
+		let size = size as usize;
+		atoms.push(Atom {
+			kind: &data[4..8],
+			payload: &data[header_size..size],
+		});
+		data = &data[size..];
+	}
+	atoms
+}
+
+fn find_atom<'a>(data: &'a [u8], kind: &[u8]) -> Option<&'a [u8]> {
+	read_atoms(data)
+		.into_iter()
+		.find(|a| a.kind == kind)
+		.map(|a| a.payload)
+}
+
+// Data atoms start with a type indicator and a locale before the actual value
+fn read_data(item: &[u8]) -> Option<&[u8]> {
+	find_atom(item, b"data").and_then(|d| d.get(8..))
+}
+
+fn read_string(item: &[u8]) -> Option<String> {
+	read_data(item).map(|d| String::from_utf8_lossy(d).into_owned())
+}
+
+// Track and disc numbers are stored as reserved u16, number u16, total u16
+fn read_number_pair(item: &[u8]) -> Option<u32> {
+	read_data(item)
+		.and_then(|d| d.get(2..4))
+		.map(|n| u32::from(u16::from_be_bytes(n.try_into().unwrap())))
+		.filter(|n| *n > 0)
+}
+
+// Freeform atoms hold a mean (eg. com.apple.iTunes), a name and a data atom.
+// mean and name are full atoms, with four bytes of version and flags.
+fn read_freeform(item: &[u8]) -> Option<(String, String)> {
+	let name = find_atom(item, b"name").and_then(|n| n.get(4..))?;
+	let value = read_string(item)?;
+	Some((String::from_utf8_lossy(name).into_owned(), value))
+}
+
+fn read_duration(mvhd: &[u8]) -> Option<u32> {
+	let (time_scale, duration) = match mvhd.get(0)? {
+		0 => (
+			read_u32(mvhd.get(12..)?)?,
+			read_u32(mvhd.get(16..)?)? as u64,
+		),
+		1 => (read_u32(mvhd.get(20..)?)?, read_u64(mvhd.get(24..)?)?),
+		_ => return None,
+	};
+	if time_scale == 0 {
+		return None;
+	}
+	Some((duration / time_scale as u64) as u32)
+}
+
+// Audio sample entries (moov/trak/mdia/minf/stbl/stsd) start with 16 bytes of reserved
+// fields and data reference index, followed by channel count, sample size and sample rate
+fn read_sample_description(moov: &[u8], tags: &mut SongTags) {
+	let stsd = read_atoms(moov)
+		.into_iter()
+		.filter(|a| a.kind == b"trak")
+		.filter_map(|trak| {
+			find_atom(trak.payload, b"mdia")
+				.and_then(|mdia| find_atom(mdia, b"minf"))
+				.and_then(|minf| find_atom(minf, b"stbl"))
+				.and_then(|stbl| find_atom(stbl, b"stsd"))
+		})
+		.next();
+	let entry = match stsd
+		.and_then(|s| s.get(8..))
+		.and_then(|s| read_atoms(s).into_iter().next())
+	{
+		Some(e) => e,
+		None => return,
+	};
+
+	tags.codec = match entry.kind {
+		b"mp4a" => Some("AAC".into()),
+		b"alac" => Some("ALAC".into()),
+		_ => return,
+	};
+	tags.channels = read_u16(entry.payload.get(16..).unwrap_or(&[])).map(u32::from);
+	if entry.kind == b"alac" {
+		tags.bit_depth = read_u16(entry.payload.get(18..).unwrap_or(&[])).map(u32::from);
+	}
+	// Sample rate is a 16.16 fixed point number
+	tags.sample_rate = read_u32(entry.payload.get(24..).unwrap_or(&[])).map(|r| r >> 16);
+}
+
+fn read_moov(moov: &[u8]) -> SongTags {
+	let mut tags = SongTags::default();
+
+	tags.duration = find_atom(moov, b"mvhd").and_then(read_duration);
+	read_sample_description(moov, &mut tags);
+
+	let ilst = find_atom(moov, b"udta")
+		.and_then(|udta| find_atom(udta, b"meta"))
+		.and_then(|meta| meta.get(4..))
+		.and_then(|meta| find_atom(meta, b"ilst"));
+	let ilst = match ilst {
+		Some(i) => i,
+		None => return tags,
+	};
+
+	for item in read_atoms(ilst) {
+		match item.kind {
+			b"\xa9nam" => tags.title = read_string(item.payload),
+			b"\xa9ART" => tags.artist = read_string(item.payload),
+			b"aART" => tags.album_artist = read_string(item.payload),
+			b"\xa9alb" => tags.album = read_string(item.payload),
+			b"sonm" => tags.title_sort = read_string(item.payload),
+			b"soar" => tags.artist_sort = read_string(item.payload),
+			b"soal" => tags.album_sort = read_string(item.payload),
+			b"\xa9gen" => tags.genre = read_string(item.payload),
+			b"\xa9day" => {
+				tags.year = read_string(item.payload)
+					.and_then(|d| d.get(0..4).and_then(|y| y.parse::<i32>().ok()))
+			}
+			b"trkn" => tags.track_number = read_number_pair(item.payload),
+			b"disk" => tags.disc_number = read_number_pair(item.payload),
+			b"cpil" => tags.compilation = read_data(item.payload).map_or(false, |d| d == [1]),
+			b"\xa9lyr" => {
+				tags.lyrics = read_string(item.payload).and_then(|l| lyrics::parse_lrc(&l))
+			}
+			b"----" => {
+				if let Some((name, value)) = read_freeform(item.payload) {
+					tags.add_replaygain(&name, &value);
+				}
+			}
+			_ => (),
+		}
+	}
+
+	tags
+}
+
+pub fn read(path: &Path) -> Result<SongTags> {
+	let mut file = fs::File::open(path)?;
+	let file_size = file.metadata()?.len();
+	read_from(&mut file, file_size)
+}
+
+fn read_from<R: Read + Seek>(file: &mut R, file_size: u64) -> Result<SongTags> {
+	let mut position: u64 = 0;
+	while position + 8 <= file_size {
+		let mut header = [0; 16];
+		file.seek(SeekFrom::Start(position))?;
+		file.read_exact(&mut header[..8])?;
+		let (header_size, size) = match read_u32(&header).unwrap() {
+			0 => (8, file_size - position),
+			1 => {
+				file.read_exact(&mut header[8..])?;
+				(16, read_u64(&header[8..]).unwrap())
+			}
+			s => (8, s as u64),
+		};
+		if size < header_size {
+			break;
+		}
+		// Sizes come from the file itself and must not be trusted for allocations
+		if size > file_size - position {
+			bail!("MP4 atom exceeds the file size");
+		}
+		if &header[4..8] == b"moov" {
+			let mut moov = vec![0; (size - header_size) as usize];
+			file.read_exact(&mut moov)?;
+			return Ok(read_moov(&moov));
+		}
+		position = match position.checked_add(size) {
+			Some(p) => p,
+			None => break,
+		};
+	}
+	bail!("Missing MP4 metadata")
+}
+
+#[cfg(test)]
+fn atom(kind: &[u8], payload: &[u8]) -> Vec<u8> {
+	let mut atom = ((payload.len() + 8) as u32).to_be_bytes().to_vec();
+	atom.extend_from_slice(kind);
+	atom.extend_from_slice(payload);
+	atom
+}
+
+#[test]
+fn test_read_moov() {
+	let data = |value: &[u8]| {
+		let mut payload = vec![0, 0, 0, 1, 0, 0, 0, 0];
+		payload.extend_from_slice(value);
+		atom(b"data", &payload)
+	};
+	let freeform = |name: &str, value: &str| {
+		let mut payload = atom(b"mean", b"\0\0\0\0com.apple.iTunes");
+		payload.extend(atom(b"name", &[b"\0\0\0\0", name.as_bytes()].concat()));
+		payload.extend(data(value.as_bytes()));
+		atom(b"----", &payload)
+	};
+
+	let mut ilst = Vec::new();
+	ilst.extend(atom(b"\xa9nam", &data(b"TEST TITLE")));
+	ilst.extend(atom(b"\xa9ART", &data(b"TEST ARTIST")));
+	ilst.extend(atom(b"aART", &data(b"TEST ALBUM ARTIST")));
+	ilst.extend(atom(b"\xa9alb", &data(b"TEST ALBUM")));
+	ilst.extend(atom(b"soar", &data(b"ARTIST, TEST")));
+	ilst.extend(atom(b"\xa9gen", &data(b"Doom Metal")));
+	ilst.extend(atom(b"\xa9day", &data(b"2016-05-01")));
+	ilst.extend(atom(b"trkn", &data(&[0, 0, 0, 1, 0, 10, 0, 0])));
+	ilst.extend(atom(b"disk", &data(&[0, 0, 0, 3, 0, 3])));
+	ilst.extend(atom(b"cpil", &data(&[1])));
+	ilst.extend(atom(b"\xa9lyr", &data(b"First line\nSecond line")));
+	ilst.extend(freeform("replaygain_track_gain", "-6.48 dB"));
+	ilst.extend(freeform("replaygain_track_peak", "0.988312"));
+
+	let mut meta = vec![0, 0, 0, 0];
+	meta.extend(atom(b"hdlr", &[0; 25]));
+	meta.extend(atom(b"ilst", &ilst));
+
+	let mut mvhd = vec![0; 100];
+	mvhd[12..16].copy_from_slice(&1000u32.to_be_bytes());
+	mvhd[16..20].copy_from_slice(&215_000u32.to_be_bytes());
+
+	let mut mp4a = vec![0; 28];
+	mp4a[16..18].copy_from_slice(&2u16.to_be_bytes());
+	mp4a[18..20].copy_from_slice(&16u16.to_be_bytes());
+	mp4a[24..28].copy_from_slice(&(44100u32 << 16).to_be_bytes());
+	let mut stsd = vec![0, 0, 0, 0, 0, 0, 0, 1];
+	stsd.extend(atom(b"mp4a", &mp4a));
+	let stbl = atom(b"stbl", &atom(b"stsd", &stsd));
+	let trak = atom(b"trak", &atom(b"mdia", &atom(b"minf", &stbl)));
+
+	let mut moov = atom(b"mvhd", &mvhd);
+	moov.extend(trak);
+	moov.extend(atom(b"udta", &atom(b"meta", &meta)));
+
+	let tags = read_moov(&moov);
+	assert_eq!(tags.title, Some("TEST TITLE".into()));
+	assert_eq!(tags.artist, Some("TEST ARTIST".into()));
+	assert_eq!(tags.album_artist, Some("TEST ALBUM ARTIST".into()));
+	assert_eq!(tags.album, Some("TEST ALBUM".into()));
+	assert_eq!(tags.artist_sort, Some("ARTIST, TEST".into()));
+	assert_eq!(tags.year, Some(2016));
+	assert_eq!(tags.genre, Some("Doom Metal".into()));
+	assert_eq!(tags.track_number, Some(1));
+	assert_eq!(tags.disc_number, Some(3));
+	assert!(tags.compilation);
+	assert_eq!(tags.duration, Some(215));
+	assert_eq!(tags.replaygain_track_gain, Some(-6.48));
+	assert_eq!(tags.replaygain_track_peak, Some(0.988312));
+	assert_eq!(tags.replaygain_album_gain, None);
+	assert_eq!(tags.codec, Some("AAC".into()));
+	assert_eq!(tags.channels, Some(2));
+	assert_eq!(tags.sample_rate, Some(44100));
+	assert_eq!(tags.bit_depth, None);
+	assert_eq!(tags.lyrics.unwrap().lines.len(), 2);
+}
+
+#[test]
+fn test_read_invalid_atom_sizes() {
+	use std::io::Cursor;
+
+	let ftyp = atom(b"ftyp", b"M4A \0\0\0\0");
+	let moov = atom(b"moov", &atom(b"mvhd", &[0; 100]));
+
+	// Truncated moov
+	let mut file = ftyp.clone();
+	file.extend_from_slice(&moov[..moov.len() - 10]);
+	let size = file.len() as u64;
+	assert!(read_from(&mut Cursor::new(file), size).is_err());
+
+	// Oversized 64-bit moov
+	let mut file = ftyp.clone();
+	file.extend_from_slice(&1u32.to_be_bytes());
+	file.extend_from_slice(b"moov");
+	file.extend_from_slice(&u64::max_value().to_be_bytes());
+	file.extend_from_slice(&[0; 32]);
+	let size = file.len() as u64;
+	assert!(read_from(&mut Cursor::new(file), size).is_err());
+
+	let mut file = ftyp;
+	file.extend_from_slice(&moov);
+	let size = file.len() as u64;
+	assert!(read_from(&mut Cursor::new(file), size).is_ok());
+}
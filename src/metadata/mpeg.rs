@@ -0,0 +1,121 @@
+use error_chain::bail;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::errors::*;
+
+const MPEG_HEADER_SEARCH_LENGTH: u64 = 64 * 1024; // Bytes scanned for a frame after the ID3 tag
+
+// Kbps for MPEG-1 Layer III, then MPEG-2/2.5 Layer III
+const BITRATES: [[u32; 15]; 2] = [
+	[
+		0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+	],
+	[0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+];
+const SAMPLE_RATES: [u32; 3] = [44100, 48000, 32000];
+
+#[derive(Debug, PartialEq)]
+pub struct FrameHeader {
+	pub bitrate: u32,
+	pub sample_rate: u32,
+	pub channels: u32,
+	pub audio_start: u64,
+}
+
+struct Frame {
+	bitrate: u32,
+	sample_rate: u32,
+	channels: u32,
+	length: usize,
+}
+
+fn parse_frame(header: &[u8]) -> Option<Frame> {
+	if header.len() < 4 || header[0] != 0xFF || header[1] & 0xE0 != 0xE0 {
+		return None;
+	}
+	let version = (header[1] >> 3) & 0b11; // 0: MPEG-2.5, 2: MPEG-2, 3: MPEG-1
+	let layer = (header[1] >> 1) & 0b11; // 1: Layer III
+	let bitrate_index = (header[2] >> 4) as usize;
+	let sample_rate_index = ((header[2] >> 2) & 0b11) as usize;
+	let padding = ((header[2] >> 1) & 1) as usize;
+	if version == 1
+		|| layer != 1
+		|| bitrate_index == 0
+		|| bitrate_index == 15
+		|| sample_rate_index == 3
+	{
+		return None;
+	}
+
+	let mpeg1 = version == 3;
+	let bitrate = BITRATES[if mpeg1 { 0 } else { 1 }][bitrate_index];
+	let sample_rate = match version {
+		3 => SAMPLE_RATES[sample_rate_index],
+		2 => SAMPLE_RATES[sample_rate_index] / 2,
+		_ => SAMPLE_RATES[sample_rate_index] / 4,
+	};
+	let samples_per_frame = if mpeg1 { 1152 } else { 576 };
+	let length = (samples_per_frame / 8 * bitrate * 1000 / sample_rate) as usize + padding;
+	let channels = if header[3] >> 6 == 0b11 { 1 } else { 2 };
+	Some(Frame {
+		bitrate,
+		sample_rate,
+		channels,
+		length,
+	})
+}
+
+// Reads the first MPEG audio frame header following the ID3v2 tag, if any
+pub fn read_frame_header(path: &Path) -> Result<FrameHeader> {
+	let mut file = fs::File::open(path)?;
+	let mut id3_header = [0; 10];
+	file.read_exact(&mut id3_header)?;
+
+	let mut audio_start = 0;
+	if &id3_header[0..3] == b"ID3" {
+		let size = id3_header[6..10]
+			.iter()
+			.fold(0u64, |size, b| (size << 7) | u64::from(b & 0x7F));
+		let footer = if id3_header[5] & 0x10 != 0 { 10 } else { 0 };
+		audio_start = 10 + size + footer;
+	}
+
+	let mut data = Vec::new();
+	file.seek(SeekFrom::Start(audio_start))?;
+	file.take(MPEG_HEADER_SEARCH_LENGTH)
+		.read_to_end(&mut data)?;
+
+	// Random data can look like a frame header, so the next frame must be valid too
+	for offset in 0..data.len() {
+		if let Some(frame) = parse_frame(&data[offset..]) {
+			let next_offset = offset + frame.length;
+			if next_offset < data.len() && parse_frame(&data[next_offset..]).is_none() {
+				continue;
+			}
+			return Ok(FrameHeader {
+				bitrate: frame.bitrate,
+				sample_rate: frame.sample_rate,
+				channels: frame.channels,
+				audio_start: audio_start + offset as u64,
+			});
+		}
+	}
+	bail!("Missing MPEG frame header")
+}
+
+#[test]
+fn test_read_frame_header() {
+	let header = read_frame_header(Path::new("test/sample.mp3")).unwrap();
+	assert_eq!(
+		header,
+		FrameHeader {
+			bitrate: 320,
+			sample_rate: 44100,
+			channels: 1,
+			audio_start: 2200,
+		}
+	);
+	assert!(read_frame_header(Path::new("test/sample.flac")).is_err());
+}
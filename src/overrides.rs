@@ -0,0 +1,320 @@
+use core::ops::Deref;
+use diesel;
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+
+#[cfg(test)]
+use crate::db;
+use crate::db::metadata_overrides;
+use crate::db::ConnectionSource;
+use crate::errors::*;
+use crate::index::{CollectionFile, Directory, Song};
+use crate::metadata::SongTags;
+use crate::vfs::VFSSource;
+
+// Values replacing what is read from the tags of a song, or of all songs within a
+// directory. Fields left empty are read from the files as usual.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct MetadataOverride {
+	pub path: String,
+	pub title: Option<String>,
+	pub artist: Option<String>,
+	pub album_artist: Option<String>,
+	pub album: Option<String>,
+	pub year: Option<i32>,
+	pub track_number: Option<i32>,
+	pub disc_number: Option<i32>,
+	pub genre: Option<String>,
+}
+
+#[derive(Insertable, Queryable)]
+#[table_name = "metadata_overrides"]
+struct OverrideRow {
+	path: String,
+	title: Option<String>,
+	artist: Option<String>,
+	album_artist: Option<String>,
+	album: Option<String>,
+	year: Option<i32>,
+	track_number: Option<i32>,
+	disc_number: Option<i32>,
+	genre: Option<String>,
+}
+
+fn merge<T: Clone>(value: &mut Option<T>, replacement: &Option<T>) {
+	if replacement.is_some() {
+		*value = replacement.clone();
+	}
+}
+
+// Sort names read from the tags no longer match a replaced value
+fn clear_sort_name(sort_name: &mut Option<String>, replacement: &Option<String>) {
+	if replacement.is_some() {
+		*sort_name = None;
+	}
+}
+
+// All overrides, by real path
+pub struct Overrides {
+	rows: HashMap<String, OverrideRow>,
+}
+
+impl Overrides {
+	pub fn load(connection: &SqliteConnection) -> Result<Overrides> {
+		let rows: Vec<OverrideRow> = metadata_overrides::table
+			.select((
+				metadata_overrides::path,
+				metadata_overrides::title,
+				metadata_overrides::artist,
+				metadata_overrides::album_artist,
+				metadata_overrides::album,
+				metadata_overrides::year,
+				metadata_overrides::track_number,
+				metadata_overrides::disc_number,
+				metadata_overrides::genre,
+			))
+			.load(connection)?;
+		Ok(Overrides {
+			rows: rows.into_iter().map(|r| (r.path.clone(), r)).collect(),
+		})
+	}
+
+	// Overrides of a directory apply to everything below it, closest ones first
+	fn find(&self, real_path: &Path) -> Vec<&OverrideRow> {
+		real_path
+			.ancestors()
+			.filter_map(|p| p.to_str().and_then(|p| self.rows.get(p)))
+			.collect()
+	}
+
+	pub fn apply_to_tags(&self, real_path: &Path, tags: &mut SongTags) {
+		let to_u32 = |n: &Option<i32>| n.map(|n| n as u32);
+		for row in self.find(real_path).into_iter().rev() {
+			// Titles and track numbers only make sense for individual songs
+			if Path::new(&row.path) == real_path {
+				merge(&mut tags.title, &row.title);
+				merge(&mut tags.track_number, &to_u32(&row.track_number));
+				clear_sort_name(&mut tags.title_sort, &row.title);
+			}
+			merge(&mut tags.artist, &row.artist);
+			merge(&mut tags.album_artist, &row.album_artist);
+			merge(&mut tags.album, &row.album);
+			clear_sort_name(&mut tags.artist_sort, &row.artist);
+			clear_sort_name(&mut tags.album_sort, &row.album);
+			merge(&mut tags.year, &row.year);
+			merge(&mut tags.disc_number, &to_u32(&row.disc_number));
+			merge(&mut tags.genre, &row.genre);
+		}
+	}
+
+	fn apply_to_song(&self, real_path: &Path, song: &mut Song) {
+		for row in self.find(real_path).into_iter().rev() {
+			if Path::new(&row.path) == real_path {
+				merge(&mut song.title, &row.title);
+				merge(&mut song.track_number, &row.track_number);
+			}
+			merge(&mut song.artist, &row.artist);
+			merge(&mut song.album_artist, &row.album_artist);
+			merge(&mut song.album, &row.album);
+			merge(&mut song.year, &row.year);
+			merge(&mut song.disc_number, &row.disc_number);
+			merge(&mut song.genre, &row.genre);
+		}
+	}
+
+	pub fn apply_to_directory(
+		&self,
+		real_path: &Path,
+		artist: &mut Option<String>,
+		album: &mut Option<String>,
+		year: &mut Option<i32>,
+	) {
+		for row in self.find(real_path).into_iter().rev() {
+			merge(artist, &row.artist);
+			merge(artist, &row.album_artist);
+			merge(album, &row.album);
+			merge(year, &row.year);
+		}
+	}
+}
+
+pub fn list<T>(db: &T) -> Result<Vec<MetadataOverride>>
+where
+	T: ConnectionSource + VFSSource,
+{
+	let vfs = db.get_vfs()?;
+	let connection = db.get_connection();
+	let mut rows: Vec<OverrideRow> = Overrides::load(connection.deref())?
+		.rows
+		.into_iter()
+		.map(|(_, r)| r)
+		.collect();
+	rows.sort_by(|a, b| a.path.cmp(&b.path));
+
+	// Overrides outside of the current mount points are left out
+	let overrides = rows
+		.into_iter()
+		.filter_map(|r| {
+			let path = vfs.real_to_virtual(Path::new(&r.path)).ok()?;
+			Some(MetadataOverride {
+				path: path.to_string_lossy().into_owned(),
+				title: r.title,
+				artist: r.artist,
+				album_artist: r.album_artist,
+				album: r.album,
+				year: r.year,
+				track_number: r.track_number,
+				disc_number: r.disc_number,
+				genre: r.genre,
+			})
+		})
+		.collect();
+	Ok(overrides)
+}
+
+pub fn set<T>(db: &T, metadata_override: &MetadataOverride) -> Result<()>
+where
+	T: ConnectionSource + VFSSource,
+{
+	let vfs = db.get_vfs()?;
+	let real_path = vfs.virtual_to_real(Path::new(&metadata_override.path))?;
+	let row = OverrideRow {
+		path: real_path.to_string_lossy().into_owned(),
+		title: metadata_override.title.clone(),
+		artist: metadata_override.artist.clone(),
+		album_artist: metadata_override.album_artist.clone(),
+		album: metadata_override.album.clone(),
+		year: metadata_override.year,
+		track_number: metadata_override.track_number,
+		disc_number: metadata_override.disc_number,
+		genre: metadata_override.genre.clone(),
+	};
+	let connection = db.get_connection();
+	diesel::insert_into(metadata_overrides::table)
+		.values(&row)
+		.execute(connection.deref())?;
+	Ok(())
+}
+
+pub fn delete<T>(db: &T, virtual_path: &Path) -> Result<()>
+where
+	T: ConnectionSource + VFSSource,
+{
+	let vfs = db.get_vfs()?;
+	let real_path = vfs.virtual_to_real(virtual_path)?;
+	let connection = db.get_connection();
+	diesel::delete(
+		metadata_overrides::table.filter(metadata_overrides::path.eq(real_path.to_string_lossy())),
+	)
+	.execute(connection.deref())?;
+	Ok(())
+}
+
+// Overrides are merged by the indexer, this covers changes made since the last reindex
+pub fn annotate_songs<'a, T, I>(db: &T, songs: I) -> Result<()>
+where
+	T: ConnectionSource + VFSSource,
+	I: IntoIterator<Item = &'a mut Song>,
+{
+	let vfs = db.get_vfs()?;
+	let overrides = Overrides::load(db.get_connection().deref())?;
+	if overrides.rows.is_empty() {
+		return Ok(());
+	}
+	for song in songs {
+		if let Ok(real_path) = vfs.virtual_to_real(Path::new(&song.path)) {
+			overrides.apply_to_song(&real_path, song);
+		}
+	}
+	Ok(())
+}
+
+pub fn annotate_directories<'a, T, I>(db: &T, directories: I) -> Result<()>
+where
+	T: ConnectionSource + VFSSource,
+	I: IntoIterator<Item = &'a mut Directory>,
+{
+	let vfs = db.get_vfs()?;
+	let overrides = Overrides::load(db.get_connection().deref())?;
+	if overrides.rows.is_empty() {
+		return Ok(());
+	}
+	for directory in directories {
+		if let Ok(real_path) = vfs.virtual_to_real(Path::new(&directory.path)) {
+			overrides.apply_to_directory(
+				&real_path,
+				&mut directory.artist,
+				&mut directory.album,
+				&mut directory.year,
+			);
+		}
+	}
+	Ok(())
+}
+
+pub fn annotate_collection_files<T>(db: &T, files: &mut [CollectionFile]) -> Result<()>
+where
+	T: ConnectionSource + VFSSource,
+{
+	let mut songs = Vec::new();
+	let mut directories = Vec::new();
+	for file in files.iter_mut() {
+		match file {
+			CollectionFile::Song(s) => songs.push(s),
+			CollectionFile::Directory(d) => directories.push(d),
+		}
+	}
+	annotate_songs(db, songs)?;
+	annotate_directories(db, directories)
+}
+
+#[test]
+fn test_overrides() {
+	use crate::index;
+
+	let db = db::_get_test_db("overrides.sqlite");
+	index::update(&db).unwrap();
+
+	let directory_override = MetadataOverride {
+		path: "root/Khemmis".into(),
+		artist: Some("Khemmis (US)".into()),
+		genre: Some("Doom Metal".into()),
+		title: Some("Ignored".into()),
+		..Default::default()
+	};
+	let song_override = MetadataOverride {
+		path: "root/Khemmis/Hunted/02 - Candlelight.mp3".into(),
+		title: Some("Candlelight (Remastered)".into()),
+		genre: Some("Heavy Metal".into()),
+		..Default::default()
+	};
+	set(&db, &directory_override).unwrap();
+	set(&db, &song_override).unwrap();
+	assert_eq!(
+		list(&db).unwrap(),
+		vec![directory_override.clone(), song_override.clone()]
+	);
+
+	// Merged over responses right away
+	let mut songs = index::flatten(&db, Path::new("root/Khemmis")).unwrap();
+	annotate_songs(&db, songs.iter_mut()).unwrap();
+	let candlelight = songs.iter().find(|s| s.path == song_override.path).unwrap();
+	assert_eq!(candlelight.title, song_override.title);
+	assert_eq!(candlelight.genre, song_override.genre);
+	assert_eq!(candlelight.artist, directory_override.artist);
+	let other_song = songs.iter().find(|s| s.path != song_override.path).unwrap();
+	assert_ne!(other_song.title, directory_override.title);
+	assert_eq!(other_song.genre, directory_override.genre);
+
+	// And stored in the index when it is rebuilt
+	index::update(&db).unwrap();
+	let candlelight = index::get_song(&db, Path::new(&song_override.path)).unwrap();
+	assert_eq!(candlelight.title, song_override.title);
+	assert_eq!(candlelight.artist, directory_override.artist);
+
+	delete(&db, Path::new(&song_override.path)).unwrap();
+	assert_eq!(list(&db).unwrap(), vec![directory_override]);
+}
@@ -2,18 +2,29 @@ use core::clone::Clone;
 use core::ops::Deref;
 use diesel;
 use diesel::prelude::*;
-use diesel::sql_types;
+use diesel::sqlite::SqliteConnection;
 use diesel::BelongingToDsl;
+use error_chain::bail;
+use log::info;
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
 use std::path::Path;
 
 #[cfg(test)]
 use crate::db;
 use crate::db::ConnectionSource;
-use crate::db::{playlist_songs, playlists, users};
+use crate::db::{playlist_shares, playlist_songs, playlists, songs, users};
 use crate::errors::*;
 use crate::index::{self, Song};
+use crate::share_links;
 use crate::vfs::VFSSource;
 
+const PLAYLIST_LOOKUP_BUFFER_SIZE: usize = 500; // Songs looked up in each query
+const CONTENT_HASH_LENGTH: u64 = 64 * 1024; // Bytes read to identify a file
+
 #[derive(Insertable)]
 #[table_name = "playlists"]
 struct NewPlaylist {
@@ -46,6 +57,121 @@ pub struct NewPlaylistSong {
 	playlist: i32,
 	path: String,
 	ordering: i32,
+	track_number: Option<i32>,
+	disc_number: Option<i32>,
+	title: Option<String>,
+	artist: Option<String>,
+	album: Option<String>,
+	duration: Option<i32>,
+	file_size: Option<i64>,
+	content_hash: Option<String>,
+}
+
+#[derive(Queryable)]
+struct PlaylistEntry {
+	id: i32,
+	path: String,
+	ordering: i32,
+	track_number: Option<i32>,
+	disc_number: Option<i32>,
+	title: Option<String>,
+	artist: Option<String>,
+	album: Option<String>,
+	duration: Option<i32>,
+	file_size: Option<i64>,
+	content_hash: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MissingSong {
+	pub position: i32,
+	pub path: Option<String>,
+	pub track_number: Option<i32>,
+	pub disc_number: Option<i32>,
+	pub title: Option<String>,
+	pub artist: Option<String>,
+	pub album: Option<String>,
+	pub duration: Option<i32>,
+}
+
+#[derive(Insertable)]
+#[table_name = "playlist_shares"]
+struct NewPlaylistShare {
+	playlist: i32,
+	user: Option<i32>,
+	collaborative: i32,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PlaylistShare {
+	pub user: Option<String>, // None means shared with everyone
+	pub collaborative: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SharedPlaylist {
+	pub name: String,
+	pub owner: String,
+	pub collaborative: bool,
+}
+
+fn find_user(connection: &SqliteConnection, username: &str) -> Result<User> {
+	use self::users::dsl::*;
+	let user = users
+		.filter(name.eq(username))
+		.select((id,))
+		.get_result(connection)?;
+	Ok(user)
+}
+
+fn find_playlist(
+	connection: &SqliteConnection,
+	playlist_name: &str,
+	owner: &User,
+) -> Result<Playlist> {
+	let playlist = playlists::table
+		.select((playlists::id, playlists::owner))
+		.filter(
+			playlists::name
+				.eq(playlist_name)
+				.and(playlists::owner.eq(owner.id)),
+		)
+		.get_result(connection)
+		.optional()?
+		.ok_or(ErrorKind::PlaylistNotFound)?;
+	Ok(playlist)
+}
+
+fn check_access(
+	connection: &SqliteConnection,
+	playlist: &Playlist,
+	user: &User,
+	edit: bool,
+) -> Result<()> {
+	if playlist.owner == user.id {
+		return Ok(());
+	}
+
+	let grants: Vec<i32> = playlist_shares::table
+		.filter(playlist_shares::playlist.eq(playlist.id))
+		.filter(
+			playlist_shares::user
+				.eq(user.id)
+				.or(playlist_shares::user.is_null()),
+		)
+		.select(playlist_shares::collaborative)
+		.load(connection)?;
+
+	let allowed = if edit {
+		grants.iter().any(|c| *c != 0)
+	} else {
+		!grants.is_empty()
+	};
+
+	if !allowed {
+		bail!(ErrorKind::PlaylistAccessDenied);
+	}
+	Ok(())
 }
 
 pub fn list_playlists<T>(owner: &str, db: &T) -> Result<Vec<String>>
@@ -72,12 +198,55 @@ where
 	}
 }
 
-pub fn save_playlist<T>(playlist_name: &str, owner: &str, content: &[String], db: &T) -> Result<()>
+pub fn list_shared_playlists<T>(username: &str, db: &T) -> Result<Vec<SharedPlaylist>>
+where
+	T: ConnectionSource + VFSSource,
+{
+	let connection = db.get_connection();
+	let user = find_user(connection.deref(), username)?;
+
+	let grants: Vec<(String, String, i32)> = playlist_shares::table
+		.inner_join(playlists::table.inner_join(users::table))
+		.filter(
+			playlist_shares::user
+				.eq(user.id)
+				.or(playlist_shares::user.is_null()),
+		)
+		.filter(playlists::owner.ne(user.id))
+		.order((users::name, playlists::name))
+		.select((playlists::name, users::name, playlist_shares::collaborative))
+		.load(connection.deref())?;
+
+	// A playlist can be shared with everyone and with this user specifically
+	let mut shared_playlists: Vec<SharedPlaylist> = Vec::new();
+	for (name, owner, collaborative) in grants {
+		if let Some(existing) = shared_playlists
+			.iter_mut()
+			.find(|p| p.name == name && p.owner == owner)
+		{
+			existing.collaborative |= collaborative != 0;
+			continue;
+		}
+		shared_playlists.push(SharedPlaylist {
+			name,
+			owner,
+			collaborative: collaborative != 0,
+		});
+	}
+
+	Ok(shared_playlists)
+}
+
+pub fn save_playlist<T>(
+	playlist_name: &str,
+	owner: &str,
+	editor: &str,
+	content: &[String],
+	db: &T,
+) -> Result<()>
 where
 	T: ConnectionSource + VFSSource,
 {
-	let user: User;
-	let new_playlist: NewPlaylist;
 	let playlist: Playlist;
 	let vfs = db.get_vfs()?;
 
@@ -85,45 +254,87 @@ where
 		let connection = db.get_connection();
 
 		// Find owner
-		{
-			use self::users::dsl::*;
-			user = users
-				.filter(name.eq(owner))
-				.select((id,))
-				.get_result(connection.deref())?;
-		}
-
-		// Create playlist
-		new_playlist = NewPlaylist {
-			name: playlist_name.into(),
-			owner: user.id,
+		let owner_user = find_user(connection.deref(), owner)?;
+		let editor_user = find_user(connection.deref(), editor)?;
+
+		// Find or create playlist
+		playlist = match find_playlist(connection.deref(), playlist_name, &owner_user) {
+			Ok(p) => {
+				check_access(connection.deref(), &p, &editor_user, true)?;
+				p
+			}
+			Err(Error(ErrorKind::PlaylistNotFound, _)) => {
+				if owner_user.id != editor_user.id {
+					bail!(ErrorKind::PlaylistAccessDenied);
+				}
+				let new_playlist = NewPlaylist {
+					name: playlist_name.into(),
+					owner: owner_user.id,
+				};
+				diesel::insert_into(playlists::table)
+					.values(&new_playlist)
+					.execute(connection.deref())?;
+				find_playlist(connection.deref(), playlist_name, &owner_user)?
+			}
+			Err(e) => return Err(e),
 		};
+	}
 
-		diesel::insert_into(playlists::table)
-			.values(&new_playlist)
-			.execute(connection.deref())?;
+	let real_paths: Vec<Option<String>> = content
+		.iter()
+		.map(|path| {
+			vfs.virtual_to_real(Path::new(&path))
+				.ok()
+				.and_then(|p| p.to_str().map(|s| s.to_owned()))
+		})
+		.collect();
 
-		{
-			use self::playlists::dsl::*;
-			playlist = playlists
-				.select((id, owner))
-				.filter(name.eq(playlist_name).and(owner.eq(user.id)))
-				.get_result(connection.deref())?;
+	// Remember what each song looked like so it can be found again if it moves
+	let mut indexed_songs: HashMap<String, Song> = HashMap::new();
+	let previous_hashes: HashMap<String, String>;
+	{
+		let connection = db.get_connection();
+		let previous_entries: Vec<(String, Option<String>)> = playlist_songs::table
+			.filter(playlist_songs::playlist.eq(playlist.id))
+			.select((playlist_songs::path, playlist_songs::content_hash))
+			.load(connection.deref())?;
+		previous_hashes = previous_entries
+			.into_iter()
+			.filter_map(|(path, hash)| hash.map(|h| (path, h)))
+			.collect();
+		let known_paths: Vec<&String> = real_paths.iter().filter_map(|p| p.as_ref()).collect();
+		for chunk in known_paths[..].chunks(PLAYLIST_LOOKUP_BUFFER_SIZE) {
+			let chunk_songs: Vec<Song> = songs::table
+				.filter(songs::path.eq_any(chunk))
+				.load(connection.deref())?;
+			for song in chunk_songs {
+				indexed_songs.insert(song.path.clone(), song);
+			}
 		}
 	}
 
 	let mut new_songs: Vec<NewPlaylistSong> = Vec::new();
 	new_songs.reserve(content.len());
 
-	for (i, path) in content.iter().enumerate() {
-		let virtual_path = Path::new(&path);
-		if let Some(real_path) = vfs
-			.virtual_to_real(virtual_path)
-			.ok()
-			.and_then(|p| p.to_str().map(|s| s.to_owned()))
-		{
+	for (i, real_path) in real_paths.into_iter().enumerate() {
+		if let Some(real_path) = real_path {
+			let song = indexed_songs.get(&real_path);
+			let content_hash = song.filter(|s| s.cue_source.is_none()).and_then(|_| {
+				previous_hashes
+					.get(&real_path)
+					.cloned()
+					.or_else(|| get_content_hash(Path::new(&real_path)))
+			});
 			new_songs.push(NewPlaylistSong {
 				playlist: playlist.id,
+				track_number: song.and_then(|s| s.track_number),
+				disc_number: song.and_then(|s| s.disc_number),
+				title: song.and_then(|s| s.title.clone()),
+				artist: song.and_then(|s| s.artist.clone()),
+				album: song.and_then(|s| s.album.clone()),
+				duration: song.and_then(|s| s.duration),
+				file_size: song.and_then(|s| s.file_size),
+				content_hash,
 				path: real_path,
 				ordering: i as i32,
 			});
@@ -150,7 +361,7 @@ where
 	Ok(())
 }
 
-pub fn read_playlist<T>(playlist_name: &str, owner: &str, db: &T) -> Result<Vec<Song>>
+pub fn read_playlist<T>(playlist_name: &str, owner: &str, reader: &str, db: &T) -> Result<Vec<Song>>
 where
 	T: ConnectionSource + VFSSource,
 {
@@ -159,37 +370,20 @@ where
 
 	{
 		let connection = db.get_connection();
-		let user: User;
-		let playlist: Playlist;
-
-		// Find owner
-		{
-			use self::users::dsl::*;
-			user = users
-				.filter(name.eq(owner))
-				.select((id,))
-				.get_result(connection.deref())?;
-		}
 
 		// Find playlist
-		{
-			use self::playlists::dsl::*;
-			playlist = playlists
-				.select((id, owner))
-				.filter(name.eq(playlist_name).and(owner.eq(user.id)))
-				.get_result(connection.deref())?;
-		}
-
-		// Select songs. Not using Diesel because we need to LEFT JOIN using a custom column
-		let query = diesel::sql_query(r#"
-			SELECT s.id, s.path, s.parent, s.track_number, s.disc_number, s.title, s.artist, s.album_artist, s.year, s.album, s.artwork, s.duration
-			FROM playlist_songs ps
-			LEFT JOIN songs s ON ps.path = s.path
-			WHERE ps.playlist = ?
-			ORDER BY ps.ordering
-		"#);
-		let query = query.clone().bind::<sql_types::Integer, _>(playlist.id);
-		songs = query.get_results(connection.deref())?;
+		let owner_user = find_user(connection.deref(), owner)?;
+		let reader_user = find_user(connection.deref(), reader)?;
+		let playlist = find_playlist(connection.deref(), playlist_name, &owner_user)?;
+		check_access(connection.deref(), &playlist, &reader_user, false)?;
+
+		// Select songs
+		songs = playlist_songs::table
+			.inner_join(songs::table.on(songs::path.eq(playlist_songs::path)))
+			.filter(playlist_songs::playlist.eq(playlist.id))
+			.order(playlist_songs::ordering)
+			.select(songs::all_columns)
+			.load(connection.deref())?;
 	}
 
 	// Map real path to virtual paths
@@ -201,27 +395,275 @@ where
 	Ok(virtual_songs)
 }
 
-pub fn delete_playlist<T>(playlist_name: &str, owner: &str, db: &T) -> Result<()>
+fn load_orphans(
+	connection: &SqliteConnection,
+	playlist: Option<&Playlist>,
+) -> Result<Vec<PlaylistEntry>> {
+	let mut query = playlist_songs::table
+		.filter(playlist_songs::path.ne_all(songs::table.select(songs::path)))
+		.select((
+			playlist_songs::id,
+			playlist_songs::path,
+			playlist_songs::ordering,
+			playlist_songs::track_number,
+			playlist_songs::disc_number,
+			playlist_songs::title,
+			playlist_songs::artist,
+			playlist_songs::album,
+			playlist_songs::duration,
+			playlist_songs::file_size,
+			playlist_songs::content_hash,
+		))
+		.order(playlist_songs::ordering)
+		.into_boxed();
+	if let Some(playlist) = playlist {
+		query = query.filter(playlist_songs::playlist.eq(playlist.id));
+	}
+	Ok(query.load(connection)?)
+}
+
+// Identifies the content of a file regardless of where it lives
+fn get_content_hash(real_path: &Path) -> Option<String> {
+	let file = File::open(real_path).ok()?;
+	let length = file.metadata().ok()?.len();
+	let mut head = Vec::new();
+	file.take(CONTENT_HASH_LENGTH).read_to_end(&mut head).ok()?;
+	let mut context = digest::Context::new(&digest::SHA256);
+	context.update(&length.to_le_bytes());
+	context.update(&head);
+	Some(hex::encode(context.finish()))
+}
+
+// The connection is only held for each lookup, so that relinking a large number
+// of songs does not hold up other requests
+fn find_moved_song<T>(db: &T, entry: &PlaylistEntry) -> Result<Option<String>>
+where
+	T: ConnectionSource,
+{
+	// Match on tags first
+	if entry.title.is_some() {
+		let candidates: Vec<Song> = songs::table
+			.filter(songs::title.eq(&entry.title))
+			.load(db.get_connection().deref())?;
+		let candidates: Vec<Song> = candidates
+			.into_iter()
+			.filter(|s| {
+				s.artist == entry.artist
+					&& s.album == entry.album
+					&& s.track_number == entry.track_number
+					&& s.disc_number == entry.disc_number
+			})
+			.collect();
+		if let Some(path) = pick_unique_candidate(candidates, entry) {
+			return Ok(Some(path));
+		}
+	}
+
+	// Fall back to the file name
+	let file_name = match Path::new(&entry.path).file_name().and_then(|n| n.to_str()) {
+		Some(n) => n.to_owned(),
+		None => return Ok(None),
+	};
+	let candidates: Vec<Song> = songs::table
+		.filter(songs::path.like(format!("%{}", file_name)))
+		.load(db.get_connection().deref())?;
+	let candidates: Vec<Song> = candidates
+		.into_iter()
+		.filter(|s| Path::new(&s.path).file_name().and_then(|n| n.to_str()) == Some(&file_name))
+		.collect();
+	Ok(pick_unique_candidate(candidates, entry))
+}
+
+fn pick_unique_candidate(candidates: Vec<Song>, entry: &PlaylistEntry) -> Option<String> {
+	let mut candidates = candidates;
+	if candidates.len() > 1 && entry.duration.is_some() {
+		candidates.retain(|s| s.duration == entry.duration);
+	}
+	if entry.file_size.is_some() {
+		candidates.retain(|s| s.file_size.is_none() || s.file_size == entry.file_size);
+	}
+	// Songs with identical tags are told apart by their content. Candidates which
+	// cannot be read are given the benefit of the doubt.
+	if let Some(ref content_hash) = entry.content_hash {
+		candidates.retain(|s| match get_content_hash(Path::new(&s.path)) {
+			Some(hash) => &hash == content_hash,
+			None => true,
+		});
+	}
+	if candidates.len() == 1 {
+		candidates.into_iter().next().map(|s| s.path)
+	} else {
+		None
+	}
+}
+
+pub fn relink<T>(db: &T) -> Result<()>
+where
+	T: ConnectionSource,
+{
+	let orphans = load_orphans(db.get_connection().deref(), None)?;
+	if orphans.is_empty() {
+		return Ok(());
+	}
+
+	let mut relinked = 0;
+	for orphan in &orphans {
+		if let Some(new_path) = find_moved_song(db, orphan)? {
+			diesel::update(playlist_songs::table.find(orphan.id))
+				.set(playlist_songs::path.eq(new_path))
+				.execute(db.get_connection().deref())?;
+			relinked += 1;
+		}
+	}
+
+	info!(
+		"Relinked {} of {} missing playlist songs",
+		relinked,
+		orphans.len()
+	);
+	Ok(())
+}
+
+pub fn read_missing_songs<T>(
+	playlist_name: &str,
+	owner: &str,
+	reader: &str,
+	db: &T,
+) -> Result<Vec<MissingSong>>
 where
 	T: ConnectionSource + VFSSource,
 {
+	let vfs = db.get_vfs()?;
 	let connection = db.get_connection();
 
-	let user: User;
-	{
-		use self::users::dsl::*;
-		user = users
-			.filter(name.eq(owner))
-			.select((id,))
-			.first(connection.deref())?;
+	let owner_user = find_user(connection.deref(), owner)?;
+	let reader_user = find_user(connection.deref(), reader)?;
+	let playlist = find_playlist(connection.deref(), playlist_name, &owner_user)?;
+	check_access(connection.deref(), &playlist, &reader_user, false)?;
+
+	let orphans = load_orphans(connection.deref(), Some(&playlist))?;
+	let missing_songs = orphans
+		.into_iter()
+		.map(|e| MissingSong {
+			position: e.ordering,
+			path: vfs
+				.real_to_virtual(Path::new(&e.path))
+				.ok()
+				.map(|p| p.to_string_lossy().into_owned()),
+			track_number: e.track_number,
+			disc_number: e.disc_number,
+			title: e.title,
+			artist: e.artist,
+			album: e.album,
+			duration: e.duration,
+		})
+		.collect();
+
+	Ok(missing_songs)
+}
+
+pub fn delete_playlist<T>(playlist_name: &str, owner: &str, editor: &str, db: &T) -> Result<()>
+where
+	T: ConnectionSource + VFSSource,
+{
+	let connection = db.get_connection();
+
+	let owner_user = find_user(connection.deref(), owner)?;
+	let editor_user = find_user(connection.deref(), editor)?;
+	if owner_user.id != editor_user.id {
+		bail!(ErrorKind::PlaylistAccessDenied);
 	}
 
-	{
-		use self::playlists::dsl::*;
-		let q = Playlist::belonging_to(&user).filter(name.eq(playlist_name));
-		diesel::delete(q).execute(connection.deref())?;
+	let playlist = find_playlist(connection.deref(), playlist_name, &owner_user)?;
+	connection.deref().transaction::<_, Error, _>(|| {
+		diesel::delete(PlaylistSong::belonging_to(&playlist)).execute(connection.deref())?;
+		diesel::delete(playlist_shares::table.filter(playlist_shares::playlist.eq(playlist.id)))
+			.execute(connection.deref())?;
+		diesel::delete(&playlist).execute(connection.deref())?;
+		share_links::delete_playlist_links(connection.deref(), owner_user.id, playlist_name)?;
+		Ok(())
+	})?;
+
+	Ok(())
+}
+
+pub fn read_shares<T>(playlist_name: &str, owner: &str, db: &T) -> Result<Vec<PlaylistShare>>
+where
+	T: ConnectionSource + VFSSource,
+{
+	let connection = db.get_connection();
+	let owner_user = find_user(connection.deref(), owner)?;
+	let playlist = find_playlist(connection.deref(), playlist_name, &owner_user)?;
+
+	let grants: Vec<(Option<i32>, i32)> = playlist_shares::table
+		.filter(playlist_shares::playlist.eq(playlist.id))
+		.select((playlist_shares::user, playlist_shares::collaborative))
+		.load(connection.deref())?;
+
+	let mut shares = Vec::new();
+	for (user_id, collaborative) in grants {
+		let user = match user_id {
+			Some(user_id) => Some(
+				users::table
+					.filter(users::id.eq(user_id))
+					.select(users::name)
+					.get_result(connection.deref())?,
+			),
+			None => None,
+		};
+		shares.push(PlaylistShare {
+			user,
+			collaborative: collaborative != 0,
+		});
+	}
+
+	Ok(shares)
+}
+
+pub fn save_shares<T>(
+	playlist_name: &str,
+	owner: &str,
+	shares: &[PlaylistShare],
+	db: &T,
+) -> Result<()>
+where
+	T: ConnectionSource + VFSSource,
+{
+	let connection = db.get_connection();
+	let owner_user = find_user(connection.deref(), owner)?;
+	let playlist = find_playlist(connection.deref(), playlist_name, &owner_user)?;
+
+	let mut new_shares: Vec<NewPlaylistShare> = Vec::new();
+	for share in shares {
+		let user_id = match share.user {
+			Some(ref username) => Some(find_user(connection.deref(), username)?.id),
+			None => None,
+		};
+		if user_id == Some(owner_user.id) {
+			continue;
+		}
+		// Later grants to the same user (or to everyone) replace earlier ones
+		new_shares.retain(|s| s.user != user_id);
+		new_shares.push(NewPlaylistShare {
+			playlist: playlist.id,
+			user: user_id,
+			collaborative: share.collaborative as i32,
+		});
 	}
 
+	connection
+		.deref()
+		.transaction::<_, diesel::result::Error, _>(|| {
+			diesel::delete(
+				playlist_shares::table.filter(playlist_shares::playlist.eq(playlist.id)),
+			)
+			.execute(connection.deref())?;
+			diesel::insert_into(playlist_shares::table)
+				.values(&new_shares)
+				.execute(connection.deref())?;
+			Ok(())
+		})?;
+
 	Ok(())
 }
 
@@ -232,7 +674,7 @@ fn test_create_playlist() {
 	let found_playlists = list_playlists("test_user", &db).unwrap();
 	assert!(found_playlists.is_empty());
 
-	save_playlist("chill_and_grill", "test_user", &Vec::new(), &db).unwrap();
+	save_playlist("chill_and_grill", "test_user", "test_user", &Vec::new(), &db).unwrap();
 	let found_playlists = list_playlists("test_user", &db).unwrap();
 	assert_eq!(found_playlists.len(), 1);
 	assert_eq!(found_playlists[0], "chill_and_grill");
@@ -246,18 +688,23 @@ fn test_delete_playlist() {
 	let db = db::_get_test_db("delete_playlist.sqlite");
 	let playlist_content = Vec::new();
 
-	save_playlist("chill_and_grill", "test_user", &playlist_content, &db).unwrap();
-	save_playlist("mellow_bungalow", "test_user", &playlist_content, &db).unwrap();
+	save_playlist("chill_and_grill", "test_user", "test_user", &playlist_content, &db).unwrap();
+	save_playlist("mellow_bungalow", "test_user", "test_user", &playlist_content, &db).unwrap();
 	let found_playlists = list_playlists("test_user", &db).unwrap();
 	assert_eq!(found_playlists.len(), 2);
 
-	delete_playlist("chill_and_grill", "test_user", &db).unwrap();
+	delete_playlist("chill_and_grill", "test_user", "test_user", &db).unwrap();
 	let found_playlists = list_playlists("test_user", &db).unwrap();
 	assert_eq!(found_playlists.len(), 1);
 	assert_eq!(found_playlists[0], "mellow_bungalow");
 
-	let delete_result = delete_playlist("mellow_bungalow", "someone_else", &db);
+	let delete_result = delete_playlist("mellow_bungalow", "someone_else", "someone_else", &db);
 	assert!(delete_result.is_err());
+
+	match delete_playlist("chill_and_grill", "test_user", "test_user", &db) {
+		Err(Error(ErrorKind::PlaylistNotFound, _)) => (),
+		_ => panic!("Expected a missing playlist"),
+	}
 }
 
 #[test]
@@ -278,9 +725,9 @@ fn test_fill_playlist() {
 	playlist_content.push(first_song);
 	assert_eq!(playlist_content.len(), 13);
 
-	save_playlist("all_the_music", "test_user", &playlist_content, &db).unwrap();
+	save_playlist("all_the_music", "test_user", "test_user", &playlist_content, &db).unwrap();
 
-	let songs = read_playlist("all_the_music", "test_user", &db).unwrap();
+	let songs = read_playlist("all_the_music", "test_user", "test_user", &db).unwrap();
 	assert_eq!(songs.len(), 13);
 	assert_eq!(songs[0].title, Some("Above The Water".to_owned()));
 	assert_eq!(songs[12].title, Some("Above The Water".to_owned()));
@@ -294,7 +741,164 @@ fn test_fill_playlist() {
 	assert_eq!(songs[0].path, first_song_path.to_str().unwrap());
 
 	// Save again to verify that we don't dupe the content
-	save_playlist("all_the_music", "test_user", &playlist_content, &db).unwrap();
-	let songs = read_playlist("all_the_music", "test_user", &db).unwrap();
+	save_playlist("all_the_music", "test_user", "test_user", &playlist_content, &db).unwrap();
+	let songs = read_playlist("all_the_music", "test_user", "test_user", &db).unwrap();
 	assert_eq!(songs.len(), 13);
 }
+
+#[test]
+fn test_share_playlist() {
+	use crate::user;
+
+	let db = db::_get_test_db("share_playlist.sqlite");
+	{
+		let connection = db.get_connection();
+		diesel::insert_into(users::table)
+			.values(&user::User::new("other_user", "other_password"))
+			.execute(connection.deref())
+			.unwrap();
+	}
+
+	save_playlist("chill_and_grill", "test_user", "test_user", &Vec::new(), &db).unwrap();
+	assert!(list_shared_playlists("other_user", &db).unwrap().is_empty());
+	assert!(read_playlist("chill_and_grill", "test_user", "other_user", &db).is_err());
+
+	// Read-only share
+	let shares = vec![PlaylistShare {
+		user: Some("other_user".into()),
+		collaborative: false,
+	}];
+	save_shares("chill_and_grill", "test_user", &shares, &db).unwrap();
+	assert_eq!(read_shares("chill_and_grill", "test_user", &db).unwrap(), shares);
+	assert_eq!(
+		list_shared_playlists("other_user", &db).unwrap(),
+		vec![SharedPlaylist {
+			name: "chill_and_grill".into(),
+			owner: "test_user".into(),
+			collaborative: false,
+		}]
+	);
+	assert!(list_shared_playlists("test_user", &db).unwrap().is_empty());
+	assert!(read_playlist("chill_and_grill", "test_user", "other_user", &db).is_ok());
+	assert!(save_playlist("chill_and_grill", "test_user", "other_user", &Vec::new(), &db).is_err());
+
+	// Collaborative share with everyone
+	let shares = vec![PlaylistShare {
+		user: None,
+		collaborative: true,
+	}];
+	save_shares("chill_and_grill", "test_user", &shares, &db).unwrap();
+	{
+		// Only one grant to everyone may exist for a playlist
+		let connection = db.get_connection();
+		let playlist_id: i32 = playlists::table
+			.filter(playlists::name.eq("chill_and_grill"))
+			.select(playlists::id)
+			.get_result(connection.deref())
+			.unwrap();
+		let duplicate = NewPlaylistShare {
+			playlist: playlist_id,
+			user: None,
+			collaborative: 0,
+		};
+		assert!(diesel::insert_into(playlist_shares::table)
+			.values(&duplicate)
+			.execute(connection.deref())
+			.is_err());
+	}
+	assert_eq!(read_shares("chill_and_grill", "test_user", &db).unwrap(), shares);
+	assert!(save_playlist("chill_and_grill", "test_user", "other_user", &Vec::new(), &db).is_ok());
+	assert!(delete_playlist("chill_and_grill", "test_user", "other_user", &db).is_err());
+	assert!(save_playlist("new_playlist", "test_user", "other_user", &Vec::new(), &db).is_err());
+
+	// Saving keeps shares intact
+	save_playlist("chill_and_grill", "test_user", "test_user", &Vec::new(), &db).unwrap();
+	assert_eq!(list_shared_playlists("other_user", &db).unwrap().len(), 1);
+
+	delete_playlist("chill_and_grill", "test_user", "test_user", &db).unwrap();
+	assert!(list_shared_playlists("other_user", &db).unwrap().is_empty());
+}
+
+#[test]
+fn test_relink_playlist() {
+	use crate::index;
+
+	let db = db::_get_test_db("relink_playlist.sqlite");
+	index::update(&db).unwrap();
+
+	let playlist_content: Vec<String> = index::flatten(&db, Path::new("root"))
+		.unwrap()
+		.into_iter()
+		.take(4)
+		.map(|s| s.path)
+		.collect();
+	save_playlist(
+		"chill_and_grill",
+		"test_user",
+		"test_user",
+		&playlist_content,
+		&db,
+	)
+	.unwrap();
+	assert!(
+		read_missing_songs("chill_and_grill", "test_user", "test_user", &db)
+			.unwrap()
+			.is_empty()
+	);
+
+	let vfs = db.get_vfs().unwrap();
+	let moved_song = vfs
+		.virtual_to_real(Path::new(&playlist_content[0]))
+		.unwrap();
+	let moved_song = moved_song.to_str().unwrap().to_owned();
+	let deleted_song = vfs
+		.virtual_to_real(Path::new(&playlist_content[1]))
+		.unwrap();
+	let deleted_song = deleted_song.to_str().unwrap().to_owned();
+	{
+		let connection = db.get_connection();
+		diesel::update(songs::table.filter(songs::path.eq(&moved_song)))
+			.set(songs::path.eq(moved_song.replace("Khemmis", "Khemmis (Moved)")))
+			.execute(connection.deref())
+			.unwrap();
+	}
+
+	// Another song now has the exact same tags as a deleted one, but not its content
+	let lookalike_song = vfs
+		.virtual_to_real(Path::new(&playlist_content[2]))
+		.unwrap();
+	let lookalike_song = lookalike_song.to_str().unwrap().to_owned();
+	{
+		let connection = db.get_connection();
+		let deleted: Song = songs::table
+			.filter(songs::path.eq(&deleted_song))
+			.get_result(connection.deref())
+			.unwrap();
+		diesel::delete(songs::table.filter(songs::path.eq(&deleted_song)))
+			.execute(connection.deref())
+			.unwrap();
+		diesel::update(songs::table.filter(songs::path.eq(&lookalike_song)))
+			.set((
+				songs::title.eq(&deleted.title),
+				songs::track_number.eq(deleted.track_number),
+				songs::duration.eq(deleted.duration),
+				songs::file_size.eq(deleted.file_size),
+			))
+			.execute(connection.deref())
+			.unwrap();
+	}
+
+	relink(&db).unwrap();
+
+	let songs = read_playlist("chill_and_grill", "test_user", "test_user", &db).unwrap();
+	assert_eq!(songs.len(), 3);
+	assert_eq!(songs[0].title, Some("Above The Water".to_owned()));
+	assert!(songs[0].path.contains("Khemmis (Moved)"));
+
+	let missing_songs =
+		read_missing_songs("chill_and_grill", "test_user", "test_user", &db).unwrap();
+	assert_eq!(missing_songs.len(), 1);
+	assert_eq!(missing_songs[0].position, 1);
+	assert_eq!(missing_songs[0].path, Some(playlist_content[1].clone()));
+	assert!(missing_songs[0].title.is_some());
+}
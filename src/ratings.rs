@@ -0,0 +1,374 @@
+use core::ops::Deref;
+use diesel;
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+use error_chain::bail;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+
+#[cfg(test)]
+use crate::db;
+use crate::db::ConnectionSource;
+use crate::db::{directories, ratings, songs, users};
+use crate::errors::*;
+use crate::index::{self, CollectionFile, Directory, Song};
+use crate::vfs::VFSSource;
+
+const RATINGS_LOOKUP_BUFFER_SIZE: usize = 500; // Targets looked up in each query
+pub const MAX_RATING: i32 = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+	Song,
+	Album,
+	Artist,
+}
+
+impl Kind {
+	fn name(self) -> &'static str {
+		match self {
+			Kind::Song => "song",
+			Kind::Album => "album",
+			Kind::Artist => "artist",
+		}
+	}
+}
+
+#[derive(Insertable)]
+#[table_name = "ratings"]
+struct NewRating {
+	user: i32,
+	kind: String,
+	target: String,
+	rating: Option<i32>,
+	starred: i32,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Starred {
+	pub songs: Vec<Song>,
+	pub albums: Vec<Directory>,
+	pub artists: Vec<String>,
+}
+
+fn find_user_id(connection: &SqliteConnection, username: &str) -> Result<i32> {
+	let id = users::table
+		.filter(users::name.eq(username))
+		.select(users::id)
+		.get_result(connection)?;
+	Ok(id)
+}
+
+// Songs and albums are identified by their real path, artists by their name
+fn get_target<T>(db: &T, kind: Kind, target: &str) -> Result<String>
+where
+	T: VFSSource,
+{
+	match kind {
+		Kind::Artist => Ok(target.to_owned()),
+		Kind::Song | Kind::Album => {
+			let vfs = db.get_vfs()?;
+			let real_path = vfs.virtual_to_real(Path::new(target))?;
+			Ok(real_path.to_string_lossy().into_owned())
+		}
+	}
+}
+
+fn load_ratings(
+	connection: &SqliteConnection,
+	user: i32,
+	kind: Kind,
+	targets: &[&String],
+) -> Result<HashMap<String, (Option<i32>, bool)>> {
+	let mut result = HashMap::new();
+	for chunk in targets.chunks(RATINGS_LOOKUP_BUFFER_SIZE) {
+		let chunk_ratings: Vec<(String, Option<i32>, i32)> = ratings::table
+			.filter(ratings::user.eq(user))
+			.filter(ratings::kind.eq(kind.name()))
+			.filter(ratings::target.eq_any(chunk))
+			.select((ratings::target, ratings::rating, ratings::starred))
+			.load(connection)?;
+		for (target, rating, starred) in chunk_ratings {
+			result.insert(target, (rating, starred != 0));
+		}
+	}
+	Ok(result)
+}
+
+fn write_rating(
+	connection: &SqliteConnection,
+	user: i32,
+	kind: Kind,
+	target: &str,
+	rating: Option<i32>,
+	starred: Option<bool>,
+) -> Result<()> {
+	let existing = load_ratings(connection, user, kind, &[&target.to_owned()])?
+		.remove(target)
+		.unwrap_or((None, false));
+
+	let new_rating = match rating {
+		// Songs keep an explicit 0 so that it overrides the rating found in their tags
+		Some(0) if kind != Kind::Song => None,
+		Some(r) => Some(r),
+		None => existing.0,
+	};
+	let new_starred = starred.unwrap_or(existing.1);
+
+	connection.transaction::<_, diesel::result::Error, _>(|| {
+		diesel::delete(
+			ratings::table
+				.filter(ratings::user.eq(user))
+				.filter(ratings::kind.eq(kind.name()))
+				.filter(ratings::target.eq(target)),
+		)
+		.execute(connection)?;
+		if new_rating.is_some() || new_starred {
+			diesel::insert_into(ratings::table)
+				.values(&NewRating {
+					user,
+					kind: kind.name().to_owned(),
+					target: target.to_owned(),
+					rating: new_rating,
+					starred: new_starred as i32,
+				})
+				.execute(connection)?;
+		}
+		Ok(())
+	})?;
+
+	Ok(())
+}
+
+// A rating of 0 clears the rating, even one read from tags. None leaves it unchanged
+pub fn set_rating<T>(
+	db: &T,
+	username: &str,
+	kind: Kind,
+	target: &str,
+	rating: Option<i32>,
+	starred: Option<bool>,
+) -> Result<()>
+where
+	T: ConnectionSource + VFSSource,
+{
+	if let Some(rating) = rating {
+		if rating < 0 || rating > MAX_RATING {
+			bail!(ErrorKind::InvalidRating(rating));
+		}
+	}
+
+	let target = get_target(db, kind, target)?;
+	let connection = db.get_connection();
+	let user = find_user_id(connection.deref(), username)?;
+	write_rating(connection.deref(), user, kind, &target, rating, starred)
+}
+
+// Stars songs identified by their real path, as found in the songs table
+pub fn star_songs<T>(db: &T, username: &str, real_paths: &[String]) -> Result<()>
+where
+	T: ConnectionSource,
+{
+	let connection = db.get_connection();
+	let user = find_user_id(connection.deref(), username)?;
+	for path in real_paths {
+		write_rating(connection.deref(), user, Kind::Song, path, None, Some(true))?;
+	}
+	Ok(())
+}
+
+pub fn annotate_songs<'a, T, I>(db: &T, username: &str, songs: I) -> Result<()>
+where
+	T: ConnectionSource + VFSSource,
+	I: IntoIterator<Item = &'a mut Song>,
+{
+	let vfs = db.get_vfs()?;
+	let songs: Vec<(String, &mut Song)> = songs
+		.into_iter()
+		.filter_map(|s| {
+			vfs.virtual_to_real(Path::new(&s.path))
+				.ok()
+				.map(|p| (p.to_string_lossy().into_owned(), s))
+		})
+		.collect();
+
+	let connection = db.get_connection();
+	let user = find_user_id(connection.deref(), username)?;
+	let paths: Vec<&String> = songs.iter().map(|(p, _)| p).collect();
+	let ratings = load_ratings(connection.deref(), user, Kind::Song, &paths)?;
+
+	for (path, song) in songs {
+		let (rating, starred) = ratings.get(&path).cloned().unwrap_or((None, false));
+		song.rating = match rating {
+			Some(0) => None,
+			Some(r) => Some(r),
+			None => song.rating,
+		};
+		song.starred = Some(starred);
+	}
+
+	Ok(())
+}
+
+pub fn annotate_directories<'a, T, I>(db: &T, username: &str, directories: I) -> Result<()>
+where
+	T: ConnectionSource + VFSSource,
+	I: IntoIterator<Item = &'a mut Directory>,
+{
+	let vfs = db.get_vfs()?;
+	let directories: Vec<(String, &mut Directory)> = directories
+		.into_iter()
+		.filter_map(|d| {
+			vfs.virtual_to_real(Path::new(&d.path))
+				.ok()
+				.map(|p| (p.to_string_lossy().into_owned(), d))
+		})
+		.collect();
+
+	let connection = db.get_connection();
+	let user = find_user_id(connection.deref(), username)?;
+	let paths: Vec<&String> = directories.iter().map(|(p, _)| p).collect();
+	let ratings = load_ratings(connection.deref(), user, Kind::Album, &paths)?;
+
+	for (path, directory) in directories {
+		let (rating, starred) = ratings.get(&path).cloned().unwrap_or((None, false));
+		directory.rating = rating;
+		directory.starred = Some(starred);
+	}
+
+	Ok(())
+}
+
+pub fn annotate_collection_files<T>(
+	db: &T,
+	username: &str,
+	files: &mut [CollectionFile],
+) -> Result<()>
+where
+	T: ConnectionSource + VFSSource,
+{
+	let mut songs = Vec::new();
+	let mut directories = Vec::new();
+	for file in files.iter_mut() {
+		match file {
+			CollectionFile::Song(s) => songs.push(s),
+			CollectionFile::Directory(d) => directories.push(d),
+		}
+	}
+	annotate_songs(db, username, songs)?;
+	annotate_directories(db, username, directories)
+}
+
+pub fn get_starred<T>(db: &T, username: &str) -> Result<Starred>
+where
+	T: ConnectionSource + VFSSource,
+{
+	let vfs = db.get_vfs()?;
+	let connection = db.get_connection();
+	let user = find_user_id(connection.deref(), username)?;
+
+	let starred_targets = |kind: Kind| {
+		ratings::table
+			.filter(ratings::user.eq(user))
+			.filter(ratings::kind.eq(kind.name()))
+			.filter(ratings::starred.ne(0))
+			.select(ratings::target)
+	};
+
+	let mut songs: Vec<Song> = songs::table
+		.filter(songs::path.eq_any(starred_targets(Kind::Song)))
+		.order(songs::path)
+		.load::<Song>(connection.deref())?
+		.into_iter()
+		.filter_map(|s| index::virtualize_song(&vfs, s))
+		.collect();
+
+	let mut albums: Vec<Directory> = directories::table
+		.filter(directories::path.eq_any(starred_targets(Kind::Album)))
+		.order(directories::path)
+		.load::<Directory>(connection.deref())?
+		.into_iter()
+		.filter_map(|d| index::virtualize_directory(&vfs, d))
+		.collect();
+
+	let artists: Vec<String> = starred_targets(Kind::Artist)
+		.order(ratings::target)
+		.load(connection.deref())?;
+
+	drop(connection);
+	annotate_songs(db, username, songs.iter_mut())?;
+	annotate_directories(db, username, albums.iter_mut())?;
+
+	Ok(Starred {
+		songs,
+		albums,
+		artists,
+	})
+}
+
+#[test]
+fn test_ratings() {
+	let db = db::_get_test_db("ratings.sqlite");
+	index::update(&db).unwrap();
+
+	let mut songs = index::flatten(&db, Path::new("root")).unwrap();
+	let song_path = songs[0].path.clone();
+	let album_path = Path::new(&song_path)
+		.parent()
+		.unwrap()
+		.to_string_lossy()
+		.into_owned();
+
+	set_rating(&db, "test_user", Kind::Song, &song_path, Some(4), None).unwrap();
+	set_rating(&db, "test_user", Kind::Album, &album_path, None, Some(true)).unwrap();
+	set_rating(&db, "test_user", Kind::Artist, "Khemmis", None, Some(true)).unwrap();
+	assert!(set_rating(&db, "test_user", Kind::Song, &song_path, Some(6), None).is_err());
+
+	annotate_songs(&db, "test_user", songs.iter_mut()).unwrap();
+	assert_eq!(songs[0].rating, Some(4));
+	assert_eq!(songs[0].starred, Some(false));
+	assert_eq!(songs[1].starred, Some(false));
+
+	set_rating(&db, "test_user", Kind::Song, &song_path, None, Some(true)).unwrap();
+	let starred = get_starred(&db, "test_user").unwrap();
+	assert_eq!(starred.songs.len(), 1);
+	assert_eq!(starred.songs[0].path, song_path);
+	assert_eq!(starred.songs[0].rating, Some(4));
+	assert_eq!(starred.albums.len(), 1);
+	assert_eq!(starred.albums[0].path, album_path);
+	assert_eq!(starred.albums[0].starred, Some(true));
+	assert_eq!(starred.artists, vec!["Khemmis".to_owned()]);
+
+	// Clearing a rating hides the one found in tags
+	songs[0].rating = Some(3);
+	set_rating(
+		&db,
+		"test_user",
+		Kind::Song,
+		&song_path,
+		Some(0),
+		Some(false),
+	)
+	.unwrap();
+	annotate_songs(&db, "test_user", songs.iter_mut()).unwrap();
+	assert_eq!(songs[0].rating, None);
+	assert_eq!(songs[0].starred, Some(false));
+
+	set_rating(
+		&db,
+		"test_user",
+		Kind::Album,
+		&album_path,
+		Some(0),
+		Some(false),
+	)
+	.unwrap();
+	let connection = db.get_connection();
+	let count: i64 = ratings::table
+		.filter(ratings::kind.eq("album"))
+		.count()
+		.get_result(connection.deref())
+		.unwrap();
+	assert_eq!(count, 0);
+}
@@ -0,0 +1,520 @@
+use core::ops::Deref;
+use diesel;
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+use log::{error, info};
+use std::cmp;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time;
+
+#[cfg(test)]
+use crate::db;
+use crate::db::ConnectionSource;
+use crate::db::{scrobble_queue, users};
+use crate::errors::*;
+use crate::index;
+use crate::lastfm;
+use crate::listenbrainz;
+use crate::user;
+use crate::vfs::VFSSource;
+
+const SCROBBLE_QUEUE_POLL_INTERVAL_SECONDS: u64 = 10;
+const SCROBBLE_QUEUE_MAX_RETRY_DELAY_SECONDS: i32 = 6 * 60 * 60;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Service {
+	LastFM,
+	ListenBrainz,
+}
+
+impl Service {
+	fn name(self) -> &'static str {
+		match self {
+			Service::LastFM => "lastfm",
+			Service::ListenBrainz => "listenbrainz",
+		}
+	}
+
+	fn from_name(name: &str) -> Option<Service> {
+		match name {
+			"lastfm" => Some(Service::LastFM),
+			"listenbrainz" => Some(Service::ListenBrainz),
+			_ => None,
+		}
+	}
+
+	fn batch_size(self) -> usize {
+		match self {
+			Service::LastFM => lastfm::LASTFM_MAX_SCROBBLES_PER_REQUEST,
+			Service::ListenBrainz => listenbrainz::LISTENBRAINZ_MAX_LISTENS_PER_REQUEST,
+		}
+	}
+}
+
+#[derive(Debug, Queryable)]
+pub struct QueuedScrobble {
+	id: i32,
+	service: String,
+	pub artist: Option<String>,
+	pub title: Option<String>,
+	pub album: Option<String>,
+	pub track_number: Option<i32>,
+	pub duration: Option<i32>,
+	pub played_at: i32,
+	attempts: i32,
+	username: String,
+}
+
+#[derive(Insertable)]
+#[table_name = "scrobble_queue"]
+struct NewScrobble {
+	user: i32,
+	service: String,
+	artist: Option<String>,
+	title: Option<String>,
+	album: Option<String>,
+	track_number: Option<i32>,
+	duration: Option<i32>,
+	played_at: i32,
+}
+
+fn now() -> Result<i32> {
+	let now = time::SystemTime::now().duration_since(time::UNIX_EPOCH)?;
+	Ok(now.as_secs() as i32)
+}
+
+fn find_user_id(connection: &SqliteConnection, username: &str) -> Result<i32> {
+	let id = users::table
+		.filter(users::name.eq(username))
+		.select(users::id)
+		.get_result(connection)?;
+	Ok(id)
+}
+
+pub fn enqueue_plays<T>(
+	db: &T,
+	username: &str,
+	service: Service,
+	plays: &[(PathBuf, i64)],
+) -> Result<()>
+where
+	T: ConnectionSource + VFSSource,
+{
+	let user;
+	{
+		let connection = db.get_connection();
+		user = find_user_id(connection.deref(), username)?;
+	}
+
+	let mut new_scrobbles = Vec::new();
+	for (track, played_at) in plays {
+		// Songs that left the collection cannot be described anymore
+		if let Ok(song) = index::get_song(db, track) {
+			new_scrobbles.push(NewScrobble {
+				user,
+				service: service.name().to_owned(),
+				artist: song.artist,
+				title: song.title,
+				album: song.album,
+				track_number: song.track_number,
+				duration: song.duration,
+				played_at: *played_at as i32,
+			});
+		}
+	}
+
+	let connection = db.get_connection();
+	diesel::insert_into(scrobble_queue::table)
+		.values(&new_scrobbles)
+		.execute(connection.deref())?;
+	Ok(())
+}
+
+pub fn enqueue<T>(db: &T, username: &str, track: &Path) -> Result<()>
+where
+	T: ConnectionSource + VFSSource,
+{
+	let plays = vec![(track.to_path_buf(), now()? as i64)];
+	if user::is_lastfm_linked(db, username)? {
+		enqueue_plays(db, username, Service::LastFM, &plays)?;
+	}
+	if user::is_listenbrainz_linked(db, username)? {
+		enqueue_plays(db, username, Service::ListenBrainz, &plays)?;
+	}
+	Ok(())
+}
+
+// Now playing notifications are worthless once late, so they are sent once in the
+// background instead of being queued
+pub fn now_playing<T>(db: Arc<T>, username: &str, track: &Path) -> Result<()>
+where
+	T: ConnectionSource + VFSSource + Send + Sync + 'static,
+{
+	let lastfm_linked = user::is_lastfm_linked(db.deref(), username)?;
+	let listenbrainz_linked = user::is_listenbrainz_linked(db.deref(), username)?;
+	if !lastfm_linked && !listenbrainz_linked {
+		return Ok(());
+	}
+	let username = username.to_owned();
+	let track = track.to_path_buf();
+	thread::spawn(move || {
+		if lastfm_linked {
+			if let Err(e) = lastfm::now_playing(db.deref(), &username, &track) {
+				error!(
+					"Could not send now playing to lastfm for {}: {}",
+					username, e
+				);
+			}
+		}
+		if listenbrainz_linked {
+			if let Err(e) = listenbrainz::now_playing(db.deref(), &username, &track) {
+				error!(
+					"Could not send now playing to listenbrainz for {}: {}",
+					username, e
+				);
+			}
+		}
+	});
+	Ok(())
+}
+
+pub fn clear<T>(db: &T, username: &str, service: Service) -> Result<()>
+where
+	T: ConnectionSource,
+{
+	let connection = db.get_connection();
+	let user = find_user_id(connection.deref(), username)?;
+	diesel::delete(
+		scrobble_queue::table
+			.filter(scrobble_queue::user.eq(user))
+			.filter(scrobble_queue::service.eq(service.name())),
+	)
+	.execute(connection.deref())?;
+	Ok(())
+}
+
+fn submit<T>(db: &T, username: &str, service: Service, batch: &[QueuedScrobble]) -> Result<()>
+where
+	T: ConnectionSource,
+{
+	match service {
+		Service::LastFM => lastfm::submit_scrobbles(db, username, batch),
+		Service::ListenBrainz => listenbrainz::submit_listens(db, username, batch),
+	}
+}
+
+// Errors which retrying will not fix, like a revoked session or a track the service refuses
+fn is_permanent(error: &Error) -> bool {
+	match error.kind() {
+		ErrorKind::MissingLastFMCredentials | ErrorKind::MissingListenBrainzCredentials => true,
+		ErrorKind::LastFMRequestFailed(status) | ErrorKind::ListenBrainzRequestFailed(status) => {
+			*status >= 400 && *status < 500 && *status != 408 && *status != 429
+		}
+		_ => false,
+	}
+}
+
+fn remove(connection: &SqliteConnection, scrobbles: &[QueuedScrobble]) -> Result<()> {
+	let ids: Vec<i32> = scrobbles.iter().map(|s| s.id).collect();
+	diesel::delete(scrobble_queue::table.filter(scrobble_queue::id.eq_any(&ids)))
+		.execute(connection)?;
+	Ok(())
+}
+
+// Scrobbles are kept for as long as the service is unreachable, however long the outage
+fn postpone(connection: &SqliteConnection, now: i32, scrobble: &QueuedScrobble) -> Result<()> {
+	let attempts = scrobble.attempts + 1;
+	let delay = cmp::min(
+		60 << cmp::min(attempts, 16),
+		SCROBBLE_QUEUE_MAX_RETRY_DELAY_SECONDS,
+	);
+	diesel::update(scrobble_queue::table.find(scrobble.id))
+		.set((
+			scrobble_queue::attempts.eq(attempts),
+			scrobble_queue::next_attempt.eq(now + delay),
+		))
+		.execute(connection)?;
+	Ok(())
+}
+
+// Submits scrobbles one at a time to tell refused ones apart from the rest of their batch
+fn submit_each<T>(
+	db: &T,
+	now: i32,
+	username: &str,
+	service: Service,
+	batch: &[QueuedScrobble],
+) -> Result<()>
+where
+	T: ConnectionSource,
+{
+	for scrobble in batch {
+		let scrobble = std::slice::from_ref(scrobble);
+		let result = submit(db, username, service, scrobble);
+		let connection = db.get_connection();
+		match result {
+			Ok(()) => remove(connection.deref(), scrobble)?,
+			Err(ref e) if is_permanent(e) => {
+				error!(
+					"Dropping scrobble refused by {} for {}: {}",
+					service.name(),
+					username,
+					e
+				);
+				remove(connection.deref(), scrobble)?;
+			}
+			Err(_) => postpone(connection.deref(), now, &scrobble[0])?,
+		}
+	}
+	Ok(())
+}
+
+pub fn process<T>(db: &T) -> Result<()>
+where
+	T: ConnectionSource,
+{
+	let now = now()?;
+	let due: Vec<QueuedScrobble>;
+	{
+		let connection = db.get_connection();
+		due = scrobble_queue::table
+			.inner_join(users::table)
+			.filter(scrobble_queue::next_attempt.le(now))
+			.order(scrobble_queue::id)
+			.select((
+				scrobble_queue::id,
+				scrobble_queue::service,
+				scrobble_queue::artist,
+				scrobble_queue::title,
+				scrobble_queue::album,
+				scrobble_queue::track_number,
+				scrobble_queue::duration,
+				scrobble_queue::played_at,
+				scrobble_queue::attempts,
+				users::name,
+			))
+			.load(connection.deref())?;
+	}
+
+	// Group scrobbles by destination, preserving their order
+	let mut destinations: Vec<(String, Service, Vec<QueuedScrobble>)> = Vec::new();
+	for scrobble in due {
+		let service = match Service::from_name(&scrobble.service) {
+			Some(s) => s,
+			None => continue,
+		};
+		match destinations
+			.iter_mut()
+			.find(|(u, s, _)| *u == scrobble.username && *s == service)
+		{
+			Some((_, _, scrobbles)) => scrobbles.push(scrobble),
+			None => destinations.push((scrobble.username.clone(), service, vec![scrobble])),
+		}
+	}
+
+	for (username, service, scrobbles) in destinations {
+		let batch_size = service.batch_size();
+		for (batch_index, batch) in scrobbles.chunks(batch_size).enumerate() {
+			match submit(db, &username, service, batch) {
+				Ok(()) => {
+					let connection = db.get_connection();
+					remove(connection.deref(), batch)?;
+					info!(
+						"Submitted {} scrobbles to {} for {}",
+						batch.len(),
+						service.name(),
+						username
+					);
+				}
+				Err(e) => {
+					error!(
+						"Could not submit scrobbles to {} for {}: {}",
+						service.name(),
+						username,
+						e
+					);
+					if is_permanent(&e) {
+						if batch.len() > 1 {
+							submit_each(db, now, &username, service, batch)?;
+						} else {
+							let connection = db.get_connection();
+							remove(connection.deref(), batch)?;
+						}
+						continue;
+					}
+					// Later batches would likely fail the same way
+					let connection = db.get_connection();
+					for scrobble in &scrobbles[batch_index * batch_size..] {
+						postpone(connection.deref(), now, scrobble)?;
+					}
+					break;
+				}
+			}
+		}
+	}
+
+	Ok(())
+}
+
+pub fn run<T>(db: &T)
+where
+	T: ConnectionSource,
+{
+	loop {
+		if let Err(e) = process(db) {
+			error!("Error while processing scrobble queue: {}", e);
+		}
+		thread::sleep(time::Duration::from_secs(
+			SCROBBLE_QUEUE_POLL_INTERVAL_SECONDS,
+		));
+	}
+}
+
+#[test]
+fn test_process_queue() {
+	use crate::db::misc_settings;
+	use crate::test;
+
+	let db = db::_get_test_db("scrobble_queue.sqlite");
+	index::update(&db).unwrap();
+	user::listenbrainz_link(&db, "test_user", "some-token", 0).unwrap();
+
+	let songs = index::flatten(&db, Path::new("root")).unwrap();
+	let plays = vec![
+		(PathBuf::from(&songs[0].path), 1_500_000_000),
+		(PathBuf::from(&songs[1].path), 1_500_000_300),
+	];
+	enqueue_plays(&db, "test_user", Service::ListenBrainz, &plays).unwrap();
+
+	let queue_length = |db: &db::DB| -> i64 {
+		let connection = db.get_connection();
+		scrobble_queue::table
+			.count()
+			.get_result(connection.deref())
+			.unwrap()
+	};
+
+	// Service is unreachable
+	{
+		let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let url = format!("http://{}", listener.local_addr().unwrap());
+		drop(listener);
+		let connection = db.get_connection();
+		diesel::update(misc_settings::table)
+			.set(misc_settings::listenbrainz_url.eq(&url))
+			.execute(connection.deref())
+			.unwrap();
+	}
+	process(&db).unwrap();
+	assert_eq!(queue_length(&db), 2);
+	{
+		let connection = db.get_connection();
+		let attempts: Vec<i32> = scrobble_queue::table
+			.select(scrobble_queue::attempts)
+			.load(connection.deref())
+			.unwrap();
+		assert_eq!(attempts, vec![1, 1]);
+	}
+
+	// Service is back up
+	let (url, requests) = test::start_test_server(1);
+	{
+		let connection = db.get_connection();
+		diesel::update(misc_settings::table)
+			.set(misc_settings::listenbrainz_url.eq(&url))
+			.execute(connection.deref())
+			.unwrap();
+		diesel::update(scrobble_queue::table)
+			.set(scrobble_queue::next_attempt.eq(0))
+			.execute(connection.deref())
+			.unwrap();
+	}
+	process(&db).unwrap();
+	assert_eq!(queue_length(&db), 0);
+
+	let request = requests.recv().unwrap();
+	assert!(request.contains(r#""listen_type":"import""#));
+	assert!(request.contains(r#""listened_at":1500000000"#));
+	assert!(request.contains(r#""listened_at":1500000300"#));
+}
+
+#[test]
+fn test_drop_failed_scrobbles() {
+	use crate::db::misc_settings;
+	use crate::test;
+
+	let db = db::_get_test_db("scrobble_queue_drop.sqlite");
+	index::update(&db).unwrap();
+	user::lastfm_link(&db, "test_user", "lastfm_user", "some-session-key").unwrap();
+
+	let songs = index::flatten(&db, Path::new("root")).unwrap();
+	let plays = vec![
+		(PathBuf::from(&songs[0].path), 1_500_000_000),
+		(PathBuf::from(&songs[1].path), 1_500_000_300),
+	];
+	enqueue_plays(&db, "test_user", Service::LastFM, &plays).unwrap();
+
+	let queue_length = |db: &db::DB| -> i64 {
+		let connection = db.get_connection();
+		scrobble_queue::table
+			.count()
+			.get_result(connection.deref())
+			.unwrap()
+	};
+
+	// The batch is refused, so scrobbles are sent again one by one
+	let (url, requests) = test::start_test_server_with_responses(vec![
+		(400, r#"{"error": 6}"#.to_owned()),
+		(200, "{}".to_owned()),
+		(400, r#"{"error": 6}"#.to_owned()),
+	]);
+	{
+		let connection = db.get_connection();
+		diesel::update(misc_settings::table)
+			.set(misc_settings::lastfm_url.eq(&url))
+			.execute(connection.deref())
+			.unwrap();
+	}
+	process(&db).unwrap();
+	assert_eq!(queue_length(&db), 0);
+
+	let request = requests.recv().unwrap();
+	assert!(request.starts_with("POST /"));
+	assert!(request.contains("method=track.scrobble"));
+	assert!(request.contains("timestamp%5B1%5D=1500000300"));
+	let request = requests.recv().unwrap();
+	assert!(request.contains("timestamp%5B0%5D=1500000000"));
+	let request = requests.recv().unwrap();
+	assert!(request.contains("timestamp%5B0%5D=1500000300"));
+
+	// Scrobbles which cannot reach the service are retried for as long as it takes
+	enqueue_plays(&db, "test_user", Service::LastFM, &plays[..1]).unwrap();
+	{
+		let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let url = format!("http://{}", listener.local_addr().unwrap());
+		drop(listener);
+		let connection = db.get_connection();
+		diesel::update(misc_settings::table)
+			.set(misc_settings::lastfm_url.eq(&url))
+			.execute(connection.deref())
+			.unwrap();
+		diesel::update(scrobble_queue::table)
+			.set(scrobble_queue::attempts.eq(100))
+			.execute(connection.deref())
+			.unwrap();
+	}
+	let before = now().unwrap();
+	process(&db).unwrap();
+	assert_eq!(queue_length(&db), 1);
+	let next_attempt: i32 = {
+		let connection = db.get_connection();
+		scrobble_queue::table
+			.select(scrobble_queue::next_attempt)
+			.get_result(connection.deref())
+			.unwrap()
+	};
+	assert!(next_attempt >= before + SCROBBLE_QUEUE_MAX_RETRY_DELAY_SECONDS);
+	assert!(next_attempt <= now().unwrap() + SCROBBLE_QUEUE_MAX_RETRY_DELAY_SECONDS);
+}
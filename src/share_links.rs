@@ -0,0 +1,586 @@
+use core::ops::Deref;
+use diesel;
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+use error_chain::bail;
+use rand;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time;
+
+#[cfg(test)]
+use crate::db;
+use crate::db::ConnectionSource;
+use crate::db::{directories, playlist_songs, playlists, share_links, songs, users};
+use crate::errors::*;
+use crate::index::{self, Song};
+use crate::playlist;
+use crate::user;
+use crate::vfs::VFSSource;
+
+const UNLOCK_MAX_ATTEMPTS: i32 = 5;
+const UNLOCK_BLOCK_DURATION: i32 = 60 * 5; // Seconds
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+	Song,
+	Directory,
+	Playlist,
+}
+
+impl Kind {
+	fn name(self) -> &'static str {
+		match self {
+			Kind::Song => "song",
+			Kind::Directory => "directory",
+			Kind::Playlist => "playlist",
+		}
+	}
+
+	fn from_name(name: &str) -> Option<Kind> {
+		match name {
+			"song" => Some(Kind::Song),
+			"directory" => Some(Kind::Directory),
+			"playlist" => Some(Kind::Playlist),
+			_ => None,
+		}
+	}
+}
+
+#[derive(Insertable)]
+#[table_name = "share_links"]
+struct NewShareLinkRow {
+	token: String,
+	owner: i32,
+	kind: String,
+	target: String,
+	created: i32,
+	expires: Option<i32>,
+	password_salt: Option<Vec<u8>>,
+	password_hash: Option<Vec<u8>>,
+}
+
+#[derive(Queryable)]
+struct ShareLinkRow {
+	owner: String,
+	kind: String,
+	target: String,
+	expires: Option<i32>,
+	password_salt: Option<Vec<u8>>,
+	password_hash: Option<Vec<u8>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NewShareLink {
+	pub kind: Kind,
+	pub target: String, // Virtual path, or playlist name
+	pub expires: Option<i32>,
+	pub password: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ShareLink {
+	pub token: String,
+	pub kind: Kind,
+	pub target: String,
+	pub created: i32,
+	pub expires: Option<i32>,
+	pub protected: bool,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct SharedContent {
+	pub kind: Kind,
+	pub name: String,
+	pub expires: Option<i32>,
+	pub songs: Vec<Song>,
+}
+
+// Share link that passed expiration and password checks
+pub struct OpenShareLink {
+	kind: Kind,
+	owner: String,
+	target: String, // Real path, or playlist name
+	expires: Option<i32>,
+}
+
+fn now() -> Result<i32> {
+	let now = time::SystemTime::now().duration_since(time::UNIX_EPOCH)?;
+	Ok(now.as_secs() as i32)
+}
+
+fn find_user_id(connection: &SqliteConnection, username: &str) -> Result<i32> {
+	let id = users::table
+		.filter(users::name.eq(username))
+		.select(users::id)
+		.get_result(connection)?;
+	Ok(id)
+}
+
+fn generate_token() -> String {
+	rand::random::<[u8; 16]>()
+		.iter()
+		.map(|b| format!("{:02x}", b))
+		.collect()
+}
+
+fn load_row(connection: &SqliteConnection, token: &str) -> Result<ShareLinkRow> {
+	let row: Option<ShareLinkRow> = share_links::table
+		.inner_join(users::table)
+		.filter(share_links::token.eq(token))
+		.select((
+			users::name,
+			share_links::kind,
+			share_links::target,
+			share_links::expires,
+			share_links::password_salt,
+			share_links::password_hash,
+		))
+		.get_result(connection)
+		.optional()?;
+
+	// Expired links are indistinguishable from revoked ones
+	match row {
+		Some(row) if row.expires.map_or(true, |e| e > now().unwrap_or(0)) => Ok(row),
+		_ => bail!(ErrorKind::ShareLinkNotFound),
+	}
+}
+
+// Songs and directories are identified by their real path, playlists by their name
+fn get_target<T>(db: &T, owner: i32, kind: Kind, target: &str) -> Result<String>
+where
+	T: ConnectionSource + VFSSource,
+{
+	let vfs = db.get_vfs()?;
+	let connection = db.get_connection();
+	match kind {
+		Kind::Song | Kind::Directory => {
+			let real_path = vfs.virtual_to_real(Path::new(target))?;
+			let real_path = real_path.to_string_lossy().into_owned();
+			let exists = if kind == Kind::Song {
+				songs::table
+					.filter(songs::path.eq(&real_path))
+					.select(songs::id)
+					.first::<i32>(connection.deref())
+					.optional()?
+					.is_some()
+			} else {
+				directories::table
+					.filter(directories::path.eq(&real_path))
+					.select(directories::id)
+					.first::<i32>(connection.deref())
+					.optional()?
+					.is_some()
+			};
+			if !exists {
+				bail!(ErrorKind::ShareLinkNotFound);
+			}
+			Ok(real_path)
+		}
+		Kind::Playlist => {
+			playlists::table
+				.filter(playlists::owner.eq(owner))
+				.filter(playlists::name.eq(target))
+				.select(playlists::id)
+				.first::<i32>(connection.deref())
+				.optional()?
+				.ok_or(ErrorKind::ShareLinkNotFound)?;
+			Ok(target.to_owned())
+		}
+	}
+}
+
+pub fn create<T>(db: &T, owner: &str, share_link: &NewShareLink) -> Result<ShareLink>
+where
+	T: ConnectionSource + VFSSource,
+{
+	let owner_id = find_user_id(db.get_connection().deref(), owner)?;
+	let target = get_target(db, owner_id, share_link.kind, &share_link.target)?;
+
+	let (password_salt, password_hash) = match share_link.password {
+		Some(ref password) if !password.is_empty() => {
+			let salt = rand::random::<[u8; 16]>().to_vec();
+			let hash = user::hash_password(&salt, password);
+			(Some(salt), Some(hash))
+		}
+		_ => (None, None),
+	};
+
+	let new_row = NewShareLinkRow {
+		token: generate_token(),
+		owner: owner_id,
+		kind: share_link.kind.name().to_owned(),
+		target,
+		created: now()?,
+		expires: share_link.expires,
+		password_salt,
+		password_hash,
+	};
+
+	let connection = db.get_connection();
+	diesel::insert_into(share_links::table)
+		.values(&new_row)
+		.execute(connection.deref())?;
+
+	Ok(ShareLink {
+		token: new_row.token,
+		kind: share_link.kind,
+		target: share_link.target.clone(),
+		created: new_row.created,
+		expires: new_row.expires,
+		protected: new_row.password_hash.is_some(),
+	})
+}
+
+pub fn list<T>(db: &T, owner: &str) -> Result<Vec<ShareLink>>
+where
+	T: ConnectionSource + VFSSource,
+{
+	let vfs = db.get_vfs()?;
+	let connection = db.get_connection();
+	let owner_id = find_user_id(connection.deref(), owner)?;
+
+	let rows: Vec<(String, String, String, i32, Option<i32>, Option<Vec<u8>>)> = share_links::table
+		.filter(share_links::owner.eq(owner_id))
+		.order(share_links::created)
+		.select((
+			share_links::token,
+			share_links::kind,
+			share_links::target,
+			share_links::created,
+			share_links::expires,
+			share_links::password_hash,
+		))
+		.load(connection.deref())?;
+
+	// Links to content outside of the current mount points are left out
+	let share_links = rows
+		.into_iter()
+		.filter_map(|(token, kind, target, created, expires, password_hash)| {
+			let kind = Kind::from_name(&kind)?;
+			let target = match kind {
+				Kind::Playlist => target,
+				Kind::Song | Kind::Directory => vfs
+					.real_to_virtual(Path::new(&target))
+					.ok()?
+					.to_string_lossy()
+					.into_owned(),
+			};
+			Some(ShareLink {
+				token,
+				kind,
+				target,
+				created,
+				expires,
+				protected: password_hash.is_some(),
+			})
+		})
+		.collect();
+
+	Ok(share_links)
+}
+
+pub fn delete<T>(db: &T, owner: &str, token: &str) -> Result<()>
+where
+	T: ConnectionSource,
+{
+	let connection = db.get_connection();
+	let owner_id = find_user_id(connection.deref(), owner)?;
+	let deleted = diesel::delete(
+		share_links::table
+			.filter(share_links::owner.eq(owner_id))
+			.filter(share_links::token.eq(token)),
+	)
+	.execute(connection.deref())?;
+	if deleted == 0 {
+		bail!(ErrorKind::ShareLinkNotFound);
+	}
+	Ok(())
+}
+
+pub fn delete_playlist_links(
+	connection: &SqliteConnection,
+	owner: i32,
+	playlist_name: &str,
+) -> Result<()> {
+	diesel::delete(
+		share_links::table
+			.filter(share_links::owner.eq(owner))
+			.filter(share_links::kind.eq(Kind::Playlist.name()))
+			.filter(share_links::target.eq(playlist_name)),
+	)
+	.execute(connection)?;
+	Ok(())
+}
+
+// Password protected links must be unlocked before they can be opened.
+// Too many wrong passwords block further attempts for a while.
+pub fn unlock<T>(db: &T, token: &str, password: &str) -> Result<()>
+where
+	T: ConnectionSource,
+{
+	let connection = db.get_connection();
+	let row = load_row(connection.deref(), token)?;
+	let (failed_attempts, blocked_until): (i32, Option<i32>) = share_links::table
+		.filter(share_links::token.eq(token))
+		.select((share_links::failed_attempts, share_links::blocked_until))
+		.get_result(connection.deref())?;
+	let now = now()?;
+	if blocked_until.map_or(false, |b| b > now) {
+		bail!(ErrorKind::ShareLinkUnlockBlocked);
+	}
+
+	if let (Some(hash), Some(salt)) = (row.password_hash, row.password_salt) {
+		if !user::verify_password(&hash, &salt, password) {
+			let failed_attempts = failed_attempts + 1;
+			let (failed_attempts, blocked_until) = if failed_attempts >= UNLOCK_MAX_ATTEMPTS {
+				(0, Some(now + UNLOCK_BLOCK_DURATION))
+			} else {
+				(failed_attempts, None)
+			};
+			diesel::update(share_links::table.filter(share_links::token.eq(token)))
+				.set((
+					share_links::failed_attempts.eq(failed_attempts),
+					share_links::blocked_until.eq(blocked_until),
+				))
+				.execute(connection.deref())?;
+			bail!(ErrorKind::IncorrectCredentials);
+		}
+	}
+
+	diesel::update(share_links::table.filter(share_links::token.eq(token)))
+		.set((
+			share_links::failed_attempts.eq(0),
+			share_links::blocked_until.eq(None::<i32>),
+		))
+		.execute(connection.deref())?;
+	Ok(())
+}
+
+pub fn open<T>(db: &T, token: &str, unlocked: bool) -> Result<OpenShareLink>
+where
+	T: ConnectionSource,
+{
+	let row = load_row(db.get_connection().deref(), token)?;
+	if row.password_hash.is_some() && !unlocked {
+		bail!(ErrorKind::ShareLinkLocked);
+	}
+	match Kind::from_name(&row.kind) {
+		Some(kind) => Ok(OpenShareLink {
+			kind,
+			owner: row.owner,
+			target: row.target,
+			expires: row.expires,
+		}),
+		None => bail!(ErrorKind::ShareLinkNotFound),
+	}
+}
+
+pub fn get_songs<T>(db: &T, share_link: &OpenShareLink) -> Result<Vec<Song>>
+where
+	T: ConnectionSource + VFSSource,
+{
+	match share_link.kind {
+		Kind::Playlist => {
+			playlist::read_playlist(&share_link.target, &share_link.owner, &share_link.owner, db)
+		}
+		Kind::Song | Kind::Directory => {
+			let vfs = db.get_vfs()?;
+			let virtual_path = vfs.real_to_virtual(Path::new(&share_link.target))?;
+			if share_link.kind == Kind::Song {
+				Ok(vec![index::get_song(db, &virtual_path)?])
+			} else {
+				index::flatten(db, &virtual_path)
+			}
+		}
+	}
+}
+
+pub fn get_content<T>(db: &T, share_link: &OpenShareLink) -> Result<SharedContent>
+where
+	T: ConnectionSource + VFSSource,
+{
+	let songs = get_songs(db, share_link)?;
+	let name = match share_link.kind {
+		Kind::Playlist => share_link.target.clone(),
+		Kind::Song | Kind::Directory => Path::new(&share_link.target)
+			.file_name()
+			.map(|n| n.to_string_lossy().into_owned())
+			.unwrap_or_default(),
+	};
+	Ok(SharedContent {
+		kind: share_link.kind,
+		name,
+		expires: share_link.expires,
+		songs,
+	})
+}
+
+// Only the shared songs and their artwork can be served
+pub fn contains<T>(db: &T, share_link: &OpenShareLink, virtual_path: &Path) -> Result<bool>
+where
+	T: ConnectionSource + VFSSource,
+{
+	let vfs = db.get_vfs()?;
+	let real_path = match vfs.virtual_to_real(virtual_path) {
+		Ok(p) => p.to_string_lossy().into_owned(),
+		Err(_) => return Ok(false),
+	};
+	let connection = db.get_connection();
+
+	let mut query = songs::table
+		.filter(songs::path.eq(&real_path).or(songs::artwork.eq(&real_path)))
+		.select(songs::id)
+		.into_boxed();
+	query = match share_link.kind {
+		Kind::Song => query.filter(songs::path.eq(&share_link.target)),
+		Kind::Directory => {
+			let descendants = index::get_descendants_pattern(Path::new(&share_link.target));
+			query.filter(songs::path.like(descendants).escape('\\'))
+		}
+		Kind::Playlist => {
+			let playlist_id: i32 = playlists::table
+				.inner_join(users::table)
+				.filter(users::name.eq(&share_link.owner))
+				.filter(playlists::name.eq(&share_link.target))
+				.select(playlists::id)
+				.get_result(connection.deref())?;
+			let playlist_paths = playlist_songs::table
+				.filter(playlist_songs::playlist.eq(playlist_id))
+				.select(playlist_songs::path);
+			query.filter(songs::path.eq_any(playlist_paths))
+		}
+	};
+
+	let song = query.first::<i32>(connection.deref()).optional()?;
+	Ok(song.is_some())
+}
+
+#[test]
+fn test_share_directory() {
+	let db = db::_get_test_db("share_directory.sqlite");
+	index::update(&db).unwrap();
+
+	let new_link = NewShareLink {
+		kind: Kind::Directory,
+		target: "root/Khemmis".into(),
+		expires: None,
+		password: None,
+	};
+	let link = create(&db, "test_user", &new_link).unwrap();
+	assert_eq!(link.target, "root/Khemmis");
+	assert!(!link.protected);
+	assert_eq!(list(&db, "test_user").unwrap(), vec![link.clone()]);
+
+	let open_link = open(&db, &link.token, false).unwrap();
+	let content = get_content(&db, &open_link).unwrap();
+	assert_eq!(content.name, "Khemmis");
+	assert_eq!(content.songs.len(), 5);
+
+	let song = Path::new("root/Khemmis/Hunted/02 - Candlelight.mp3");
+	let artwork = Path::new("root/Khemmis/Hunted/Folder.jpg");
+	let outsider = Path::new("root/Tobokegao/Picnic/Folder.png");
+	assert!(contains(&db, &open_link, song).unwrap());
+	assert!(contains(&db, &open_link, artwork).unwrap());
+	assert!(!contains(&db, &open_link, outsider).unwrap());
+
+	delete(&db, "test_user", &link.token).unwrap();
+	assert!(open(&db, &link.token, false).is_err());
+	assert!(delete(&db, "test_user", &link.token).is_err());
+}
+
+#[test]
+fn test_share_link_restrictions() {
+	let db = db::_get_test_db("share_link_restrictions.sqlite");
+	index::update(&db).unwrap();
+
+	let song = "root/Khemmis/Hunted/02 - Candlelight.mp3";
+	let protected_link = create(
+		&db,
+		"test_user",
+		&NewShareLink {
+			kind: Kind::Song,
+			target: song.into(),
+			expires: None,
+			password: Some("hunter2".into()),
+		},
+	)
+	.unwrap();
+	assert!(protected_link.protected);
+	assert!(open(&db, &protected_link.token, false).is_err());
+	assert!(unlock(&db, &protected_link.token, "wrong").is_err());
+	unlock(&db, &protected_link.token, "hunter2").unwrap();
+	let open_link = open(&db, &protected_link.token, true).unwrap();
+	assert_eq!(get_songs(&db, &open_link).unwrap().len(), 1);
+
+	// Repeated wrong passwords block unlocking, even with the right password
+	for _ in 0..UNLOCK_MAX_ATTEMPTS {
+		match unlock(&db, &protected_link.token, "wrong") {
+			Err(Error(ErrorKind::IncorrectCredentials, _)) => (),
+			_ => panic!("Expected incorrect credentials"),
+		}
+	}
+	match unlock(&db, &protected_link.token, "hunter2") {
+		Err(Error(ErrorKind::ShareLinkUnlockBlocked, _)) => (),
+		_ => panic!("Expected unlocking to be blocked"),
+	}
+
+	let expired_link = create(
+		&db,
+		"test_user",
+		&NewShareLink {
+			kind: Kind::Song,
+			target: song.into(),
+			expires: Some(now().unwrap() - 1),
+			password: None,
+		},
+	)
+	.unwrap();
+	assert!(open(&db, &expired_link.token, false).is_err());
+
+	let missing_playlist = NewShareLink {
+		kind: Kind::Playlist,
+		target: "missing".into(),
+		expires: None,
+		password: None,
+	};
+	assert!(create(&db, "test_user", &missing_playlist).is_err());
+}
+
+#[test]
+fn test_share_directory_siblings() {
+	use std::fs;
+
+	// Directory names which are prefixes of their siblings, or contain LIKE wildcards
+	let (db, collection_path) =
+		db::_get_test_db_with_collection("share_directory_siblings.sqlite", "share_directory_siblings");
+	for directory in &["Khemmis", "Khemmis Tribute", "Khem_is"] {
+		fs::create_dir_all(collection_path.join(directory)).unwrap();
+		fs::copy(
+			"test/sample.mp3",
+			collection_path.join(directory).join("01.mp3"),
+		)
+		.unwrap();
+	}
+	index::update(&db).unwrap();
+
+	for directory in &["Khemmis", "Khem_is"] {
+		let new_link = NewShareLink {
+			kind: Kind::Directory,
+			target: format!("root/{}", directory),
+			expires: None,
+			password: None,
+		};
+		let link = create(&db, "test_user", &new_link).unwrap();
+		let open_link = open(&db, &link.token, false).unwrap();
+		let songs = get_songs(&db, &open_link).unwrap();
+		let paths: Vec<&str> = songs.iter().map(|s| s.path.as_str()).collect();
+		assert_eq!(paths, vec![format!("root/{}/01.mp3", directory)]);
+
+		let song = format!("root/{}/01.mp3", directory);
+		assert!(contains(&db, &open_link, Path::new(&song)).unwrap());
+		let sibling = Path::new("root/Khemmis Tribute/01.mp3");
+		assert!(!contains(&db, &open_link, sibling).unwrap());
+	}
+}
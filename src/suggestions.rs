@@ -0,0 +1,296 @@
+use core::ops::Deref;
+use diesel;
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::collation;
+#[cfg(test)]
+use crate::db;
+use crate::db::ConnectionSource;
+use crate::db::{songs, suggestions};
+use crate::errors::*;
+
+const INSERT_BUFFER_SIZE: usize = 1000; // Insertions in each statement
+const MAX_TERMS_PER_VALUE: usize = 8;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+enum Kind {
+	Artist,
+	Album,
+	Title,
+}
+
+impl Kind {
+	fn name(self) -> &'static str {
+		match self {
+			Kind::Artist => "artist",
+			Kind::Album => "album",
+			Kind::Title => "title",
+		}
+	}
+}
+
+// Completions of a search prefix, most common ones first
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Suggestions {
+	pub artists: Vec<String>,
+	pub albums: Vec<String>,
+	pub titles: Vec<String>,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "suggestions"]
+struct NewSuggestion<'a> {
+	kind: &'static str,
+	value: &'a str,
+	term: String,
+	weight: i32,
+}
+
+// Prefixes of a value are looked up from the start of each of its words, so that
+// "hunt" completes to "The Hunted"
+fn get_terms(value: &str) -> Vec<String> {
+	let folded = collation::fold(value);
+	let mut terms = Vec::new();
+	let mut previous = None;
+	for (index, c) in folded.char_indices() {
+		let is_word_start = c.is_alphanumeric() && !previous.map_or(false, char::is_alphanumeric);
+		if is_word_start && terms.len() < MAX_TERMS_PER_VALUE {
+			terms.push(folded[index..].to_owned());
+		}
+		previous = Some(c);
+	}
+	terms
+}
+
+// Number of songs a value appears on, as counted while indexing
+fn count_songs(connection: &SqliteConnection, kind: Kind, value: &str) -> Result<i64> {
+	let query = songs::table.into_boxed();
+	let query = match kind {
+		Kind::Artist => query.filter(songs::artist.eq(value).or(songs::album_artist.eq(value))),
+		Kind::Album => query.filter(songs::album.eq(value)),
+		Kind::Title => query.filter(songs::title.eq(value)),
+	};
+	Ok(query.count().get_result(connection)?)
+}
+
+// Values seen while indexing, weighted by how many songs they appear on
+#[derive(Default)]
+pub struct SuggestionsBuilder {
+	weights: HashMap<(Kind, String), i32>,
+}
+
+impl SuggestionsBuilder {
+	fn add(&mut self, kind: Kind, value: &Option<String>) {
+		if let Some(value) = value.as_ref().filter(|v| !v.trim().is_empty()) {
+			*self.weights.entry((kind, value.clone())).or_insert(0) += 1;
+		}
+	}
+
+	fn touch(&mut self, kind: Kind, value: &Option<String>) {
+		if let Some(value) = value.as_ref().filter(|v| !v.trim().is_empty()) {
+			self.weights.entry((kind, value.clone())).or_insert(0);
+		}
+	}
+
+	pub fn add_song(
+		&mut self,
+		title: &Option<String>,
+		artist: &Option<String>,
+		album_artist: &Option<String>,
+		album: &Option<String>,
+	) {
+		self.add(Kind::Artist, artist);
+		if album_artist != artist {
+			self.add(Kind::Artist, album_artist);
+		}
+		self.add(Kind::Album, album);
+		self.add(Kind::Title, title);
+	}
+
+	// Values of songs leaving the index are recounted on the next partial flush
+	pub fn remove_song(
+		&mut self,
+		title: &Option<String>,
+		artist: &Option<String>,
+		album_artist: &Option<String>,
+		album: &Option<String>,
+	) {
+		self.touch(Kind::Artist, artist);
+		self.touch(Kind::Artist, album_artist);
+		self.touch(Kind::Album, album);
+		self.touch(Kind::Title, title);
+	}
+
+	// A complete index replaces all previous suggestions. Partial ones recount the
+	// values they saw from the songs table, since other songs may share them, and
+	// must be flushed after the songs themselves.
+	pub fn flush(&mut self, connection: &SqliteConnection, complete: bool) -> Result<()> {
+		connection.transaction::<_, Error, _>(|| {
+			if complete {
+				diesel::delete(suggestions::table).execute(connection)?;
+			} else {
+				for ((kind, value), weight) in self.weights.iter_mut() {
+					*weight = count_songs(connection, *kind, value)? as i32;
+					diesel::delete(
+						suggestions::table
+							.filter(suggestions::kind.eq(kind.name()))
+							.filter(suggestions::value.eq(value.as_str())),
+					)
+					.execute(connection)?;
+				}
+			}
+			let mut rows = Vec::with_capacity(INSERT_BUFFER_SIZE);
+			for ((kind, value), weight) in self.weights.iter().filter(|(_, w)| **w > 0) {
+				for term in get_terms(value) {
+					rows.push(NewSuggestion {
+						kind: kind.name(),
+						value,
+						term,
+						weight: *weight,
+					});
+				}
+				if rows.len() >= INSERT_BUFFER_SIZE {
+					diesel::insert_into(suggestions::table)
+						.values(&rows)
+						.execute(connection)?;
+					rows.clear();
+				}
+			}
+			diesel::insert_into(suggestions::table)
+				.values(&rows)
+				.execute(connection)?;
+			Ok(())
+		})?;
+		self.weights.clear();
+		Ok(())
+	}
+}
+
+fn get_values(
+	connection: &SqliteConnection,
+	kind: Kind,
+	prefix: &str,
+	count: i64,
+) -> Result<Vec<String>> {
+	use self::suggestions::dsl;
+	// Every term starting with the prefix sorts between these two bounds
+	let upper_bound = format!("{}{}", prefix, std::char::MAX);
+	let values = dsl::suggestions
+		.select(dsl::value)
+		.distinct()
+		.filter(dsl::kind.eq(kind.name()))
+		.filter(dsl::term.ge(prefix))
+		.filter(dsl::term.lt(upper_bound))
+		.order((dsl::weight.desc(), dsl::value.asc()))
+		.limit(count)
+		.load(connection)?;
+	Ok(values)
+}
+
+pub fn suggest<T>(db: &T, query: &str, count: i64) -> Result<Suggestions>
+where
+	T: ConnectionSource,
+{
+	let prefix = collation::fold(query.trim_start());
+	if prefix.is_empty() || count <= 0 {
+		return Ok(Suggestions::default());
+	}
+	let connection = db.get_connection();
+	let connection = connection.deref();
+	Ok(Suggestions {
+		artists: get_values(connection, Kind::Artist, &prefix, count)?,
+		albums: get_values(connection, Kind::Album, &prefix, count)?,
+		titles: get_values(connection, Kind::Title, &prefix, count)?,
+	})
+}
+
+#[test]
+fn test_get_terms() {
+	assert_eq!(
+		get_terms("The Hunted"),
+		vec!["the hunted".to_owned(), "hunted".to_owned()]
+	);
+	assert_eq!(get_terms("Beyoncé"), vec!["beyonce".to_owned()]);
+	assert!(get_terms("...").is_empty());
+}
+
+#[test]
+fn test_suggest() {
+	use crate::index;
+
+	let db = db::_get_test_db("suggestions.sqlite");
+	index::update(&db).unwrap();
+
+	let suggestions = suggest(&db, "KHE", 5).unwrap();
+	assert_eq!(suggestions.artists, vec!["Khemmis".to_owned()]);
+	assert!(suggestions.titles.is_empty());
+
+	// Prefixes are matched from the start of any word
+	let suggestions = suggest(&db, "gates", 5).unwrap();
+	assert_eq!(suggestions.titles, vec!["Three Gates".to_owned()]);
+	let suggestions = suggest(&db, "h", 5).unwrap();
+	assert_eq!(suggestions.albums, vec!["Hunted".to_owned()]);
+	assert_eq!(suggestions.titles, vec!["Hunted".to_owned()]);
+
+	// Equally common values are sorted by name
+	let suggestions = suggest(&db, "the", 2).unwrap();
+	assert_eq!(
+		suggestions.titles,
+		vec!["Above The Water".to_owned(), "Beyond The Door".to_owned()]
+	);
+
+	assert_eq!(suggest(&db, " ", 5).unwrap(), Suggestions::default());
+	assert_eq!(suggest(&db, "zzz", 5).unwrap(), Suggestions::default());
+}
+
+#[test]
+fn test_partial_flush() {
+	let db = db::_get_test_db("suggestions_partial_flush.sqlite");
+	let connection = db.get_connection();
+	let connection = connection.deref();
+	let add_song = |title: &str, artist: &str| {
+		diesel::insert_into(songs::table)
+			.values((
+				songs::path.eq(format!("/music/{}.mp3", title)),
+				songs::parent.eq("/music"),
+				songs::title.eq(title),
+				songs::artist.eq(artist),
+			))
+			.execute(connection)
+			.unwrap();
+		let mut builder = SuggestionsBuilder::default();
+		builder.add_song(&Some(title.into()), &Some(artist.into()), &None, &None);
+		builder.flush(connection, false).unwrap();
+	};
+	let get_weight = |value: &str| -> Option<i32> {
+		suggestions::table
+			.filter(suggestions::kind.eq("artist"))
+			.filter(suggestions::value.eq(value))
+			.select(suggestions::weight)
+			.first(connection)
+			.optional()
+			.unwrap()
+	};
+
+	// Values found again on partial flushes are counted once per song
+	add_song("Three Gates", "Khemmis");
+	add_song("Candlelight", "Khemmis");
+	assert_eq!(get_weight("Khemmis"), Some(2));
+
+	// Songs leaving the index lower the weight of their values, down to removal
+	let remove_song = |title: &str, artist: &str| {
+		diesel::delete(songs::table.filter(songs::title.eq(title)))
+			.execute(connection)
+			.unwrap();
+		let mut builder = SuggestionsBuilder::default();
+		builder.remove_song(&Some(title.into()), &Some(artist.into()), &None, &None);
+		builder.flush(connection, false).unwrap();
+	};
+	remove_song("Candlelight", "Khemmis");
+	assert_eq!(get_weight("Khemmis"), Some(1));
+	remove_song("Three Gates", "Khemmis");
+	assert_eq!(get_weight("Khemmis"), None);
+}
@@ -0,0 +1,127 @@
+use error_chain::bail;
+use std::path::{Path, PathBuf};
+
+#[cfg(test)]
+use crate::db;
+use crate::db::ConnectionSource;
+use crate::errors::*;
+use crate::index::{self, Song};
+use crate::metadata::{self, TagEdits};
+use crate::vfs::VFSSource;
+
+// Edits are written to the files, then read back into the index so it reflects
+// exactly what ended up on disk, overrides included. Directories pick up changes
+// on the next reindex.
+pub fn edit_songs<T>(db: &T, virtual_paths: &[String], edits: &TagEdits) -> Result<Vec<Song>>
+where
+	T: ConnectionSource + VFSSource,
+{
+	let vfs = db.get_vfs()?;
+
+	// Nothing is written unless every song can be edited
+	let mut real_paths: Vec<PathBuf> = Vec::new();
+	for virtual_path in virtual_paths {
+		index::get_song(db, Path::new(virtual_path))?;
+		let real_path = vfs.virtual_to_real(Path::new(virtual_path))?;
+		if !metadata::is_writable(&real_path) {
+			bail!(ErrorKind::UnsupportedTagWriting(virtual_path.clone()));
+		}
+		real_paths.push(real_path);
+	}
+
+	for real_path in &real_paths {
+		metadata::write(real_path, edits)?;
+		index::update_song(db, real_path)?;
+	}
+
+	virtual_paths
+		.iter()
+		.map(|p| index::get_song(db, Path::new(p)))
+		.collect()
+}
+
+#[test]
+fn test_edit_songs() {
+	use crate::index::SearchFilters;
+	use crate::overrides::{self, MetadataOverride};
+	use crate::suggestions;
+	use std::fs;
+
+	let (db, collection_path) = db::_get_test_db_with_collection("edit_songs.sqlite", "edit_songs");
+	fs::copy("test/sample.mp3", collection_path.join("sample.mp3")).unwrap();
+	fs::copy("test/sample.ogg", collection_path.join("sample.ogg")).unwrap();
+	let album_override = MetadataOverride {
+		path: "root/sample.mp3".into(),
+		album: Some("Overridden Album".into()),
+		..Default::default()
+	};
+	overrides::set(&db, &album_override).unwrap();
+	index::update(&db).unwrap();
+
+	let edits = TagEdits {
+		title: Some("NEW TITLE".into()),
+		genre: Some("Doom Metal".into()),
+		..Default::default()
+	};
+
+	// Batches including songs that cannot be written to are refused as a whole
+	let paths = vec!["root/sample.mp3".to_owned(), "root/sample.ogg".to_owned()];
+	assert!(edit_songs(&db, &paths, &edits).is_err());
+	let song = index::get_song(&db, Path::new("root/sample.mp3")).unwrap();
+	assert_eq!(song.title, Some("TEST TITLE".into()));
+
+	let songs = edit_songs(&db, &paths[0..1], &edits).unwrap();
+	assert_eq!(songs[0].title, Some("NEW TITLE".into()));
+	assert_eq!(songs[0].genre, Some("Doom Metal".into()));
+	assert_eq!(songs[0].artist, Some("TEST ARTIST".into()));
+	assert_eq!(songs[0].album, Some("Overridden Album".into()));
+
+	// Search and suggestions follow the new tags
+	let results = index::search(&db, "new title", &SearchFilters::default()).unwrap();
+	assert_eq!(results.len(), 1);
+	let suggestions = suggestions::suggest(&db, "new", 5).unwrap();
+	assert_eq!(suggestions.titles, vec!["NEW TITLE".to_owned()]);
+}
+
+#[test]
+fn test_edit_flac_and_ape_songs() {
+	use std::fs;
+
+	let (db, collection_path) = db::_get_test_db_with_collection(
+		"edit_flac_and_ape_songs.sqlite",
+		"edit_flac_and_ape_songs",
+	);
+	fs::copy("test/sample.flac", collection_path.join("sample.flac")).unwrap();
+
+	// Musepack files hold APE tags, which are appended to the file as needed
+	let mpc_path = collection_path.join("sample.mpc");
+	fs::write(&mpc_path, b"MPCK").unwrap();
+	let initial_tags = TagEdits {
+		title: Some("TEST TITLE".into()),
+		artist: Some("TEST ARTIST".into()),
+		..Default::default()
+	};
+	metadata::write(&mpc_path, &initial_tags).unwrap();
+	index::update(&db).unwrap();
+
+	let edits = TagEdits {
+		title: Some("NEW TITLE".into()),
+		year: Some(2016),
+		track_number: Some(7),
+		..Default::default()
+	};
+	let paths = vec!["root/sample.flac".to_owned(), "root/sample.mpc".to_owned()];
+	let songs = edit_songs(&db, &paths, &edits).unwrap();
+
+	for (song, real_path) in songs.iter().zip(&["sample.flac", "sample.mpc"]) {
+		assert_eq!(song.title, Some("NEW TITLE".into()));
+		assert_eq!(song.year, Some(2016));
+		assert_eq!(song.track_number, Some(7));
+		assert_eq!(song.artist, Some("TEST ARTIST".into()));
+
+		let tags = metadata::read(&collection_path.join(real_path)).unwrap();
+		assert_eq!(tags.title, Some("NEW TITLE".into()));
+		assert_eq!(tags.year, Some(2016));
+		assert_eq!(tags.track_number, Some(7));
+	}
+}
@@ -1,9 +1,13 @@
 use rocket;
 use rocket::local::Client;
 use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
 use std::ops::Deref;
 use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver};
 use std::sync::Arc;
+use std::thread;
 
 use crate::db::DB;
 use crate::index;
@@ -19,6 +23,11 @@ impl TestEnvironment {
 	pub fn update_index(&self) {
 		index::update(self.db.deref()).unwrap();
 	}
+
+	// Client with its own cookies, for requests made without logging in
+	pub fn new_client(&self) -> Client {
+		get_client(self.db.clone(), self.command_sender.clone())
+	}
 }
 
 impl Drop for TestEnvironment {
@@ -36,12 +45,19 @@ pub fn get_test_environment(db_name: &str) -> TestEnvironment {
 	}
 
 	let db = Arc::new(DB::new(&db_path).unwrap());
+	let command_sender = index::init(db.clone());
+	let client = get_client(db.clone(), command_sender.clone());
+	TestEnvironment {
+		client,
+		command_sender,
+		db,
+	}
+}
 
+fn get_client(db: Arc<DB>, command_sender: Arc<index::CommandSender>) -> Client {
 	let web_dir_path = PathBuf::from("web");
 	let mut swagger_dir_path = PathBuf::from("docs");
 	swagger_dir_path.push("swagger");
-	let command_sender = index::init(db.clone());
-
 	let server = server::get_server(
 		5050,
 		None,
@@ -50,14 +66,57 @@ pub fn get_test_environment(db_name: &str) -> TestEnvironment {
 		&web_dir_path,
 		"/swagger",
 		&swagger_dir_path,
-		db.clone(),
-		command_sender.clone(),
+		db,
+		command_sender,
 	)
 	.unwrap();
-	let client = Client::new(server).unwrap();
-	TestEnvironment {
-		client,
-		command_sender,
-		db,
-	}
+	Client::new(server).unwrap()
+}
+
+pub fn start_test_server(responses: usize) -> (String, Receiver<String>) {
+	let response_body =
+		r#"{"code": 200, "message": "Token valid.", "valid": true, "status": "ok"}"#;
+	start_test_server_with_responses(vec![(200, response_body.to_owned()); responses])
+}
+
+// Serves the given status codes and bodies in order, and forwards each request it received
+pub fn start_test_server_with_responses(
+	responses: Vec<(u16, String)>,
+) -> (String, Receiver<String>) {
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let url = format!("http://{}", listener.local_addr().unwrap());
+	let (sender, receiver) = channel();
+	thread::spawn(move || {
+		for (stream, (status, response_body)) in listener.incoming().zip(responses) {
+			let mut stream = stream.unwrap();
+			let mut reader = BufReader::new(stream.try_clone().unwrap());
+			let mut request = String::new();
+			let mut content_length = 0;
+			loop {
+				let mut line = String::new();
+				reader.read_line(&mut line).unwrap();
+				if line.to_lowercase().starts_with("content-length:") {
+					content_length = line[15..].trim().parse().unwrap();
+				}
+				request.push_str(&line);
+				if line == "\r\n" || line.is_empty() {
+					break;
+				}
+			}
+			let mut body = vec![0; content_length];
+			reader.read_exact(&mut body).unwrap();
+			request.push_str(&String::from_utf8(body).unwrap());
+
+			write!(
+				stream,
+				"HTTP/1.1 {} Test\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+				status,
+				response_body.len(),
+				response_body
+			)
+			.unwrap();
+			sender.send(request).unwrap();
+		}
+	});
+	(url, receiver)
 }
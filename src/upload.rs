@@ -0,0 +1,430 @@
+use crypto::digest::Digest;
+use crypto::md5::Md5;
+use error_chain::bail;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+use crate::cue;
+#[cfg(test)]
+use crate::db;
+use crate::db::ConnectionSource;
+use crate::errors::*;
+use crate::index;
+use crate::metadata;
+use crate::utils;
+use crate::vfs::VFSSource;
+
+const UPLOADS_PATH: &str = "uploads";
+const MAX_RENAME_ATTEMPTS: u32 = 1000;
+const PARTIAL_UPLOADS_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60); // Since the last chunk
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnConflict {
+	Rename,
+	Replace,
+	Reject,
+}
+
+impl OnConflict {
+	pub fn from_name(name: &str) -> Option<OnConflict> {
+		match name {
+			"rename" => Some(OnConflict::Rename),
+			"replace" => Some(OnConflict::Replace),
+			"reject" => Some(OnConflict::Reject),
+			_ => None,
+		}
+	}
+}
+
+impl Default for OnConflict {
+	fn default() -> OnConflict {
+		OnConflict::Rename
+	}
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct UploadStatus {
+	pub session: String,
+	pub path: String,
+	pub received: u64,
+	pub complete: bool,
+}
+
+// Uploads are only accepted for files the index knows what to do with
+fn is_supported(path: &Path) -> bool {
+	utils::get_audio_format(path).is_some() || utils::is_image(path) || cue::is_cue_sheet(path)
+}
+
+// Received files must also decode as what their extension claims
+fn is_valid_content(partial_path: &Path) -> bool {
+	if utils::get_audio_format(partial_path).is_some() {
+		metadata::read(partial_path).is_ok()
+	} else if utils::is_image(partial_path) {
+		image::open(partial_path).is_ok()
+	} else if cue::is_cue_sheet(partial_path) {
+		cue::read(partial_path)
+			.map(|sheet| !sheet.tracks.is_empty())
+			.unwrap_or(false)
+	} else {
+		false
+	}
+}
+
+fn check_path(virtual_path: &Path) -> Result<()> {
+	let is_valid = virtual_path.components().count() > 1
+		&& virtual_path.components().all(|c| match c {
+			Component::Normal(_) => true,
+			_ => false,
+		});
+	if !is_valid {
+		bail!(ErrorKind::InvalidUploadPath(
+			virtual_path.to_string_lossy().into_owned()
+		));
+	}
+	if !is_supported(virtual_path) {
+		bail!(ErrorKind::UnsupportedUploadFormat(
+			virtual_path.to_string_lossy().into_owned()
+		));
+	}
+	Ok(())
+}
+
+// Each upload gets its own session, so concurrent uploads to the same path do not
+// append to each other's data
+fn generate_session() -> String {
+	rand::random::<[u8; 16]>()
+		.iter()
+		.map(|b| format!("{:02x}", b))
+		.collect()
+}
+
+// Incomplete uploads are kept under the data root until their last chunk arrives.
+// Their names must not change across restarts, so that uploads can be resumed.
+// The extension is preserved so the file can be validated like any other.
+fn get_partial_path(virtual_path: &Path, session: &str) -> Result<PathBuf> {
+	let mut hasher = Md5::new();
+	hasher.input_str(session);
+	hasher.input_str("\n");
+	hasher.input_str(&virtual_path.to_string_lossy());
+	let extension = virtual_path
+		.extension()
+		.map(|e| e.to_string_lossy().to_lowercase())
+		.unwrap_or_default();
+
+	let mut partial_path = utils::get_data_root()?;
+	partial_path.push(UPLOADS_PATH);
+	fs::create_dir_all(&partial_path)?;
+	partial_path.push(format!("{}.{}", hasher.result_str(), extension));
+	Ok(partial_path)
+}
+
+// Uploads which were abandoned by their client would otherwise never be removed
+pub fn remove_stale_partials() -> Result<()> {
+	let mut directory = utils::get_data_root()?;
+	directory.push(UPLOADS_PATH);
+	remove_partials_older_than(&directory, PARTIAL_UPLOADS_MAX_AGE)
+}
+
+fn remove_partials_older_than(directory: &Path, max_age: Duration) -> Result<()> {
+	if !directory.exists() {
+		return Ok(());
+	}
+	for file in fs::read_dir(directory)? {
+		let path = file?.path();
+		let age = fs::metadata(&path)?
+			.modified()?
+			.elapsed()
+			.unwrap_or_default();
+		if age >= max_age {
+			info!("Removing abandoned upload {}", path.display());
+			fs::remove_file(&path)?;
+		}
+	}
+	Ok(())
+}
+
+fn get_received(partial_path: &Path) -> u64 {
+	fs::metadata(partial_path).map(|m| m.len()).unwrap_or(0)
+}
+
+// Finds a name that is not taken yet, eg. song (1).mp3
+fn get_free_path(real_path: &Path) -> Option<PathBuf> {
+	let stem = real_path
+		.file_stem()
+		.map(|s| s.to_string_lossy().into_owned())
+		.unwrap_or_default();
+	let extension = real_path
+		.extension()
+		.map(|e| e.to_string_lossy().into_owned())
+		.unwrap_or_default();
+	let mut candidate = real_path.to_path_buf();
+	for index in 1..=MAX_RENAME_ATTEMPTS {
+		candidate.set_file_name(format!("{} ({}).{}", stem, index, extension));
+		if !candidate.exists() {
+			return Some(candidate);
+		}
+	}
+	None
+}
+
+pub fn get_status(virtual_path: &Path, session: &str) -> Result<UploadStatus> {
+	check_path(virtual_path)?;
+	let partial_path = get_partial_path(virtual_path, session)?;
+	Ok(UploadStatus {
+		session: session.to_owned(),
+		path: virtual_path.to_string_lossy().into_owned(),
+		received: get_received(&partial_path),
+		complete: false,
+	})
+}
+
+// Chunks must be sent in order, starting where the previous one ended. The first
+// chunk is sent without a session and starts a new one.
+pub fn write_chunk<T, R>(
+	db: &T,
+	virtual_path: &Path,
+	session: Option<&str>,
+	offset: u64,
+	chunk: &mut R,
+) -> Result<UploadStatus>
+where
+	T: VFSSource,
+	R: Read,
+{
+	check_path(virtual_path)?;
+	db.get_vfs()?.virtual_to_real(virtual_path)?;
+
+	let session = session
+		.map(|s| s.to_owned())
+		.unwrap_or_else(generate_session);
+	let partial_path = get_partial_path(virtual_path, &session)?;
+	let received = get_received(&partial_path);
+	if offset != received {
+		bail!(ErrorKind::UploadOffsetMismatch(received));
+	}
+
+	let mut file = OpenOptions::new()
+		.create(true)
+		.append(true)
+		.open(&partial_path)?;
+	io::copy(chunk, &mut file)?;
+
+	Ok(UploadStatus {
+		session,
+		path: virtual_path.to_string_lossy().into_owned(),
+		received: get_received(&partial_path),
+		complete: false,
+	})
+}
+
+pub fn cancel(virtual_path: &Path, session: &str) -> Result<()> {
+	check_path(virtual_path)?;
+	let partial_path = get_partial_path(virtual_path, session)?;
+	if partial_path.exists() {
+		fs::remove_file(&partial_path)?;
+	}
+	Ok(())
+}
+
+// Moves a fully received file into the collection and indexes its directory
+pub fn complete<T>(
+	db: &T,
+	virtual_path: &Path,
+	session: &str,
+	on_conflict: OnConflict,
+) -> Result<UploadStatus>
+where
+	T: ConnectionSource + VFSSource,
+{
+	check_path(virtual_path)?;
+	let vfs = db.get_vfs()?;
+	let mut real_path = vfs.virtual_to_real(virtual_path)?;
+	let partial_path = get_partial_path(virtual_path, session)?;
+	if !partial_path.exists() {
+		bail!("No data received for this upload");
+	}
+	let received = get_received(&partial_path);
+
+	if !is_valid_content(&partial_path) {
+		fs::remove_file(&partial_path)?;
+		bail!(ErrorKind::UnsupportedUploadFormat(
+			virtual_path.to_string_lossy().into_owned()
+		));
+	}
+
+	if real_path.exists() {
+		match on_conflict {
+			OnConflict::Rename => {
+				real_path = get_free_path(&real_path).ok_or_else(|| {
+					ErrorKind::UploadConflict(virtual_path.to_string_lossy().into_owned())
+				})?
+			}
+			OnConflict::Replace => (),
+			OnConflict::Reject => bail!(ErrorKind::UploadConflict(
+				virtual_path.to_string_lossy().into_owned()
+			)),
+		}
+	}
+
+	let directory = real_path.parent().ok_or("Invalid upload path")?;
+	fs::create_dir_all(directory)?;
+	if fs::rename(&partial_path, &real_path).is_err() {
+		// The data root and the collection may live on different file systems
+		fs::copy(&partial_path, &real_path)?;
+		fs::remove_file(&partial_path)?;
+	}
+
+	index::update_directory(db, directory)?;
+
+	Ok(UploadStatus {
+		session: session.to_owned(),
+		path: vfs
+			.real_to_virtual(&real_path)?
+			.to_string_lossy()
+			.into_owned(),
+		received,
+		complete: true,
+	})
+}
+
+#[test]
+fn test_upload() {
+	let (db, collection_path) = db::_get_test_db_with_collection("upload.sqlite", "upload");
+	index::update(&db).unwrap();
+
+	let content = fs::read("test/sample.mp3").unwrap();
+	let (head, tail) = content.split_at(content.len() / 2);
+	let virtual_path = Path::new("root/Artist/Album/sample.mp3");
+
+	// Chunks are appended in order
+	let status = write_chunk(&db, virtual_path, None, 0, &mut &head[..]).unwrap();
+	let session = status.session;
+	assert!(write_chunk(&db, virtual_path, Some(&session), 0, &mut &tail[..]).is_err());
+	assert_eq!(
+		get_status(virtual_path, &session).unwrap().received,
+		head.len() as u64
+	);
+
+	// Other sessions uploading to the same path do not interfere
+	let other = write_chunk(&db, virtual_path, None, 0, &mut &tail[..]).unwrap();
+	assert_ne!(other.session, session);
+	assert_eq!(other.received, tail.len() as u64);
+	cancel(virtual_path, &other.session).unwrap();
+	assert_eq!(
+		get_status(virtual_path, &other.session).unwrap().received,
+		0
+	);
+
+	let status = write_chunk(
+		&db,
+		virtual_path,
+		Some(&session),
+		head.len() as u64,
+		&mut &tail[..],
+	)
+	.unwrap();
+	assert_eq!(status.received, content.len() as u64);
+
+	// Completed uploads are indexed right away
+	let status = complete(&db, virtual_path, &session, OnConflict::Rename).unwrap();
+	assert_eq!(status.path, "root/Artist/Album/sample.mp3");
+	assert_eq!(
+		fs::read(collection_path.join("Artist/Album/sample.mp3")).unwrap(),
+		content
+	);
+	let song = index::get_song(&db, virtual_path).unwrap();
+	assert_eq!(song.title, Some("TEST TITLE".into()));
+	assert_eq!(
+		index::browse(&db, Path::new("root/Artist")).unwrap().len(),
+		1
+	);
+
+	// Existing files are renamed around, replaced or kept
+	let session = write_chunk(&db, virtual_path, None, 0, &mut &content[..])
+		.unwrap()
+		.session;
+	assert!(complete(&db, virtual_path, &session, OnConflict::Reject).is_err());
+	let status = complete(&db, virtual_path, &session, OnConflict::Rename).unwrap();
+	assert_eq!(status.path, "root/Artist/Album/sample (1).mp3");
+	assert!(index::get_song(&db, Path::new(&status.path)).is_ok());
+
+	assert!(get_status(Path::new("root/Artist/notes.txt"), &session).is_err());
+	assert!(get_status(Path::new("root/../sample.mp3"), &session).is_err());
+	assert!(get_status(Path::new("sample.mp3"), &session).is_err());
+}
+
+#[test]
+fn test_upload_content() {
+	let (db, collection_path) =
+		db::_get_test_db_with_collection("upload_content.sqlite", "upload_content");
+
+	// Files are refused when their content does not match their extension
+	let fake_files = vec!["root/Album/folder.png", "root/Album/album.cue"];
+	for fake_file in fake_files {
+		let virtual_path = Path::new(fake_file);
+		let session = write_chunk(&db, virtual_path, None, 0, &mut &b"not really"[..])
+			.unwrap()
+			.session;
+		assert!(complete(&db, virtual_path, &session, OnConflict::Rename).is_err());
+		assert!(!collection_path.join(&fake_file[5..]).exists());
+	}
+
+	let sheet = fs::read("test/cue/Hunted.cue").unwrap();
+	let virtual_path = Path::new("root/Album/album.cue");
+	let session = write_chunk(&db, virtual_path, None, 0, &mut &sheet[..])
+		.unwrap()
+		.session;
+	complete(&db, virtual_path, &session, OnConflict::Rename).unwrap();
+	assert!(collection_path.join("Album/album.cue").exists());
+}
+
+#[test]
+fn test_partial_path() {
+	let path = get_partial_path(Path::new("root/Album/sample.MP3"), "session").unwrap();
+	assert_eq!(
+		path.file_name().unwrap().to_string_lossy(),
+		"d3a18d51e941d05875916d564e106ae6.mp3"
+	);
+}
+
+#[test]
+fn test_remove_partials() {
+	let mut directory = utils::get_data_root().unwrap();
+	directory.push("test_remove_partials");
+	if directory.exists() {
+		fs::remove_dir_all(&directory).unwrap();
+	}
+	fs::create_dir_all(&directory).unwrap();
+	let partial_path = directory.join("partial.mp3");
+	fs::write(&partial_path, b"").unwrap();
+
+	remove_partials_older_than(&directory, Duration::from_secs(60)).unwrap();
+	assert!(partial_path.exists());
+	remove_partials_older_than(&directory, Duration::from_secs(0)).unwrap();
+	assert!(!partial_path.exists());
+
+	fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn test_get_free_path() {
+	let (_db, collection_path) =
+		db::_get_test_db_with_collection("upload_free_path.sqlite", "upload_free_path");
+	let real_path = collection_path.join("sample.mp3");
+	fs::write(&real_path, b"").unwrap();
+	fs::write(collection_path.join("sample (1).mp3"), b"").unwrap();
+	assert_eq!(
+		get_free_path(&real_path),
+		Some(collection_path.join("sample (2).mp3"))
+	);
+
+	for index in 2..=MAX_RENAME_ATTEMPTS {
+		fs::write(collection_path.join(format!("sample ({}).mp3", index)), b"").unwrap();
+	}
+	assert_eq!(get_free_path(&real_path), None);
+}
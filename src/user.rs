@@ -48,7 +48,7 @@ pub fn hash_password(salt: &[u8], password: &str) -> Vec<u8> {
 	hash.to_vec()
 }
 
-fn verify_password(
+pub fn verify_password(
 	password_hash: &Vec<u8>,
 	password_salt: &Vec<u8>,
 	attempted_password: &str,
@@ -134,6 +134,35 @@ where
 	}
 }
 
+pub fn get_lastfm_username<T>(db: &T, username: &str) -> Result<String>
+where
+	T: ConnectionSource,
+{
+	use crate::db::users::dsl::*;
+	let connection = db.get_connection();
+	let login = users
+		.filter(name.eq(username))
+		.select(lastfm_username)
+		.get_result(connection.deref())?;
+	match login {
+		Some(l) => Ok(l),
+		_ => bail!(ErrorKind::MissingLastFMCredentials),
+	}
+}
+
+pub fn is_lastfm_linked<T>(db: &T, username: &str) -> Result<bool>
+where
+	T: ConnectionSource,
+{
+	use crate::db::users::dsl::*;
+	let connection = db.get_connection();
+	let token: Option<String> = users
+		.filter(name.eq(username))
+		.select(lastfm_session_key)
+		.get_result(connection.deref())?;
+	Ok(token.map(|t| !t.is_empty()).unwrap_or(false))
+}
+
 pub fn lastfm_unlink<T>(db: &T, username: &str) -> Result<()>
 where
 	T: ConnectionSource,
@@ -145,3 +174,92 @@ where
 		.execute(connection.deref())?;
 	Ok(())
 }
+
+pub fn listenbrainz_link<T>(db: &T, username: &str, token: &str, linked_at: i32) -> Result<()>
+where
+	T: ConnectionSource,
+{
+	use crate::db::users::dsl::*;
+	let connection = db.get_connection();
+	diesel::update(users.filter(name.eq(username)))
+		.set((
+			listenbrainz_token.eq(token),
+			listenbrainz_linked_at.eq(linked_at),
+		))
+		.execute(connection.deref())?;
+	Ok(())
+}
+
+// Plays in this time range are not on ListenBrainz yet: later ones are submitted
+// as they happen, earlier ones were imported already
+pub fn get_listenbrainz_import_range<T>(db: &T, username: &str) -> Result<(i32, i32)>
+where
+	T: ConnectionSource,
+{
+	use crate::db::users::dsl::*;
+	let connection = db.get_connection();
+	let (imported_until, linked_at): (i32, Option<i32>) = users
+		.filter(name.eq(username))
+		.select((listenbrainz_imported_until, listenbrainz_linked_at))
+		.get_result(connection.deref())?;
+	match linked_at {
+		Some(linked_at) => Ok((imported_until, linked_at)),
+		None => bail!(ErrorKind::MissingListenBrainzCredentials),
+	}
+}
+
+pub fn set_listenbrainz_imported_until<T>(db: &T, username: &str, until: i32) -> Result<()>
+where
+	T: ConnectionSource,
+{
+	use crate::db::users::dsl::*;
+	let connection = db.get_connection();
+	diesel::update(users.filter(name.eq(username)))
+		.set(listenbrainz_imported_until.eq(until))
+		.execute(connection.deref())?;
+	Ok(())
+}
+
+pub fn get_listenbrainz_token<T>(db: &T, username: &str) -> Result<String>
+where
+	T: ConnectionSource,
+{
+	use crate::db::users::dsl::*;
+	let connection = db.get_connection();
+	let token = users
+		.filter(name.eq(username))
+		.select(listenbrainz_token)
+		.get_result(connection.deref())?;
+	match token {
+		Some(t) => Ok(t),
+		_ => bail!(ErrorKind::MissingListenBrainzCredentials),
+	}
+}
+
+pub fn is_listenbrainz_linked<T>(db: &T, username: &str) -> Result<bool>
+where
+	T: ConnectionSource,
+{
+	use crate::db::users::dsl::*;
+	let connection = db.get_connection();
+	let token: Option<String> = users
+		.filter(name.eq(username))
+		.select(listenbrainz_token)
+		.get_result(connection.deref())?;
+	Ok(token.is_some())
+}
+
+pub fn listenbrainz_unlink<T>(db: &T, username: &str) -> Result<()>
+where
+	T: ConnectionSource,
+{
+	use crate::db::users::dsl::*;
+	let connection = db.get_connection();
+	diesel::update(users.filter(name.eq(username)))
+		.set((
+			listenbrainz_token.eq(None::<String>),
+			listenbrainz_linked_at.eq(None::<i32>),
+		))
+		.execute(connection.deref())?;
+	Ok(())
+}
@@ -0,0 +1,220 @@
+use core::ops::Deref;
+use diesel::prelude::*;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::time::{self, SystemTime};
+
+use crate::db::ConnectionSource;
+use crate::db::{misc_settings, songs};
+use crate::decoding;
+use crate::errors::*;
+use crate::utils;
+
+const WAVEFORMS_PATH: &str = "waveforms";
+const PEAK_RESOLUTION: u64 = 64; // Frames summarized before splitting into buckets
+pub const DEFAULT_BUCKETS: usize = 1000;
+pub const MAX_BUCKETS: usize = 10_000;
+
+// Lowest and highest sample value within each bucket, between -1.0 and 1.0
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Waveform {
+	pub min: Vec<f32>,
+	pub max: Vec<f32>,
+}
+
+impl Waveform {
+	// Each bucket is a pair of signed bytes, min then max
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let to_byte = |v: f32| (v.max(-1.0).min(1.0) * 127.0).round() as i8 as u8;
+		self.min
+			.iter()
+			.zip(self.max.iter())
+			.flat_map(|(min, max)| vec![to_byte(*min), to_byte(*max)])
+			.collect()
+	}
+}
+
+pub fn is_enabled<T>(db: &T) -> Result<bool>
+where
+	T: ConnectionSource,
+{
+	let connection = db.get_connection();
+	let enabled: i32 = misc_settings::table
+		.select(misc_settings::precompute_waveforms)
+		.get_result(connection.deref())?;
+	Ok(enabled != 0)
+}
+
+// Section of the file in milliseconds, for songs coming from a CUE sheet
+pub type Section = (u32, Option<u32>);
+
+fn hash(path: &Path, modified: SystemTime, section: Option<Section>, buckets: usize) -> u64 {
+	let hash_input = format!(
+		"{}:{:?}:{:?}:{}",
+		path.to_string_lossy(),
+		modified,
+		section,
+		buckets
+	);
+	let mut hasher = DefaultHasher::new();
+	hash_input.hash(&mut hasher);
+	hasher.finish()
+}
+
+// Cached waveforms are invalidated when their file changes
+fn get_cache_path(path: &Path, section: Option<Section>, buckets: usize) -> Result<PathBuf> {
+	let modified = fs::metadata(path)?.modified()?;
+	let mut out_path = utils::get_data_root()?;
+	out_path.push(WAVEFORMS_PATH);
+	fs::create_dir_all(&out_path)?;
+	out_path.push(format!("{}.json", hash(path, modified, section, buckets)));
+	Ok(out_path)
+}
+
+fn compute(path: &Path, section: Option<Section>, buckets: usize) -> Result<Waveform> {
+	let (start, end) = section.unwrap_or((0, None));
+	let mut peaks: Vec<(f32, f32)> = Vec::new();
+	let mut position: u64 = 0; // In frames since the start of the section
+	let mut peak = (0.0f32, 0.0f32);
+	let mut pending = false;
+
+	decoding::decode_section(path, start, end, |format, samples| {
+		for frame in samples.chunks_exact(format.channels) {
+			for sample in frame {
+				peak = (peak.0.min(*sample), peak.1.max(*sample));
+			}
+			pending = true;
+			position += 1;
+			if position % PEAK_RESOLUTION == 0 {
+				peaks.push(peak);
+				peak = (0.0, 0.0);
+				pending = false;
+			}
+		}
+	})?;
+	if pending || peaks.is_empty() {
+		peaks.push(peak);
+	}
+
+	let mut waveform = Waveform {
+		min: Vec::with_capacity(buckets),
+		max: Vec::with_capacity(buckets),
+	};
+	for bucket in 0..buckets {
+		let from = bucket * peaks.len() / buckets;
+		let to = ((bucket + 1) * peaks.len() / buckets).max(from + 1);
+		let (min, max) = peaks[from..to]
+			.iter()
+			.fold((0.0f32, 0.0f32), |(min, max), p| {
+				(min.min(p.0), max.max(p.1))
+			});
+		waveform.min.push(min);
+		waveform.max.push(max);
+	}
+	Ok(waveform)
+}
+
+// Waveforms are cached under the data root, like thumbnails
+pub fn get_waveform(path: &Path, section: Option<Section>, buckets: usize) -> Result<Waveform> {
+	let out_path = get_cache_path(path, section, buckets)?;
+	if let Ok(content) = fs::read(&out_path) {
+		if let Ok(waveform) = serde_json::from_slice(&content) {
+			return Ok(waveform);
+		}
+	}
+
+	let waveform = compute(path, section, buckets)?;
+	fs::write(&out_path, serde_json::to_vec(&waveform)?)?;
+	Ok(waveform)
+}
+
+// Computes waveforms of all songs ahead of time, at the default resolution
+pub fn precompute<T>(db: &T) -> Result<()>
+where
+	T: ConnectionSource,
+{
+	let start = time::Instant::now();
+	let all_songs: Vec<(String, Option<String>, Option<i32>, Option<i32>)>;
+	{
+		let connection = db.get_connection();
+		all_songs = songs::table
+			.select((
+				songs::path,
+				songs::cue_source,
+				songs::cue_start,
+				songs::cue_end,
+			))
+			.load(connection.deref())?;
+	}
+
+	for (path, cue_source, cue_start, cue_end) in all_songs {
+		let (source, section) = match cue_source {
+			Some(source) => (
+				PathBuf::from(source),
+				Some((cue_start.unwrap_or(0) as u32, cue_end.map(|n| n as u32))),
+			),
+			None => (PathBuf::from(&path), None),
+		};
+		if !decoding::can_decode(&source) {
+			continue;
+		}
+		let is_cached = get_cache_path(&source, section, DEFAULT_BUCKETS)
+			.map(|p| p.exists())
+			.unwrap_or(false);
+		if is_cached {
+			continue;
+		}
+		if let Err(e) = get_waveform(&source, section, DEFAULT_BUCKETS) {
+			error!("Could not compute waveform of {}: {}", path, e);
+		}
+	}
+
+	info!(
+		"Waveform generation took {} seconds",
+		start.elapsed().as_secs()
+	);
+	Ok(())
+}
+
+#[test]
+fn test_get_waveform() {
+	let path = Path::new("test/sample.ogg");
+	let waveform = get_waveform(path, None, 20).unwrap();
+	assert_eq!(waveform.min.len(), 20);
+	assert_eq!(waveform.max.len(), 20);
+	assert!(waveform
+		.min
+		.iter()
+		.zip(waveform.max.iter())
+		.all(|(min, max)| *min >= -1.0 && min <= max && *max <= 1.0));
+
+	// Cached waveforms are returned as is
+	assert_eq!(get_waveform(path, None, 20).unwrap(), waveform);
+	assert_eq!(waveform.to_bytes().len(), 40);
+}
+
+#[test]
+fn test_get_section_waveform() {
+	// Each block of 4096 samples holds a constant value, from 100000 up to 800000 out of 2^23
+	let path = Path::new("test/cue/Hunted.flac");
+	let waveform = get_waveform(path, Some((1493, Some(3000))), 2).unwrap();
+	assert_eq!(waveform.min, vec![0.0, 0.0]);
+	let scale = (1 << 23) as f32;
+	assert!((waveform.max[0] - 500_000.0 / scale).abs() < 1e-6);
+	assert!((waveform.max[1] - 600_000.0 / scale).abs() < 1e-6);
+}
+
+#[test]
+fn test_hash_includes_modification_time() {
+	let path = Path::new("test/sample.ogg");
+	let modified = SystemTime::UNIX_EPOCH;
+	let later = modified + time::Duration::from_secs(1);
+	assert_ne!(
+		hash(path, modified, None, DEFAULT_BUCKETS),
+		hash(path, later, None, DEFAULT_BUCKETS)
+	);
+}
@@ -0,0 +1,13 @@
+REM DATE 2016
+PERFORMER "Khemmis"
+TITLE "Hunted"
+FILE "Hunted.flac" WAVE
+  TRACK 01 AUDIO
+    TITLE "Above The Water"
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    TITLE "Candlelight"
+    INDEX 01 00:01:37
+  TRACK 03 AUDIO
+    TITLE "Three Gates"
+    INDEX 01 00:03:00